@@ -0,0 +1,222 @@
+//! Backends of storage with an async client (feature `with-async`)
+//!
+//! Storage is reached by URL (see `Repo::open`); `register_async_backend`
+//! adds a URL scheme served by an `AsyncBackend`, the async counterpart of
+//! the backends built in. Its futures are run to completion on the IO
+//! threads of the repository, so they don't need any particular runtime,
+//! but can't rely on being polled by one either (eg. with the IO types of
+//! tokio: spawn the work on the runtime, and return its handle).
+use async_api::block_on;
+use sgdata::SGData;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+use super::{Backend, BackendThread, Lock, Metadata};
+
+/// Future of an `AsyncBackend` operation
+pub type BackendFuture<T> =
+    Pin<Box<dyn Future<Output = io::Result<T>> + Send>>;
+
+/// Storage of a repository, with async operations
+///
+/// Paths are relative to the repository, and operations on objects that
+/// don't exist fail with `io::ErrorKind::NotFound`. There's no locking (as
+/// with B2): a repository mustn't be garbage collected while written to.
+pub trait AsyncBackend: Send + Sync {
+    /// Data of the object at `path`
+    fn read(&self, path: PathBuf) -> BackendFuture<Vec<u8>>;
+
+    /// `len` bytes at `offset` of the object at `path`, failing with
+    /// `io::ErrorKind::UnexpectedEof` past its end
+    fn read_range(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> BackendFuture<Vec<u8>>;
+
+    /// Store `data` at `path`, replacing what's there
+    ///
+    /// With `idempotent`, what's there already has the same data, so it
+    /// doesn't need to be written again.
+    fn write(
+        &self,
+        path: PathBuf,
+        data: Vec<u8>,
+        idempotent: bool,
+    ) -> BackendFuture<()>;
+
+    fn remove(&self, path: PathBuf) -> BackendFuture<()>;
+
+    fn rename(&self, src_path: PathBuf, dst_path: PathBuf)
+        -> BackendFuture<()>;
+
+    /// Remove everything under `path`
+    fn remove_dir_all(&self, path: PathBuf) -> BackendFuture<()>;
+
+    /// Length of the object at `path`, in bytes
+    fn object_len(&self, path: PathBuf) -> BackendFuture<u64>;
+
+    /// Objects and directories right under `path` (none if there's nothing
+    /// there), by paths ending with their names
+    fn list(&self, path: PathBuf) -> BackendFuture<Vec<PathBuf>>;
+
+    /// Objects anywhere under `path`, by paths ending with their names
+    fn list_recursively(&self, path: PathBuf) -> BackendFuture<Vec<PathBuf>>;
+
+    /// Is every request a network round-trip (chunks are cached by
+    /// default if so)
+    fn remote(&self) -> bool {
+        true
+    }
+}
+
+type Factory =
+    dyn Fn(&Url) -> io::Result<Box<dyn AsyncBackend>> + Send + Sync;
+
+lazy_static! {
+    static ref SCHEMES: Mutex<HashMap<String, Arc<Factory>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Open repositories at URLs with `scheme` with backends made by `factory`
+///
+/// Registering a scheme again replaces its factory.
+pub fn register_async_backend<F>(scheme: &str, factory: F)
+where
+    F: Fn(&Url) -> io::Result<Box<dyn AsyncBackend>> + Send + Sync + 'static,
+{
+    SCHEMES
+        .lock()
+        .unwrap()
+        .insert(scheme.to_owned(), Arc::new(factory));
+}
+
+/// Backend for `url`, if its scheme is registered
+pub(crate) fn backend_from_url(
+    url: &Url,
+) -> io::Result<Option<Box<dyn Backend + Send + Sync>>> {
+    let factory = match SCHEMES.lock().unwrap().get(url.scheme()) {
+        Some(factory) => Arc::clone(factory),
+        None => return Ok(None),
+    };
+    let inner = factory(url)?;
+    Ok(Some(Box::new(Async {
+        inner: Arc::from(inner),
+    })))
+}
+
+struct Async {
+    inner: Arc<dyn AsyncBackend>,
+}
+
+struct NoLock;
+
+impl Lock for NoLock {}
+
+impl Backend for Async {
+    fn lock_exclusive(
+        &self,
+        _wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(NoLock))
+    }
+
+    fn lock_shared(
+        &self,
+        _wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(NoLock))
+    }
+
+    fn lock_audit(
+        &self,
+        _wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(NoLock))
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(AsyncThread {
+            inner: Arc::clone(&self.inner),
+        }))
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+}
+
+struct AsyncThread {
+    inner: Arc<dyn AsyncBackend>,
+}
+
+impl BackendThread for AsyncThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        block_on(self.inner.remove_dir_all(path))
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        block_on(self.inner.rename(src_path, dst_path))
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        block_on(self.inner.write(path, sg.to_linear_vec(), idempotent))
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        block_on(self.inner.read(path)).map(SGData::from_single)
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        block_on(self.inner.read_range(path, offset, len))
+            .map(SGData::from_single)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        block_on(self.inner.remove(path))
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let len = block_on(self.inner.object_len(path))?;
+        Ok(Metadata {
+            len,
+            _is_file: true,
+        })
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        block_on(self.inner.list(path))
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        match block_on(self.inner.list_recursively(path)) {
+            Ok(ref paths) if paths.is_empty() => {}
+            res => tx.send(res).expect("send failed"),
+        }
+    }
+}
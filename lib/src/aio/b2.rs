@@ -5,10 +5,11 @@ use serde_json;
 use sgdata::SGData;
 use std;
 use std::borrow::BorrowMut;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::io::Read;
 use std::path::PathBuf;
 use std::sync::mpsc;
+use std::time::Duration;
 use std::{fs, io};
 
 use aio;
@@ -56,6 +57,8 @@ struct B2Thread {
     auth: RefCell<Option<Auth>>,
     client: Client,
     bucket: String,
+    /// Retries since the last `take_counts`
+    retries: Cell<u64>,
 }
 
 /// Retry operations that can fail due to network/service issues
@@ -84,6 +87,9 @@ where
                         )
                     });
                 }
+                if let Some(instance) = instance {
+                    instance.retries.set(instance.retries.get() + 1);
+                }
 
                 if e.should_back_off() {
                     std::thread::sleep(std::time::Duration::from_secs(backoff));
@@ -132,6 +138,7 @@ impl B2Thread {
             client,
             auth: RefCell::new(None),
             bucket,
+            retries: Cell::new(0),
         };
 
         i.reauth()?;
@@ -141,20 +148,39 @@ impl B2Thread {
 }
 
 impl Backend for B2 {
-    fn lock_exclusive(&self) -> io::Result<Box<dyn aio::Lock>> {
+    fn lock_exclusive(
+        &self,
+        _wait: Option<Duration>,
+    ) -> io::Result<Box<dyn aio::Lock>> {
         Ok(Box::new(Lock::new(PathBuf::from(config::LOCK_FILE))))
     }
 
-    fn lock_shared(&self) -> io::Result<Box<dyn aio::Lock>> {
+    fn lock_shared(
+        &self,
+        _wait: Option<Duration>,
+    ) -> io::Result<Box<dyn aio::Lock>> {
         Ok(Box::new(Lock::new(PathBuf::from(config::LOCK_FILE))))
     }
 
+    fn lock_audit(
+        &self,
+        _wait: Option<Duration>,
+    ) -> io::Result<Box<dyn aio::Lock>> {
+        Ok(Box::new(Lock::new(
+            PathBuf::from(config::AUDIT_SUBDIR).join(config::AUDIT_LOCK_FILE),
+        )))
+    }
+
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
         Ok(Box::new(B2Thread::new_from_cred(
             &self.cred,
             self.bucket.clone(),
         )?))
     }
+
+    fn remote(&self) -> bool {
+        true
+    }
 }
 
 impl B2 {
@@ -211,6 +237,13 @@ impl BackendThread for B2Thread {
         unimplemented!();
     }
 
+    fn take_counts(&mut self) -> aio::BackendCounts {
+        aio::BackendCounts {
+            retries: self.retries.replace(0),
+            ..Default::default()
+        }
+    }
+
     fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
         let mut list: FileNameListing<serde_json::value::Value> =
             retry(Some(self), || {
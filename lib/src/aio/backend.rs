@@ -2,6 +2,18 @@ use sgdata::SGData;
 use std::io;
 use std::path::PathBuf;
 use std::sync::mpsc;
+use std::time::Duration;
+
+/// What a backend thread counted itself, see `BackendThread::take_counts`
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct BackendCounts {
+    /// Requests retried
+    pub retries: u64,
+    /// Requests answered from a cache
+    pub cache_hits: u64,
+    /// Requests a cache was checked for, but didn't have
+    pub cache_misses: u64,
+}
 
 /// A lock held on the backend
 ///
@@ -12,20 +24,69 @@ pub(crate) trait Lock {}
 ///
 /// Backend is thread-safe, and the actual work
 /// is implemented by per-thread instances of it.
+///
+/// There's no storage class (eg. of S3) to choose per object: none of the
+/// backends could apply one, and `write` doesn't tell index and data
+/// chunks apart, as they share a directory.
 pub(crate) trait Backend: Send + Sync {
     /// Lock the repository exclusively
     ///
     /// Use to protect operations that are potentially destructive,
-    /// like GC.
-    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>>;
+    /// like GC. Waits at most `wait` for the lock (forever if `None`),
+    /// then fails with `io::ErrorKind::TimedOut`.
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>>;
     /// Lock the repository in shared mode
     ///
     /// This will only prevent anyone from grabing exclusive lock.
     /// Use to protect operations that only add new data, like `write`.
-    fn lock_shared(&self) -> io::Result<Box<dyn Lock>>;
+    /// Waits like `lock_exclusive`.
+    fn lock_shared(&self, wait: Option<Duration>)
+        -> io::Result<Box<dyn Lock>>;
+    /// Lock the audit log exclusively, waiting like `lock_exclusive`
+    ///
+    /// Taken while appending an entry, so writers holding the repository
+    /// lock in shared mode don't number their entries the same.
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>>;
 
     /// Spawn a new thread object of the backend.
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>>;
+
+    /// Is the repository read-only (eg. on read-only media)
+    ///
+    /// Read-only repositories can be read, but nothing in them is modified,
+    /// not even as a side effect of reading.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Is every request a network round-trip
+    ///
+    /// Chunks are cached (see `Cached`) by default for remote backends.
+    fn remote(&self) -> bool {
+        false
+    }
+
+    /// Make everything stored read-only for the storage itself (eg. by
+    /// file permissions), so nothing can be modified even by mistake
+    fn restrict_permissions(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "restricting permissions is not supported by the backend",
+        ))
+    }
+
+    /// Temporary files left by writes that never completed (eg. of a
+    /// process killed while writing), removed unless `dry_run`
+    ///
+    /// Only to be used with the repository locked exclusively, so no write
+    /// is in progress. Paths are relative to the repository. Backends
+    /// writing objects at once leave none.
+    fn remove_temp_files(&self, _dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
 }
 
 pub(crate) trait BackendThread: Send {
@@ -46,8 +107,34 @@ pub(crate) trait BackendThread: Send {
 
     fn read(&mut self, path: PathBuf) -> io::Result<SGData>;
 
+    /// Read `len` bytes at `offset` of a file
+    ///
+    /// By default the whole file is read; backends should do better.
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let data = self.read(path)?.to_linear_vec();
+        match offset.checked_add(len) {
+            Some(end) if end <= data.len() as u64 => Ok(SGData::from_single(
+                data[offset as usize..end as usize].to_vec(),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of file",
+            )),
+        }
+    }
+
     fn remove(&mut self, path: PathBuf) -> io::Result<()>;
 
+    /// Counts since the last call
+    fn take_counts(&mut self) -> BackendCounts {
+        BackendCounts::default()
+    }
+
     fn read_metadata(&mut self, path: PathBuf) -> io::Result<super::Metadata>;
     fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>>;
 
@@ -0,0 +1,323 @@
+//! Chunk-caching backend wrapper
+//!
+//! Chunk files never change: a path holds the same data until it's removed
+//! (or renamed, by `gc`). So with backends where every request is a
+//! network round-trip, it pays to remember which chunks exist, and the
+//! data of the recently read ones. Everything else goes straight to the
+//! inner backend.
+//!
+//! Only positive answers are cached: a chunk missing now can be written
+//! by someone else at any time. The cache is cleared whenever a lock is
+//! taken, as chunks can be moved or removed by a `gc` done by someone else
+//! only while we don't hold any.
+// {{{ use and mod
+use config::DATA_SUBDIR;
+use sgdata::SGData;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use super::{Backend, BackendCounts, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+/// Chunk cache used by default for remote backends, in bytes
+pub(crate) const DEFAULT_CHUNK_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Default)]
+struct CacheInner {
+    /// Sizes of the chunks known to exist
+    exists: HashMap<PathBuf, u64>,
+    /// Data of the recently read chunks, with their last use
+    data: HashMap<PathBuf, (SGData, u64)>,
+    /// Paths in `data` by their last use
+    lru: BTreeMap<u64, PathBuf>,
+    /// Total size of `data`
+    size: u64,
+    /// Incremented on every use
+    tick: u64,
+}
+
+impl CacheInner {
+    fn touch(&mut self, path: &Path) -> Option<SGData> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.data.get_mut(path)?;
+        self.lru.remove(&entry.1);
+        self.lru.insert(tick, path.to_owned());
+        entry.1 = tick;
+        Some(entry.0.clone())
+    }
+
+    fn insert_data(&mut self, path: PathBuf, sg: SGData, capacity: u64) {
+        let len = sg.len() as u64;
+        if len > capacity {
+            return;
+        }
+        self.remove_data(&path);
+        while self.size + len > capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            let path = self.lru.remove(&oldest).expect("just found");
+            self.remove_data(&path);
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, path.clone());
+        self.data.insert(path, (sg, self.tick));
+        self.size += len;
+    }
+
+    fn remove_data(&mut self, path: &Path) {
+        if let Some((sg, tick)) = self.data.remove(path) {
+            self.lru.remove(&tick);
+            self.size -= sg.len() as u64;
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        self.exists.remove(path);
+        self.remove_data(path);
+    }
+}
+
+/// Cache shared by all threads of a `Cached` backend
+pub(crate) struct ChunkCache {
+    /// Maximum size of the cached data, in bytes
+    capacity: u64,
+    inner: Mutex<CacheInner>,
+}
+
+impl ChunkCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        ChunkCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Maximum size of the cached data, in bytes
+    pub(crate) fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn clear(&self) {
+        *self.inner.lock().unwrap() = CacheInner::default();
+    }
+}
+
+/// Is `path` a chunk file
+fn is_chunk(path: &Path) -> bool {
+    match path.parent() {
+        Some(dir) => dir.components().any(|c| c.as_os_str() == DATA_SUBDIR),
+        None => false,
+    }
+}
+
+pub(crate) struct Cached {
+    inner: Box<dyn Backend + Send + Sync>,
+    cache: Arc<ChunkCache>,
+}
+
+impl Cached {
+    pub(crate) fn new(
+        inner: Box<dyn Backend + Send + Sync>,
+        cache: Arc<ChunkCache>,
+    ) -> Self {
+        Cached { inner, cache }
+    }
+}
+
+struct CachedThread {
+    inner: Box<dyn BackendThread>,
+    cache: Arc<ChunkCache>,
+    counts: BackendCounts,
+}
+
+impl Backend for Cached {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        let lock = self.inner.lock_exclusive(wait)?;
+        self.cache.clear();
+        Ok(lock)
+    }
+
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        let lock = self.inner.lock_shared(wait)?;
+        self.cache.clear();
+        Ok(lock)
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_audit(wait)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(CachedThread {
+            inner: self.inner.new_thread()?,
+            cache: Arc::clone(&self.cache),
+            counts: BackendCounts::default(),
+        }))
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        self.inner.restrict_permissions()
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        self.inner.remove_temp_files(dry_run)
+    }
+}
+
+impl CachedThread {
+    fn cache(&self) -> ::std::sync::MutexGuard<'_, CacheInner> {
+        self.cache.inner.lock().unwrap()
+    }
+}
+
+impl BackendThread for CachedThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        {
+            let mut cache = self.cache();
+            let removed: Vec<_> = cache
+                .exists
+                .keys()
+                .chain(cache.data.keys())
+                .filter(|cached| cached.starts_with(&path))
+                .cloned()
+                .collect();
+            for cached in removed {
+                cache.remove(&cached);
+            }
+        }
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let len = {
+            let mut cache = self.cache();
+            let len = cache.exists.get(&src_path).cloned();
+            cache.remove(&src_path);
+            cache.remove(&dst_path);
+            len
+        };
+        self.inner.rename(src_path, dst_path.clone())?;
+        if let Some(len) = len {
+            if is_chunk(&dst_path) {
+                self.cache().exists.insert(dst_path, len);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        let len = sg.len() as u64;
+        self.inner.write(path.clone(), sg, idempotent)?;
+        if is_chunk(&path) {
+            let mut cache = self.cache();
+            cache.remove_data(&path);
+            cache.exists.insert(path, len);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        if !is_chunk(&path) {
+            return self.inner.read(path);
+        }
+        let cached = self.cache().touch(&path);
+        if let Some(sg) = cached {
+            self.counts.cache_hits += 1;
+            return Ok(sg);
+        }
+        self.counts.cache_misses += 1;
+        match self.inner.read(path.clone()) {
+            Ok(sg) => {
+                let mut cache = self.cache();
+                cache.exists.insert(path.clone(), sg.len() as u64);
+                cache.insert_data(path, sg.clone(), self.cache.capacity);
+                Ok(sg)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    self.cache().remove(&path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        self.inner.read_range(path, offset, len)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.cache().remove(&path);
+        self.inner.remove(path)
+    }
+
+    fn take_counts(&mut self) -> BackendCounts {
+        let mut counts = self.inner.take_counts();
+        counts.cache_hits += self.counts.cache_hits;
+        counts.cache_misses += self.counts.cache_misses;
+        self.counts = BackendCounts::default();
+        counts
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        if !is_chunk(&path) {
+            return self.inner.read_metadata(path);
+        }
+        let cached = self.cache().exists.get(&path).cloned();
+        if let Some(len) = cached {
+            self.counts.cache_hits += 1;
+            return Ok(Metadata {
+                len,
+                _is_file: true,
+            });
+        }
+        self.counts.cache_misses += 1;
+        let metadata = self.inner.read_metadata(path.clone())?;
+        self.cache().exists.insert(path, metadata.len);
+        Ok(metadata)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(path)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        self.inner.list_recursively(path, tx)
+    }
+}
@@ -0,0 +1,365 @@
+//! Failure-injecting backend wrapper
+//!
+//! Wraps any other backend and makes it misbehave in a configurable, but
+//! deterministic way: whether a given operation on a given path fails
+//! depends only on the seed, the kind of operation and the path, and not
+//! on timing or thread scheduling.
+// {{{ use and mod
+use error;
+use sgdata::SGData;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{Backend, BackendCounts, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+/// Failures to inject into backend operations
+///
+/// Each kind of failure is specified as "one in N" operations (per path).
+/// Partial writes and corrupt reads affect only chunks: metadata (`.yml`
+/// files) is left intact, so the repository stays navigable.
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjection {
+    seed: u64,
+    latency: Option<Duration>,
+    partial_writes: Option<u32>,
+    corrupt_reads: Option<u32>,
+    listing_gaps: Option<u32>,
+    transient_errors: Option<u32>,
+    space_limit: Option<u64>,
+    read_only: bool,
+}
+
+#[derive(Hash)]
+enum FaultKind {
+    PartialWrite,
+    CorruptRead,
+    ListingGap,
+    TransientError,
+}
+
+impl FaultInjection {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Seed deciding which paths are affected
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Delay every backend operation
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = Some(latency);
+    }
+
+    /// Silently store only the first half of the data written
+    pub fn set_partial_writes(&mut self, one_in: u32) {
+        self.partial_writes = Some(one_in);
+    }
+
+    /// Flip bits in the data being read
+    pub fn set_corrupt_reads(&mut self, one_in: u32) {
+        self.corrupt_reads = Some(one_in);
+    }
+
+    /// Omit entries from directory listings
+    pub fn set_listing_gaps(&mut self, one_in: u32) {
+        self.listing_gaps = Some(one_in);
+    }
+
+    /// Fail the first operation on a path with a timeout, letting the
+    /// next ones through, like a flaky network storage
+    pub fn set_transient_errors(&mut self, one_in: u32) {
+        self.transient_errors = Some(one_in);
+    }
+
+    /// Fail writes once `bytes` were written, like on a full disk
+    ///
+    /// Writes of all the threads of the backend count, so which write
+    /// fails first depends on their order.
+    pub fn set_space_limit(&mut self, bytes: u64) {
+        self.space_limit = Some(bytes);
+    }
+
+    /// Fail all modifying operations, like on read-only media
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "read-only repository (injected)",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn hits(&self, kind: FaultKind, path: &Path) -> bool {
+        let one_in = match kind {
+            FaultKind::PartialWrite => self.partial_writes,
+            FaultKind::CorruptRead => self.corrupt_reads,
+            FaultKind::ListingGap => self.listing_gaps,
+            FaultKind::TransientError => self.transient_errors,
+        };
+        let one_in = match one_in {
+            Some(0) | None => return false,
+            Some(n) => u64::from(n),
+        };
+
+        match kind {
+            FaultKind::PartialWrite | FaultKind::CorruptRead
+                if path.extension() == Some("yml".as_ref()) =>
+            {
+                return false
+            }
+            _ => {}
+        }
+
+        let mut hasher = DefaultHasher::new();
+        self.seed.hash(&mut hasher);
+        kind.hash(&mut hasher);
+        path.hash(&mut hasher);
+        hasher.finish() % one_in == 0
+    }
+
+    fn delay(&self) {
+        if let Some(latency) = self.latency {
+            thread::sleep(latency);
+        }
+    }
+}
+
+/// Flip bits in the middle of `sg`
+fn corrupt_data(sg: SGData) -> SGData {
+    let mut data = sg.to_linear_vec();
+    if data.is_empty() {
+        data.push(0xff);
+    } else {
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+    }
+    SGData::from_single(data)
+}
+
+pub(crate) struct Faulty {
+    inner: Box<dyn Backend + Send + Sync>,
+    faults: Arc<FaultInjection>,
+    /// Bytes written by all the threads, see `set_space_limit`
+    written: Arc<AtomicU64>,
+    /// Paths failed once already, see `set_transient_errors`
+    failed_once: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Faulty {
+    pub(crate) fn new(
+        inner: Box<dyn Backend + Send + Sync>,
+        faults: FaultInjection,
+    ) -> Self {
+        Faulty {
+            inner,
+            faults: Arc::new(faults),
+            written: Arc::new(AtomicU64::new(0)),
+            failed_once: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+}
+
+struct FaultyThread {
+    inner: Box<dyn BackendThread>,
+    faults: Arc<FaultInjection>,
+    written: Arc<AtomicU64>,
+    failed_once: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Backend for Faulty {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.faults.check_writable()?;
+        self.inner.lock_exclusive(wait)
+    }
+
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared(wait)
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.faults.check_writable()?;
+        self.inner.lock_audit(wait)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(FaultyThread {
+            inner: self.inner.new_thread()?,
+            faults: Arc::clone(&self.faults),
+            written: Arc::clone(&self.written),
+            failed_once: Arc::clone(&self.failed_once),
+        }))
+    }
+
+    fn read_only(&self) -> bool {
+        self.faults.read_only || self.inner.read_only()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        self.inner.restrict_permissions()
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        self.inner.remove_temp_files(dry_run)
+    }
+}
+
+impl FaultyThread {
+    /// Fail the first operation on `path`, if it's hit
+    fn transient_error(&self, path: &Path) -> io::Result<()> {
+        if self.faults.hits(FaultKind::TransientError, path)
+            && self.failed_once.lock().unwrap().insert(path.to_owned())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "operation timed out (injected)",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Count `len` bytes written, failing if they don't fit anymore
+    fn take_space(&self, len: u64) -> io::Result<()> {
+        let limit = match self.faults.space_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let written = self.written.fetch_add(len, Ordering::SeqCst);
+        if written + len > limit {
+            self.written.fetch_sub(len, Ordering::SeqCst);
+            return Err(io::Error::from_raw_os_error(
+                error::NO_SPACE_OS_ERROR,
+            ));
+        }
+        Ok(())
+    }
+
+    fn filter_listing(&self, mut list: Vec<PathBuf>) -> Vec<PathBuf> {
+        list.retain(|path| !self.faults.hits(FaultKind::ListingGap, path));
+        list
+    }
+}
+
+impl BackendThread for FaultyThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.faults.delay();
+        self.faults.check_writable()?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.faults.delay();
+        self.faults.check_writable()?;
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        self.faults.delay();
+        self.faults.check_writable()?;
+        self.transient_error(&path)?;
+        self.take_space(sg.len() as u64)?;
+        let sg = if self.faults.hits(FaultKind::PartialWrite, &path) {
+            let mut data = sg.to_linear_vec();
+            let half = data.len() / 2;
+            data.truncate(half);
+            SGData::from_single(data)
+        } else {
+            sg
+        };
+        self.inner.write(path, sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.faults.delay();
+        self.transient_error(&path)?;
+        let corrupt = self.faults.hits(FaultKind::CorruptRead, &path);
+        let sg = self.inner.read(path)?;
+        Ok(if corrupt { corrupt_data(sg) } else { sg })
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        self.faults.delay();
+        self.transient_error(&path)?;
+        let corrupt = self.faults.hits(FaultKind::CorruptRead, &path);
+        let sg = self.inner.read_range(path, offset, len)?;
+        Ok(if corrupt { corrupt_data(sg) } else { sg })
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.faults.delay();
+        self.faults.check_writable()?;
+        self.transient_error(&path)?;
+        self.inner.remove(path)
+    }
+
+    fn take_counts(&mut self) -> BackendCounts {
+        self.inner.take_counts()
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.faults.delay();
+        self.transient_error(&path)?;
+        self.inner.read_metadata(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.faults.delay();
+        self.transient_error(&path)?;
+        let list = self.inner.list(path)?;
+        Ok(self.filter_listing(list))
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        self.faults.delay();
+        let (inner_tx, inner_rx) = mpsc::channel();
+        self.inner.list_recursively(path, inner_tx);
+
+        for batch in inner_rx {
+            tx.send(batch.map(|list| self.filter_listing(list)))
+                .expect("send failed")
+        }
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -4,12 +4,13 @@ use rand::distributions::Alphanumeric;
 use rand::Rng;
 use INGRESS_BUFFER_SIZE;
 
-use fs2::FileExt;
+use fs2::{self, FileExt};
 use sgdata::SGData;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::{fs, io, mem};
+use std::time::{Duration, Instant};
+use std::{fs, io, mem, thread};
 use walkdir::WalkDir;
 
 use super::{Backend, BackendThread};
@@ -23,6 +24,71 @@ pub(crate) fn lock_file_path(path: &Path) -> PathBuf {
     path.join(config::LOCK_FILE)
 }
 
+/// Length of the random part of names of files being written
+const RAND_EXT_LEN: usize = 20;
+
+/// How often a lock is retried when waiting for it with a timeout
+const LOCK_POLL_INTERVAL_MS: u64 = 100;
+
+/// Open a lock file for writing, creating it if needed
+///
+/// Not truncating it: on Windows that fails while another process has it
+/// locked, instead of waiting for the lock.
+fn create_lock_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Open (creating if possible) a lock file
+///
+/// On read-only media, the lock file can't be created, but locking an
+/// existing one works.
+fn open_lock_file(path: &Path) -> io::Result<fs::File> {
+    create_lock_file(path).or_else(|_| fs::File::open(path))
+}
+
+/// Lock `file`, waiting until `deadline` (forever if `None`)
+fn lock_file(
+    file: &fs::File,
+    exclusive: bool,
+    deadline: Option<Instant>,
+) -> io::Result<()> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None if exclusive => return FileExt::lock_exclusive(file),
+        None => return FileExt::lock_shared(file),
+    };
+    loop {
+        let res = if exclusive {
+            FileExt::try_lock_exclusive(file)
+        } else {
+            FileExt::try_lock_shared(file)
+        };
+        match res {
+            Err(ref e)
+                if e.kind() == fs2::lock_contended_error().kind() =>
+            {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for the repository lock",
+                    ));
+                }
+                thread::sleep(
+                    (deadline - now)
+                        .min(Duration::from_millis(LOCK_POLL_INTERVAL_MS)),
+                );
+            }
+            res => return res,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Local {
     path: PathBuf,
@@ -35,20 +101,33 @@ struct LocalThread {
 }
 
 impl Backend for Local {
-    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
         let lock_path = lock_file_path(&self.path);
 
-        let file = fs::File::create(&lock_path)?;
-        file.lock_exclusive()?;
+        let file = create_lock_file(&lock_path)?;
+        self.lock_queued(&file, true, wait)?;
 
         Ok(Box::new(file))
     }
 
-    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
         let lock_path = lock_file_path(&self.path);
 
-        let file = fs::File::create(&lock_path)?;
-        file.lock_shared()?;
+        let file = open_lock_file(&lock_path)?;
+        self.lock_queued(&file, false, wait)?;
+
+        Ok(Box::new(file))
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        let dir = self.path.join(config::AUDIT_SUBDIR);
+        fs::create_dir_all(&dir)?;
+
+        let file = create_lock_file(&dir.join(config::AUDIT_LOCK_FILE))?;
+        lock_file(&file, true, wait.map(|wait| Instant::now() + wait))?;
 
         Ok(Box::new(file))
     }
@@ -58,16 +137,101 @@ impl Backend for Local {
             path: self.path.clone(),
             rand_ext: rand::thread_rng()
                 .sample_iter(&Alphanumeric)
-                .take(20)
+                .take(RAND_EXT_LEN)
                 .collect::<String>(),
         }))
     }
+
+    fn read_only(&self) -> bool {
+        if !self.path.is_dir() {
+            return false;
+        }
+
+        create_lock_file(&lock_file_path(&self.path)).is_err()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        // directories after their contents, to still be able to get to them
+        for entry in WalkDir::new(&self.path).contents_first(true) {
+            let path = entry?.into_path();
+            let mut permissions = fs::metadata(&path)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&path, permissions)?;
+        }
+        Ok(())
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        let mut removed = vec![];
+        if !self.path.is_dir() {
+            return Ok(removed);
+        }
+        for entry in WalkDir::new(&self.path) {
+            let entry = entry?;
+            if !entry.file_type().is_file()
+                || !is_temp_file_name(&entry.file_name().to_string_lossy())
+            {
+                continue;
+            }
+            if !dry_run {
+                fs::remove_file(entry.path())?;
+            }
+            removed.push(
+                entry
+                    .path()
+                    .strip_prefix(&self.path)
+                    .expect("walked path outside of the repository")
+                    .to_owned(),
+            );
+        }
+        removed.sort();
+        Ok(removed)
+    }
+}
+
+/// Is `name` of a file being written by `LocalThread::write`
+///
+/// Eg. `<digest>.<rand_ext>.tmp`, or `config.<rand_ext>.tmp` (the
+/// extension of the file written is replaced).
+fn is_temp_file_name(name: &str) -> bool {
+    let stem = match name.rsplitn(2, '.').collect::<Vec<_>>()[..] {
+        ["tmp", stem] => stem,
+        _ => return false,
+    };
+    match stem.rsplitn(2, '.').collect::<Vec<_>>()[..] {
+        [ext, _] => {
+            ext.len() == RAND_EXT_LEN
+                && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
 }
 
 impl Local {
     pub(crate) fn new(path: PathBuf) -> Self {
         Local { path }
     }
+
+    /// Lock `file`, in turns with other lockers
+    ///
+    /// Everyone holds the queue lock while waiting for the lock itself, so
+    /// eg. a `gc` waiting for readers to finish doesn't let new readers in
+    /// first, and isn't starved by overlapping `store`s.
+    fn lock_queued(
+        &self,
+        file: &fs::File,
+        exclusive: bool,
+        wait: Option<Duration>,
+    ) -> io::Result<()> {
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        // Without it (eg. on read-only media), just don't queue
+        let queue = open_lock_file(&self.path.join(config::LOCK_QUEUE_FILE));
+        if let Ok(ref queue) = queue {
+            lock_file(queue, true, deadline)?;
+        }
+        // the queue lock is released when `queue` is dropped
+        lock_file(file, exclusive, deadline)
+    }
 }
 
 impl BackendThread for LocalThread {
@@ -115,14 +279,17 @@ impl BackendThread for LocalThread {
             }
         }?;
 
-        for data_part in sg.as_parts() {
-            chunk_file.write_all(data_part)?;
+        let res = sg
+            .as_parts()
+            .iter()
+            .try_for_each(|data_part| chunk_file.write_all(data_part))
+            .and_then(|()| chunk_file.sync_data())
+            .and_then(|()| fs::rename(&tmp_path, &path));
+        if res.is_err() {
+            // eg. on a full disk, not to take the space of what was written
+            let _ = fs::remove_file(&tmp_path);
         }
-
-        chunk_file.sync_data()?;
-        fs::rename(&tmp_path, &path)?;
-
-        Ok(())
+        res
     }
 
     fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
@@ -143,6 +310,21 @@ impl BackendThread for LocalThread {
         }
     }
 
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let path = self.path.join(path);
+
+        let mut file = fs::File::open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; len as usize];
+        file.read_exact(&mut buf)?;
+        Ok(SGData::from_single(buf))
+    }
+
     fn remove(&mut self, path: PathBuf) -> io::Result<()> {
         let path = self.path.join(path);
         fs::remove_file(&path)
@@ -152,7 +334,7 @@ impl BackendThread for LocalThread {
         let path = self.path.join(path);
         let md = fs::metadata(&path)?;
         Ok(Metadata {
-            _len: md.len(),
+            len: md.len(),
             _is_file: md.is_file(),
         })
     }
@@ -0,0 +1,155 @@
+//! Backend wrapper timing the requests, see `Repo::set_metrics`
+// {{{ use and mod
+use metrics::{BackendOp, Metrics};
+use sgdata::SGData;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use super::{Backend, BackendCounts, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+pub(crate) struct Measured {
+    inner: Box<dyn Backend + Send + Sync>,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Measured {
+    pub(crate) fn new(
+        inner: Box<dyn Backend + Send + Sync>,
+        metrics: Arc<dyn Metrics>,
+    ) -> Self {
+        Measured { inner, metrics }
+    }
+}
+
+struct MeasuredThread {
+    inner: Box<dyn BackendThread>,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl MeasuredThread {
+    /// Do `f`, reporting it as `op`
+    fn measure<T, F>(&mut self, op: BackendOp, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut dyn BackendThread) -> io::Result<T>,
+    {
+        let start = Instant::now();
+        let res = f(&mut *self.inner);
+        self.metrics
+            .backend_request(op, start.elapsed(), res.is_ok());
+        res
+    }
+}
+
+impl Backend for Measured {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive(wait)
+    }
+
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared(wait)
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_audit(wait)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(MeasuredThread {
+            inner: self.inner.new_thread()?,
+            metrics: Arc::clone(&self.metrics),
+        }))
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        self.inner.restrict_permissions()
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        self.inner.remove_temp_files(dry_run)
+    }
+}
+
+impl BackendThread for MeasuredThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.measure(BackendOp::Remove, |inner| inner.remove_dir_all(path))
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.measure(BackendOp::Rename, |inner| {
+            inner.rename(src_path, dst_path)
+        })
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        self.measure(BackendOp::Write, |inner| {
+            inner.write(path, sg, idempotent)
+        })
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.measure(BackendOp::Read, |inner| inner.read(path))
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        self.measure(BackendOp::ReadRange, |inner| {
+            inner.read_range(path, offset, len)
+        })
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.measure(BackendOp::Remove, |inner| inner.remove(path))
+    }
+
+    fn take_counts(&mut self) -> BackendCounts {
+        self.inner.take_counts()
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.measure(BackendOp::ReadMetadata, |inner| inner.read_metadata(path))
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.measure(BackendOp::List, |inner| inner.list(path))
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        // errors are sent along with the results
+        let start = Instant::now();
+        self.inner.list_recursively(path, tx);
+        self.metrics
+            .backend_request(BackendOp::List, start.elapsed(), true);
+    }
+}
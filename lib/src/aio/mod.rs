@@ -5,6 +5,7 @@ use url;
 use dangerous_option::DangerousOption as AutoOption;
 
 use crossbeam_channel;
+use error::Error;
 use num_cpus;
 use sgdata::SGData;
 use slog;
@@ -13,18 +14,42 @@ use slog_perf::TimeReporter;
 use std;
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{io, thread};
 
 mod local;
 pub(crate) use self::local::Local;
+#[cfg(feature = "with-b2")]
 mod b2;
+#[cfg(feature = "with-b2")]
 pub(crate) use self::b2::B2;
+mod faulty;
+pub use self::faulty::FaultInjection;
+pub(crate) use self::faulty::Faulty;
+mod throttled;
+pub use self::throttled::{Rate, ReadRate};
+pub(crate) use self::throttled::{Pacer, Throttled};
+mod measured;
+pub(crate) use self::measured::Measured;
+mod replicated;
+pub(crate) use self::replicated::Replicated;
+mod retrying;
+pub use self::retrying::RetryPolicy;
+pub(crate) use self::retrying::Retrying;
+mod cached;
+pub(crate) use self::cached::{Cached, ChunkCache, DEFAULT_CHUNK_CACHE_SIZE};
 
 mod backend;
-use self::backend::*;
+pub(crate) use self::backend::*;
+#[cfg(feature = "with-async")]
+mod async_backend;
+#[cfg(feature = "with-async")]
+pub use self::async_backend::{
+    register_async_backend, AsyncBackend, BackendFuture,
+};
 
 // {{{ Misc
 struct WriteArgs {
@@ -35,7 +60,7 @@ struct WriteArgs {
 }
 
 pub(crate) struct Metadata {
-    _len: u64,
+    pub(crate) len: u64,
     _is_file: bool,
 }
 
@@ -60,6 +85,65 @@ impl<T> AsyncIOResult<T> {
 pub struct WriteStats {
     pub new_chunks: usize,
     pub new_bytes: u64,
+    /// Name already storing the same data, if any
+    pub unchanged_from: Option<String>,
+}
+
+/// Requests made to the backend, and the data transferred
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct IoStats {
+    pub reads: u64,
+    /// Reads of parts of files (eg. of chunks in pack files)
+    pub range_reads: u64,
+    pub writes: u64,
+    pub metadata_reads: u64,
+    pub lists: u64,
+    pub removes: u64,
+    pub renames: u64,
+    /// Requests that failed, including lookups of missing files
+    pub errors: u64,
+    /// Requests retried by the backend (eg. after network errors)
+    pub retries: u64,
+    /// Requests answered from the chunk cache, without the backend
+    pub cache_hits: u64,
+    /// Requests the chunk cache was checked for, but didn't have
+    pub cache_misses: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl IoStats {
+    /// Part of the chunk cache lookups it could answer, if there were any
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.cache_hits as f64 / lookups as f64)
+        }
+    }
+
+    /// Number of all the requests, including ones answered from the cache
+    pub fn requests(&self) -> u64 {
+        self.reads
+            + self.range_reads
+            + self.writes
+            + self.metadata_reads
+            + self.lists
+            + self.removes
+            + self.renames
+    }
+}
+
+/// `IoStats` shared by all `AsyncIO`s of a `Repo` handle
+#[derive(Clone, Default)]
+pub(crate) struct IoCounters(Arc<Mutex<IoStats>>);
+
+impl IoCounters {
+    pub(crate) fn get(&self) -> IoStats {
+        self.0.lock().unwrap().clone()
+    }
 }
 // }}}
 
@@ -70,6 +154,7 @@ pub struct WriteStats {
 enum Message {
     Write(WriteArgs),
     Read(PathBuf, mpsc::Sender<io::Result<SGData>>),
+    ReadRange(PathBuf, u64, u64, mpsc::Sender<io::Result<SGData>>),
     ReadMetadata(PathBuf, mpsc::Sender<io::Result<Metadata>>),
     List(PathBuf, mpsc::Sender<io::Result<Vec<PathBuf>>>),
     ListRecursively(PathBuf, mpsc::Sender<io::Result<Vec<PathBuf>>>),
@@ -95,12 +180,13 @@ pub struct AsyncIO {
 impl AsyncIO {
     pub(crate) fn new(
         backend: Box<dyn Backend + Send + Sync>,
+        io_counters: IoCounters,
         log: Logger,
     ) -> io::Result<Self> {
         let thread_num = 4 * num_cpus::get();
         let (tx, rx) = crossbeam_channel::bounded(thread_num);
 
-        let shared = AsyncIOThreadShared::new();
+        let shared = AsyncIOThreadShared::new(io_counters);
 
         let mut spawn_res: Vec<io::Result<_>> = (0..thread_num)
             .map(|_| {
@@ -137,18 +223,48 @@ impl AsyncIO {
         })
     }
 
-    pub(crate) fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
-        self.shared.backend.lock_exclusive()
+    pub(crate) fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.shared.backend.lock_exclusive(wait)
     }
 
-    pub(crate) fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
-        self.shared.backend.lock_shared()
+    pub(crate) fn lock_shared(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.shared.backend.lock_shared(wait)
+    }
+
+    pub(crate) fn lock_audit(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.shared.backend.lock_audit(wait)
+    }
+
+    pub(crate) fn restrict_permissions(&self) -> io::Result<()> {
+        self.shared.backend.restrict_permissions()
+    }
+
+    pub(crate) fn remove_temp_files(
+        &self,
+        dry_run: bool,
+    ) -> io::Result<Vec<PathBuf>> {
+        self.shared.backend.remove_temp_files(dry_run)
     }
 
     pub fn stats(&self) -> AsyncIOThreadShared {
         self.shared.stats.clone()
     }
 
+    /// Counters of the requests made, shared with other `AsyncIO`s using
+    /// them
+    pub(crate) fn io_counters(&self) -> IoCounters {
+        self.shared.stats.io_counters.clone()
+    }
+
     pub fn list(&self, path: PathBuf) -> AsyncIOResult<Vec<PathBuf>> {
         let (tx, rx) = mpsc::channel();
         self.tx.send(Message::List(path, tx)).expect("aio tx closed: list");
@@ -184,8 +300,6 @@ impl AsyncIO {
         AsyncIOResult { rx }
     }
 
-    // TODO: No need for it anymore
-    #[allow(dead_code)]
     pub fn write_idempotent(
         &self,
         path: PathBuf,
@@ -214,13 +328,28 @@ impl AsyncIO {
         })).expect("aio tx closed: write_checked");
     }
 
-    pub fn write_checked_idempotent(&self, path: PathBuf, sg: SGData) {
-        self.tx.send(Message::Write(WriteArgs {
-            path,
-            data: sg,
-            idempotent: true,
-            complete_tx: None,
-        })).expect("aio tx closed: write_checked_idempotent");
+    /// Write idempotently, then read the file back to check it's what was
+    /// written
+    ///
+    /// A file stored concurrently with the same content, but eg. encrypted
+    /// differently, fails the check too.
+    pub(crate) fn write_verified(
+        &self,
+        path: PathBuf,
+        sg: SGData,
+    ) -> io::Result<()> {
+        self.write_idempotent(path.clone(), sg.clone()).wait()?;
+        let read = self.read(path.clone()).wait()?;
+        if read.to_linear()[..] != sg.to_linear()[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} reads back differently than it was written",
+                    path.display()
+                ),
+            ));
+        }
+        Ok(())
     }
 
     pub fn read(&self, path: PathBuf) -> AsyncIOResult<SGData> {
@@ -229,6 +358,18 @@ impl AsyncIO {
         AsyncIOResult { rx }
     }
 
+    /// Read `len` bytes at `offset` of a file that is never modified
+    pub(crate) fn read_range(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> AsyncIOResult<SGData> {
+        let (tx, rx) = mpsc::channel();
+        self.tx.send(Message::ReadRange(path, offset, len, tx)).expect("aio tx closed: read_range");
+        AsyncIOResult { rx }
+    }
+
     pub(crate) fn read_metadata(
         &self,
         path: PathBuf,
@@ -304,20 +445,23 @@ impl Drop for AsyncIOSharedInner {
 #[derive(Clone)]
 pub struct AsyncIOThreadShared {
     inner: Arc<Mutex<AsyncIOSharedInner>>,
+    io_counters: IoCounters,
 }
 
 impl AsyncIOThreadShared {
-    pub fn new() -> Self {
+    pub(crate) fn new(io_counters: IoCounters) -> Self {
         let inner = AsyncIOSharedInner {
             write_stats: WriteStats {
                 new_bytes: 0,
                 new_chunks: 0,
+                unchanged_from: None,
             },
             in_progress: Default::default(),
         };
 
         AsyncIOThreadShared {
             inner: Arc::new(Mutex::new(inner)),
+            io_counters,
         }
     }
 
@@ -325,6 +469,13 @@ impl AsyncIOThreadShared {
         let sh = self.inner.lock().unwrap();
         sh.write_stats.clone()
     }
+
+    /// Count a chunk of `len` bytes written other way than on its own
+    pub(crate) fn record_write(&self, len: u64) {
+        let mut sh = self.inner.lock().unwrap();
+        sh.write_stats.new_bytes += len;
+        sh.write_stats.new_chunks += 1;
+    }
 }
 // }}}
 
@@ -382,6 +533,9 @@ impl AsyncIOThread {
                         complete_tx,
                     }) => self.write(path, data, idempotent, complete_tx),
                     Message::Read(path, tx) => self.read(path, tx),
+                    Message::ReadRange(path, offset, len, tx) => {
+                        self.read_range(path, offset, len, tx)
+                    }
                     Message::ReadMetadata(path, tx) => {
                         self.read_metadata(path, tx)
                     }
@@ -403,6 +557,27 @@ impl AsyncIOThread {
         }
     }
 
+    /// Count a request done with `res`, after `f` counted it by the type
+    fn record<T, F>(&self, res: &io::Result<T>, f: F)
+    where
+        F: FnOnce(&mut IoStats),
+    {
+        let counts = self.backend.borrow_mut().take_counts();
+        let mut stats = self.shared.io_counters.0.lock().unwrap();
+        f(&mut stats);
+        stats.retries += counts.retries;
+        stats.cache_hits += counts.cache_hits;
+        stats.cache_misses += counts.cache_misses;
+        if res.is_err() {
+            stats.errors += 1;
+        }
+    }
+
+    /// `res` of an operation on `path`, with the path in the error
+    fn at_path<T>(res: io::Result<T>, path: &Path) -> io::Result<T> {
+        res.map_err(|e| Error::at_path(path.to_owned(), e))
+    }
+
     fn write_inner(
         &mut self,
         path: PathBuf,
@@ -434,11 +609,18 @@ impl AsyncIOThread {
             .backend
             .borrow_mut()
             .write(path.clone(), sg, idempotent);
+        let res = Self::at_path(res, &path);
+        self.record(&res, |stats| {
+            stats.writes += 1;
+            stats.bytes_written += len as u64;
+        });
         {
             let mut sh = self.shared.inner.lock().unwrap();
             sh.in_progress.remove(&path);
-            sh.write_stats.new_bytes += len as u64;
-            sh.write_stats.new_chunks += 1;
+            if res.is_ok() {
+                sh.write_stats.new_bytes += len as u64;
+                sh.write_stats.new_chunks += 1;
+            }
         }
 
         res
@@ -492,6 +674,39 @@ impl AsyncIOThread {
             let _guard = self.pending_wait_and_insert(&path);
             self.backend.borrow_mut().read(path.clone())
         };
+        let res = Self::at_path(res, &path);
+        self.record(&res, |stats| {
+            stats.reads += 1;
+            stats.bytes_read +=
+                res.as_ref().map(|sg| sg.len() as u64).unwrap_or(0);
+        });
+        self.time_reporter.start("read send response");
+        tx.send(res).expect("send failed")
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        tx: mpsc::Sender<io::Result<SGData>>,
+    ) {
+        trace!(self.log, "read-range"; "path" => %path.display(),
+               "offset" => offset, "len" => len);
+
+        // Not waiting for other operations on the path: the file is never
+        // modified, and many parts of it are read at once
+        self.time_reporter.start("read-range");
+        let res = self
+            .backend
+            .borrow_mut()
+            .read_range(path.clone(), offset, len);
+        let res = Self::at_path(res, &path);
+        self.record(&res, |stats| {
+            stats.range_reads += 1;
+            stats.bytes_read +=
+                res.as_ref().map(|sg| sg.len() as u64).unwrap_or(0);
+        });
         self.time_reporter.start("read send response");
         tx.send(res).expect("send failed")
     }
@@ -508,6 +723,8 @@ impl AsyncIOThread {
             let _guard = self.pending_wait_and_insert(&path);
             self.backend.borrow_mut().read_metadata(path.clone())
         };
+        let res = Self::at_path(res, &path);
+        self.record(&res, |stats| stats.metadata_reads += 1);
 
         self.time_reporter.start("read send response");
         tx.send(res).expect("send failed")
@@ -522,6 +739,7 @@ impl AsyncIOThread {
 
         self.time_reporter.start("list");
         let res = self.backend.borrow_mut().list(path);
+        self.record(&res, |stats| stats.lists += 1);
         self.time_reporter.start("list send response");
         tx.send(res).expect("send failed")
     }
@@ -534,7 +752,8 @@ impl AsyncIOThread {
         trace!(self.log, "list"; "path" => %path.display());
         self.time_reporter.start("list");
 
-        self.backend.borrow_mut().list_recursively(path, tx)
+        self.backend.borrow_mut().list_recursively(path, tx);
+        self.record(&Ok(()), |stats| stats.lists += 1);
     }
 
     fn remove(&mut self, path: PathBuf, tx: mpsc::Sender<io::Result<()>>) {
@@ -545,6 +764,7 @@ impl AsyncIOThread {
             let _guard = self.pending_wait_and_insert(&path);
             self.backend.borrow_mut().remove(path.clone())
         };
+        self.record(&res, |stats| stats.removes += 1);
         self.time_reporter.start("remove send response");
         tx.send(res).expect("send failed")
     }
@@ -558,6 +778,7 @@ impl AsyncIOThread {
 
         self.time_reporter.start("remove-dir-all");
         let res = self.backend.borrow_mut().remove_dir_all(path);
+        self.record(&res, |stats| stats.removes += 1);
 
         self.time_reporter.start("remove send response");
         tx.send(res).expect("send failed")
@@ -580,6 +801,7 @@ impl AsyncIOThread {
                 .borrow_mut()
                 .rename(src_path.clone(), dst_path.clone())
         };
+        self.record(&res, |stats| stats.renames += 1);
         self.time_reporter.start("remove send response");
         tx.send(res).expect("send failed")
     }
@@ -596,32 +818,42 @@ pub(crate) fn backend_from_url(
 ) -> io::Result<Box<dyn Backend + Send + Sync>> {
     if u.scheme() == "file" {
         return Ok(Box::new(Local::new(u.to_file_path().unwrap())));
-    } else if u.scheme() == "b2" {
-        let id = u.path();
-        let bucket = u.fragment().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "bucket in the url missing",
-            )
-        })?;
-        let key = std::env::var_os("RDEDUP_B2_KEY")
-            .ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "RDEDUP_B2_KEY environment variable not found",
-                )
-            })?
-            .into_string()
-            .map_err(|os_string| {
+    }
+    #[cfg(feature = "with-async")]
+    {
+        if let Some(backend) = async_backend::backend_from_url(u)? {
+            return Ok(backend);
+        }
+    }
+    #[cfg(feature = "with-b2")]
+    {
+        if u.scheme() == "b2" {
+            let id = u.path();
+            let bucket = u.fragment().ok_or_else(|| {
                 io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    format!(
-                        "b2 key is not utf8 string: {}",
-                        os_string.to_string_lossy()
-                    ),
+                    "bucket in the url missing",
                 )
             })?;
-        return Ok(Box::new(B2::new(id, bucket, &key)));
+            let key = std::env::var_os("RDEDUP_B2_KEY")
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "RDEDUP_B2_KEY environment variable not found",
+                    )
+                })?
+                .into_string()
+                .map_err(|os_string| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "b2 key is not utf8 string: {}",
+                            os_string.to_string_lossy()
+                        ),
+                    )
+                })?;
+            return Ok(Box::new(B2::new(id, bucket, &key)));
+        }
     }
 
     return Err(io::Error::new(
@@ -0,0 +1,211 @@
+//! Replicating backend wrapper
+//!
+//! Everything stored in generations (chunks, packs and names) is written to
+//! every replica too, before the write is reported done, so data stored is
+//! redundant without a separate mirroring job. Reads failing on the inner
+//! backend are retried on the replicas. Files at the top of the repository
+//! (config, locks, etc.) are kept only by the inner backend.
+// {{{ use and mod
+use sgdata::SGData;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use super::{Backend, BackendCounts, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+/// Is `path` replicated (is it in a generation)
+fn replicated(path: &Path) -> bool {
+    path.components().count() > 1
+}
+
+/// Ignore `NotFound` (eg. removing something stored before the replica was
+/// added)
+fn ignore_not_found(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+pub(crate) struct Replicated {
+    inner: Box<dyn Backend + Send + Sync>,
+    replicas: Vec<Box<dyn Backend + Send + Sync>>,
+}
+
+impl Replicated {
+    pub(crate) fn new(
+        inner: Box<dyn Backend + Send + Sync>,
+        replicas: Vec<Box<dyn Backend + Send + Sync>>,
+    ) -> Self {
+        Replicated { inner, replicas }
+    }
+}
+
+struct ReplicatedThread {
+    inner: Box<dyn BackendThread>,
+    replicas: Vec<Box<dyn BackendThread>>,
+}
+
+impl ReplicatedThread {
+    /// Do `f` on the inner backend, and then on the replicas (for
+    /// replicated `path`)
+    fn mutate<F>(&mut self, path: &Path, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut dyn BackendThread) -> io::Result<()>,
+    {
+        f(&mut *self.inner)?;
+        if replicated(path) {
+            for replica in &mut self.replicas {
+                ignore_not_found(f(&mut **replica))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Do `f` on the inner backend, and if it fails, on the replicas (for
+    /// replicated `path`) until one succeeds
+    fn read_any<T, F>(&mut self, path: &Path, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut dyn BackendThread) -> io::Result<T>,
+    {
+        let err = match f(&mut *self.inner) {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+        if replicated(path) {
+            for replica in &mut self.replicas {
+                if let Ok(res) = f(&mut **replica) {
+                    return Ok(res);
+                }
+            }
+        }
+        Err(err)
+    }
+}
+
+impl Backend for Replicated {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive(wait)
+    }
+
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared(wait)
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_audit(wait)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(ReplicatedThread {
+            inner: self.inner.new_thread()?,
+            replicas: self
+                .replicas
+                .iter()
+                .map(|replica| replica.new_thread())
+                .collect::<io::Result<_>>()?,
+        }))
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        self.inner.restrict_permissions()?;
+        for replica in &self.replicas {
+            replica.restrict_permissions()?;
+        }
+        Ok(())
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        let mut removed = self.inner.remove_temp_files(dry_run)?;
+        for replica in &self.replicas {
+            removed.extend(replica.remove_temp_files(dry_run)?);
+        }
+        Ok(removed)
+    }
+}
+
+impl BackendThread for ReplicatedThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        // generations are removed as a whole, so their directories are
+        // replicated despite being at the top
+        self.inner.remove_dir_all(path.clone())?;
+        for replica in &mut self.replicas {
+            ignore_not_found(replica.remove_dir_all(path.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.mutate(&src_path, |backend| {
+            backend.rename(src_path.clone(), dst_path.clone())
+        })
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        self.mutate(&path, |backend| {
+            backend.write(path.clone(), sg.clone(), idempotent)
+        })
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.read_any(&path, |backend| backend.read(path.clone()))
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        self.read_any(&path, |backend| {
+            backend.read_range(path.clone(), offset, len)
+        })
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.mutate(&path, |backend| backend.remove(path.clone()))
+    }
+
+    fn take_counts(&mut self) -> BackendCounts {
+        self.inner.take_counts()
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.read_any(&path, |backend| backend.read_metadata(path.clone()))
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(path)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        self.inner.list_recursively(path, tx)
+    }
+}
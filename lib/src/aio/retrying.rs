@@ -0,0 +1,223 @@
+//! Retrying backend wrapper
+//!
+//! Retries operations failing with errors that are likely to go away (eg.
+//! a timeout, or a connection reset), as a `RetryPolicy` tells, so a
+//! single hiccup of a network storage doesn't fail a whole backup. Locks
+//! and recursive listings go straight to the inner backend.
+// {{{ use and mod
+use rand::{self, Rng};
+use sgdata::SGData;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::{Backend, BackendCounts, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+/// When and how backend operations are retried
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts of an operation, the first one included
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubled for every next one
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts
+    pub max_backoff: Duration,
+    /// Wait a random time up to the backoff instead, so many threads
+    /// failing at once don't retry at once
+    pub jitter: bool,
+    /// Errors worth retrying; others fail the operation right away
+    pub retryable: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+            // not `UnexpectedEof`: a file cut short (eg. a chunk of a
+            // crashed write) stays so, however many times it's read
+            retryable: vec![
+                io::ErrorKind::Interrupted,
+                io::ErrorKind::WouldBlock,
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::ConnectionAborted,
+                io::ErrorKind::NotConnected,
+                io::ErrorKind::BrokenPipe,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry` (from 1)
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry - 1).unwrap_or(::std::u32::MAX);
+        let backoff = self
+            .initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        if self.jitter {
+            backoff.mul_f64(rand::thread_rng().gen::<f64>())
+        } else {
+            backoff
+        }
+    }
+}
+
+pub(crate) struct Retrying {
+    inner: Box<dyn Backend + Send + Sync>,
+    policy: RetryPolicy,
+}
+
+impl Retrying {
+    pub(crate) fn new(
+        inner: Box<dyn Backend + Send + Sync>,
+        policy: RetryPolicy,
+    ) -> Self {
+        Retrying { inner, policy }
+    }
+}
+
+struct RetryingThread {
+    inner: Box<dyn BackendThread>,
+    policy: RetryPolicy,
+    /// Retries since the last `take_counts`
+    retries: u64,
+}
+
+impl RetryingThread {
+    fn retry<T, F>(&mut self, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut dyn BackendThread) -> io::Result<T>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f(&mut *self.inner) {
+                Err(ref e)
+                    if attempt < self.policy.max_attempts
+                        && self.policy.retryable.contains(&e.kind()) =>
+                {
+                    thread::sleep(self.policy.backoff(attempt));
+                    self.retries += 1;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Backend for Retrying {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive(wait)
+    }
+
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared(wait)
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_audit(wait)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(RetryingThread {
+            inner: self.inner.new_thread()?,
+            policy: self.policy.clone(),
+            retries: 0,
+        }))
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        self.inner.restrict_permissions()
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        self.inner.remove_temp_files(dry_run)
+    }
+}
+
+impl BackendThread for RetryingThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.retry(|inner| inner.remove_dir_all(path.clone()))
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.retry(|inner| inner.rename(src_path.clone(), dst_path.clone()))
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        self.retry(|inner| inner.write(path.clone(), sg.clone(), idempotent))
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.retry(|inner| inner.read(path.clone()))
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        self.retry(|inner| inner.read_range(path.clone(), offset, len))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.retry(|inner| inner.remove(path.clone()))
+    }
+
+    fn take_counts(&mut self) -> BackendCounts {
+        let mut counts = self.inner.take_counts();
+        counts.retries += self.retries;
+        self.retries = 0;
+        counts
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.retry(|inner| inner.read_metadata(path.clone()))
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.retry(|inner| inner.list(path.clone()))
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        self.inner.list_recursively(path, tx)
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
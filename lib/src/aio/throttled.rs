@@ -0,0 +1,232 @@
+//! Rate-limiting backend wrapper
+//!
+//! Limits the rates data is read from and written to the inner backend
+//! at, so eg. a large restore or backup doesn't starve other users of a
+//! shared storage. Everything else goes straight to the inner backend.
+// {{{ use and mod
+use sgdata::SGData;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Backend, BackendCounts, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+/// Limit of the rate data is read or written at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    /// Average rate, in bytes per second
+    pub bytes_per_sec: u64,
+    /// Bytes that can be passed at once, after a pause
+    pub burst: u64,
+}
+
+/// Former name of `Rate`, when only reads were limited
+pub type ReadRate = Rate;
+
+impl Rate {
+    /// `bytes_per_sec`, with a burst of one second worth of data
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Rate {
+            bytes_per_sec,
+            burst: bytes_per_sec,
+        }
+    }
+}
+
+/// Token bucket
+///
+/// Reads and writes are never split: one bigger than the tokens available
+/// puts the bucket in debt, paid off before the next one can continue.
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Take `len` tokens, returning for how long to wait
+    fn take(&mut self, len: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        let elapsed_secs = elapsed.as_secs() as f64
+            + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+        let rate = self.rate.bytes_per_sec.max(1) as f64;
+        self.tokens =
+            (self.tokens + elapsed_secs * rate).min(self.rate.burst as f64);
+        self.tokens -= len as f64;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let wait_secs = -self.tokens / rate;
+        Duration::new(
+            wait_secs as u64,
+            (wait_secs.fract() * 1_000_000_000.0) as u32,
+        )
+    }
+}
+
+/// Token bucket shared by the threads passing data at a `Rate`
+#[derive(Clone)]
+pub(crate) struct Pacer(Arc<Mutex<Bucket>>);
+
+impl Pacer {
+    pub(crate) fn new(rate: Rate) -> Self {
+        Pacer(Arc::new(Mutex::new(Bucket {
+            rate,
+            tokens: rate.burst as f64,
+            last: Instant::now(),
+        })))
+    }
+
+    /// Wait until `len` bytes can be passed
+    pub(crate) fn pace(&self, len: u64) {
+        let wait = self.0.lock().unwrap().take(len);
+        thread::sleep(wait);
+    }
+}
+
+pub(crate) struct Throttled {
+    inner: Box<dyn Backend + Send + Sync>,
+    read: Option<Pacer>,
+    write: Option<Pacer>,
+}
+
+impl Throttled {
+    pub(crate) fn new(
+        inner: Box<dyn Backend + Send + Sync>,
+        read_rate: Option<Rate>,
+        write_rate: Option<Rate>,
+    ) -> Self {
+        Throttled {
+            inner,
+            read: read_rate.map(Pacer::new),
+            write: write_rate.map(Pacer::new),
+        }
+    }
+}
+
+struct ThrottledThread {
+    inner: Box<dyn BackendThread>,
+    read: Option<Pacer>,
+    write: Option<Pacer>,
+}
+
+impl ThrottledThread {
+    fn pace_read(&self, res: io::Result<SGData>) -> io::Result<SGData> {
+        if let (Ok(ref sg), Some(ref read)) = (&res, &self.read) {
+            read.pace(sg.len() as u64);
+        }
+        res
+    }
+}
+
+impl Backend for Throttled {
+    fn lock_exclusive(
+        &self,
+        wait: Option<Duration>,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive(wait)
+    }
+
+    fn lock_shared(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared(wait)
+    }
+
+    fn lock_audit(&self, wait: Option<Duration>) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_audit(wait)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(ThrottledThread {
+            inner: self.inner.new_thread()?,
+            read: self.read.clone(),
+            write: self.write.clone(),
+        }))
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    fn restrict_permissions(&self) -> io::Result<()> {
+        self.inner.restrict_permissions()
+    }
+
+    fn remove_temp_files(&self, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        self.inner.remove_temp_files(dry_run)
+    }
+}
+
+impl BackendThread for ThrottledThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        if let Some(ref write) = self.write {
+            write.pace(sg.len() as u64);
+        }
+        self.inner.write(path, sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let res = self.inner.read(path);
+        self.pace_read(res)
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let res = self.inner.read_range(path, offset, len);
+        self.pace_read(res)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn take_counts(&mut self) -> BackendCounts {
+        self.inner.take_counts()
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.inner.read_metadata(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(path)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        self.inner.list_recursively(path, tx)
+    }
+}
@@ -0,0 +1,307 @@
+//! `async` variants of `Repo` operations (feature `with-async`)
+//!
+//! The operations return futures that don't depend on any particular
+//! runtime. They're not async all the way down: like tokio's
+//! `spawn_blocking`, each one runs the blocking operation on a thread of a
+//! pool shared by all the repositories, and wakes the task awaiting it once
+//! done. So eg. a server on tokio doesn't block its own threads on them,
+//! but every operation in progress still takes a thread of the pool, and
+//! the readers and writers given to them are the blocking `Read` and
+//! `Write`.
+//!
+//! The pool starts another thread whenever none is free, up to 64 of them
+//! (see `set_async_threads`); operations beyond that wait for a thread to
+//! be done. So an operation mustn't wait on another one (eg. a write
+//! reading from a pipe the reading side of another operation fills), or
+//! both can wait forever once the pool is full. Threads left idle for a
+//! while exit. Storage with an async client can be used through an
+//! `AsyncBackend`.
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+use {DecryptHandle, EncryptHandle, ReadOptions, Repo, Result};
+use {WriteOptions, WriteStats};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Idle threads of the pool exit after this long
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// Threads of the pool, unless changed with `set_async_threads`
+const DEFAULT_MAX_THREADS: usize = 64;
+
+struct PoolState {
+    /// Jobs waiting for a thread
+    jobs: VecDeque<Job>,
+    /// Threads waiting for a job
+    idle: usize,
+    /// Threads running
+    threads: usize,
+    /// Threads allowed to run at once
+    max_threads: usize,
+    /// Threads started so far, to name them
+    started: usize,
+}
+
+struct Pool {
+    state: Mutex<PoolState>,
+    job_queued: Condvar,
+}
+
+lazy_static! {
+    static ref POOL: Pool = Pool {
+        state: Mutex::new(PoolState {
+            jobs: VecDeque::new(),
+            idle: 0,
+            threads: 0,
+            max_threads: DEFAULT_MAX_THREADS,
+            started: 0,
+        }),
+        job_queued: Condvar::new(),
+    };
+}
+
+impl Pool {
+    /// Run `job` on an idle thread, or a new one if none is (and there are
+    /// less than the maximum), or else once a thread is done
+    fn run(&'static self, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.push_back(job);
+        self.start_threads(&mut state);
+    }
+
+    /// Wake an idle thread, and start threads for the jobs left, as many as
+    /// allowed
+    fn start_threads(&'static self, state: &mut MutexGuard<PoolState>) {
+        if state.idle > 0 {
+            self.job_queued.notify_one();
+        }
+        let mut starting = 0;
+        while state.jobs.len() > state.idle + starting
+            && state.threads < state.max_threads
+        {
+            state.threads += 1;
+            state.started += 1;
+            thread::Builder::new()
+                .name(format!("rdedup-async-{}", state.started))
+                .spawn(move || self.work())
+                .expect("can't start async pool thread");
+            starting += 1;
+        }
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // after `set_async_threads` lowered the maximum
+            if state.threads > state.max_threads {
+                state.threads -= 1;
+                return;
+            }
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                // `Task` catches panics already; this keeps `threads` right
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state.idle += 1;
+            let (guard, timeout) =
+                self.job_queued.wait_timeout(state, KEEP_ALIVE).unwrap();
+            state = guard;
+            state.idle -= 1;
+            if timeout.timed_out() && state.jobs.is_empty() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+/// Run up to `max` (at least 1) operations at once, each on a thread of
+/// its own; 64 by default
+///
+/// Operations started beyond that wait for one of the running ones to be
+/// done. Applies to all the repositories.
+pub fn set_async_threads(max: usize) {
+    let mut state = POOL.state.lock().unwrap();
+    state.max_threads = max.max(1);
+    // idle threads beyond the maximum exit
+    POOL.job_queued.notify_all();
+    POOL.start_threads(&mut state);
+}
+
+/// Threads of the pool running
+#[cfg(test)]
+pub(crate) fn async_threads() -> usize {
+    POOL.state.lock().unwrap().threads
+}
+
+/// Error of an operation that panicked
+fn panicked(payload: &(dyn Any + Send)) -> io::Error {
+    let msg = match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => match payload.downcast_ref::<String>() {
+            Some(msg) => msg.as_str(),
+            None => "unknown error",
+        },
+    };
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("operation panicked: {}", msg),
+    )
+}
+
+struct TaskState<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Future of an operation running on the pool
+#[must_use = "futures do nothing unless awaited"]
+pub struct Task<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+impl<T: Send + 'static> Task<T> {
+    /// Run `f` on the pool
+    ///
+    /// If it panics, the task fails with `io::ErrorKind::Other`.
+    pub(crate) fn spawn<F>(f: F) -> Self
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(TaskState {
+            result: None,
+            waker: None,
+        }));
+        let job = {
+            let state = Arc::clone(&state);
+            Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(f))
+                    .unwrap_or_else(|payload| Err(panicked(&*payload)));
+                let mut state = state.lock().unwrap();
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake()
+                }
+            })
+        };
+        POOL.run(job);
+        Task { state }
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// {{{ block_on
+static THREAD_WAKER: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+/// Waker unparking `thread`
+fn thread_waker(thread: Thread) -> Waker {
+    let data = Arc::into_raw(Arc::new(thread)) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(data, &THREAD_WAKER)) }
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let thread = Arc::from_raw(data as *const Thread);
+    let clone = Arc::clone(&thread);
+    mem::forget(thread);
+    RawWaker::new(Arc::into_raw(clone) as *const (), &THREAD_WAKER)
+}
+
+unsafe fn wake(data: *const ()) {
+    Arc::from_raw(data as *const Thread).unpark()
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    (*(data as *const Thread)).unpark()
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const Thread))
+}
+
+/// Run `future` to completion, blocking the current thread
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = thread_waker(thread::current());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+// }}}
+
+impl Repo {
+    /// `write_with_options`, as a future
+    pub fn write_async<R>(
+        &self,
+        name_str: &str,
+        reader: R,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+    ) -> Task<WriteStats>
+    where
+        R: Read + Send + 'static,
+    {
+        let (repo, name_str) = (self.clone(), name_str.to_owned());
+        let (enc, options) = (enc.clone(), options.clone());
+        Task::spawn(move || {
+            repo.write_with_options(&name_str, reader, &enc, &options)
+        })
+    }
+
+    /// `read_with_options` into `writer`, as a future giving the writer
+    /// back
+    pub fn read_async<W>(
+        &self,
+        name_str: &str,
+        mut writer: W,
+        dec: &DecryptHandle,
+        options: &ReadOptions,
+    ) -> Task<W>
+    where
+        W: Write + Send + 'static,
+    {
+        let (repo, name_str) = (self.clone(), name_str.to_owned());
+        let (dec, options) = (dec.clone(), options.clone());
+        Task::spawn(move || {
+            repo.read_with_options(&name_str, &mut writer, &dec, &options)?;
+            Ok(writer)
+        })
+    }
+
+    /// `list_names`, as a future
+    pub fn list_names_async(&self) -> Task<Vec<String>> {
+        let repo = self.clone();
+        Task::spawn(move || repo.list_names())
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
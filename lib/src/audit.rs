@@ -0,0 +1,200 @@
+//! Audit log of repository operations
+//!
+//! In repositories with `audit_log` (see `config::Repo`), every operation
+//! storing, removing or re-keying anything records an entry, in a file of
+//! its own under `AUDIT_SUBDIR`. Every entry carries the SHA-256 of the
+//! previous one, so entries can't be changed, removed or put in between
+//! unnoticed (see `verify`), other than the latest ones: keep the digest
+//! of the head elsewhere to detect these too.
+//!
+//! Entries are appended with the audit log locked (see
+//! `Backend::lock_audit`), so processes writing to the repository at the
+//! same time, holding its lock in shared mode, take turns numbering them.
+use aio;
+use chrono::prelude::*;
+use config::AUDIT_SUBDIR;
+use serde_yaml;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use util::*;
+use SGData;
+
+/// Recorded operation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Number of the entry, counted from 0
+    pub seq: u64,
+    #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
+    pub time: DateTime<Utc>,
+    /// Eg. `store`, `rm` or `gc`
+    pub operation: String,
+    /// Name operated on (not recorded in repositories hiding names)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Other name of the operation (eg. the old name of `rename`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// Digest of the data of the name
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_hex_opt",
+        deserialize_with = "from_hex_opt"
+    )]
+    pub digest: Option<Vec<u8>>,
+    /// Bytes of data, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// SHA-256 of the previous entry (none for the first one)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_hex_opt",
+        deserialize_with = "from_hex_opt"
+    )]
+    pub prev: Option<Vec<u8>>,
+}
+
+impl AuditEntry {
+    /// Entry of `operation`, done now; numbered and chained by `append`
+    pub(crate) fn new(operation: &str) -> Self {
+        AuditEntry {
+            seq: 0,
+            time: Utc::now(),
+            operation: operation.into(),
+            name: None,
+            from: None,
+            digest: None,
+            bytes: None,
+            prev: None,
+        }
+    }
+
+    pub(crate) fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub(crate) fn with_from(mut self, from: &str) -> Self {
+        self.from = Some(from.into());
+        self
+    }
+
+    pub(crate) fn with_digest(mut self, digest: &[u8]) -> Self {
+        self.digest = Some(digest.into());
+        self
+    }
+
+    pub(crate) fn with_bytes(mut self, bytes: Option<u64>) -> Self {
+        self.bytes = bytes;
+        self
+    }
+}
+
+/// Result of `Repo::verify_audit_log`
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditVerifyResults {
+    pub entries: u64,
+    /// SHA-256 of the latest entry, if any
+    pub head: Option<Vec<u8>>,
+}
+
+fn entry_path(seq: u64) -> PathBuf {
+    PathBuf::from(AUDIT_SUBDIR).join(format!("{:020}.yml", seq))
+}
+
+/// Numbers of the entries stored, in order
+fn list_seqs(aio: &aio::AsyncIO) -> io::Result<Vec<u64>> {
+    let list = substitute_err_not_found(
+        aio.list(PathBuf::from(AUDIT_SUBDIR)).wait(),
+        Vec::new,
+    )?;
+    let mut seqs: Vec<u64> = list
+        .iter()
+        .filter(|path| path.extension() == Some("yml".as_ref()))
+        .filter_map(|path| path.file_stem())
+        .filter_map(|stem| stem.to_str())
+        .filter_map(|stem| stem.parse().ok())
+        .collect();
+    seqs.sort();
+    Ok(seqs)
+}
+
+fn parse(seq: u64, data: &[u8]) -> io::Result<AuditEntry> {
+    serde_yaml::from_slice(data).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("couldn't parse audit log entry {}: {}", seq, e),
+        )
+    })
+}
+
+/// Record `entry` after the latest one, waiting for the audit log lock
+/// like `Repo` does for its lock
+pub(crate) fn append(
+    aio: &aio::AsyncIO,
+    wait: Option<Duration>,
+    mut entry: AuditEntry,
+) -> io::Result<()> {
+    let _lock = aio.lock_audit(wait)?;
+    let seqs = list_seqs(aio)?;
+    match seqs.last() {
+        Some(&last) => {
+            let data = aio.read(entry_path(last)).wait()?.to_linear_vec();
+            entry.seq = last + 1;
+            entry.prev = Some(Sha256::digest(&data).to_vec());
+        }
+        None => {
+            entry.seq = 0;
+            entry.prev = None;
+        }
+    }
+    let serialized =
+        serde_yaml::to_string(&entry).expect("yaml serialization failed");
+    aio.write(
+        entry_path(entry.seq),
+        SGData::from_single(serialized.into_bytes()),
+    ).wait()
+}
+
+/// All the entries, oldest first, without checking the chain
+pub(crate) fn read_all(aio: &aio::AsyncIO) -> io::Result<Vec<AuditEntry>> {
+    list_seqs(aio)?
+        .into_iter()
+        .map(|seq| {
+            let data = aio.read(entry_path(seq)).wait()?.to_linear_vec();
+            parse(seq, &data)
+        })
+        .collect()
+}
+
+/// Check that entries are numbered from 0 with no gaps, and each one
+/// carries the digest of the previous one
+pub(crate) fn verify(aio: &aio::AsyncIO) -> io::Result<AuditVerifyResults> {
+    let broken = |seq: u64, why: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("audit log broken at entry {}: {}", seq, why),
+        )
+    };
+    let mut head: Option<Vec<u8>> = None;
+    let mut entries = 0;
+    for seq in list_seqs(aio)? {
+        if seq != entries {
+            return Err(broken(entries, "entry missing"));
+        }
+        let data = aio.read(entry_path(seq)).wait()?.to_linear_vec();
+        let entry = parse(seq, &data)?;
+        if entry.seq != seq {
+            return Err(broken(seq, "numbered differently"));
+        }
+        if entry.prev != head {
+            return Err(broken(seq, "previous entry doesn't match"));
+        }
+        head = Some(Sha256::digest(&data).to_vec());
+        entries += 1;
+    }
+    Ok(AuditVerifyResults { entries, head })
+}
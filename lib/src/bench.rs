@@ -0,0 +1,177 @@
+//! Reproducible data for tests and benchmarks, see `Repo::bench`
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// Shortest run of data generated at once
+const MIN_SEGMENT_SIZE: u64 = 64 * 1024;
+/// Longest run of data generated at once
+const MAX_SEGMENT_SIZE: u64 = 1024 * 1024;
+/// Bytes of compressible segments are taken from it, so they compress to
+/// about half
+const ALPHABET: &[u8; 16] = b"etaoinshrdlucmfw";
+
+/// SplitMix64, stable across releases (unlike the generators of `rand`)
+#[derive(Clone)]
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Run of data generated from its own seed, so it can be generated again
+/// (duplicated)
+struct Segment {
+    rng: SplitMix,
+    left: u64,
+    compressible: bool,
+    word: u64,
+    word_left: u32,
+}
+
+impl Segment {
+    fn new(seed: u64, i: u64) -> Self {
+        let mut rng = SplitMix(seed ^ i.wrapping_mul(0xd6e8_feb8_6659_fd93));
+        let left = MIN_SEGMENT_SIZE
+            + rng.next() % (MAX_SEGMENT_SIZE - MIN_SEGMENT_SIZE + 1);
+        let compressible = rng.next() & 1 == 0;
+        Segment {
+            rng,
+            left,
+            compressible,
+            word: 0,
+            word_left: 0,
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let len = (buf.len() as u64).min(self.left) as usize;
+        for b in &mut buf[..len] {
+            if self.word_left == 0 {
+                self.word = self.rng.next();
+                self.word_left = 8;
+            }
+            let byte = self.word as u8;
+            self.word >>= 8;
+            self.word_left -= 1;
+            *b = if self.compressible {
+                ALPHABET[usize::from(byte & 0xf)]
+            } else {
+                byte
+            };
+        }
+        self.left -= len as u64;
+        len
+    }
+}
+
+/// Reader of `size` bytes of pseudo-random data, the same for the same
+/// seed
+///
+/// The data is made of runs of 64 KiB to 1 MiB: a third of them repeat an
+/// earlier one (so deduplicate), and half of the others compress to about
+/// half their size.
+pub struct BenchData {
+    seed: u64,
+    left: u64,
+    rng: SplitMix,
+    /// Runs generated so far (not counting repeated ones)
+    segments: u64,
+    segment: Option<Segment>,
+}
+
+impl BenchData {
+    pub fn new(size: u64, seed: u64) -> Self {
+        BenchData {
+            seed,
+            left: size,
+            rng: SplitMix(seed),
+            segments: 0,
+            segment: None,
+        }
+    }
+
+    fn next_segment(&mut self) -> Segment {
+        let r = self.rng.next();
+        if self.segments > 0 && r % 3 == 0 {
+            return Segment::new(self.seed, (r >> 8) % self.segments);
+        }
+        self.segments += 1;
+        Segment::new(self.seed, self.segments - 1)
+    }
+}
+
+impl Read for BenchData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.left) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        loop {
+            if let Some(ref mut segment) = self.segment {
+                let read = segment.fill(&mut buf[..len]);
+                if read > 0 {
+                    self.left -= read as u64;
+                    return Ok(read);
+                }
+            }
+            self.segment = Some(self.next_segment());
+        }
+    }
+}
+
+/// Results of `Repo::bench`
+///
+/// Every stage ran in a single thread, so the rates are per CPU core.
+#[derive(Clone, Debug, Default)]
+pub struct BenchResults {
+    /// Bytes of data chunked and hashed
+    pub bytes: u64,
+    pub chunks: u64,
+    /// Chunks that wouldn't deduplicate, and were compressed and encrypted
+    pub unique_chunks: u64,
+    /// Bytes of the unique chunks
+    pub unique_bytes: u64,
+    /// Bytes of the unique chunks, as compressed
+    pub compressed_bytes: u64,
+    pub chunking: Duration,
+    pub hashing: Duration,
+    pub compression: Duration,
+    pub encryption: Duration,
+}
+
+impl BenchResults {
+    /// Bytes per second chunked
+    pub fn chunking_rate(&self) -> f64 {
+        rate(self.bytes, self.chunking)
+    }
+
+    /// Bytes per second hashed
+    pub fn hashing_rate(&self) -> f64 {
+        rate(self.bytes, self.hashing)
+    }
+
+    /// Bytes per second compressed (as before compression)
+    pub fn compression_rate(&self) -> f64 {
+        rate(self.unique_bytes, self.compression)
+    }
+
+    /// Bytes per second encrypted (as compressed)
+    pub fn encryption_rate(&self) -> f64 {
+        rate(self.compressed_bytes, self.encryption)
+    }
+}
+
+fn rate(bytes: u64, time: Duration) -> f64 {
+    let secs = time.as_secs() as f64 + f64::from(time.subsec_nanos()) / 1e9;
+    if secs == 0.0 {
+        0.0
+    } else {
+        bytes as f64 / secs
+    }
+}
@@ -0,0 +1,713 @@
+//! Reading of bup repositories, see `Repo::import_bup`
+//!
+//! A bup repository is a bare git repository: every `bup save -n BRANCH`
+//! is a commit on `BRANCH`, with the tree of the files saved. Files larger
+//! than a chunk are trees named `FILE.bup`, with the chunks (blobs) in
+//! order, possibly in nested fan-out trees; names that end in `.bup`
+//! themselves get `.bupl` appended. The metadata of the entries of every
+//! directory (owners, times, ...) is in its `.bupm` file.
+//!
+//! Objects are looked up in the (version 2) `.idx` files of the packs,
+//! then among the loose objects; deltified objects (eg. after `git gc`)
+//! are supported too.
+use chrono::prelude::*;
+use flate2::read::ZlibDecoder;
+use hex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str;
+use tree::{EntryKind, TreeEntry};
+use walkdir::WalkDir;
+
+/// Git object identifier (SHA-1)
+pub(crate) type ObjectId = [u8; 20];
+
+/// bup splits files into chunks of this size on average
+pub(crate) const AVERAGE_BLOB_SIZE: u64 = 8 * 1024;
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// Longest chain of deltas followed (git makes them 50 long at most)
+const MAX_DELTA_DEPTH: usize = 1000;
+
+const IDX_MAGIC: &[u8] = b"\xfftOc";
+const IDX_HEADER_LEN: usize = 8 + 256 * 4;
+
+fn invalid_data<T>(msg: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn be32(data: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[pos..pos + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn parse_id(s: &str) -> io::Result<ObjectId> {
+    match hex::decode(s) {
+        Ok(ref bytes) if bytes.len() == 20 => {
+            let mut id = [0; 20];
+            id.copy_from_slice(bytes);
+            Ok(id)
+        }
+        _ => invalid_data(format!("invalid object id: {}", s)),
+    }
+}
+
+/// Rest of `s` after `prefix`, if it starts with it
+fn after<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    if s.starts_with(prefix) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Pack file, with its index
+struct Pack {
+    path: PathBuf,
+    /// Content of the `.idx` file
+    idx: Vec<u8>,
+}
+
+impl Pack {
+    fn load(idx_path: &Path) -> io::Result<Self> {
+        let idx = fs::read(idx_path)?;
+        if idx.len() < IDX_HEADER_LEN
+            || &idx[..4] != IDX_MAGIC
+            || be32(&idx, 4) != 2
+        {
+            return invalid_data(format!(
+                "{}: not a version 2 pack index",
+                idx_path.display()
+            ));
+        }
+        let pack = Pack {
+            path: idx_path.with_extension("pack"),
+            idx,
+        };
+        if pack.idx.len() < IDX_HEADER_LEN + pack.len() * 28 {
+            return invalid_data(format!(
+                "{}: truncated pack index",
+                idx_path.display()
+            ));
+        }
+        Ok(pack)
+    }
+
+    /// Number of objects
+    fn len(&self) -> usize {
+        self.fanout(255)
+    }
+
+    fn fanout(&self, i: usize) -> usize {
+        be32(&self.idx, 8 + i * 4) as usize
+    }
+
+    fn id(&self, i: usize) -> &[u8] {
+        let pos = IDX_HEADER_LEN + i * 20;
+        &self.idx[pos..pos + 20]
+    }
+
+    /// Offset of object `id` in the pack, if it's there
+    fn offset(&self, id: &ObjectId) -> io::Result<Option<u64>> {
+        let first = id[0] as usize;
+        let mut lo = if first == 0 {
+            0
+        } else {
+            self.fanout(first - 1)
+        };
+        let mut hi = self.fanout(first).min(self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.id(mid).cmp(&id[..]) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => {
+                    return self.offset_of(mid).map(Some)
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn offset_of(&self, i: usize) -> io::Result<u64> {
+        let offsets = IDX_HEADER_LEN + self.len() * 24;
+        let offset = be32(&self.idx, offsets + i * 4);
+        if offset & 0x8000_0000 == 0 {
+            return Ok(u64::from(offset));
+        }
+        let pos =
+            offsets + self.len() * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        match self.idx.get(pos..pos + 8) {
+            Some(large) => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(large);
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => invalid_data(format!(
+                "{}: invalid large offset",
+                self.path.display()
+            )),
+        }
+    }
+}
+
+/// A save: a commit of a branch
+#[derive(Clone, Debug)]
+pub(crate) struct BupSave {
+    pub branch: String,
+    /// Time of the commit
+    pub time: DateTime<Utc>,
+    tree: ObjectId,
+}
+
+impl BupSave {
+    /// Name to store the save as: the branch (with `/` replaced by `-`)
+    /// and the time of the save in UTC, like `home-2019-01-31T10:00:00Z`
+    pub fn name(&self) -> String {
+        format!(
+            "{}-{}",
+            self.branch.replace('/', "-"),
+            self.time.format("%Y-%m-%dT%H:%M:%SZ")
+        )
+    }
+}
+
+/// File of a save: a blob, or a tree of blobs (chunks)
+#[derive(Clone, Debug)]
+pub(crate) struct BupFile {
+    /// Blob or tree with the data; the same file has the same identifier
+    /// in every save
+    pub id: ObjectId,
+    chunked: bool,
+}
+
+/// bup repository
+pub(crate) struct BupRepo {
+    dir: PathBuf,
+    packs: Vec<Pack>,
+}
+
+impl BupRepo {
+    /// Open the bup repository at `dir` (usually `~/.bup`)
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let pack_dir = dir.join("objects").join("pack");
+        if !pack_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: not a bup repository", dir.display()),
+            ));
+        }
+        let mut idx_paths = vec![];
+        for entry in fs::read_dir(&pack_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("idx") {
+                idx_paths.push(path);
+            }
+        }
+        idx_paths.sort();
+        Ok(BupRepo {
+            dir: dir.to_owned(),
+            packs: idx_paths
+                .iter()
+                .map(|path| Pack::load(path))
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    /// Branches, with the commits at their heads
+    fn branches(&self) -> io::Result<BTreeMap<String, ObjectId>> {
+        let mut branches = BTreeMap::new();
+        match fs::read_to_string(self.dir.join("packed-refs")) {
+            Ok(packed) => {
+                for line in packed.lines() {
+                    let mut parts = line.splitn(2, ' ');
+                    let (id, name) = match (parts.next(), parts.next()) {
+                        (Some(id), Some(name)) => (id, name),
+                        _ => continue,
+                    };
+                    if id.starts_with('#') || id.starts_with('^') {
+                        continue;
+                    }
+                    if let Some(name) = after(name, "refs/heads/") {
+                        branches.insert(name.to_owned(), parse_id(id)?);
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let heads = self.dir.join("refs").join("heads");
+        if heads.is_dir() {
+            for entry in WalkDir::new(&heads).min_depth(1) {
+                let entry = entry.map_err(io::Error::from)?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let name = entry
+                    .path()
+                    .strip_prefix(&heads)
+                    .expect("under refs/heads")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let id = fs::read_to_string(entry.path())?;
+                branches.insert(name, parse_id(id.trim())?);
+            }
+        }
+        Ok(branches)
+    }
+
+    /// Saves of `branches` (all of them if empty), each branch oldest
+    /// first
+    pub fn saves(&self, branches: &[&str]) -> io::Result<Vec<BupSave>> {
+        let all = self.branches()?;
+        let selected: Vec<(&str, &ObjectId)> = if branches.is_empty() {
+            all.iter().map(|(name, id)| (name.as_str(), id)).collect()
+        } else {
+            branches
+                .iter()
+                .map(|name| match all.get(*name) {
+                    Some(id) => Ok((*name, id)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "no such branch in the bup repository: {}",
+                            name
+                        ),
+                    )),
+                })
+                .collect::<io::Result<_>>()?
+        };
+        let mut saves = vec![];
+        for (branch, head) in selected {
+            let mut branch_saves = vec![];
+            let mut commit = Some(*head);
+            while let Some(id) = commit {
+                let data = self.read_object_of(&id, OBJ_COMMIT)?;
+                let (tree, parent, time) =
+                    parse_commit(&data).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid commit {}", hex::encode(id)),
+                        )
+                    })?;
+                branch_saves.push(BupSave {
+                    branch: branch.to_owned(),
+                    time,
+                    tree,
+                });
+                commit = parent;
+            }
+            branch_saves.reverse();
+            saves.extend(branch_saves);
+        }
+        Ok(saves)
+    }
+
+    /// Entries of `save`, every directory before its contents, with the
+    /// data of the files
+    ///
+    /// The metadata in `.bupm` files is not read: files get the
+    /// permissions kept by git, and the time of the save as their
+    /// modification time.
+    pub fn walk(
+        &self,
+        save: &BupSave,
+    ) -> io::Result<Vec<(Option<BupFile>, TreeEntry)>> {
+        let mut entries = vec![];
+        self.walk_tree(&save.tree, "", save.time, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn walk_tree(
+        &self,
+        tree: &ObjectId,
+        prefix: &str,
+        mtime: DateTime<Utc>,
+        entries: &mut Vec<(Option<BupFile>, TreeEntry)>,
+    ) -> io::Result<()> {
+        for (mode, name, id) in self.read_tree(tree)? {
+            if name.ends_with(".bupm") {
+                continue;
+            }
+            let (name, chunked) = if name.ends_with(".bupl") {
+                (&name[..name.len() - 5], false)
+            } else if name.ends_with(".bup") {
+                (&name[..name.len() - 4], true)
+            } else {
+                (name.as_str(), false)
+            };
+            let path = if prefix.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let mut entry = TreeEntry {
+                path,
+                kind: EntryKind::File,
+                mode: mode & 0o777,
+                mtime,
+                size: 0,
+                first_chunk: 0,
+                chunks: 0,
+                target: None,
+            };
+            match mode & 0o170_000 {
+                0o040_000 if chunked => {
+                    entry.mode = 0o644;
+                    entries.push((Some(BupFile { id, chunked }), entry));
+                }
+                0o040_000 => {
+                    entry.kind = EntryKind::Dir;
+                    entry.mode = 0o755;
+                    let path = entry.path.clone();
+                    entries.push((None, entry));
+                    self.walk_tree(&id, &path, mtime, entries)?;
+                }
+                0o100_000 => {
+                    entries.push((Some(BupFile { id, chunked }), entry))
+                }
+                0o120_000 => {
+                    let target = self.read_object_of(&id, OBJ_BLOB)?;
+                    entry.kind = EntryKind::Symlink;
+                    entry.mode = 0o777;
+                    entry.target =
+                        Some(String::from_utf8(target).or_else(|_| {
+                            invalid_data(format!(
+                                "symlink {}: target not UTF-8",
+                                entry.path
+                            ))
+                        })?);
+                    entries.push((None, entry));
+                }
+                // submodules
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Blobs with the data of `file`, in order
+    pub fn blobs(&self, file: &BupFile) -> io::Result<Vec<ObjectId>> {
+        let mut blobs = vec![];
+        if file.chunked {
+            self.chunk_blobs(&file.id, &mut blobs)?;
+        } else {
+            blobs.push(file.id);
+        }
+        Ok(blobs)
+    }
+
+    fn chunk_blobs(
+        &self,
+        tree: &ObjectId,
+        blobs: &mut Vec<ObjectId>,
+    ) -> io::Result<()> {
+        // named by their offsets, of the same width: sorted by git
+        for (mode, _, id) in self.read_tree(tree)? {
+            if mode & 0o170_000 == 0o040_000 {
+                self.chunk_blobs(&id, blobs)?;
+            } else {
+                blobs.push(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reader of the data of `blobs`
+    pub fn reader<'a>(&'a self, blobs: &'a [ObjectId]) -> BlobReader<'a> {
+        BlobReader {
+            repo: self,
+            blobs: blobs.iter(),
+            current: io::Cursor::new(vec![]),
+        }
+    }
+
+    /// Entries (mode, name, object) of tree `id`
+    fn read_tree(
+        &self,
+        id: &ObjectId,
+    ) -> io::Result<Vec<(u32, String, ObjectId)>> {
+        let data = self.read_object_of(id, OBJ_TREE)?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid tree {}", hex::encode(id)),
+            )
+        };
+        let mut entries = vec![];
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let space = rest.iter().position(|&b| b == b' ');
+            let nul = rest.iter().position(|&b| b == 0);
+            let (space, nul) = match (space, nul) {
+                (Some(space), Some(nul)) if space < nul => (space, nul),
+                _ => return Err(invalid()),
+            };
+            let mode = str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                .ok_or_else(invalid)?;
+            let name = str::from_utf8(&rest[space + 1..nul])
+                .map_err(|_| invalid())?
+                .to_owned();
+            let object = rest.get(nul + 1..nul + 21).ok_or_else(invalid)?;
+            let mut object_id = [0; 20];
+            object_id.copy_from_slice(object);
+            entries.push((mode, name, object_id));
+            rest = &rest[nul + 21..];
+        }
+        Ok(entries)
+    }
+
+    /// Content of object `id`, which has to be of type `kind`
+    fn read_object_of(&self, id: &ObjectId, kind: u8) -> io::Result<Vec<u8>> {
+        let (actual, data) = self.read_object(id, 0)?;
+        if actual != kind {
+            return invalid_data(format!(
+                "object {}: unexpected type",
+                hex::encode(id)
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Type and content of object `id`
+    fn read_object(
+        &self,
+        id: &ObjectId,
+        depth: usize,
+    ) -> io::Result<(u8, Vec<u8>)> {
+        for pack in &self.packs {
+            if let Some(offset) = pack.offset(id)? {
+                let mut file = BufReader::new(fs::File::open(&pack.path)?);
+                return self.read_packed(&mut file, offset, depth);
+            }
+        }
+        self.read_loose(id)
+    }
+
+    fn read_loose(&self, id: &ObjectId) -> io::Result<(u8, Vec<u8>)> {
+        let id = hex::encode(id);
+        let path = self.dir.join("objects").join(&id[..2]).join(&id[2..]);
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("object {} not found", id),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        let mut data = vec![];
+        ZlibDecoder::new(file).read_to_end(&mut data)?;
+        let header_end = match data.iter().position(|&b| b == 0) {
+            Some(end) => end,
+            None => return invalid_data(format!("invalid object {}", id)),
+        };
+        let kind = match data[..header_end].split(|&b| b == b' ').next() {
+            Some(b"commit") => OBJ_COMMIT,
+            Some(b"tree") => OBJ_TREE,
+            Some(b"blob") => OBJ_BLOB,
+            Some(b"tag") => OBJ_TAG,
+            _ => return invalid_data(format!("invalid object {}", id)),
+        };
+        data.drain(..=header_end);
+        Ok((kind, data))
+    }
+
+    /// Type and content of the object at `offset` of pack `file`
+    fn read_packed(
+        &self,
+        file: &mut BufReader<fs::File>,
+        offset: u64,
+        depth: usize,
+    ) -> io::Result<(u8, Vec<u8>)> {
+        if depth > MAX_DELTA_DEPTH {
+            return invalid_data("delta chain too long".into());
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut byte = read_byte(file)?;
+        let kind = (byte >> 4) & 7;
+        let mut size = u64::from(byte & 0xf);
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            if shift > 57 {
+                return invalid_data("invalid object size in pack".into());
+            }
+            byte = read_byte(file)?;
+            size |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+        }
+        match kind {
+            OBJ_COMMIT | OBJ_TREE | OBJ_BLOB | OBJ_TAG => {
+                Ok((kind, inflate(file, size)?))
+            }
+            OBJ_OFS_DELTA => {
+                let mut byte = read_byte(file)?;
+                let mut back = u64::from(byte & 0x7f);
+                while byte & 0x80 != 0 {
+                    byte = read_byte(file)?;
+                    back = back
+                        .checked_add(1)
+                        .and_then(|back| back.checked_mul(128))
+                        .map(|back| back | u64::from(byte & 0x7f))
+                        .unwrap_or(::std::u64::MAX);
+                }
+                if back == 0 || back > offset {
+                    return invalid_data("invalid delta base in pack".into());
+                }
+                let delta = inflate(file, size)?;
+                let (kind, base) =
+                    self.read_packed(file, offset - back, depth + 1)?;
+                Ok((kind, apply_delta(&base, &delta)?))
+            }
+            OBJ_REF_DELTA => {
+                let mut base_id = [0; 20];
+                file.read_exact(&mut base_id)?;
+                let delta = inflate(file, size)?;
+                let (kind, base) = self.read_object(&base_id, depth + 1)?;
+                Ok((kind, apply_delta(&base, &delta)?))
+            }
+            _ => invalid_data(format!("invalid object type {} in pack", kind)),
+        }
+    }
+}
+
+/// Reader of the data of blobs, returned by `BupRepo::reader`
+pub(crate) struct BlobReader<'a> {
+    repo: &'a BupRepo,
+    blobs: std::slice::Iter<'a, ObjectId>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl<'a> Read for BlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.blobs.next() {
+                Some(id) => {
+                    self.current =
+                        io::Cursor::new(self.repo.read_object_of(id, OBJ_BLOB)?)
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// `size` bytes of zlib compressed data, from the position of `reader`
+fn inflate<R: Read>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    ZlibDecoder::new(reader).take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return invalid_data("truncated object in pack".into());
+    }
+    Ok(data)
+}
+
+/// Size at `pos` of a delta, moving past it
+fn delta_size(delta: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match delta.get(*pos) {
+            Some(&byte) if shift < 64 => byte,
+            _ => return invalid_data("invalid delta".into()),
+        };
+        *pos += 1;
+        size |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Object made from `base` and git `delta` instructions
+fn apply_delta(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid =
+        || io::Error::new(io::ErrorKind::InvalidData, "invalid delta");
+    let mut pos = 0;
+    if delta_size(delta, &mut pos)? != base.len() as u64 {
+        return Err(invalid());
+    }
+    let size = delta_size(delta, &mut pos)?;
+    let mut data = vec![];
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // copy from the base, offset and length in the bytes flagged
+            let mut arg = |flags: u8, bytes: usize| -> io::Result<usize> {
+                let mut value = 0;
+                for i in 0..bytes {
+                    if flags & (1 << i) != 0 {
+                        value |=
+                            usize::from(*delta.get(pos).ok_or_else(invalid)?)
+                                << (8 * i);
+                        pos += 1;
+                    }
+                }
+                Ok(value)
+            };
+            let offset = arg(op, 4)?;
+            let len = match arg(op >> 4, 3)? {
+                0 => 0x10000,
+                len => len,
+            };
+            let end = offset.checked_add(len).ok_or_else(invalid)?;
+            data.extend_from_slice(base.get(offset..end).ok_or_else(invalid)?);
+        } else if op != 0 {
+            let end = pos + op as usize;
+            data.extend_from_slice(delta.get(pos..end).ok_or_else(invalid)?);
+            pos = end;
+        } else {
+            return Err(invalid());
+        }
+    }
+    if data.len() as u64 != size {
+        return Err(invalid());
+    }
+    Ok(data)
+}
+
+/// Tree, first parent and time of a commit
+fn parse_commit(
+    data: &[u8],
+) -> Option<(ObjectId, Option<ObjectId>, DateTime<Utc>)> {
+    let text = str::from_utf8(data).ok()?;
+    let (mut tree, mut parent, mut time) = (None, None, None);
+    for line in text.lines().take_while(|line| !line.is_empty()) {
+        if let Some(id) = after(line, "tree ") {
+            tree = Some(parse_id(id).ok()?);
+        } else if let Some(id) = after(line, "parent ") {
+            parent = parent.or(Some(parse_id(id).ok()?));
+        } else if line.starts_with("committer ") {
+            // `committer NAME <EMAIL> SECONDS TIMEZONE`
+            let seconds = line.rsplit(' ').nth(1)?.parse().ok()?;
+            time = Utc.timestamp_opt(seconds, 0).single();
+        }
+    }
+    Some((tree?, parent, time?))
+}
@@ -0,0 +1,86 @@
+//! Cancelling long operations from another thread, see `CancelToken`
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use error::Error;
+use {ChunkAccessor, DataType, DigestRef, Repo};
+
+/// Token to cancel an operation it was passed to (eg. with
+/// `WriteOptions::cancel`)
+///
+/// Clones share the state, so one can be kept by the caller (eg. in a
+/// signal handler) and cancelled while the operation runs in another
+/// thread. The operation then fails with `error::Error::Cancelled`, as soon
+/// as it gets to a point where it can stop without leaving anything broken.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the operations this token was passed to
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `Error::Cancelled`, if cancelled
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// `ChunkAccessor` failing once `cancel` is cancelled, before reading
+/// another chunk
+pub(crate) struct CancellingChunkAccessor<'a> {
+    inner: &'a dyn ChunkAccessor,
+    cancel: &'a CancelToken,
+}
+
+impl<'a> CancellingChunkAccessor<'a> {
+    pub(crate) fn new(
+        inner: &'a dyn ChunkAccessor,
+        cancel: &'a CancelToken,
+    ) -> Self {
+        CancellingChunkAccessor { inner, cancel }
+    }
+}
+
+impl<'a> ChunkAccessor for CancellingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.inner.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        self.cancel.check()?;
+        self.inner.read_chunk_into(digest, data_type, writer)
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.cancel.check()?;
+        self.inner.touch(digest)
+    }
+
+    fn touch_data(
+        &self,
+        digest: DigestRef,
+        len: Option<u64>,
+    ) -> io::Result<()> {
+        self.cancel.check()?;
+        self.inner.touch_data(digest, len)
+    }
+}
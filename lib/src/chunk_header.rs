@@ -0,0 +1,150 @@
+//! Header stored in front of chunks
+//!
+//! Identifies the format of the rest of the chunk, so it can be checked
+//! before decrypting anything, and future formats can be told apart:
+//!
+//! ```norust
+//! magic "rdch" | version | flags | compression id | encryption id
+//! ```
+//!
+//! All fields after the magic are single bytes. Repositories created with
+//! bare chunks (or by older versions) have no headers at all.
+//!
+//! Data chunks not worth compressing (see
+//! `config::Repo::min_compression_savings`) are flagged, with no
+//! compression id.
+use config;
+use sgdata::SGData;
+use std::io;
+use DataType;
+
+const MAGIC: &[u8] = b"rdch";
+const VERSION: u8 = 1;
+pub(crate) const HEADER_LEN: usize = 8;
+
+/// Chunk contains digests of other chunks (as opposed to data)
+const FLAG_INDEX: u8 = 1;
+/// Chunk is stored uncompressed, though its data type is compressed
+const FLAG_UNCOMPRESSED: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ChunkHeader {
+    flags: u8,
+    compression: u8,
+    encryption: u8,
+}
+
+impl ChunkHeader {
+    /// Header of `data_type` chunks stored with `config`
+    pub(crate) fn new(config: &config::Repo, data_type: DataType) -> Self {
+        ChunkHeader {
+            flags: if data_type == DataType::Index {
+                FLAG_INDEX
+            } else {
+                0
+            },
+            compression: if data_type.should_compress() {
+                config.compression.id()
+            } else {
+                0
+            },
+            encryption: if data_type.should_encrypt() {
+                config.encryption.id()
+            } else {
+                0
+            },
+        }
+    }
+
+    /// Header of the same chunks, stored uncompressed
+    pub(crate) fn uncompressed(self) -> Self {
+        ChunkHeader {
+            flags: self.flags | FLAG_UNCOMPRESSED,
+            compression: 0,
+            encryption: self.encryption,
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[
+            VERSION,
+            self.flags,
+            self.compression,
+            self.encryption,
+        ]);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN
+            || &bytes[..MAGIC.len()] != MAGIC
+            || bytes[MAGIC.len()] != VERSION
+        {
+            return None;
+        }
+        Some(ChunkHeader {
+            flags: bytes[5],
+            compression: bytes[6],
+            encryption: bytes[7],
+        })
+    }
+
+    /// Is `bytes` this header (of a chunk stored compressed or not)
+    pub(crate) fn matches(&self, bytes: &[u8]) -> bool {
+        match ChunkHeader::parse(bytes) {
+            Some(ref header) => {
+                bytes.len() == HEADER_LEN && self.accepts(header)
+            }
+            None => false,
+        }
+    }
+
+    fn accepts(&self, header: &ChunkHeader) -> bool {
+        header == self || *header == self.uncompressed()
+    }
+
+    /// `sg` with the header in front
+    pub(crate) fn prepend(&self, sg: SGData) -> SGData {
+        let mut with_header = SGData::from_single(self.to_bytes());
+        for part in sg.as_parts() {
+            with_header.push_arcref(part.clone());
+        }
+        with_header
+    }
+
+    /// `sg` of chunk `hex_digest` without the header, and whether it's
+    /// stored uncompressed
+    ///
+    /// Fails with `InvalidData` if there's no header (eg. the chunk is
+    /// truncated or overwritten), or it describes a format other than
+    /// `self`.
+    pub(crate) fn strip(
+        &self,
+        sg: SGData,
+        hex_digest: &str,
+    ) -> io::Result<(SGData, bool)> {
+        let data = sg.to_linear();
+        let msg = match ChunkHeader::parse(&data) {
+            None => "no valid chunk header".to_owned(),
+            Some(ref header) if !self.accepts(header) => format!(
+                "unexpected chunk header (flags {}, compression {}, \
+                 encryption {})",
+                header.flags, header.compression, header.encryption
+            ),
+            // the rest of the chunk is not copied
+            Some(header) => {
+                return Ok((
+                    SGData::from_vec(vec![
+                        data.map(|data| &data[HEADER_LEN..])
+                    ]),
+                    header.flags & FLAG_UNCOMPRESSED != 0,
+                ))
+            }
+        };
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk {}: {}", hex_digest, msg),
+        ))
+    }
+}
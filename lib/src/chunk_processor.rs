@@ -1,15 +1,63 @@
+//! Processing of chunks, in stages
+//!
+//! ```norust
+//! chunker -> hash -> dedup -> compress -> encrypt -> store
+//! ```
+//!
+//! Every stage has its own threads, taking chunks from a work-stealing
+//! queue (see `work_queue`), so eg. slow compression doesn't hold up
+//! hashing (and deduplicating) the chunks that don't need it. Chunks
+//! already stored, or already on their way (eg. the same data written
+//! twice in a `WriteSession`), leave after `dedup`; or right after `hash`
+//! if it's known from the earlier chunks, so repeated data isn't queued,
+//! nor looked up in the backend, again. The digest of every
+//! chunk is sent back once it's stored (in whatever order), or right
+//! away if it's already on its way: then it's only sure to be stored once
+//! the processor is done.
 use super::aio;
 use super::{DataType, Repo};
-use compression::ArcCompression;
-use crossbeam_channel;
+use compression::{self, ArcCompression};
+use crossbeam::thread::Scope;
 use encryption::ArcEncrypter;
+use error;
 use hashing::ArcHasher;
+use hex;
+use num_cpus;
+use progress::ProgressCounter;
 use sgdata::SGData;
 use slog::{Level, Logger};
 use slog_perf::TimeReporter;
-use std::io;
-use std::sync::mpsc;
-use {Digest, Generation};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
+use work_queue::{self, WorkSender};
+use {CancelToken, Digest, EncryptHandle, Generation, PackWriter};
+
+/// Number of threads of each stage of processing chunks, see
+/// `WriteOptions::threads`
+///
+/// `0` (the default) means one thread per CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageThreads {
+    /// Calculating digests
+    pub hash: usize,
+    /// Looking up chunks already stored (I/O bound)
+    pub dedup: usize,
+    pub compress: usize,
+    pub encrypt: usize,
+    /// Writing chunks (I/O bound)
+    pub store: usize,
+}
+
+fn or_num_cpus(threads: usize) -> usize {
+    if threads == 0 {
+        num_cpus::get()
+    } else {
+        threads
+    }
+}
 
 pub(crate) struct Message {
     pub data: (u64, SGData),
@@ -17,165 +65,439 @@ pub(crate) struct Message {
     pub response_tx: mpsc::Sender<(u64, Digest)>,
 }
 
+/// Chunk between the stages, after `hash`
+struct Chunk {
+    id: u64,
+    sg: SGData,
+    /// Length of the data, before compression and encryption
+    len: u64,
+    data_type: DataType,
+    digest: Digest,
+    /// `sg` is compressed, after `compress`
+    compressed: bool,
+    response_tx: mpsc::Sender<(u64, Digest)>,
+}
+
+impl Chunk {
+    fn done(self) {
+        self.response_tx
+            .send((self.id, self.digest))
+            .expect("chunk_processor: digests_tx.send")
+    }
+}
+
+/// Start `threads` threads of a stage called `name`, doing `f` with the
+/// items sent to the returned sender
+///
+/// The threads finish after all the senders are gone.
+fn stage<'env, T, F>(
+    scope: &Scope<'env>,
+    name: &'static str,
+    log: &Logger,
+    threads: usize,
+    f: F,
+) -> WorkSender<T>
+where
+    T: Send + 'env,
+    F: Fn(T) + Clone + Send + 'env,
+{
+    let (tx, rxs) = work_queue::bounded(threads, threads);
+    for rx in rxs {
+        let f = f.clone();
+        let log = log.clone();
+        scope.spawn(move |_| {
+            let mut timer =
+                TimeReporter::new_with_level(name, log, Level::Debug);
+            loop {
+                timer.start("rx");
+                match rx.recv() {
+                    Some(item) => {
+                        timer.start("processing");
+                        f(item)
+                    }
+                    None => return,
+                }
+            }
+        });
+    }
+    tx
+}
+
+#[derive(Clone)]
 pub(crate) struct ChunkProcessor {
     repo: Repo,
-    rx: crossbeam_channel::Receiver<Message>,
     aio: aio::AsyncIO,
     log: Logger,
     encrypter: ArcEncrypter,
     compressor: ArcCompression,
     hasher: ArcHasher,
-    generations: Vec<Generation>,
+    /// Hasher of data chunks, keyed if the repo uses keyed digests
+    data_hasher: ArcHasher,
+    gen_strings: Vec<String>,
+    /// Pack new chunks with it, if they fit
+    packer: Option<Arc<PackWriter>>,
+    /// Count data chunks in it
+    progress: Option<Arc<ProgressCounter>>,
+    /// Check the chunks stored, see `WriteOptions::verify_writes`
+    verify_writes: bool,
+    /// First chunk that failed to be stored (or the check), see
+    /// `check_stored`
+    failure: Arc<Mutex<Option<io::Error>>>,
+    /// Cancelled once a chunk failed to be stored, so no more data is read
+    stop: CancelToken,
+    /// Digests of the chunks found stored by `dedup`, or passed on by it
+    /// to be stored, so far
+    known: Arc<Mutex<HashSet<Vec<u8>>>>,
 }
 
 impl ChunkProcessor {
     pub fn new(
         repo: Repo,
-        rx: crossbeam_channel::Receiver<Message>,
         aio: aio::AsyncIO,
-        encrypter: ArcEncrypter,
+        enc: &EncryptHandle,
         compressor: ArcCompression,
-        hasher: ArcHasher,
-        generations: Vec<Generation>,
+        generations: &[Generation],
+        packer: Option<Arc<PackWriter>>,
     ) -> Self {
-        assert!(generations.len() >= 1);
+        assert!(!generations.is_empty());
         ChunkProcessor {
             log: repo.log.clone(),
+            hasher: Arc::clone(&repo.hasher),
             repo,
-            rx,
             aio,
-            encrypter,
+            encrypter: Arc::clone(&enc.encrypter),
             compressor,
-            hasher,
-            generations,
+            data_hasher: Arc::clone(&enc.hasher),
+            gen_strings: generations
+                .iter()
+                .map(|gen| gen.to_string())
+                .collect(),
+            packer,
+            progress: None,
+            verify_writes: false,
+            failure: Arc::new(Mutex::new(None)),
+            stop: CancelToken::new(),
+            known: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn with_progress(
+        mut self,
+        progress: Option<Arc<ProgressCounter>>,
+    ) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    pub fn with_verify_writes(mut self, verify_writes: bool) -> Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    /// Fail if any chunk processed so far failed to be stored
+    ///
+    /// Such chunks are still sent back, so the data and its index get
+    /// through the stages, but they mustn't be referred to by a name.
+    pub fn check_stored(&self) -> io::Result<()> {
+        match self.failure.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Cancelled once storing a chunk failed: the data read afterwards
+    /// wouldn't be stored anyway
+    pub fn stop_token(&self) -> &CancelToken {
+        &self.stop
+    }
+
+    /// Did storing any chunk fail already
+    fn failed(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    fn fail(&self, e: io::Error) {
+        let mut failure = self.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(e);
+        }
+        self.stop.cancel();
+    }
+
+    /// Start all the stages in `scope`, returning the sender of chunks to
+    /// process
+    pub fn spawn<'env>(
+        &self,
+        scope: &Scope<'env>,
+        threads: &StageThreads,
+    ) -> WorkSender<Message> {
+        let log = &self.log;
+        let store = {
+            let processor = self.clone();
+            stage(scope, "chunk-store", log, or_num_cpus(threads.store), {
+                move |chunk| processor.store(chunk)
+            })
+        };
+        let encrypt = {
+            let processor = self.clone();
+            stage(
+                scope,
+                "chunk-encrypt",
+                log,
+                or_num_cpus(threads.encrypt),
+                move |chunk| processor.encrypt(chunk, &store),
+            )
+        };
+        let compress = {
+            let processor = self.clone();
+            stage(
+                scope,
+                "chunk-compress",
+                log,
+                or_num_cpus(threads.compress),
+                move |chunk| processor.compress(chunk, &encrypt),
+            )
+        };
+        let dedup = {
+            let processor = self.clone();
+            stage(scope, "chunk-dedup", log, or_num_cpus(threads.dedup), {
+                move |chunk| processor.dedup(chunk, &compress)
+            })
+        };
+        let processor = self.clone();
+        stage(scope, "chunk-hash", log, or_num_cpus(threads.hash), {
+            move |message| processor.hash(message, &dedup)
+        })
+    }
+
+    fn hash(&self, message: Message, tx: &WorkSender<Chunk>) {
+        let Message {
+            data,
+            response_tx,
+            data_type,
+        } = message;
+        let (id, sg) = data;
+
+        let digest = Digest(self.hasher_of(data_type).calculate_digest(&sg));
+        let chunk = Chunk {
+            id,
+            len: sg.len() as u64,
+            sg,
+            data_type,
+            digest,
+            compressed: false,
+            response_tx,
+        };
+        if self.known.lock().unwrap().contains(&chunk.digest.0) {
+            trace!(self.log, "already known");
+            return self.dedup_hit(chunk);
         }
+        send(tx, chunk)
     }
 
-    pub fn run(&self) {
-        let mut timer = TimeReporter::new_with_level(
-            "chunk-processing",
-            self.log.clone(),
-            Level::Debug,
-        );
-
-        let gen_strings: Vec<_> =
-            self.generations.iter().map(|gen| gen.to_string()).collect();
-
-        let last_gen_str = gen_strings.last().unwrap().to_owned();
-        loop {
-            timer.start("rx");
-
-            if let Ok(input) = self.rx.recv() {
-                timer.start("processing");
-
-                let Message {
-                    data,
-                    response_tx,
-                    data_type,
-                } = input;
-                let (sg_id, sg) = data;
-
-                let digest = Digest(self.hasher.calculate_digest(&sg));
-
-                let mut found = false;
-                // lookup all generations in order, starting from current one
-                // and at the end try the current gen. again, in case some other
-                // thread/ instance just moved it from older generation to the
-                // current one
-                for gen_str in gen_strings
-                    .iter()
-                    .rev()
-                    .chain([&last_gen_str].iter().cloned())
-                {
-                    let chunk_path = self.repo.chunk_rel_path_by_digest(
-                        digest.as_digest_ref(),
-                        gen_str,
-                    );
-                    match self.aio.read_metadata(chunk_path.clone()).wait() {
-                        Ok(_metadata) => {
-                            found = true;
-                            if gen_str == &last_gen_str {
-                                trace!(self.log, "already exists"; "path" => %chunk_path.display());
-                            } else {
-                                trace!(self.log, "already exists in previous generation";
-                                       "path" => %chunk_path.display());
-                                let dst_path =
-                                    self.repo.chunk_rel_path_by_digest(
-                                        digest.as_digest_ref(),
-                                        gen_strings.last().unwrap(),
-                                    );
-                                self.aio
-                                    .rename(
-                                        chunk_path.clone(),
-                                        dst_path.clone(),
-                                    )
-                                    .wait()
-                                    .unwrap_or_else(|_e| {
-                                        // chunk might have been upated
-                                        // concurrently; check
-                                        // if it's already in the destination
-                                        if self
-                                            .aio
-                                            .read_metadata(dst_path.clone())
-                                            .wait()
-                                            .is_err()
-                                        {
-                                            panic!(
-                                                "rename failed {} -> {}",
-                                                chunk_path.display(),
-                                                dst_path.display()
-                                            )
-                                        }
-                                    });
+    /// Pass the chunk on, unless it's already stored
+    fn dedup(&self, chunk: Chunk, tx: &WorkSender<Chunk>) {
+        let digest = &chunk.digest;
+        let last_gen_str = self.gen_strings.last().unwrap();
+        // lookup all generations in order, starting from current one
+        // and at the end try the current gen. again, in case some other
+        // thread/ instance just moved it from older generation to the
+        // current one
+        for gen_str in self
+            .gen_strings
+            .iter()
+            .rev()
+            .chain(Some(last_gen_str))
+        {
+            let location = self
+                .repo
+                .find_chunk_in(digest.as_digest_ref(), gen_str)
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "looking up chunk {} failed, err: {}",
+                        hex::encode(&digest.0),
+                        e
+                    )
+                });
+            if let Some(location) = location {
+                if gen_str == last_gen_str {
+                    trace!(self.log, "already exists"; "gen" => gen_str);
+                } else {
+                    trace!(self.log, "already exists in previous generation";
+                           "gen" => gen_str);
+                    self.repo
+                        .move_chunk_to_gen(
+                            digest.as_digest_ref(),
+                            &location,
+                            None,
+                            last_gen_str,
+                        )
+                        .unwrap_or_else(|e| {
+                            // chunk might have been upated
+                            // concurrently; check
+                            // if it's already in the destination
+                            let moved = self.repo.find_chunk_in(
+                                digest.as_digest_ref(),
+                                last_gen_str,
+                            );
+                            if let Ok(Some(_)) = moved {
+                                return;
                             }
-                            break;
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                        Err(e) => panic!(
-                            "read_metadata failed for {}, err: {}",
-                            chunk_path.display(),
-                            e
-                        ),
-                    }
+                            panic!(
+                                "moving chunk {} from {} failed: {}",
+                                hex::encode(&digest.0),
+                                gen_str,
+                                e
+                            )
+                        });
                 }
+                self.known.lock().unwrap().insert(digest.0.clone());
+                return self.dedup_hit(chunk);
+            }
+        }
+        if !self.known.lock().unwrap().insert(digest.0.clone()) {
+            trace!(self.log, "already on its way");
+            return self.dedup_hit(chunk);
+        }
+        send(tx, chunk)
+    }
 
-                if !found {
-                    let chunk_path = self.repo.chunk_rel_path_by_digest(
-                        digest.as_digest_ref(),
-                        gen_strings.last().unwrap(),
-                    );
-                    let sg = if data_type.should_compress() {
-                        trace!(self.log, "compress"; "path" => %chunk_path.display());
-                        timer.start("compress");
-                        self.compressor.compress(sg).unwrap()
-                    } else {
-                        sg
-                    };
+    /// Done with a chunk that's stored already (or on its way)
+    fn dedup_hit(&self, chunk: Chunk) {
+        self.repo.report_metrics(|metrics| {
+            metrics.dedup_hit(chunk.data_type, chunk.len)
+        });
+        self.done(chunk, true)
+    }
 
-                    let sg = if data_type.should_encrypt() {
-                        trace!(self.log, "encrypt"; "path" => %chunk_path.display());
-                        timer.start("encrypt");
-                        self.encrypter.encrypt(sg, &digest.0).unwrap()
-                    } else {
-                        sg
-                    };
+    fn compress(&self, mut chunk: Chunk, tx: &WorkSender<Chunk>) {
+        if chunk.data_type.should_compress() {
+            trace!(self.log, "compress";
+                   "digest" => hex::encode(&chunk.digest.0));
+            let len = chunk.sg.len() as u64;
+            let (sg, compressed) = compression::compress_if_worth_it(
+                &*self.compressor,
+                chunk.sg,
+                self.repo.config.min_compression_savings,
+            ).unwrap();
+            chunk.sg = sg;
+            chunk.compressed = compressed;
+            self.repo.report_metrics(|metrics| {
+                metrics.compressed(len, chunk.sg.len() as u64)
+            });
+        }
+        if self.verify_writes {
+            if let Err(e) = self.check_decodes(&chunk) {
+                self.fail(e);
+            }
+        }
+        send(tx, chunk)
+    }
 
-                    timer.start("tx-writer");
-                    self.aio.write_checked_idempotent(
-                        self.repo.chunk_rel_path_by_digest(
-                            digest.as_digest_ref(),
-                            &last_gen_str,
-                        ),
-                        sg,
-                    );
+    /// Check the chunk, as it's going to be encrypted, decompresses to
+    /// its digest
+    ///
+    /// Without the secret key, that's as far as a stored chunk can be
+    /// decoded.
+    fn check_decodes(&self, chunk: &Chunk) -> io::Result<()> {
+        let mut digest = self.hasher_of(chunk.data_type).digest_writer();
+        if chunk.compressed {
+            self.compressor
+                .decompress_into(chunk.sg.clone(), &mut digest)?;
+        } else {
+            for part in chunk.sg.as_parts() {
+                digest.write_all(part)?;
+            }
+        }
+        if digest.finish() != chunk.digest.0 {
+            return Err(error::Error::ChunkCorrupted {
+                digest: chunk.digest.0.clone(),
+                reason: "doesn't decompress to its digest".into(),
+            }.into());
+        }
+        Ok(())
+    }
+
+    fn encrypt(&self, mut chunk: Chunk, tx: &WorkSender<Chunk>) {
+        if chunk.data_type.should_encrypt() {
+            trace!(self.log, "encrypt";
+                   "digest" => hex::encode(&chunk.digest.0));
+            let start = Instant::now();
+            chunk.sg =
+                self.encrypter.encrypt(chunk.sg, &chunk.digest.0).unwrap();
+            self.repo
+                .report_metrics(|metrics| metrics.encrypted(start.elapsed()));
+        }
+        send(tx, chunk)
+    }
+
+    fn store(&self, mut chunk: Chunk) {
+        let sg = mem::replace(&mut chunk.sg, SGData::empty());
+        let sg =
+            self.repo
+                .with_chunk_header(sg, chunk.data_type, chunk.compressed);
+        let len = sg.len() as u64;
+        match self.packer {
+            // eg. the storage is full: the data won't be stored anyway, so
+            // don't try the rest of it
+            _ if self.failed() => {}
+            Some(ref packer) if packer.fits(sg.len() as u64) => {
+                if let Err(e) = packer.add(&chunk.digest.0, sg) {
+                    self.fail(e);
                 }
-                timer.start("tx-digest");
-                response_tx
-                    .send((sg_id, digest))
-                    .expect("chunk_processor: digests_tx.send")
-            } else {
-                return;
+            }
+            _ => {
+                let path = self.repo.chunk_rel_path_by_digest(
+                    chunk.digest.as_digest_ref(),
+                    self.gen_strings.last().unwrap(),
+                );
+                // like packs, not counted as new chunks
+                let res = match self.repo.parity_file(&path, &sg) {
+                    Some((parity_path, parity)) => self
+                        .repo
+                        .aio
+                        .write_idempotent(parity_path, parity)
+                        .wait(),
+                    None => Ok(()),
+                };
+                let res = res.and_then(|()| {
+                    if self.verify_writes {
+                        self.aio.write_verified(path, sg)
+                    } else {
+                        self.aio.write_idempotent(path, sg).wait()
+                    }
+                });
+                if let Err(e) = res {
+                    self.fail(e);
+                }
+            }
+        }
+        self.repo.report_metrics(|metrics| {
+            metrics.chunk_written(chunk.data_type, len)
+        });
+        self.done(chunk, false)
+    }
+
+    fn hasher_of(&self, data_type: DataType) -> &ArcHasher {
+        if data_type == DataType::Data {
+            &self.data_hasher
+        } else {
+            &self.hasher
+        }
+    }
+
+    fn done(&self, chunk: Chunk, dedup_hit: bool) {
+        if let Some(ref progress) = self.progress {
+            if chunk.data_type == DataType::Data {
+                progress.chunk_done(chunk.len, dedup_hit);
             }
         }
+        chunk.done()
+    }
+}
+
+fn send<T>(tx: &WorkSender<T>, item: T) {
+    if tx.send(item).is_err() {
+        panic!("chunk process queue closed")
     }
 }
@@ -1,6 +1,7 @@
 use owning_ref::ArcRef;
 use rollsum;
 use rollsum::CDC;
+use std::cmp;
 use std::mem;
 use std::sync::Arc;
 use SGData;
@@ -9,16 +10,21 @@ use SGData;
 pub(crate) trait Chunking {
     fn find_chunk<'a>(&mut self, buf: &'a [u8])
         -> Option<(&'a [u8], &'a [u8])>;
+
+    /// Start over, as at the beginning of the data
+    fn reset(&mut self);
 }
 
 pub(crate) struct Bup {
     engine: rollsum::Bup,
+    bits: u32,
 }
 
 impl Bup {
     pub fn new(bits: u32) -> Self {
         Bup {
             engine: rollsum::Bup::new_with_chunk_bits(bits),
+            bits,
         }
     }
 }
@@ -30,16 +36,22 @@ impl Chunking for Bup {
     ) -> Option<(&'a [u8], &'a [u8])> {
         self.engine.find_chunk(buf)
     }
+
+    fn reset(&mut self) {
+        *self = Bup::new(self.bits);
+    }
 }
 
 pub(crate) struct Gear {
     engine: rollsum::Gear,
+    bits: u32,
 }
 
 impl Gear {
     pub fn new(bits: u32) -> Self {
         Gear {
             engine: rollsum::Gear::new_with_chunk_bits(bits),
+            bits,
         }
     }
 }
@@ -51,16 +63,22 @@ impl Chunking for Gear {
     ) -> Option<(&'a [u8], &'a [u8])> {
         self.engine.find_chunk(buf)
     }
+
+    fn reset(&mut self) {
+        *self = Gear::new(self.bits);
+    }
 }
 
 pub(crate) struct FastCDC {
     engine: rollsum::FastCDC,
+    bits: u32,
 }
 
 impl FastCDC {
     pub fn new(bits: u32) -> Self {
         FastCDC {
             engine: rollsum::FastCDC::new_with_chunk_bits(bits),
+            bits,
         }
     }
 }
@@ -72,8 +90,191 @@ impl Chunking for FastCDC {
     ) -> Option<(&'a [u8], &'a [u8])> {
         self.engine.find_chunk(buf)
     }
+
+    fn reset(&mut self) {
+        *self = FastCDC::new(self.bits);
+    }
+}
+
+/// Fixed-size chunking
+///
+/// Cheap, but any insertion or removal shifts all the following chunk
+/// edges, so it deduplicates well only data modified in-place (eg. disk
+/// images).
+pub(crate) struct Fixed {
+    size: usize,
+    /// Bytes accumulated in the current chunk so far
+    cur_len: usize,
+}
+
+impl Fixed {
+    pub fn new(bits: u32) -> Self {
+        Fixed {
+            size: 1 << bits,
+            cur_len: 0,
+        }
+    }
+}
+
+impl Chunking for Fixed {
+    fn find_chunk<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(&'a [u8], &'a [u8])> {
+        let room = self.size - self.cur_len;
+        if buf.len() < room {
+            self.cur_len += buf.len();
+            None
+        } else {
+            self.cur_len = 0;
+            Some(buf.split_at(room))
+        }
+    }
+
+    fn reset(&mut self) {
+        self.cur_len = 0;
+    }
 }
 
+/// Chunking wrapper enforcing minimum and maximum chunk size
+///
+/// Edges found by the wrapped algorithm before `min_size` bytes were
+/// accumulated are skipped, and an edge is forced once `max_size` bytes
+/// were accumulated without finding one.
+pub(crate) struct Bounded {
+    inner: Box<dyn Chunking>,
+    min_size: usize,
+    max_size: usize,
+    /// Bytes accumulated in the current chunk so far
+    cur_len: usize,
+}
+
+impl Bounded {
+    pub fn new(
+        inner: Box<dyn Chunking>,
+        min_size: usize,
+        max_size: usize,
+    ) -> Self {
+        debug_assert!(min_size <= max_size);
+        debug_assert!(max_size > 0);
+        Bounded {
+            inner,
+            min_size,
+            max_size,
+            cur_len: 0,
+        }
+    }
+}
+
+impl Chunking for Bounded {
+    fn find_chunk<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(&'a [u8], &'a [u8])> {
+        let mut offset = 0;
+        loop {
+            let rest = &buf[offset..];
+            let room = self.max_size - self.cur_len;
+            let window = &rest[..cmp::min(room, rest.len())];
+
+            let (consumed, edge_found) = match self.inner.find_chunk(window) {
+                Some((last, _)) => (last.len(), true),
+                None => (window.len(), false),
+            };
+
+            offset += consumed;
+            self.cur_len += consumed;
+
+            if (edge_found && self.cur_len >= self.min_size)
+                || self.cur_len >= self.max_size
+            {
+                self.cur_len = 0;
+                return Some(buf.split_at(offset));
+            }
+
+            if offset == buf.len() {
+                return None;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.cur_len = 0;
+    }
+}
+
+/// Chunking wrapper moving edges to the next multiple of `align` bytes
+///
+/// For streams of fixed-size records (see `index`), so no record is split
+/// between chunks. The bytes between an edge found by the wrapped algorithm
+/// and the moved one are not passed to it.
+pub(crate) struct Aligned {
+    inner: Box<dyn Chunking>,
+    align: usize,
+    /// Bytes accumulated in the current chunk so far
+    cur_len: usize,
+    /// Bytes left until the moved edge, if an edge was found
+    pad_left: Option<usize>,
+}
+
+impl Aligned {
+    pub fn new(inner: Box<dyn Chunking>, align: usize) -> Self {
+        debug_assert!(align > 0);
+        Aligned {
+            inner,
+            align,
+            cur_len: 0,
+            pad_left: None,
+        }
+    }
+}
+
+impl Chunking for Aligned {
+    fn find_chunk<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(&'a [u8], &'a [u8])> {
+        let offset = match self.pad_left {
+            Some(_) => 0,
+            None => match self.inner.find_chunk(buf) {
+                Some((last, _)) => {
+                    self.cur_len += last.len();
+                    let pad = (self.align - self.cur_len % self.align)
+                        % self.align;
+                    self.pad_left = Some(pad);
+                    last.len()
+                }
+                None => {
+                    self.cur_len += buf.len();
+                    return None;
+                }
+            },
+        };
+
+        let pad = self.pad_left.unwrap();
+        let rest = buf.len() - offset;
+        if rest < pad {
+            self.cur_len += rest;
+            self.pad_left = Some(pad - rest);
+            return None;
+        }
+        self.cur_len = 0;
+        self.pad_left = None;
+        Some(buf.split_at(offset + pad))
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.cur_len = 0;
+        self.pad_left = None;
+    }
+}
+
+/// Iterator splitting data into chunks
+///
+/// An empty buffer in the data forces a chunk edge (eg. at a file boundary
+/// in a tar stream), and starts the chunking over.
 pub(crate) struct Chunker<I> {
     iter: I,
     /// Pieces of chunk to return next, but yet
@@ -98,7 +299,11 @@ impl<I> Chunker<I> {
     }
 }
 
-impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
+impl<I, B> Iterator for Chunker<I>
+where
+    I: Iterator<Item = B>,
+    B: Into<Arc<Vec<u8>>>,
+{
     type Item = SGData;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -106,8 +311,20 @@ impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
             if let Some(buf) = self.pending.take().or_else(|| {
                 self.iter
                     .next()
-                    .map(|v| ArcRef::new(Arc::new(v)).map(|a| a.as_slice()))
+                    .map(|v| ArcRef::new(v.into()).map(|a| a.as_slice()))
             }) {
+                if buf.is_empty() {
+                    self.chunking.reset();
+                    // minimum size of chunks, see below
+                    if self.incomplete_chunk.len() >= 64 {
+                        self.chunks_returned += 1;
+                        return Some(mem::replace(
+                            &mut self.incomplete_chunk,
+                            SGData::empty(),
+                        ));
+                    }
+                    continue;
+                }
                 if let Some((last, rest)) = self.chunking.find_chunk(&*buf) {
                     debug_assert_eq!(last.len() + rest.len(), buf.len());
                     self.incomplete_chunk
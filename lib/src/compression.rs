@@ -7,14 +7,6 @@ use std::io;
 use std::cmp;
 #[cfg(feature = "with-zstd")]
 use std::io::Read;
-#[cfg(
-    any(
-        feature = "with-bzip2",
-        feature = "with-deflate",
-        feature = "with-xz2",
-        feature = "with-zstd"
-    )
-)]
 use std::io::Write;
 use std::sync::Arc;
 
@@ -32,6 +24,14 @@ pub type ArcCompression = Arc<dyn Compression + Send + Sync>;
 pub trait Compression {
     fn compress(&self, buf: SGData) -> io::Result<SGData>;
     fn decompress(&self, bug: SGData) -> io::Result<SGData>;
+
+    /// Decompress `buf` into `writer`, passing the output on as it's
+    /// produced instead of collecting all of it first
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()>;
 }
 
 pub struct NoCompression;
@@ -43,6 +43,16 @@ impl Compression for NoCompression {
     fn decompress(&self, buf: SGData) -> io::Result<SGData> {
         Ok(buf)
     }
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        for part in buf.as_parts() {
+            writer.write_all(part)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(feature = "with-deflate")]
@@ -87,6 +97,20 @@ impl Compression for Deflate {
         }
         Ok(SGData::from_single(decompressor.finish()?))
     }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor = flate2::write::DeflateDecoder::new(writer);
+
+        for part in buf.as_parts() {
+            decompressor.write_all(part)?;
+        }
+        decompressor.finish()?;
+        Ok(())
+    }
 }
 
 #[cfg(feature = "with-bzip2")]
@@ -131,6 +155,20 @@ impl Compression for Bzip2 {
         }
         Ok(SGData::from_single(decompressor.finish()?))
     }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor = bzip2::write::BzDecoder::new(writer);
+
+        for sg_part in buf.as_parts() {
+            decompressor.write_all(sg_part)?;
+        }
+        decompressor.finish()?;
+        Ok(())
+    }
 }
 
 #[cfg(feature = "with-xz2")]
@@ -189,6 +227,26 @@ impl Compression for Xz2 {
         }
         Ok(SGData::from_single(backing))
     }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor =
+            lzma::LzmaWriter::new_decompressor(writer).unwrap();
+        for sg_part in buf.as_parts() {
+            // see `decompress`
+            let todo = sg_part.len();
+            let mut index = 0;
+            while index < todo {
+                let bytes = decompressor.write(&sg_part[index..]).unwrap();
+                index += bytes;
+            }
+        }
+        decompressor.finish().unwrap();
+        Ok(())
+    }
 }
 
 #[cfg(feature = "with-zstd")]
@@ -265,4 +323,62 @@ impl Compression for Zstd {
         }
         Ok(SGData::from_single(backing))
     }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut reader = SGReader::new(&buf);
+        let mut decompressor = zstd::Decoder::new(&mut reader)?;
+        io::copy(&mut decompressor, writer)?;
+        Ok(())
+    }
+}
+
+/// Entropy of the bytes of `buf`, in bits per byte (8 for random data)
+fn entropy(buf: &SGData) -> f64 {
+    let mut counts = [0u64; 256];
+    for part in buf.as_parts() {
+        for &byte in part.iter() {
+            counts[byte as usize] += 1;
+        }
+    }
+    let len = buf.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Compress `buf` with `compression`, unless that saves less than
+/// `min_savings` percent of its size (if any)
+///
+/// Returns the data to store, and whether it's compressed. Data whose
+/// bytes are too random to save that much (by their entropy) isn't even
+/// tried, though compressors could still shrink eg. repeated random data.
+pub(crate) fn compress_if_worth_it(
+    compression: &dyn Compression,
+    buf: SGData,
+    min_savings: Option<u8>,
+) -> io::Result<(SGData, bool)> {
+    let min_savings = match min_savings {
+        Some(min_savings) => min_savings,
+        None => return Ok((compression.compress(buf)?, true)),
+    };
+    let len = buf.len() as u64;
+    let max_len = len * (100 - u64::from(min_savings.min(100))) / 100;
+    if len == 0 || entropy(&buf) / 8.0 * len as f64 > max_len as f64 {
+        return Ok((buf, false));
+    }
+    let compressed = compression.compress(buf.clone())?;
+    if compressed.len() as u64 > max_len {
+        Ok((buf, false))
+    } else {
+        Ok((compressed, true))
+    }
 }
@@ -17,6 +17,8 @@ pub enum Chunking {
     Gear { chunk_bits: u32 },
     #[serde(rename = "fastcdc")]
     FastCDC { chunk_bits: u32 },
+    #[serde(rename = "fixed")]
+    Fixed { chunk_bits: u32 },
 }
 
 /// Default implementation for the `Chunking`
@@ -33,7 +35,8 @@ impl Chunking {
         match self {
             Chunking::Bup { chunk_bits: bits }
             | Chunking::Gear { chunk_bits: bits }
-            | Chunking::FastCDC { chunk_bits: bits } => {
+            | Chunking::FastCDC { chunk_bits: bits }
+            | Chunking::Fixed { chunk_bits: bits } => {
                 30 >= bits && bits >= 10
             }
         }
@@ -50,6 +53,92 @@ impl Chunking {
             Chunking::FastCDC { chunk_bits } => {
                 Box::new(chunking::FastCDC::new(chunk_bits))
             }
+            Chunking::Fixed { chunk_bits } => {
+                Box::new(chunking::Fixed::new(chunk_bits))
+            }
+        }
+    }
+}
+
+/// Bounds on the size of produced chunks
+///
+/// Applied on top of whatever `Chunking` algorithm is used: edges found
+/// before `min_size` bytes are ignored, and a chunk is forcibly cut after
+/// `max_size` bytes. `None` means no bound (other than the 64-byte minimum
+/// `Chunker` always imposes).
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ChunkSizeBounds {
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+impl ChunkSizeBounds {
+    pub fn valid(self) -> bool {
+        let in_range = |size: Option<u64>| match size {
+            Some(s) => (64..=1 << 30).contains(&s),
+            None => true,
+        };
+
+        if !in_range(self.min_size) || !in_range(self.max_size) {
+            return false;
+        }
+
+        match (self.min_size, self.max_size) {
+            (Some(min), Some(max)) => min <= max,
+            _ => true,
+        }
+    }
+
+    pub fn is_unbounded(self) -> bool {
+        self.min_size.is_none() && self.max_size.is_none()
+    }
+
+    pub(crate) fn wrap_engine(
+        self,
+        engine: Box<dyn chunking::Chunking>,
+    ) -> Box<dyn chunking::Chunking> {
+        if self.is_unbounded() {
+            return engine;
         }
+
+        Box::new(chunking::Bounded::new(
+            engine,
+            self.min_size.unwrap_or(0) as usize,
+            self.max_size.map_or(::std::usize::MAX, |s| s as usize),
+        ))
+    }
+}
+
+/// Largest `ChunkingTier::min_stream_size`
+///
+/// Data is read ahead (and kept in memory) up to it, to tell the tier.
+pub const MAX_TIER_STREAM_SIZE: u64 = 1 << 30;
+
+/// Name selecting the repo-wide chunking, instead of any tier
+pub const DEFAULT_CHUNKING_TIER: &str = "default";
+
+/// Chunking used for data streams of given size class
+///
+/// A stream uses the tier with the largest `min_stream_size` not bigger
+/// than the size of the stream, or the repo-wide chunking if there's
+/// none. A tier can also be picked explicitly, by the `name`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChunkingTier {
+    pub name: String,
+    pub min_stream_size: u64,
+    pub chunking: Chunking,
+    #[serde(default)]
+    pub chunk_size_bounds: ChunkSizeBounds,
+}
+
+impl ChunkingTier {
+    pub fn valid(&self) -> bool {
+        !self.name.is_empty()
+            && self.name != DEFAULT_CHUNKING_TIER
+            && self.min_stream_size <= MAX_TIER_STREAM_SIZE
+            && self.chunking.valid()
+            && self.chunk_size_bounds.valid()
     }
 }
@@ -45,6 +45,21 @@ impl Compression {
             Compression::Zstd(d) => Arc::new(compression::Zstd::new(d.level)),
         }
     }
+
+    /// Identifier of the algorithm in chunk headers
+    pub(crate) fn id(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(_) => 1,
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(_) => 2,
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(_) => 3,
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(_) => 4,
+        }
+    }
 }
 #[cfg(feature = "with-deflate")]
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -1,3 +1,4 @@
+use secret_key::SecretKey;
 use std::io;
 use std::sync::Arc;
 use PassphraseFn;
@@ -15,6 +16,87 @@ pub(crate) enum Encryption {
     Curve25519(encryption::Curve25519),
 }
 
+fn not_encrypted<T>() -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "repository is not encrypted",
+    ))
+}
+
+impl Encryption {
+    pub(crate) fn is_encrypted(&self) -> bool {
+        match *self {
+            Encryption::None => false,
+            Encryption::Curve25519(_) => true,
+        }
+    }
+
+    /// Identifier of the scheme in chunk headers
+    pub(crate) fn id(&self) -> u8 {
+        match *self {
+            Encryption::None => 0,
+            Encryption::Curve25519(ref c) => {
+                match (c.random_nonces, c.recipients.is_empty()) {
+                    (false, true) => 1,
+                    (true, true) => 2,
+                    (false, false) => 3,
+                    (true, false) => 4,
+                }
+            }
+        }
+    }
+
+    /// Secret key, unsealed with the passphrase
+    pub(crate) fn sec_key(
+        &self,
+        pass: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<SecretKey> {
+        match *self {
+            Encryption::None => not_encrypted(),
+            Encryption::Curve25519(ref c) => {
+                Ok(SecretKey::new(c.unseal_decrypt(pass, pwhash)?.0.to_vec()))
+            }
+        }
+    }
+
+    /// See `encryption::Curve25519::change_key`
+    pub(crate) fn change_key(
+        &mut self,
+        sec_key: &SecretKey,
+        new_sec_key: &SecretKey,
+        pass: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        match *self {
+            Encryption::None => not_encrypted(),
+            Encryption::Curve25519(ref mut c) => c.change_key(
+                sec_key.as_bytes(),
+                new_sec_key.as_bytes(),
+                pass,
+                pwhash,
+            ),
+        }
+    }
+
+    /// Drop keys replaced by `change_key`, after everything is re-sealed
+    pub(crate) fn forget_previous_keys(&mut self) {
+        if let Encryption::Curve25519(ref mut c) = *self {
+            c.previous_keys.clear();
+        }
+    }
+
+    pub(crate) fn decrypter_from_sec_key(
+        &self,
+        sec_key: &[u8],
+    ) -> io::Result<encryption::ArcDecrypter> {
+        match *self {
+            Encryption::None => not_encrypted(),
+            Encryption::Curve25519(ref c) => c.decrypter_from_sec_key(sec_key),
+        }
+    }
+}
+
 impl encryption::EncryptionEngine for Encryption {
     fn change_passphrase(
         &mut self,
@@ -23,7 +105,7 @@ impl encryption::EncryptionEngine for Encryption {
         pwhash: &config::PWHash,
     ) -> io::Result<()> {
         match *self {
-            Encryption::None => Ok(()),
+            Encryption::None => not_encrypted(),
             Encryption::Curve25519(ref mut c) => {
                 c.change_passphrase(old_p, new_p, pwhash)
             }
@@ -2,15 +2,25 @@
 //! from `settings`.
 
 // {{{ use and mod
-use {serde_yaml, PassphraseFn, SGData};
+use {serde_yaml, PassphraseFn, SGData, DIGEST_SIZE};
 
 use aio;
+use chunking::Chunking as ChunkingEngine;
 use pwhash;
 
+use blake2;
 use hashing;
+use sha2;
 
 use hex;
+use maintenance::MaintenancePolicy;
+use pack::Packing;
+use parity::Parity;
+use seal::Seal;
 use settings;
+use signed_name::NamesSigner;
+use sodiumoxide::crypto::sign;
+use util::*;
 
 use std::io;
 use std::path::{Path, PathBuf};
@@ -26,11 +36,42 @@ pub(crate) use self::encryption::*;
 // }}}
 
 pub const REPO_VERSION_LOWEST: u32 = 3;
-pub const REPO_VERSION_CURRENT: u32 = 3;
+/// Version 4 added chunk size bounds, fixed-size chunking and sha512-256,
+/// version 5 pack files, version 6 chunking tiers, version 7 keyed digests,
+/// version 8 random nonces, version 9 compressed name records, version 10
+/// chunk headers, version 11 additional recipients, version 12 sealing,
+/// version 13 replicas, version 14 previous keys (of an unfinished key
+/// change), version 15 parity files, version 16 lengths in index records,
+/// version 17 portable names, version 18 hidden names, version 19 nesting
+/// widths other than 2, version 20 signed names, version 21 append-only
+/// mode, version 22 audit log, version 23 chunks stored uncompressed
+///
+/// Repositories not using any of them are still written as version 3, so
+/// older releases can keep using them.
+pub const REPO_VERSION_CURRENT: u32 = 23;
 
+/// Directory of all the chunks of a generation, index and data ones alike
+///
+/// A chunk is found with a single lookup of its digest; whether it's an
+/// index chunk is known from the reference to it (and recorded in its
+/// `chunk_header::ChunkHeader`), not from where it's stored.
 pub const DATA_SUBDIR: &'static str = "chunk";
 pub const LOCK_FILE: &'static str = ".lock";
+/// Locked briefly before `LOCK_FILE`, so lockers wait in turns
+pub const LOCK_QUEUE_FILE: &str = ".lock-queue";
 pub const CONFIG_YML_FILE: &'static str = "config.yml";
+/// Secret key encrypted for `age` recipients
+pub const AGE_SEC_KEY_FILE: &str = "sec_key.age";
+/// Maintenance history, see `maintenance`
+pub const MAINTENANCE_YML_FILE: &str = "maintenance.yml";
+/// History of runs, see `run_summary`
+pub const RUNS_YML_FILE: &str = "runs.yml";
+/// Entries of the audit log, see `audit`
+pub const AUDIT_SUBDIR: &str = "audit";
+/// Locked (in `AUDIT_SUBDIR`) while appending to the audit log
+pub const AUDIT_LOCK_FILE: &str = ".lock";
+/// Manifest of a sealed repository (see `seal::SealManifest`)
+pub const SEAL_MANIFEST_FILE: &str = "seal.yml";
 
 // {{{ PWHash
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -81,6 +122,8 @@ pub enum Hashing {
     Sha256,
     #[serde(rename = "blake2b")]
     Blake2b,
+    #[serde(rename = "sha512-256")]
+    Sha512Trunc256,
 }
 
 impl Default for Hashing {
@@ -94,12 +137,34 @@ impl Hashing {
         match *self {
             Hashing::Sha256 => Arc::new(hashing::Sha256),
             Hashing::Blake2b => Arc::new(hashing::Blake2b),
+            Hashing::Sha512Trunc256 => Arc::new(hashing::Sha512Trunc256),
+        }
+    }
+
+    /// Like `to_hasher`, but calculating HMACs keyed with `key`
+    pub(crate) fn to_keyed_hasher(self, key: &[u8]) -> hashing::ArcHasher {
+        match self {
+            Hashing::Sha256 => {
+                Arc::new(hashing::Hmac::<sha2::Sha256>::new(key))
+            }
+            Hashing::Blake2b => {
+                Arc::new(hashing::Hmac::<blake2::Blake2b>::new(key))
+            }
+            Hashing::Sha512Trunc256 => {
+                Arc::new(hashing::Hmac::<sha2::Sha512Trunc256>::new(key))
+            }
         }
     }
 }
 // }}}
 
 // {{{ Nesting
+/// Levels of directories chunk files are spread over, named after the
+/// first hex digits of their digests (`Repo::nesting_width` of them per
+/// level)
+///
+/// The hex digits are always lowercase, so no two paths differ just in case,
+/// as case-insensitive filesystems (eg. on Windows) wouldn't tell them apart.
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct Nesting(pub u8);
 impl Default for Nesting {
@@ -108,9 +173,30 @@ impl Default for Nesting {
     }
 }
 
+/// Hex digits per level of `Nesting`, unless configured otherwise
+pub const DEFAULT_NESTING_WIDTH: u8 = 2;
+
+/// Most hex digits per level of `Nesting`
+pub const MAX_NESTING_WIDTH: u8 = 4;
+
+fn default_nesting_width() -> u8 {
+    DEFAULT_NESTING_WIDTH
+}
+
+fn is_default_nesting_width(width: &u8) -> bool {
+    *width == DEFAULT_NESTING_WIDTH
+}
+
 impl Nesting {
+    /// Directory levels of `width` hex digits each fit in a digest
+    pub(crate) fn valid(&self, width: u8) -> bool {
+        (1..=MAX_NESTING_WIDTH).contains(&width)
+            && usize::from(self.0) * usize::from(width) < 2 * DIGEST_SIZE
+    }
+
     pub fn get_path(
         &self,
+        width: u8,
         base: &Path,
         digest: &[u8],
         gen_str: &str,
@@ -121,8 +207,8 @@ impl Nesting {
         let levels = self.clone().0;
         if levels > 0 {
             for i in 0..levels {
-                let start = i as usize * 2;
-                let end = start + 2;
+                let start = i as usize * width as usize;
+                let end = start + width as usize;
                 dir = dir.join(&hex_digest[start..end]);
             }
         }
@@ -144,12 +230,77 @@ pub(crate) struct Repo {
     #[serde(default)]
     pub chunking: Chunking,
     #[serde(default)]
+    pub chunk_size_bounds: ChunkSizeBounds,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunking_tiers: Vec<ChunkingTier>,
+    #[serde(default)]
     pub hashing: Hashing,
+    /// Digests of data chunks are keyed (see `hashing::Hmac`)
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub keyed_digests: bool,
     #[serde(default)]
     pub compression: Compression,
+    /// Name records are compressed with `compression` too
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub compress_names: bool,
+    /// Data chunks `compression` shrinks by less than this many percent
+    /// are stored uncompressed, flagged in their `chunk_header`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_compression_savings: Option<u8>,
     pub encryption: Encryption,
+    /// Chunks start with a `chunk_header::ChunkHeader`
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub chunk_headers: bool,
+    /// Index records carry the length of the data they refer to (see
+    /// `index`)
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub index_lengths: bool,
+    /// Names are stored under file names valid on every platform (see
+    /// `name::NameIO`)
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub portable_names: bool,
+    /// Names are stored under HMACs of them, sealed inside the records
+    /// (see `hidden_name`)
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub hidden_names: bool,
+    /// Name records are signed with the key matching this public key,
+    /// derived from the secret key (see `signed_name`)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_base64_opt",
+        deserialize_with = "from_base64_opt"
+    )]
+    pub names_sign_key: Option<sign::PublicKey>,
     #[serde(default)]
     pub nesting: Nesting,
+    /// Hex digits of the digest naming each directory level of `nesting`
+    #[serde(
+        default = "default_nesting_width",
+        skip_serializing_if = "is_default_nesting_width"
+    )]
+    pub nesting_width: u8,
+    #[serde(default, skip_serializing_if = "MaintenancePolicy::is_default")]
+    pub maintenance: MaintenancePolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packing: Option<Packing>,
+    /// Chunk files have parity files, to repair them with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parity: Option<Parity>,
+    /// Nothing stored can be removed or replaced (see
+    /// `Repo::set_append_only`)
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub append_only: bool,
+    /// Operations are recorded in the audit log (see `audit`)
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub audit_log: bool,
+    /// The repository is sealed: nothing in it can be modified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<Seal>,
+    /// URLs of backends everything in generations is replicated to (see
+    /// `aio::Replicated`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<String>,
 }
 
 impl Repo {
@@ -157,25 +308,347 @@ impl Repo {
         pass: PassphraseFn,
         settings: settings::Repo,
     ) -> io::Result<Self> {
+        if let settings::Encryption::None = settings.encryption {
+            if settings.keyed_digests {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "keyed digests require encryption",
+                ));
+            }
+            if settings.hidden_names {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "hidden names require encryption",
+                ));
+            }
+            if settings.signed_names {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "signed names require encryption",
+                ));
+            }
+            if !settings.recipients.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "recipients require encryption",
+                ));
+            }
+        }
+        // Recipients couldn't verify the digests
+        if settings.keyed_digests && !settings.recipients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keyed digests can't be used with recipients",
+            ));
+        }
+        // Recipients couldn't find the names
+        if settings.hidden_names && !settings.recipients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "hidden names can't be used with recipients",
+            ));
+        }
+        // Uncompressed chunks are told apart by their headers
+        if settings.min_compression_savings.is_some() && settings.bare_chunks
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "storing chunks uncompressed requires chunk headers",
+            ));
+        }
+        if !settings.nesting.to_config().valid(settings.nesting_width.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "nesting too deep for its width",
+            ));
+        }
+        // asked once, as it's needed again to derive the names signing key
+        let passphrase = if settings.signed_names {
+            Some(pass()?)
+        } else {
+            None
+        };
+        let reuse_passphrase =
+            || Ok(passphrase.clone().expect("passphrase read"));
+        let pass: PassphraseFn = match passphrase {
+            Some(_) => &reuse_passphrase,
+            None => pass,
+        };
         let pwhash = PWHash::from_settings(settings.pwhash);
         let encryption = match settings.encryption {
-            settings::Encryption::Curve25519 => Encryption::Curve25519(
-                ::encryption::Curve25519::new(pass, &pwhash)?,
-            ),
+            settings::Encryption::Curve25519 => {
+                Encryption::Curve25519(::encryption::Curve25519 {
+                    recipients: settings
+                        .recipients
+                        .iter()
+                        .map(|&pub_key| ::encryption::Recipient { pub_key })
+                        .collect(),
+                    ..::encryption::Curve25519::new(
+                        pass,
+                        &pwhash,
+                        !settings.digest_nonces,
+                    )?
+                })
+            }
             settings::Encryption::None => Encryption::None,
         };
+        let names_sign_key = if settings.signed_names {
+            let sec_key = encryption.sec_key(pass, &pwhash)?;
+            Some(NamesSigner::new(&sec_key).pub_key())
+        } else {
+            None
+        };
+        if let Some(passphrase) = passphrase {
+            clear_string(passphrase);
+        }
 
-        Ok(Repo {
+        let mut config = Repo {
             version: REPO_VERSION_CURRENT,
             pwhash,
             chunking: settings.chunking.0,
+            chunk_size_bounds: settings.chunk_size_bounds,
+            chunking_tiers: settings.chunking_tiers,
             encryption,
+            chunk_headers: !settings.bare_chunks,
+            index_lengths: !settings.bare_index,
+            portable_names: !settings.raw_names,
+            hidden_names: settings.hidden_names,
+            names_sign_key,
             compression: settings
                 .compression
                 .to_config(settings.compression_level),
+            compress_names: settings.compress_names,
+            min_compression_savings: settings.min_compression_savings,
             nesting: settings.nesting.to_config(),
+            nesting_width: settings.nesting_width.0,
             hashing: settings.hashing.to_config(),
-        })
+            keyed_digests: settings.keyed_digests,
+            maintenance: MaintenancePolicy::default(),
+            packing: settings.packing,
+            parity: settings.parity,
+            append_only: settings.append_only,
+            audit_log: settings.audit_log,
+            seal: None,
+            replicas: vec![],
+        };
+        config.version = config.required_version();
+
+        Ok(config)
+    }
+
+    /// Lowest format version able to represent this configuration
+    pub(crate) fn required_version(&self) -> u32 {
+        // Releases not knowing about it couldn't read uncompressed chunks
+        if self.min_compression_savings.is_some() {
+            return 23;
+        }
+        // Releases not knowing about it would leave operations unrecorded
+        if self.audit_log {
+            return 22;
+        }
+        // Releases not knowing about it would remove data
+        if self.append_only {
+            return 21;
+        }
+        // Releases not knowing about signatures would store names without
+        if self.names_sign_key.is_some() {
+            return 20;
+        }
+        // Releases not knowing about them would look for names where they
+        // aren't
+        if self.nesting_width != DEFAULT_NESTING_WIDTH {
+            return 19;
+        }
+        if self.hidden_names {
+            return 18;
+        }
+        if self.portable_names {
+            return 17;
+        }
+        if self.index_lengths {
+            return 16;
+        }
+        // Releases not knowing about parity would write chunks without it
+        if self.parity.is_some() {
+            return 15;
+        }
+        // Releases not knowing about previous keys couldn't read chunks
+        // not re-sealed yet
+        if let Encryption::Curve25519(ref c) = self.encryption {
+            if !c.previous_keys.is_empty() {
+                return 14;
+            }
+        }
+        // Releases not knowing about replicas would write without them
+        if !self.replicas.is_empty() {
+            return 13;
+        }
+        // Releases not knowing about seals would still write to it
+        if self.seal.is_some() {
+            return 12;
+        }
+        if let Encryption::Curve25519(ref c) = self.encryption {
+            if !c.recipients.is_empty() {
+                return 11;
+            }
+        }
+        if self.chunk_headers {
+            return 10;
+        }
+        if self.compress_names {
+            return 9;
+        }
+        if let Encryption::Curve25519(ref c) = self.encryption {
+            if c.random_nonces {
+                return 8;
+            }
+        }
+        if self.keyed_digests {
+            return 7;
+        }
+        if !self.chunking_tiers.is_empty() {
+            return 6;
+        }
+        if self.packing.is_some() {
+            return 5;
+        }
+        if !self.chunk_size_bounds.is_unbounded()
+            || self.hashing == Hashing::Sha512Trunc256
+        {
+            return 4;
+        }
+
+        match self.chunking {
+            Chunking::Fixed { .. } => 4,
+            _ => 3,
+        }
+    }
+
+    /// Check values that deserialization alone can't catch
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |what: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {} in repo config", what),
+            ))
+        };
+
+        if !self.chunking.valid() {
+            return invalid("chunking");
+        }
+        if !self.chunk_size_bounds.valid() {
+            return invalid("chunk size bounds");
+        }
+        for (i, tier) in self.chunking_tiers.iter().enumerate() {
+            if !tier.valid()
+                || self.chunking_tiers[..i].iter().any(|t| t.name == tier.name)
+            {
+                return invalid("chunking tier");
+            }
+        }
+        if !self.packing.as_ref().map(Packing::valid).unwrap_or(true) {
+            return invalid("packing");
+        }
+        if !self.parity.as_ref().map(Parity::valid).unwrap_or(true) {
+            return invalid("parity");
+        }
+        if self.keyed_digests && !self.encryption.is_encrypted() {
+            return invalid("keyed digests");
+        }
+        if !self.nesting.valid(self.nesting_width) {
+            return invalid("nesting");
+        }
+        if self.hidden_names && !self.encryption.is_encrypted() {
+            return invalid("hidden names");
+        }
+        if self.names_sign_key.is_some() && !self.encryption.is_encrypted() {
+            return invalid("names signing key");
+        }
+        if self.version < self.required_version() {
+            return invalid("version");
+        }
+
+        Ok(())
+    }
+
+    /// Chunking engine honoring both the algorithm and size bounds
+    ///
+    /// All writers must use this (or `chunking_engine_for`), so that
+    /// chunks produced by any of them are compatible.
+    pub(crate) fn chunking_engine(&self) -> Box<dyn ChunkingEngine> {
+        self.chunking_engine_for(None)
+    }
+
+    /// Like `chunking_engine`, for data of given `tier`
+    pub(crate) fn chunking_engine_for(
+        &self,
+        tier: Option<&ChunkingTier>,
+    ) -> Box<dyn ChunkingEngine> {
+        match tier {
+            Some(tier) => {
+                tier.chunk_size_bounds.wrap_engine(tier.chunking.to_engine())
+            }
+            None => {
+                self.chunk_size_bounds.wrap_engine(self.chunking.to_engine())
+            }
+        }
+    }
+
+    /// Chunking engine for index chunks
+    ///
+    /// With `index_lengths`, edges are moved to record boundaries.
+    pub(crate) fn index_chunking_engine(&self) -> Box<dyn ChunkingEngine> {
+        let engine = self.chunking_engine();
+        if self.index_lengths {
+            Box::new(::chunking::Aligned::new(
+                engine,
+                ::index::record_size(true),
+            ))
+        } else {
+            engine
+        }
+    }
+
+    /// Chunking tier called `name`
+    ///
+    /// `None` for `DEFAULT_CHUNKING_TIER`.
+    pub(crate) fn chunking_tier(
+        &self,
+        name: &str,
+    ) -> io::Result<Option<&ChunkingTier>> {
+        if name == DEFAULT_CHUNKING_TIER {
+            return Ok(None);
+        }
+        self.chunking_tiers
+            .iter()
+            .find(|tier| tier.name == name)
+            .map(Some)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no such chunking tier: {}", name),
+                )
+            })
+    }
+
+    /// Chunking tier of a stream of `size` bytes
+    pub(crate) fn chunking_tier_for_size(
+        &self,
+        size: u64,
+    ) -> Option<&ChunkingTier> {
+        self.chunking_tiers
+            .iter()
+            .filter(|tier| tier.min_stream_size <= size)
+            .max_by_key(|tier| tier.min_stream_size)
+    }
+
+    /// How much of a stream has to be read to tell its chunking tier
+    pub(crate) fn chunking_tier_detection_size(&self) -> u64 {
+        self.chunking_tiers
+            .iter()
+            .map(|tier| tier.min_stream_size)
+            .max()
+            .unwrap_or(0)
     }
 
     pub fn write(&self, aio: &aio::AsyncIO) -> super::Result<()> {
@@ -194,6 +667,17 @@ impl Repo {
         let config_data = aio.read(CONFIG_YML_FILE.into()).wait()?;
         let config_data = config_data.to_linear_vec();
 
+        // Check the version first: config of a newer format is likely
+        // to not even parse
+        let version: VersionOnly =
+            serde_yaml::from_reader(config_data.as_slice()).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("couldn't parse yaml: {}", e),
+                )
+            })?;
+        check_version(version.version)?;
+
         let config: Repo = serde_yaml::from_reader(config_data.as_slice())
             .map_err(|e| {
                 io::Error::new(
@@ -202,12 +686,17 @@ impl Repo {
                 )
             })?;
 
-        check_version(config.version)?;
+        config.validate()?;
 
         Ok(config)
     }
 }
 
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
 fn check_version(version_int: u32) -> io::Result<()> {
     if version_int > REPO_VERSION_CURRENT {
         return Err(io::Error::new(
@@ -0,0 +1,74 @@
+//! Cache of decoded data chunks, for a single read
+//!
+//! Data often refers to the same chunk many times (eg. blocks of zeros, or
+//! headers repeated in a stream); keeping the recently decoded chunks
+//! saves reading, decrypting and decompressing them again. The least
+//! recently used chunks are dropped to keep the cache under its capacity.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Decoded data kept by default while reading, in bytes
+pub(crate) const DEFAULT_DECODED_CACHE_SIZE: u64 = 16 * 1024 * 1024;
+
+pub(crate) struct DecodedCache {
+    /// Maximum size of the cached data, in bytes
+    capacity: u64,
+    /// Data of the chunks by their digests, with their last use
+    data: HashMap<Vec<u8>, (Arc<Vec<u8>>, u64)>,
+    /// Digests in `data` by their last use
+    lru: BTreeMap<u64, Vec<u8>>,
+    /// Total size of `data`
+    size: u64,
+    /// Incremented on every use
+    tick: u64,
+}
+
+impl DecodedCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        DecodedCache {
+            capacity,
+            data: HashMap::new(),
+            lru: BTreeMap::new(),
+            size: 0,
+            tick: 0,
+        }
+    }
+
+    /// Largest data that can be cached, in bytes
+    pub(crate) fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub(crate) fn get(&mut self, digest: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.data.get_mut(digest)?;
+        self.lru.remove(&entry.1);
+        self.lru.insert(tick, digest.to_vec());
+        entry.1 = tick;
+        Some(Arc::clone(&entry.0))
+    }
+
+    /// Keep `data` of chunk `digest`, dropping the least recently used
+    /// chunks to make room for it
+    pub(crate) fn insert(&mut self, digest: &[u8], data: Vec<u8>) {
+        let len = data.len() as u64;
+        if len > self.capacity || self.data.contains_key(digest) {
+            return;
+        }
+        while self.size + len > self.capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            let digest = self.lru.remove(&oldest).expect("just found");
+            let (data, _) = self.data.remove(&digest).expect("in lru");
+            self.size -= data.len() as u64;
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, digest.to_vec());
+        self.data
+            .insert(digest.to_vec(), (Arc::new(data), self.tick));
+        self.size += len;
+    }
+}
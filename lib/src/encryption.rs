@@ -1,11 +1,16 @@
-use hex;
-use pwhash::PWHash;
+use hashing::{Hasher, Hmac};
+use owning_ref::ArcRef;
+use secret_key::SecretKey;
+use sodiumoxide::utils::memzero;
+use sha2;
+use util::clear_string;
 use PassphraseFn;
 use {as_base64, box_, from_base64, pwhash, secretbox};
 
 use sgdata::SGData;
 
 use config;
+use error::Error;
 use std::io;
 use std::sync::Arc;
 
@@ -57,6 +62,51 @@ impl Decrypter for NopDecrypter {
     }
 }
 
+/// Key sealing the secret key, derived from `passphrase`
+///
+/// The passphrase, and the intermediate copies of the key, are cleared.
+fn sealing_key(
+    passphrase: String,
+    pwhash: &dyn pwhash::PWHash,
+) -> io::Result<secretbox::Key> {
+    let derived = pwhash.derive_key(&passphrase);
+    clear_string(passphrase);
+    let mut derived = derived?;
+    let key = secretbox::Key::from_slice(&derived[..32]).unwrap();
+    memzero(&mut derived);
+    Ok(key)
+}
+
+/// Clear plaintext `data` no longer needed
+///
+/// Buffers still shared (eg. read-ahead input, holding other chunks too)
+/// are left alone.
+fn clear_if_unique(data: ArcRef<Vec<u8>, [u8]>) {
+    if let Ok(mut data) = Arc::try_unwrap(data.into_inner()) {
+        memzero(&mut data);
+    }
+}
+
+/// `sec_key` as a `box_` key
+fn box_sec_key(sec_key: &[u8]) -> io::Result<box_::SecretKey> {
+    box_::SecretKey::from_slice(sec_key).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "plain secret key in a wrong format",
+        )
+    })
+}
+
+/// Key sealing `Curve25519::previous_keys`, derived from the current
+/// secret key
+fn previous_keys_key(sec_key: &box_::SecretKey) -> secretbox::Key {
+    let mut derived = Hmac::<sha2::Sha256>::new(&sec_key.0)
+        .calculate_digest_simple(b"rdedup previous keys");
+    let key = secretbox::Key::from_slice(&derived).expect("key from digest");
+    memzero(&mut derived);
+    key
+}
+
 /// Configuration of repository encryption
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Curve25519 {
@@ -75,63 +125,217 @@ pub struct Curve25519 {
         deserialize_with = "from_base64"
     )]
     pub nonce: secretbox::Nonce,
+    /// Chunks are sealed with a random nonce, stored in front of them,
+    /// instead of one derived from their digest
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub random_nonces: bool,
+    /// Keys chunks are sealed for, in addition to `pub_key`
+    ///
+    /// Any of the matching secret keys can decrypt the chunks. Unlike the
+    /// repository secret key, they aren't stored in the repository.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<Recipient>,
+    /// Secret keys replaced by `change_key`, until all the chunks are
+    /// re-sealed for the current one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_keys: Vec<PreviousKey>,
+}
+
+/// Additional recipient of chunks (see `Curve25519::recipients`)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Recipient {
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub pub_key: box_::PublicKey,
+}
+
+/// Secret key chunks were sealed for before `Curve25519::change_key`
+///
+/// Sealed with a key derived from the current secret key, so anything
+/// unlocking the repository (not only the passphrase) unlocks it too.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PreviousKey {
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub sealed_sec_key: Vec<u8>,
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub nonce: secretbox::Nonce,
 }
 
 impl Curve25519 {
     pub(crate) fn new(
         passphrase_f: PassphraseFn,
         pwhash: &dyn pwhash::PWHash,
+        random_nonces: bool,
     ) -> super::Result<Self> {
         let (pk, sk) = box_::gen_keypair();
-        let passphrase = passphrase_f()?;
-
         let nonce = secretbox::gen_nonce();
 
-        let sealed_sk = {
-            let derived_key = secretbox::Key::from_slice(
-                &pwhash.derive_key(&passphrase)?[..32],
-            ).unwrap();
+        Ok(Curve25519 {
+            random_nonces,
+            ..Curve25519::from_keypair(pk, &sk, nonce, passphrase_f, pwhash)?
+        })
+    }
 
-            secretbox::seal(&sk.0, &nonce, &derived_key)
-        };
+    /// Seal an existing keypair (eg. to produce reproducible output)
+    pub(crate) fn from_keypair(
+        pk: box_::PublicKey,
+        sk: &box_::SecretKey,
+        nonce: secretbox::Nonce,
+        passphrase_f: PassphraseFn,
+        pwhash: &dyn pwhash::PWHash,
+    ) -> super::Result<Self> {
+        let derived_key = sealing_key(passphrase_f()?, pwhash)?;
+        let sealed_sk = secretbox::seal(&sk.0, &nonce, &derived_key);
 
         Ok(Curve25519 {
             sealed_sec_key: sealed_sk,
             pub_key: pk,
             nonce,
+            random_nonces: false,
+            recipients: vec![],
+            previous_keys: vec![],
         })
     }
 
-    fn unseal_decrypt(
+    pub(crate) fn unseal_decrypt(
         &self,
         passphrase_f: &dyn Fn() -> io::Result<String>,
         pwhash: &config::PWHash,
     ) -> io::Result<box_::SecretKey> {
-        let passphrase = passphrase_f()?;
-
-        let derived_key = secretbox::Key::from_slice(
-            &pwhash.derive_key(&passphrase)?[..32],
-        ).unwrap();
-        let plain_seckey =
+        let derived_key = sealing_key(passphrase_f()?, pwhash)?;
+        let plain_seckey = SecretKey::new(
             secretbox::open(&self.sealed_sec_key, &self.nonce, &derived_key)
-                .map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "can't decrypt key using given passphrase",
-                    )
-                })?;
+                .map_err(|_| io::Error::from(Error::WrongPassphrase))?,
+        );
 
-        Ok(box_::SecretKey::from_slice(&plain_seckey).ok_or_else(|| {
+        box_::SecretKey::from_slice(plain_seckey.as_bytes()).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
                 "plain secret key in a wrong format",
             )
-        })?)
+        })
     }
 
     fn unseal_encrypt(&self) -> super::Result<box_::PublicKey> {
         Ok(self.pub_key)
     }
+
+    /// Replace the keypair with the one of `new_sec_key`, sealed with the
+    /// passphrase
+    ///
+    /// `sec_key` (the current secret key) is added to `previous_keys`, so
+    /// chunks sealed for it can still be decrypted.
+    pub(crate) fn change_key(
+        &mut self,
+        sec_key: &[u8],
+        new_sec_key: &[u8],
+        passphrase_f: PassphraseFn,
+        pwhash: &dyn pwhash::PWHash,
+    ) -> io::Result<()> {
+        let sec_key = box_sec_key(sec_key)?;
+        if sec_key.public_key() != self.pub_key {
+            return Err(Error::KeyMismatch.into());
+        }
+        let new_sec_key = box_sec_key(new_sec_key)?;
+        let mut keys = self.unseal_previous_keys(&sec_key)?;
+        keys.push(sec_key);
+
+        let new = Curve25519::from_keypair(
+            new_sec_key.public_key(),
+            &new_sec_key,
+            secretbox::gen_nonce(),
+            passphrase_f,
+            pwhash,
+        )?;
+        let key = previous_keys_key(&new_sec_key);
+        self.previous_keys = keys
+            .iter()
+            .map(|previous| {
+                let nonce = secretbox::gen_nonce();
+                PreviousKey {
+                    sealed_sec_key: secretbox::seal(&previous.0, &nonce, &key),
+                    nonce,
+                }
+            })
+            .collect();
+        self.sealed_sec_key = new.sealed_sec_key;
+        self.pub_key = new.pub_key;
+        self.nonce = new.nonce;
+        Ok(())
+    }
+
+    fn unseal_previous_keys(
+        &self,
+        sec_key: &box_::SecretKey,
+    ) -> io::Result<Vec<box_::SecretKey>> {
+        let key = previous_keys_key(sec_key);
+        self.previous_keys
+            .iter()
+            .map(|previous| {
+                let mut plain = secretbox::open(
+                    &previous.sealed_sec_key,
+                    &previous.nonce,
+                    &key,
+                ).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "can't decrypt previous key",
+                    )
+                })?;
+                let previous = box_sec_key(&plain);
+                memzero(&mut plain);
+                previous
+            })
+            .collect()
+    }
+
+    /// Decrypter using a secret key obtained by other means than the
+    /// passphrase
+    pub(crate) fn decrypter_from_sec_key(
+        &self,
+        sec_key: &[u8],
+    ) -> io::Result<ArcDecrypter> {
+        let sec_key = box_sec_key(sec_key)?;
+        let pub_key = sec_key.public_key();
+        let slot = if pub_key == self.pub_key {
+            0
+        } else {
+            match self.recipients.iter().position(|r| r.pub_key == pub_key) {
+                Some(i) => i + 1,
+                None => return Err(Error::KeyMismatch.into()),
+            }
+        };
+        Ok(Arc::new(self.decrypter_with_slot(sec_key, slot)?))
+    }
+
+    fn decrypter_with_slot(
+        &self,
+        sec_key: box_::SecretKey,
+        slot: usize,
+    ) -> io::Result<Curve25519Decrypter> {
+        // recipients don't need previous keys: chunks are sealed for them
+        // with any key
+        let previous_keys = if slot == 0 {
+            self.unseal_previous_keys(&sec_key)?
+        } else {
+            vec![]
+        };
+        Ok(Curve25519Decrypter {
+            sec_key,
+            previous_keys,
+            random_nonces: self.random_nonces,
+            recipients: self.recipients.len(),
+            slot,
+        })
+    }
 }
 
 impl EncryptionEngine for Curve25519 {
@@ -143,16 +347,9 @@ impl EncryptionEngine for Curve25519 {
     ) -> io::Result<()> {
         let sec_key = self.unseal_decrypt(old_p, pwhash)?;
 
-        let new_passphrase = new_p()?;
-
-        let sealed_sk = {
-            let derived_key = secretbox::Key::from_slice(
-                &pwhash.derive_key(&new_passphrase)?[..32],
-            ).unwrap();
-            secretbox::seal(&sec_key.0, &self.nonce, &derived_key)
-        };
-
-        self.sealed_sec_key = sealed_sk;
+        let derived_key = sealing_key(new_p()?, pwhash)?;
+        self.sealed_sec_key =
+            secretbox::seal(&sec_key.0, &self.nonce, &derived_key);
 
         Ok(())
     }
@@ -163,7 +360,11 @@ impl EncryptionEngine for Curve25519 {
     ) -> io::Result<ArcEncrypter> {
         let key = self.unseal_encrypt()?;
 
-        Ok(Arc::new(Curve25519Encrypter { pub_key: key }))
+        Ok(Arc::new(Curve25519Encrypter {
+            pub_key: key,
+            recipients: self.recipients.iter().map(|r| r.pub_key).collect(),
+            random_nonces: self.random_nonces,
+        }))
     }
     fn decrypter(
         &self,
@@ -171,70 +372,227 @@ impl EncryptionEngine for Curve25519 {
         pwhash: &config::PWHash,
     ) -> io::Result<ArcDecrypter> {
         let key = self.unseal_decrypt(pass, pwhash)?;
-        Ok(Arc::new(Curve25519Decrypter { sec_key: key }))
+        Ok(Arc::new(self.decrypter_with_slot(key, 0)?))
     }
 }
 
 struct Curve25519Encrypter {
     pub_key: box_::PublicKey,
+    recipients: Vec<box_::PublicKey>,
+    random_nonces: bool,
 }
 
 impl Encrypter for Curve25519Encrypter {
     fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData> {
-        let nonce = box_::Nonce::from_slice(&digest[0..box_::NONCEBYTES])
-            .expect("Nonce::from_slice failed");
-
         let (ephemeral_pub, ephemeral_sec) = box_::gen_keypair();
-        let cipher =
-            box_::seal(&buf.to_linear(), &nonce, &self.pub_key, &ephemeral_sec);
-        Ok(SGData::from_many(vec![ephemeral_pub.0.to_vec(), cipher]))
+        let plain = buf.to_linear();
+        drop(buf);
+        let sealed = if !self.recipients.is_empty() {
+            let nonce = if self.random_nonces {
+                box_::gen_nonce()
+            } else {
+                digest_nonce(digest)
+            };
+            let mut pub_keys = vec![self.pub_key];
+            pub_keys.extend_from_slice(&self.recipients);
+            seal_chunk_for_recipients(
+                &plain,
+                &nonce,
+                self.random_nonces,
+                &pub_keys,
+                &secretbox::gen_key(),
+                &ephemeral_pub,
+                &ephemeral_sec,
+            )
+        } else if self.random_nonces {
+            seal_chunk_with_nonce(
+                &plain,
+                &box_::gen_nonce(),
+                &self.pub_key,
+                &ephemeral_pub,
+                &ephemeral_sec,
+            )
+        } else {
+            seal_chunk(
+                &plain,
+                &digest_nonce(digest),
+                &self.pub_key,
+                &ephemeral_pub,
+                &ephemeral_sec,
+            )
+        };
+        clear_if_unique(plain);
+        Ok(sealed)
     }
 }
 
+/// Nonce of chunks in repositories without random nonces
+fn digest_nonce(digest: &[u8]) -> box_::Nonce {
+    box_::Nonce::from_slice(&digest[0..box_::NONCEBYTES])
+        .expect("Nonce::from_slice failed")
+}
+
+/// Encrypt chunk data
+///
+/// Stored chunk is the ephemeral public key, followed by the data sealed
+/// with it for `pub_key`.
+pub(crate) fn seal_chunk(
+    data: &[u8],
+    nonce: &box_::Nonce,
+    pub_key: &box_::PublicKey,
+    ephemeral_pub: &box_::PublicKey,
+    ephemeral_sec: &box_::SecretKey,
+) -> SGData {
+    let cipher = box_::seal(data, nonce, pub_key, ephemeral_sec);
+    SGData::from_many(vec![ephemeral_pub.0.to_vec(), cipher])
+}
+
+/// Like `seal_chunk`, but with `nonce` stored between the ephemeral public
+/// key and the sealed data
+pub(crate) fn seal_chunk_with_nonce(
+    data: &[u8],
+    nonce: &box_::Nonce,
+    pub_key: &box_::PublicKey,
+    ephemeral_pub: &box_::PublicKey,
+    ephemeral_sec: &box_::SecretKey,
+) -> SGData {
+    let cipher = box_::seal(data, nonce, pub_key, ephemeral_sec);
+    SGData::from_many(vec![
+        ephemeral_pub.0.to_vec(),
+        nonce.0.to_vec(),
+        cipher,
+    ])
+}
+
+/// Length of a `data_key` sealed for one recipient
+const RECIPIENT_SLOT_LEN: usize = secretbox::KEYBYTES + box_::MACBYTES;
+
+/// Like `seal_chunk` (or `seal_chunk_with_nonce`, if `store_nonce`), but
+/// for each of `pub_keys`
+///
+/// The data is sealed with `secretbox` under a one-time `data_key` (and
+/// the same nonce), and `data_key` is sealed for every one of `pub_keys`,
+/// in order. The sealed keys are stored between the nonce (if any) and
+/// the sealed data.
+pub(crate) fn seal_chunk_for_recipients(
+    data: &[u8],
+    nonce: &box_::Nonce,
+    store_nonce: bool,
+    pub_keys: &[box_::PublicKey],
+    data_key: &secretbox::Key,
+    ephemeral_pub: &box_::PublicKey,
+    ephemeral_sec: &box_::SecretKey,
+) -> SGData {
+    let mut parts = vec![ephemeral_pub.0.to_vec()];
+    if store_nonce {
+        parts.push(nonce.0.to_vec());
+    }
+    for pub_key in pub_keys {
+        parts.push(box_::seal(&data_key.0, nonce, pub_key, ephemeral_sec));
+    }
+    parts.push(secretbox::seal(data, &secretbox::Nonce(nonce.0), data_key));
+    SGData::from_many(parts)
+}
+
 struct Curve25519Decrypter {
     sec_key: box_::SecretKey,
+    /// Unsealed `Curve25519::previous_keys`, tried if `sec_key` fails
+    previous_keys: Vec<box_::SecretKey>,
+    random_nonces: bool,
+    /// Number of `Curve25519::recipients`
+    recipients: usize,
+    /// Position of the `data_key` sealed for `sec_key`, if `recipients`
+    slot: usize,
 }
 impl Decrypter for Curve25519Decrypter {
     fn decrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData> {
-        let nonce =
-            box_::Nonce::from_slice(&digest[0..box_::NONCEBYTES]).unwrap();
-
-        let buf = buf.to_linear();
+        let linear = buf.to_linear();
+        // don't keep both the parts and their copy
+        drop(buf);
+        let buf = linear;
+        let res = self.decrypt_with(&buf, digest, &self.sec_key);
+        if res.is_err() {
+            for sec_key in &self.previous_keys {
+                if let Ok(data) = self.decrypt_with(&buf, digest, sec_key) {
+                    return Ok(data);
+                }
+            }
+        }
+        res
+    }
+}
 
-        if buf.len() < box_::PUBLICKEYBYTES {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "chunk {} too short to even contain a public key",
-                    hex::encode(digest)
-                ),
-            ));
+impl Curve25519Decrypter {
+    fn decrypt_with(
+        &self,
+        buf: &[u8],
+        digest: &[u8],
+        sec_key: &box_::SecretKey,
+    ) -> io::Result<SGData> {
+
+        let nonce_end = if self.random_nonces {
+            box_::PUBLICKEYBYTES + box_::NONCEBYTES
+        } else {
+            box_::PUBLICKEYBYTES
+        };
+        let header_len = if self.recipients > 0 {
+            nonce_end + (self.recipients + 1) * RECIPIENT_SLOT_LEN
+        } else {
+            nonce_end
+        };
+        if buf.len() < header_len {
+            return Err(Error::ChunkCorrupted {
+                digest: digest.to_vec(),
+                reason: "too short to even contain its header".into(),
+            }.into());
         }
 
+        let nonce = if self.random_nonces {
+            box_::Nonce::from_slice(&buf[box_::PUBLICKEYBYTES..nonce_end])
+                .expect("Nonce::from_slice failed")
+        } else {
+            digest_nonce(digest)
+        };
+
         let ephemeral_pub = box_::PublicKey::from_slice(
             &buf[..box_::PUBLICKEYBYTES],
         ).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Can't read ephemeral public key from chunk: {}",
-                    hex::encode(digest)
-                ),
-            )
+            io::Error::from(Error::ChunkCorrupted {
+                digest: digest.to_vec(),
+                reason: "can't read ephemeral public key".into(),
+            })
         })?;
 
+        let cant_decrypt =
+            |_| io::Error::from(Error::DecryptionFailed(digest.to_vec()));
+        if self.recipients == 0 {
+            return Ok(SGData::from_single(
+                box_::open(
+                    &buf[header_len..],
+                    &nonce,
+                    &ephemeral_pub,
+                    sec_key,
+                ).map_err(cant_decrypt)?,
+            ));
+        }
+
+        let slot_start = nonce_end + self.slot * RECIPIENT_SLOT_LEN;
+        let mut data_key = box_::open(
+            &buf[slot_start..slot_start + RECIPIENT_SLOT_LEN],
+            &nonce,
+            &ephemeral_pub,
+            sec_key,
+        ).map_err(cant_decrypt)?;
+        let key = secretbox::Key::from_slice(&data_key)
+            .expect("Key::from_slice failed");
+        memzero(&mut data_key);
+
         Ok(SGData::from_single(
-            box_::open(
-                &buf[box_::PUBLICKEYBYTES..],
-                &nonce,
-                &ephemeral_pub,
-                &self.sec_key,
-            ).map_err(|_| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("can't decrypt chunk: {}", hex::encode(digest)),
-                )
-            })?,
+            secretbox::open(
+                &buf[header_len..],
+                &secretbox::Nonce(nonce.0),
+                &key,
+            ).map_err(cant_decrypt)?,
         ))
     }
 }
@@ -1,21 +1,215 @@
+//! Errors of the library
+//!
+//! The API returns `io::Result`, so errors keep an `io::ErrorKind` that
+//! callers (and `?`) can use as before. Failures with more to say carry an
+//! `Error` inside the `io::Error`, with the name, chunk or path involved:
+//!
+//! ```norust
+//! match repo.read(name, &mut out, &dec) {
+//!     Err(ref e) => match Error::of(e) {
+//!         Some(&Error::NameNotFound(ref name)) => ...,
+//!         Some(&Error::ChunkNotFound(ref digest)) => ...,
+//!         _ => ...,
+//!     },
+//!     Ok(()) => ...,
+//! }
+//! ```
+use hex;
+use libc;
+use std::error;
+use std::fmt;
 use std::io;
-use std::result;
-
-pub type Result<T> = result::Result<T, Error>;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum Error {
-    Io(io::Error),
-    NotFound,
-    Exists,
-    InvalidPubKey,
-    DecryptionFailed
+    /// No name called that
+    NameNotFound(String),
+    /// Name already stored
+    NameExists(String),
+    /// Name can't be stored, as it's not a single file name (see
+    /// `Repo::write`)
+    InvalidName(String),
+    /// Chunk with this digest is not stored
+    ChunkNotFound(Vec<u8>),
+    /// Chunk with this digest doesn't contain what it should
+    ChunkCorrupted { digest: Vec<u8>, reason: String },
+    /// Data of this name doesn't hash to the SHA-256 recorded when it was
+    /// stored, though all its chunks were intact
+    DataCorrupted(String),
+    /// Record of this name isn't signed with the key of the repository
+    /// (see `settings::Repo::set_signed_names`)
+    BadSignature(String),
+    /// Chunk with this digest can't be decrypted with the key
+    DecryptionFailed(Vec<u8>),
+    /// Secret key can't be decrypted using the passphrase given
+    WrongPassphrase,
+    /// Secret key is not the key of the repository
+    KeyMismatch,
+    /// Backend operation on `path` failed
+    Io { path: PathBuf, source: io::Error },
+    /// Operation cancelled with a `CancelToken`
+    Cancelled,
+    /// Storage of the repository is full; `written` bytes of the data were
+    /// stored before
+    NoSpace { written: u64 },
+    /// Index records of the data with this digest, waiting to be read, took
+    /// `held` bytes, more than the memory budget of the read allowed
+    MemoryBudgetExceeded { digest: Vec<u8>, held: u64 },
+}
+
+impl Error {
+    /// `Error` carried by `err`, if any
+    pub fn of(err: &io::Error) -> Option<&Error> {
+        let inner = err.get_ref()?;
+        match inner.downcast_ref::<Shared>() {
+            Some(shared) => Error::of(&shared.0),
+            None => inner.downcast_ref::<Error>(),
+        }
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::NameNotFound(_) | Error::ChunkNotFound(_) => {
+                io::ErrorKind::NotFound
+            }
+            Error::NameExists(_) => io::ErrorKind::AlreadyExists,
+            Error::InvalidName(_) => io::ErrorKind::InvalidInput,
+            Error::ChunkCorrupted { .. }
+            | Error::DataCorrupted(_)
+            | Error::BadSignature(_)
+            | Error::DecryptionFailed(_)
+            | Error::WrongPassphrase
+            | Error::KeyMismatch => io::ErrorKind::InvalidData,
+            Error::Io { ref source, .. } => source.kind(),
+            // not `Interrupted`, which `std::io` retries
+            Error::Cancelled => io::ErrorKind::Other,
+            // `StorageFull` is too new for the Rust versions supported
+            Error::NoSpace { .. } => io::ErrorKind::Other,
+            // `OutOfMemory` is too new for the Rust versions supported
+            Error::MemoryBudgetExceeded { .. } => io::ErrorKind::Other,
+        }
+    }
+
+    /// `source` of the backend operation on `path`, with the path
+    pub(crate) fn at_path(path: PathBuf, source: io::Error) -> io::Error {
+        Error::Io { path, source }.into()
+    }
 }
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Error {
-        Error::Io(err)
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NameNotFound(ref name) => {
+                write!(f, "name not found: {}", name)
+            }
+            Error::NameExists(ref name) => {
+                write!(f, "name already exists: {}", name)
+            }
+            Error::InvalidName(ref name) => {
+                write!(f, "invalid name: {:?}", name)
+            }
+            Error::ChunkNotFound(ref digest) => {
+                write!(f, "chunk not found: {}", hex::encode(digest))
+            }
+            Error::ChunkCorrupted {
+                ref digest,
+                ref reason,
+            } => {
+                write!(f, "chunk {} corrupted: {}", hex::encode(digest), reason)
+            }
+            Error::DataCorrupted(ref name) => {
+                write!(f, "data of {} doesn't match its SHA-256", name)
+            }
+            Error::BadSignature(ref name) => {
+                write!(f, "record of {} isn't signed by the repository", name)
+            }
+            Error::DecryptionFailed(ref digest) => {
+                write!(f, "can't decrypt chunk: {}", hex::encode(digest))
+            }
+            Error::WrongPassphrase => {
+                write!(f, "can't decrypt key using given passphrase")
+            }
+            Error::KeyMismatch => {
+                write!(f, "secret key is not a key of the repository")
+            }
+            Error::Io {
+                ref path,
+                ref source,
+            } => write!(f, "{}: {}", path.display(), source),
+            Error::Cancelled => write!(f, "operation cancelled"),
+            Error::NoSpace { written } => write!(
+                f,
+                "no space left in the storage ({} bytes written)",
+                written
+            ),
+            Error::MemoryBudgetExceeded { ref digest, held } => write!(
+                f,
+                "index of {} takes more than the memory budget ({} bytes of \
+                 it held)",
+                hex::encode(digest),
+                held
+            ),
+        }
     }
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io { ref source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
+/// `io::Error` shared by several operations (eg. readers of the same
+/// chunk, see `in_flight`), as it's not `Clone`
+///
+/// `Error::of` finds the `Error` it carries, as if it wasn't shared.
+#[derive(Debug)]
+pub(crate) struct Shared(pub Arc<io::Error>);
+
+impl Shared {
+    /// Error with the kind of the shared one, carrying it
+    pub(crate) fn to_io_error(&self) -> io::Error {
+        io::Error::new(self.0.kind(), Shared(Arc::clone(&self.0)))
+    }
+}
+
+impl fmt::Display for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for Shared {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.0.get_ref() {
+            Some(inner) => Some(inner),
+            None => None,
+        }
+    }
+}
+
+/// OS error of a full storage (`ENOSPC`, or `ERROR_DISK_FULL` on Windows)
+#[cfg(not(windows))]
+pub(crate) const NO_SPACE_OS_ERROR: i32 = libc::ENOSPC;
+#[cfg(windows)]
+pub(crate) const NO_SPACE_OS_ERROR: i32 = 112;
+
+/// Is `err` of a full storage, as `Error::NoSpace` or the OS error
+pub(crate) fn is_storage_full(err: &io::Error) -> bool {
+    match Error::of(err) {
+        Some(&Error::NoSpace { .. }) => true,
+        Some(Error::Io { source, .. }) => is_storage_full(source),
+        _ => err.raw_os_error() == Some(NO_SPACE_OS_ERROR),
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(err.kind(), err)
+    }
+}
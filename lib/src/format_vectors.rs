@@ -0,0 +1,380 @@
+//! Canonical test vectors of the on-disk repository format
+//!
+//! Everything here is computed from fixed inputs, so the output is stable
+//! for a given format version. Alternative implementations (and future
+//! versions of this crate) can compare against it to prove on-disk
+//! compatibility.
+//!
+//! Input data of length `n` used by the vectors is the concatenation of
+//! SHA-256 digests of consecutive 64-bit little endian integers (starting
+//! at 0), truncated to `n` bytes. All binary values are hex encoded.
+// {{{ use and mod
+use chunking::Chunker;
+use config;
+use encryption::{self, Curve25519};
+use hex;
+use pwhash::SodiumOxide;
+use sgdata::SGData;
+use sha2::{self, Digest};
+use sodiumoxide::crypto::{box_, pwhash, secretbox};
+use std::io;
+use {serde_yaml, DIGEST_SIZE};
+// }}}
+
+const CHUNKING_INPUT_LEN: usize = 64 * 1024;
+const CHUNKING_BITS: u32 = 10;
+const INDEX_INPUT_LEN: usize = 64 * 1024;
+const CHUNK_INPUT_LEN: usize = 100;
+const PASSPHRASE: &str = "rdedup";
+
+/// All test vectors
+#[derive(Serialize, Debug)]
+pub struct FormatVectors {
+    pub chunking: Vec<ChunkingVector>,
+    pub hashing: Vec<HashingVector>,
+    pub index: Vec<IndexVector>,
+    pub chunks: Vec<ChunkVector>,
+    pub sealed_keys: Vec<SealedKeyVector>,
+}
+
+impl FormatVectors {
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("yaml serialization failed")
+    }
+}
+
+/// Chunk boundaries found in the input data
+#[derive(Serialize, Debug)]
+pub struct ChunkingVector {
+    pub algorithm: String,
+    pub chunk_bits: u32,
+    pub input_len: usize,
+    /// Lengths of consecutive chunks
+    pub chunk_lens: Vec<usize>,
+}
+
+/// Digest of a given input
+#[derive(Serialize, Debug)]
+pub struct HashingVector {
+    pub algorithm: String,
+    pub input: String,
+    pub digest: String,
+}
+
+/// Index tree of the input data
+///
+/// Level 0 are the digests of the data chunks. Every following level are
+/// digests of chunks of the previous level digests, concatenated. The
+/// single digest of the last level is stored in the name file, along with
+/// its `index_level`. This is the index of repositories without lengths in
+/// index records (see `settings::Repo::set_bare_index`).
+#[derive(Serialize, Debug)]
+pub struct IndexVector {
+    pub chunking: String,
+    pub chunk_bits: u32,
+    pub hashing: String,
+    pub input_len: usize,
+    pub levels: Vec<Vec<String>>,
+    pub digest: String,
+    pub index_level: u32,
+}
+
+/// Encrypted data chunk, as stored
+///
+/// The stored chunk is `ephemeral_pub_key` followed by `crypto_box` of the
+/// plaintext, with the nonce being the first 24 bytes of `digest`. In
+/// repositories with random nonces (version 8), the (random) `nonce` is
+/// stored between the two.
+///
+/// With additional `recipients` (version 11), the plaintext is sealed with
+/// `secretbox` under a one-time `data_key` instead, and `data_key` with
+/// `crypto_box` for `pub_key` and every one of `recipients`, stored in that
+/// order before the sealed plaintext.
+#[derive(Serialize, Debug)]
+pub struct ChunkVector {
+    pub pub_key: String,
+    pub sec_key: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    pub ephemeral_pub_key: String,
+    pub ephemeral_sec_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_key: Option<String>,
+    pub plaintext: String,
+    pub digest: String,
+    pub stored: String,
+}
+
+/// Secret key sealed with a passphrase, as in the repository config
+///
+/// The key sealing the secret key with `secretbox` is derived with
+/// `scryptsalsa208sha256` from the passphrase.
+#[derive(Serialize, Debug)]
+pub struct SealedKeyVector {
+    pub passphrase: String,
+    pub salt: String,
+    pub ops_limit: u64,
+    pub mem_limit: u64,
+    pub nonce: String,
+    pub sec_key: String,
+    pub sealed_sec_key: String,
+}
+
+/// Input data of the test vectors
+pub(crate) fn input(len: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len + DIGEST_SIZE);
+    let mut i = 0u64;
+    while data.len() < len {
+        let mut bytes = [0u8; 8];
+        for (b, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i >> (8 * b)) as u8;
+        }
+        data.extend_from_slice(&sha2::Sha256::digest(&bytes));
+        i += 1;
+    }
+    data.truncate(len);
+    data
+}
+
+/// Fixed keypair derived from a label
+fn keypair(label: &str) -> (box_::PublicKey, box_::SecretKey) {
+    let seed = box_::Seed::from_slice(&sha2::Sha256::digest(label.as_bytes()))
+        .expect("seed from digest");
+    box_::keypair_from_seed(&seed)
+}
+
+fn chunk(data: &[u8], chunking: &config::Chunking) -> Vec<SGData> {
+    Chunker::new(
+        data.chunks(1024).map(|c| c.to_vec()),
+        chunking.to_engine(),
+    ).collect()
+}
+
+fn chunkings() -> Vec<(&'static str, config::Chunking)> {
+    vec![
+        (
+            "bup",
+            config::Chunking::Bup {
+                chunk_bits: CHUNKING_BITS,
+            },
+        ),
+        (
+            "gear",
+            config::Chunking::Gear {
+                chunk_bits: CHUNKING_BITS,
+            },
+        ),
+        (
+            "fastcdc",
+            config::Chunking::FastCDC {
+                chunk_bits: CHUNKING_BITS,
+            },
+        ),
+        (
+            "fixed",
+            config::Chunking::Fixed {
+                chunk_bits: CHUNKING_BITS,
+            },
+        ),
+    ]
+}
+
+fn hashings() -> Vec<(&'static str, config::Hashing)> {
+    vec![
+        ("sha256", config::Hashing::Sha256),
+        ("blake2b", config::Hashing::Blake2b),
+        ("sha512-256", config::Hashing::Sha512Trunc256),
+    ]
+}
+
+fn chunking_vectors() -> Vec<ChunkingVector> {
+    let data = input(CHUNKING_INPUT_LEN);
+
+    chunkings()
+        .into_iter()
+        .map(|(name, chunking)| ChunkingVector {
+            algorithm: name.into(),
+            chunk_bits: CHUNKING_BITS,
+            input_len: data.len(),
+            chunk_lens: chunk(&data, &chunking)
+                .iter()
+                .map(|sg| sg.len())
+                .collect(),
+        })
+        .collect()
+}
+
+fn hashing_vectors() -> Vec<HashingVector> {
+    let inputs = vec![vec![], PASSPHRASE.as_bytes().to_vec(), input(1000)];
+
+    let mut vectors = vec![];
+    for (name, hashing) in hashings() {
+        let hasher = hashing.to_hasher();
+        for data in &inputs {
+            vectors.push(HashingVector {
+                algorithm: name.into(),
+                input: hex::encode(data),
+                digest: hex::encode(hasher.calculate_digest_simple(data)),
+            });
+        }
+    }
+    vectors
+}
+
+fn index_vectors() -> Vec<IndexVector> {
+    let data = input(INDEX_INPUT_LEN);
+    let (chunking_name, chunking) = ("fixed", config::Chunking::Fixed {
+        chunk_bits: CHUNKING_BITS,
+    });
+
+    hashings()
+        .into_iter()
+        .map(|(name, hashing)| {
+            let hasher = hashing.to_hasher();
+            let mut levels: Vec<Vec<Vec<u8>>> = vec![];
+            let mut level_data = data.clone();
+            loop {
+                let digests: Vec<_> = chunk(&level_data, &chunking)
+                    .iter()
+                    .map(|sg| hasher.calculate_digest(sg))
+                    .collect();
+                level_data = digests.concat();
+                levels.push(digests);
+                if level_data.len() == DIGEST_SIZE {
+                    break;
+                }
+            }
+
+            IndexVector {
+                chunking: chunking_name.into(),
+                chunk_bits: CHUNKING_BITS,
+                hashing: name.into(),
+                input_len: data.len(),
+                index_level: levels.len() as u32 - 1,
+                digest: hex::encode(&level_data),
+                levels: levels
+                    .iter()
+                    .map(|level| level.iter().map(hex::encode).collect())
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn chunk_vectors() -> Vec<ChunkVector> {
+    let (pk, sk) = keypair("rdedup-format-vectors-recipient");
+    let (ephemeral_pk, ephemeral_sk) =
+        keypair("rdedup-format-vectors-ephemeral");
+
+    let plaintext = input(CHUNK_INPUT_LEN);
+    let digest = config::Hashing::Sha256
+        .to_hasher()
+        .calculate_digest_simple(&plaintext);
+    let digest_nonce = box_::Nonce::from_slice(&digest[..box_::NONCEBYTES])
+        .expect("nonce from digest");
+    let random_nonce = box_::Nonce::from_slice(
+        &sha2::Sha256::digest(b"rdedup-format-vectors-nonce")
+            [..box_::NONCEBYTES],
+    ).expect("nonce from digest");
+
+    let (recipient_pk, _) = keypair("rdedup-format-vectors-recipient-2");
+    let data_key = secretbox::Key::from_slice(
+        &sha2::Sha256::digest(b"rdedup-format-vectors-data-key"),
+    ).expect("key from digest");
+
+    let vector = |nonce: Option<&box_::Nonce>, recipients: bool| {
+        let stored = match nonce {
+            _ if recipients => encryption::seal_chunk_for_recipients(
+                &plaintext,
+                nonce.unwrap_or(&digest_nonce),
+                nonce.is_some(),
+                &[pk, recipient_pk],
+                &data_key,
+                &ephemeral_pk,
+                &ephemeral_sk,
+            ),
+            Some(nonce) => encryption::seal_chunk_with_nonce(
+                &plaintext,
+                nonce,
+                &pk,
+                &ephemeral_pk,
+                &ephemeral_sk,
+            ),
+            None => encryption::seal_chunk(
+                &plaintext,
+                &digest_nonce,
+                &pk,
+                &ephemeral_pk,
+                &ephemeral_sk,
+            ),
+        };
+
+        ChunkVector {
+            pub_key: hex::encode(pk.0),
+            sec_key: hex::encode(sk.0),
+            recipients: if recipients {
+                vec![hex::encode(recipient_pk.0)]
+            } else {
+                vec![]
+            },
+            ephemeral_pub_key: hex::encode(ephemeral_pk.0),
+            ephemeral_sec_key: hex::encode(ephemeral_sk.0),
+            nonce: nonce.map(|nonce| hex::encode(nonce.0)),
+            data_key: if recipients {
+                Some(hex::encode(data_key.0))
+            } else {
+                None
+            },
+            plaintext: hex::encode(&plaintext),
+            digest: hex::encode(&digest),
+            stored: hex::encode(stored.to_linear_vec()),
+        }
+    };
+
+    vec![
+        vector(None, false),
+        vector(Some(&random_nonce), false),
+        vector(Some(&random_nonce), true),
+    ]
+}
+
+fn sealed_key_vectors() -> io::Result<Vec<SealedKeyVector>> {
+    let (pk, sk) = keypair("rdedup-format-vectors-recipient");
+    let salt_and_nonce = input(pwhash::SALTBYTES + secretbox::NONCEBYTES);
+    let (salt, nonce) = salt_and_nonce.split_at(pwhash::SALTBYTES);
+    let salt = pwhash::Salt::from_slice(salt).expect("salt from input");
+    let nonce = secretbox::Nonce::from_slice(nonce).expect("nonce from input");
+
+    let sealed = Curve25519::from_keypair(
+        pk,
+        &sk,
+        nonce,
+        &|| Ok(PASSPHRASE.into()),
+        &SodiumOxide::new_interactive_with_salt(salt),
+    )?;
+
+    Ok(vec![SealedKeyVector {
+        passphrase: PASSPHRASE.into(),
+        salt: hex::encode(salt.0),
+        ops_limit: pwhash::OPSLIMIT_INTERACTIVE.0 as u64,
+        mem_limit: pwhash::MEMLIMIT_INTERACTIVE.0 as u64,
+        nonce: hex::encode(nonce.0),
+        sec_key: hex::encode(sk.0),
+        sealed_sec_key: hex::encode(&sealed.sealed_sec_key),
+    }])
+}
+
+/// Compute the canonical test vectors
+pub fn format_vectors() -> io::Result<FormatVectors> {
+    Ok(FormatVectors {
+        chunking: chunking_vectors(),
+        hashing: hashing_vectors(),
+        index: index_vectors(),
+        chunks: chunk_vectors(),
+        sealed_keys: sealed_key_vectors()?,
+    })
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -2,8 +2,12 @@ use blake2;
 use SGData;
 use DIGEST_SIZE;
 
-use digest::{FixedOutput, Input};
+use digest::generic_array::typenum::Unsigned;
+use digest::{BlockInput, FixedOutput, Input};
+use secret_key::SecretKey;
 use sha2;
+use std::io::{self, Write};
+use std::marker::PhantomData;
 use std::sync::Arc;
 
 pub type ArcHasher = Arc<dyn Hasher + Send + Sync>;
@@ -11,6 +15,40 @@ pub type ArcHasher = Arc<dyn Hasher + Send + Sync>;
 pub trait Hasher {
     fn calculate_digest(&self, sg: &SGData) -> Vec<u8>;
     fn calculate_digest_simple(&self, sg: &[u8]) -> Vec<u8>;
+
+    /// Digest of the data written into the returned writer, so it doesn't
+    /// have to be all in memory at once
+    fn digest_writer(&self) -> Box<dyn DigestWriter>;
+}
+
+/// Digest calculated incrementally, from the data written into it
+pub trait DigestWriter: Write {
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+/// `DigestWriter` of a plain `D` hash function, truncated to `DIGEST_SIZE`
+struct PlainDigestWriter<D>(D);
+
+impl<D: Input> Write for PlainDigestWriter<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.process(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: Input + FixedOutput> DigestWriter for PlainDigestWriter<D> {
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.0.fixed_result()[..DIGEST_SIZE].to_vec()
+    }
+}
+
+/// `DigestWriter` of SHA-256, as `sha256sum` calculates it
+pub(crate) fn sha256_writer() -> Box<dyn DigestWriter + Send> {
+    Box::new(PlainDigestWriter(sha2::Sha256::default()))
 }
 
 pub struct Sha256;
@@ -39,6 +77,45 @@ impl Hasher for Sha256 {
 
         vec_result
     }
+
+    fn digest_writer(&self) -> Box<dyn DigestWriter> {
+        Box::new(PlainDigestWriter(sha2::Sha256::default()))
+    }
+}
+
+/// SHA-512/256
+///
+/// Same output size as SHA-256, but noticeably faster on 64-bit CPUs.
+pub struct Sha512Trunc256;
+
+impl Hasher for Sha512Trunc256 {
+    fn calculate_digest(&self, sg: &SGData) -> Vec<u8> {
+        let mut sha = sha2::Sha512Trunc256::default();
+
+        for sg_part in sg.as_parts() {
+            sha.process(sg_part);
+        }
+
+        let mut vec_result = vec![0u8; DIGEST_SIZE];
+        vec_result.copy_from_slice(&sha.fixed_result());
+
+        vec_result
+    }
+
+    fn calculate_digest_simple(&self, data: &[u8]) -> Vec<u8> {
+        let mut sha = sha2::Sha512Trunc256::default();
+
+        sha.process(data);
+
+        let mut vec_result = vec![0u8; DIGEST_SIZE];
+        vec_result.copy_from_slice(&sha.fixed_result());
+
+        vec_result
+    }
+
+    fn digest_writer(&self) -> Box<dyn DigestWriter> {
+        Box::new(PlainDigestWriter(sha2::Sha512Trunc256::default()))
+    }
 }
 
 pub struct Blake2b;
@@ -67,4 +144,120 @@ impl Hasher for Blake2b {
 
         vec_result
     }
+
+    fn digest_writer(&self) -> Box<dyn DigestWriter> {
+        Box::new(PlainDigestWriter(blake2::Blake2b::default()))
+    }
+}
+
+/// HMAC with the `D` hash function, truncated to `DIGEST_SIZE`
+///
+/// Used for digests of data chunks in repositories with keyed digests:
+/// without the key, nobody can tell if a chunk of known content is stored.
+pub struct Hmac<D> {
+    /// Key padded to the block size of `D`
+    key: SecretKey,
+    hash: PhantomData<D>,
+}
+
+impl<D> Hmac<D>
+where
+    D: Input + BlockInput + FixedOutput + Default,
+{
+    pub fn new(key: &[u8]) -> Self {
+        let block_size = D::BlockSize::to_usize();
+        let mut block = if key.len() > block_size {
+            let mut hash = D::default();
+            hash.process(key);
+            hash.fixed_result().to_vec()
+        } else {
+            key.to_vec()
+        };
+        block.resize(block_size, 0);
+
+        Hmac {
+            key: SecretKey::new(block),
+            hash: PhantomData,
+        }
+    }
+
+    fn padded_key(&self, pad: u8) -> SecretKey {
+        SecretKey::new(self.key.as_bytes().iter().map(|b| b ^ pad).collect())
+    }
+
+    fn calculate<'a, I>(&self, parts: I) -> Vec<u8>
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let mut writer = self.hmac_writer();
+        for part in parts {
+            writer.inner.process(part);
+        }
+        Box::new(writer).finish()
+    }
+
+    fn hmac_writer(&self) -> HmacDigestWriter<D> {
+        let mut inner = D::default();
+        inner.process(self.padded_key(0x36).as_bytes());
+        HmacDigestWriter {
+            inner,
+            outer_key: self.padded_key(0x5c),
+        }
+    }
+}
+
+/// `DigestWriter` of `Hmac`
+struct HmacDigestWriter<D> {
+    /// Hash of the inner padded key and the data so far
+    inner: D,
+    outer_key: SecretKey,
+}
+
+impl<D: Input> Write for HmacDigestWriter<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.process(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D> DigestWriter for HmacDigestWriter<D>
+where
+    D: Input + FixedOutput + Default,
+{
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        let mut outer = D::default();
+        outer.process(self.outer_key.as_bytes());
+        outer.process(&self.inner.fixed_result());
+
+        outer.fixed_result()[..DIGEST_SIZE].to_vec()
+    }
+}
+
+impl<D> Hasher for Hmac<D>
+where
+    D: Input + BlockInput + FixedOutput + Default + 'static,
+{
+    fn calculate_digest(&self, sg: &SGData) -> Vec<u8> {
+        self.calculate(sg.as_parts().iter().map(|part| &part[..]))
+    }
+
+    fn calculate_digest_simple(&self, data: &[u8]) -> Vec<u8> {
+        self.calculate(Some(data).into_iter())
+    }
+
+    fn digest_writer(&self) -> Box<dyn DigestWriter> {
+        Box::new(self.hmac_writer())
+    }
+}
+
+/// Key of keyed digests, derived from the repository secret key
+pub(crate) fn digest_key(sec_key: &SecretKey) -> SecretKey {
+    SecretKey::new(
+        Hmac::<sha2::Sha256>::new(sec_key.as_bytes())
+            .calculate_digest_simple(b"rdedup keyed digests"),
+    )
 }
@@ -0,0 +1,85 @@
+//! Names hidden from anyone listing the repository
+//!
+//! In repositories with `hidden_names` (see `config::Repo`), a name record
+//! is stored under an HMAC of the name, and the name itself is sealed
+//! inside the record, both with keys derived from the repository secret
+//! key. So names (often revealing hostnames or dates) can be listed only
+//! with the secret key (see `Repo::unlock_names`).
+use hashing::{Hasher, Hmac};
+use hex;
+use secret_key::SecretKey;
+use sha2;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+use std::io;
+
+/// Keys of hidden names, derived from the repository secret key
+pub(crate) struct NamesKey {
+    /// Key of the HMACs records are stored under
+    id_key: SecretKey,
+    /// Key the names are sealed with inside the records
+    seal_key: secretbox::Key,
+}
+
+impl NamesKey {
+    pub(crate) fn new(sec_key: &SecretKey) -> Self {
+        let derive = |purpose: &[u8]| {
+            Hmac::<sha2::Sha256>::new(sec_key.as_bytes())
+                .calculate_digest_simple(purpose)
+        };
+        let mut seal_key = derive(b"rdedup hidden names seal");
+        let key = NamesKey {
+            id_key: SecretKey::new(derive(b"rdedup hidden names id")),
+            seal_key: secretbox::Key::from_slice(&seal_key)
+                .expect("key from digest"),
+        };
+        memzero(&mut seal_key);
+        key
+    }
+
+    /// File name (without the extension) of the record of `name`
+    pub(crate) fn file_stem(&self, name: &str) -> String {
+        hex::encode(
+            Hmac::<sha2::Sha256>::new(self.id_key.as_bytes())
+                .calculate_digest_simple(name.as_bytes()),
+        )
+    }
+
+    /// `name` sealed, hex encoded (nonce first)
+    pub(crate) fn seal(&self, name: &str) -> String {
+        let nonce = secretbox::gen_nonce();
+        let mut sealed = nonce.0.to_vec();
+        sealed.extend(secretbox::seal(name.as_bytes(), &nonce, &self.seal_key));
+        hex::encode(sealed)
+    }
+
+    /// Name sealed with `seal`, checked to be stored under `file_stem`
+    ///
+    /// So records can't be swapped between names unnoticed.
+    pub(crate) fn open(
+        &self,
+        sealed: Option<&str>,
+        file_stem: &str,
+    ) -> io::Result<String> {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hidden name {}: {}", file_stem, msg),
+            )
+        };
+        let sealed = sealed.ok_or_else(|| invalid("no sealed name"))?;
+        let sealed = hex::decode(sealed).map_err(|_| invalid("not hex"))?;
+        if sealed.len() < secretbox::NONCEBYTES {
+            return Err(invalid("truncated"));
+        }
+        let (nonce, sealed) = sealed.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce).expect("nonce size");
+        let name = secretbox::open(sealed, &nonce, &self.seal_key)
+            .map_err(|_| invalid("can't unseal"))?;
+        let name = String::from_utf8(name).map_err(|_| invalid("not UTF-8"))?;
+        if self.file_stem(&name) != file_stem {
+            return Err(invalid("stored under a wrong name"));
+        }
+        Ok(name)
+    }
+}
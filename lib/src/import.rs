@@ -0,0 +1,53 @@
+//! Import of data already split into chunks by another tool
+//!
+//! Chunks are stored as they are, without chunking them again; they are
+//! still hashed, compressed and encrypted like any other data.
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::{fs, str};
+
+/// Names to import, each with its chunk files in order
+///
+/// Stored as YAML, with paths relative to the directory with the chunks:
+///
+/// ```norust
+/// names:
+///   home-2019-01-31:
+///     - ab/abcdef...
+///     - 01/0123456...
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ImportManifest {
+    pub names: BTreeMap<String, Vec<PathBuf>>,
+}
+
+impl ImportManifest {
+    /// Load the manifest from `path`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+}
+
+impl str::FromStr for ImportManifest {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        serde_yaml::from_str(s).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse import manifest: {}", e),
+            )
+        })
+    }
+}
+
+/// Summary of an import
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportResults {
+    pub names: usize,
+    /// Distinct chunk files (or bup blobs) read
+    pub chunks: usize,
+    pub bytes: u64,
+}
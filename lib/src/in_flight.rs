@@ -0,0 +1,129 @@
+//! Coalescing of concurrent chunk fetches
+//!
+//! When multiple readers (eg. a restore and a `verify`) need the same chunk
+//! at the same time, only the first one reads it from the backend; the
+//! others wait for it and share the result. Nothing is kept after the fetch
+//! completes - this is not a cache.
+//!
+//! Only the chunk as stored is shared: readers decrypt it with their own
+//! keys, so a reader holding another key (eg. of an unfinished key change)
+//! doesn't get what the first one decrypted, or fail with its errors.
+use error::Shared;
+use sgdata::SGData;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use DataType;
+
+type Key = (Vec<u8>, DataType);
+
+/// Data of a chunk fetched (still encrypted), and whether it's compressed
+pub(crate) type Fetched = (SGData, bool);
+
+/// Result shared between all the waiters
+///
+/// The waiters get the error of the fetch as `error::Shared`, so the
+/// `error::Error` it carries is still there for `Error::of`.
+type SharedResult = Result<Fetched, Arc<io::Error>>;
+
+struct Fetch {
+    result: Mutex<Option<SharedResult>>,
+    done: Condvar,
+}
+
+impl Fetch {
+    fn new() -> Self {
+        Fetch {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+
+    fn complete(&self, result: SharedResult) {
+        let mut lock = self.result.lock().unwrap();
+        if lock.is_none() {
+            *lock = Some(result);
+        }
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> io::Result<Fetched> {
+        let mut lock = self.result.lock().unwrap();
+        while lock.is_none() {
+            lock = self.done.wait(lock).unwrap();
+        }
+        match *lock {
+            Some(Ok(ref data)) => Ok(data.clone()),
+            Some(Err(ref e)) => Err(Shared(Arc::clone(e)).to_io_error()),
+            None => unreachable!(),
+        }
+    }
+}
+
+/// Fetch being performed by the current thread
+///
+/// Makes sure the waiters are released even if the fetch panics.
+struct Leading<'a> {
+    in_flight: &'a InFlightFetches,
+    key: Key,
+    fetch: Arc<Fetch>,
+}
+
+impl<'a> Drop for Leading<'a> {
+    fn drop(&mut self) {
+        self.in_flight.fetches.lock().unwrap().remove(&self.key);
+        self.fetch.complete(Err(Arc::new(io::Error::new(
+            io::ErrorKind::Other,
+            "concurrent chunk fetch aborted",
+        ))));
+    }
+}
+
+/// Table of chunk fetches in progress
+#[derive(Default)]
+pub(crate) struct InFlightFetches {
+    fetches: Mutex<HashMap<Key, Arc<Fetch>>>,
+}
+
+impl InFlightFetches {
+    /// Fetch chunk `digest` with `f`, unless someone is already doing it
+    pub(crate) fn fetch<F>(
+        &self,
+        digest: &[u8],
+        data_type: DataType,
+        f: F,
+    ) -> io::Result<Fetched>
+    where
+        F: FnOnce() -> io::Result<Fetched>,
+    {
+        let key = (digest.to_vec(), data_type);
+        let leading = {
+            let mut fetches = self.fetches.lock().unwrap();
+            match fetches.entry(key.clone()) {
+                Entry::Occupied(e) => {
+                    let fetch = Arc::clone(e.get());
+                    drop(fetches);
+                    return fetch.wait();
+                }
+                Entry::Vacant(e) => Leading {
+                    in_flight: self,
+                    fetch: Arc::clone(e.insert(Arc::new(Fetch::new()))),
+                    key,
+                },
+            }
+        };
+
+        match f() {
+            Ok(data) => {
+                leading.fetch.complete(Ok(data.clone()));
+                Ok(data)
+            }
+            Err(e) => {
+                let e = Arc::new(e);
+                leading.fetch.complete(Err(Arc::clone(&e)));
+                Err(Shared(e).to_io_error())
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Records of index chunks
+//!
+//! In repositories with `index_lengths` (see `config::Repo`), every record
+//! is a digest followed by the length of the data it refers to (a data
+//! chunk, or all the data under an index chunk), as a little-endian `u64`.
+//! Index chunk edges are always between records, so the length of every
+//! index chunk is known too. Range reads can then skip whatever comes
+//! before the range without reading it.
+//!
+//! Otherwise records are just the digests, and index chunk edges fall
+//! anywhere.
+use std::io;
+use DIGEST_SIZE;
+
+/// Size of the length in records
+const LENGTH_SIZE: usize = 8;
+
+/// Length of data not known when the record was written (see
+/// `Repo::put_name`)
+const UNKNOWN_LENGTH: u64 = ::std::u64::MAX;
+
+/// Size of records with or without `lengths`
+pub(crate) fn record_size(lengths: bool) -> usize {
+    if lengths {
+        DIGEST_SIZE + LENGTH_SIZE
+    } else {
+        DIGEST_SIZE
+    }
+}
+
+/// Record of `digest`, with the length `len` if `lengths`
+pub(crate) fn record(
+    digest: &[u8],
+    len: Option<u64>,
+    lengths: bool,
+) -> Vec<u8> {
+    let mut record = Vec::with_capacity(record_size(lengths));
+    record.extend_from_slice(digest);
+    if lengths {
+        let len = len.unwrap_or(UNKNOWN_LENGTH);
+        record.extend_from_slice(&len.to_le_bytes());
+    }
+    record
+}
+
+/// Digest and length (if known) of `record`
+pub(crate) fn parse_record(record: &[u8]) -> (Vec<u8>, Option<u64>) {
+    let digest = record[..DIGEST_SIZE].to_vec();
+    if record.len() == DIGEST_SIZE {
+        return (digest, None);
+    }
+    let mut len = [0; LENGTH_SIZE];
+    len.copy_from_slice(&record[DIGEST_SIZE..]);
+    match u64::from_le_bytes(len) {
+        UNKNOWN_LENGTH => (digest, None),
+        len => (digest, Some(len)),
+    }
+}
+
+/// Length of the data under index chunk `data`, if known for all of its
+/// records
+pub(crate) fn data_len(data: &[u8]) -> io::Result<Option<u64>> {
+    let size = record_size(true);
+    if data.len() % size != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "index chunk is not a multiple of the record size",
+        ));
+    }
+    Ok(data
+        .chunks(size)
+        .map(|record| parse_record(record).1)
+        .try_fold(0u64, |total, len| total.checked_add(len?)))
+}
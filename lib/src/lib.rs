@@ -1,4 +1,5 @@
 // {{{ extern crate ...
+#[cfg(feature = "with-b2")]
 extern crate backblaze_b2;
 extern crate base64;
 extern crate blake2;
@@ -8,10 +9,19 @@ extern crate crossbeam;
 extern crate crossbeam_channel;
 extern crate dangerous_option;
 extern crate digest;
+extern crate filetime;
 extern crate fs2;
+extern crate glob;
 extern crate hex;
+#[cfg(feature = "with-b2")]
 extern crate hyper;
+#[cfg(feature = "with-b2")]
 extern crate hyper_native_tls;
+#[cfg(feature = "with-async")]
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate libsodium_sys;
 extern crate num_cpus;
 extern crate owning_ref;
 extern crate rand;
@@ -19,6 +29,7 @@ extern crate rdedup_cdc as rollsum;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "with-b2")]
 extern crate serde_json;
 extern crate serde_yaml;
 extern crate sgdata;
@@ -45,30 +56,43 @@ use sgdata::SGData;
 use slog::{FnValue, Level, Logger};
 use slog_perf::TimeReporter;
 use sodiumoxide::crypto::{self, box_, secretbox};
-use std::collections::HashSet;
-use std::io;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{fs, io};
 use std::io::{Error, Read, Result, Write};
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 use url::Url;
 
 mod iterators;
+use iterators::StoredChunks;
 
 mod config;
 
 mod aio;
 use aio::*;
+pub use aio::{FaultInjection, IoStats, Rate, ReadRate, RetryPolicy};
 
 mod chunking;
 mod hashing;
+mod index;
+mod tar;
 
 mod chunk_processor;
 use chunk_processor::*;
+pub use chunk_processor::StageThreads;
 
 mod sorting_recv;
 use sorting_recv::SortingIterator;
 
+mod work_queue;
+
+pub mod error;
+
+mod chunk_header;
+use chunk_header::ChunkHeader;
+
 mod encryption;
 use encryption::EncryptionEngine;
 
@@ -77,6 +101,91 @@ use compression::ArcCompression;
 
 mod pwhash;
 
+mod transform;
+pub use transform::Transform;
+
+pub mod age;
+
+mod format_vectors;
+pub use format_vectors::*;
+
+mod verify_checkpoint;
+pub use verify_checkpoint::*;
+
+mod secret_key;
+pub use secret_key::SecretKey;
+
+mod prune;
+pub use prune::{PruneResults, RetentionPolicy};
+
+mod import;
+pub use import::{ImportManifest, ImportResults};
+
+#[cfg(feature = "with-deflate")]
+mod bup;
+
+mod meta;
+pub use meta::{NameRecord, NamesExport};
+
+mod tree;
+pub use tree::{EntryKind, TreeEntry, TreeIndex};
+
+mod hidden_name;
+use hidden_name::NamesKey;
+
+mod signed_name;
+use signed_name::NamesSigner;
+
+mod name_reader;
+pub use name_reader::NameReader;
+
+mod write_session;
+pub use write_session::WriteSession;
+
+mod cancel;
+use cancel::CancellingChunkAccessor;
+pub use cancel::CancelToken;
+
+#[cfg(feature = "with-async")]
+mod async_api;
+#[cfg(feature = "with-async")]
+pub use aio::{register_async_backend, AsyncBackend, BackendFuture};
+#[cfg(feature = "with-async")]
+pub use async_api::{set_async_threads, Task};
+
+mod bench;
+pub use bench::{BenchData, BenchResults};
+
+mod metrics;
+pub use metrics::{BackendOp, Metrics};
+
+mod progress;
+use progress::{ProgressChunkAccessor, ProgressCounter};
+pub use progress::{Progress, ProgressInfo};
+
+mod seal;
+use seal::Seal;
+pub use seal::SealManifest;
+
+mod pack;
+use pack::{PackIndex, PackWriter, PackedChunk};
+pub use pack::{Packing, RepackResults};
+
+mod parity;
+pub use parity::Parity;
+
+mod maintenance;
+pub use maintenance::{
+    MaintenanceEvent, MaintenancePolicy, MaintenanceState, MaintenanceTasks,
+};
+
+mod run_summary;
+pub use run_summary::{RunPhase, RunSettings, RunSummary};
+use run_summary::RunHistory;
+
+mod audit;
+pub use audit::{AuditEntry, AuditVerifyResults};
+
 pub mod settings;
 
 mod util;
@@ -85,28 +194,43 @@ use self::util::*;
 mod reading;
 use self::reading::*;
 
+mod in_flight;
+use self::in_flight::*;
+
+mod decoded_cache;
+use self::decoded_cache::DEFAULT_DECODED_CACHE_SIZE;
+
 mod generation;
 use self::generation::*;
 
 mod name;
 use self::name::*;
+pub use self::name::NameStat;
+
+mod root_index;
+use self::root_index::*;
 
 mod misc;
 use self::misc::*;
+pub use self::misc::Digest;
 
 use std::error::Error as ErrorError;
 // }}}
 
 type ArcDecrypter = Arc<dyn encryption::Decrypter + Send + Sync + 'static>;
 type ArcEncrypter = Arc<dyn encryption::Encrypter + Send + Sync + 'static>;
+/// Data to chunk, in buffers as read
+type InputIter<'a> = Box<dyn Iterator<Item = Arc<Vec<u8>>> + Send + 'a>;
 
 const INGRESS_BUFFER_SIZE: usize = 128 * 1024;
 const DIGEST_SIZE: usize = 32;
+/// Index level no real data can reach; anything above is corruption
+const MAX_INDEX_LEVEL: u32 = 32;
 
 /// Type of user provided closure that will ask user for a passphrase is needed
 type PassphraseFn<'a> = &'a dyn Fn() -> io::Result<String>;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 /// Data type (index/data)
 pub enum DataType {
     Index,
@@ -123,28 +247,403 @@ impl DataType {
     }
 }
 
+/// Where a chunk is stored in a generation
+enum ChunkLocation {
+    /// Chunk file, and its size
+    File(PathBuf, u64),
+    Packed(PackedChunk),
+}
+
+impl ChunkLocation {
+    /// Size of the chunk as stored
+    fn len(&self) -> u64 {
+        match *self {
+            ChunkLocation::File(_, len) => len,
+            ChunkLocation::Packed(ref packed) => packed.len,
+        }
+    }
+}
+
 pub struct VerifyResults {
     pub scanned: usize,
     pub errors: Vec<(Vec<u8>, Error)>,
 }
 
+/// Results of `Repo::read_verify`
+pub struct ReadVerifyResults {
+    /// Bytes of data read
+    pub size: u64,
+    /// SHA-256 of the data, the same `sha256sum` prints for the original
+    pub digest: Vec<u8>,
+}
+
+/// Writer discarding the data, only counting and hashing it
+struct ReadVerifyWriter {
+    size: u64,
+    digest: Box<dyn hashing::DigestWriter>,
+}
+
+impl Write for ReadVerifyWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.digest.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl VerifyResults {
+    /// Digests of chunks not found
+    pub fn missing(&self) -> Vec<&[u8]> {
+        self.errors
+            .iter()
+            .filter(|(_, e)| e.kind() == io::ErrorKind::NotFound)
+            .map(|(digest, _)| &digest[..])
+            .collect()
+    }
+
+    /// Digests of chunks found, but not readable (can't be decrypted or
+    /// decompressed, or don't hash to their digest)
+    pub fn corrupted(&self) -> Vec<&[u8]> {
+        self.errors
+            .iter()
+            .filter(|(_, e)| e.kind() != io::ErrorKind::NotFound)
+            .map(|(digest, _)| &digest[..])
+            .collect()
+    }
+}
+
+/// Results of `Repo::reconcile`
+pub struct ReconcileResults {
+    /// Objects of the repository in the inventory
+    pub scanned: usize,
+    /// Digests of chunks needed by names, but not in the inventory
+    pub missing: Vec<Vec<u8>>,
+    /// Objects in generations not needed by anything (garbage not
+    /// collected yet, leftovers of interrupted operations, etc.)
+    pub unexpected: Vec<PathBuf>,
+}
+
+/// Leftovers of interrupted operations, found by `Repo::plan_recover` or
+/// removed by `Repo::recover`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecoverResults {
+    /// Files of writes that never completed, relative to the repository
+    pub temp_files: Vec<PathBuf>,
+    /// Generations without a config (eg. of an interrupted `gc`)
+    pub dead_generations: Vec<String>,
+    /// Pack files without an index
+    pub incomplete_packs: Vec<PathBuf>,
+}
+
+impl RecoverResults {
+    pub fn is_empty(&self) -> bool {
+        self.temp_files.is_empty()
+            && self.dead_generations.is_empty()
+            && self.incomplete_packs.is_empty()
+    }
+}
+
+/// Data chunk skipped by `Repo::read_lossy`
+pub struct SkippedChunk {
+    /// Where the data of the chunk is missing, in the data read
+    pub offset: u64,
+    pub digest: Vec<u8>,
+    /// Why it couldn't be read
+    pub error: Error,
+}
+
+/// Results of `Repo::read_lossy`
+pub struct LossyReadResults {
+    /// Bytes of data written
+    pub bytes: u64,
+    pub skipped: Vec<SkippedChunk>,
+}
+
+/// Results of `Repo::check_refs`
+pub struct CheckRefsResults {
+    /// Chunks stored, but not reachable from any name
+    pub orphans: Vec<Vec<u8>>,
+    /// Chunks reachable from names, but not stored, with the names
+    pub dangling: BTreeMap<Vec<u8>, Vec<String>>,
+    /// Index chunks stored, but not readable
+    pub errors: Vec<(Vec<u8>, Error)>,
+}
+
+/// Results of `Repo::gc_dry_run`
+pub struct GcDryRunResults {
+    /// Chunks stored, reachable or not
+    pub stored: usize,
+    /// Chunks not reachable from any name, with the bytes they take (in
+    /// all the generations having them)
+    pub unreachable: BTreeMap<Vec<u8>, u64>,
+    /// Chunks reachable from each name, including the ones shared with
+    /// other names
+    pub reachable: BTreeMap<String, usize>,
+}
+
+impl GcDryRunResults {
+    /// Bytes `gc` would free
+    pub fn unreachable_bytes(&self) -> u64 {
+        self.unreachable.values().sum()
+    }
+}
+
 pub struct DuResults {
     pub chunks: usize,
     pub bytes: u64,
 }
 
+/// Options of `Repo::write_with_options`
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Replace the name if it already exists, instead of failing
+    ///
+    /// The name is switched to the new data atomically: readers get either
+    /// the old or the new data.
+    pub overwrite: bool,
+    /// Chunk the data using this chunking tier (or `default` for the
+    /// repo-wide chunking), instead of picking one by the size of the data
+    pub chunking_tier: Option<String>,
+    /// What to do when the same data is already stored under another name
+    /// (or the name itself), see `WriteStats::unchanged_from`
+    pub if_unchanged: IfUnchanged,
+    /// Apply the transform registered with this identifier (see
+    /// `Repo::register_transform`) to the data first
+    pub transform: Option<String>,
+    /// Number of threads of each stage of processing chunks
+    pub threads: StageThreads,
+    /// Size of buffers the data is read in (128 KiB by default); larger
+    /// ones help with eg. spinning disks or network file systems
+    pub read_buffer_size: Option<usize>,
+    /// The data (as transformed) is a tar stream: put chunk edges around
+    /// the headers of the files in it, so the files deduplicate regardless
+    /// of their order and metadata
+    pub tar: bool,
+    /// Report progress to it, after every data chunk
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Stop reading the data once it's cancelled; chunks stored by then
+    /// are left for `gc`, and the name isn't stored
+    pub cancel: Option<CancelToken>,
+    /// Read the data at most at this rate, eg. to spare the disks it's
+    /// read from (see `Repo::set_write_rate` for the repository side)
+    pub input_rate: Option<Rate>,
+    /// Check every chunk stored: that it decompresses to its digest before
+    /// it's encrypted, and that it reads back as it was written. Catches
+    /// corruption (eg. by faulty memory or disks) while the data is still
+    /// around to be stored again, instead of at restore; at the cost of
+    /// reading everything new back. The name isn't stored if any chunk
+    /// fails.
+    pub verify_writes: bool,
+}
+
+/// Options of `Repo::read_with_options`
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    /// Report progress to it, after every data chunk
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Stop before reading another chunk once it's cancelled
+    pub cancel: Option<CancelToken>,
+    /// Keep up to this many bytes of the data decoded (16 MiB by default,
+    /// `Some(0)` disables it), so chunks the data refers to many times
+    /// (eg. blocks of zeros) are read and decrypted only once
+    pub decoded_cache_size: Option<u64>,
+    /// Keep the memory the read takes within about this many bytes: up to
+    /// a quarter of it each for the decoded data kept, the chunk cache and
+    /// the index records waiting to be read, the rest for the chunk being
+    /// read. Fails with `error::Error::MemoryBudgetExceeded` if the index
+    /// of the data takes more than its share.
+    pub memory_budget: Option<u64>,
+}
+
+/// What `Repo::write_with_options` does with data already stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IfUnchanged {
+    /// Store the name anyway
+    Store,
+    /// Don't store the name (keeping the existing one, if overwriting)
+    Skip,
+    /// Store the name as an alias of the name with the same data (see
+    /// `Repo::alias`), keeping its metadata, eg. the creation time
+    Alias,
+}
+
+impl Default for IfUnchanged {
+    fn default() -> Self {
+        IfUnchanged::Store
+    }
+}
+
+/// Chunk as stored in the repository, see `Repo::export_raw_chunk`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawChunk {
+    /// Header identifying the format of the chunk (empty in repositories
+    /// with bare chunks)
+    pub header: Vec<u8>,
+    /// The rest of the chunk: compressed and encrypted data (or digests,
+    /// in index chunks)
+    pub data: Vec<u8>,
+}
+
+/// Results of `Repo::repair_from`
+#[derive(Debug, Default)]
+pub struct RepairResults {
+    /// Chunks copied from the other repository
+    pub repaired: Vec<Vec<u8>>,
+    /// Chunks that couldn't be copied (eg. missing there too)
+    pub failed: Vec<(Vec<u8>, Error)>,
+}
+
+/// Chunks (data and index) and the space they take in the repository
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChunkStats {
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+/// Chunks shared between two sets of names, and exclusive to each of them
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OverlapResults {
+    pub shared: ChunkStats,
+    pub only_a: ChunkStats,
+    pub only_b: ChunkStats,
+}
+
+/// Statistics of the whole repository, see `Repo::stat_repo`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RepoStats {
+    pub names: usize,
+    /// Names without their size recorded (stored by older versions), so
+    /// missing from `logical_bytes`
+    pub names_without_size: usize,
+    /// Data chunks reachable from the names
+    pub data_chunks: usize,
+    /// Index chunks reachable from the names
+    pub index_chunks: usize,
+    /// Chunks stored, but not reachable from any name (until `gc`)
+    pub unreachable_chunks: usize,
+    /// Bytes all the stored chunks take, in all the generations having them
+    pub stored_bytes: u64,
+    /// Part of `stored_bytes` taken by the data chunks reachable
+    pub data_bytes: u64,
+    /// Bytes of data stored under all the names, as if each had its own
+    /// copy
+    pub logical_bytes: u64,
+    /// Sizes of the data chunks, as stored
+    pub stored_sizes: SizeHistogram,
+    /// Sizes of the data chunks, before compression and encryption (as the
+    /// index has them)
+    pub logical_sizes: SizeHistogram,
+    /// Largest data chunks (as stored), largest first, at most
+    /// `LARGEST_CHUNKS` of them
+    pub largest_chunks: Vec<(Vec<u8>, u64)>,
+}
+
+/// Number of chunks in `RepoStats::largest_chunks`
+pub const LARGEST_CHUNKS: usize = 10;
+
+/// Distribution of chunk sizes, in power of two buckets
+///
+/// Eg. most chunks in the bucket of the maximum chunk size mean the data
+/// mostly doesn't have the edges the chunking looks for.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SizeHistogram {
+    /// Chunks by `i`, with sizes over `2^(i-1)` and at most `2^i` bytes
+    pub buckets: BTreeMap<u32, usize>,
+    /// Chunks of unknown size (eg. logical sizes in repos created with
+    /// `settings::Repo::set_bare_index`)
+    pub unknown: usize,
+    /// Size of the largest chunk
+    pub max: u64,
+}
+
+impl SizeHistogram {
+    fn add(&mut self, size: Option<u64>) {
+        let size = match size {
+            Some(size) => size,
+            None => {
+                self.unknown += 1;
+                return;
+            }
+        };
+        let bucket = 64 - size.saturating_sub(1).leading_zeros();
+        *self.buckets.entry(bucket).or_insert(0) += 1;
+        self.max = self.max.max(size);
+    }
+}
+
+impl RepoStats {
+    /// Average size of a data chunk, as stored (compressed and encrypted)
+    pub fn average_chunk_size(&self) -> u64 {
+        if self.data_chunks == 0 {
+            0
+        } else {
+            self.data_bytes / self.data_chunks as u64
+        }
+    }
+
+    /// Bytes of data stored under the names per byte the data chunks take,
+    /// so it includes compression
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.data_bytes == 0 {
+            0.0
+        } else {
+            self.logical_bytes as f64 / self.data_bytes as f64
+        }
+    }
+}
+
+/// Results of `Repo::diff`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DiffResults {
+    /// Chunks used by both names
+    pub shared: DiffStats,
+    /// Chunks used only by the first name, eg. the data changed since
+    pub only_a: DiffStats,
+    /// Chunks used only by the second name, eg. the data new in it
+    pub only_b: DiffStats,
+}
+
+/// Chunks in a part of `DiffResults`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DiffStats {
+    /// Chunks, data and index
+    pub chunks: usize,
+    /// Bytes the chunks take as stored
+    pub bytes: u64,
+    /// Bytes of data in the data chunks, as far as the index has their
+    /// lengths (see `settings::Repo::set_bare_index`)
+    pub data_bytes: u64,
+}
+
 /// A decryption handle
 ///
 /// Used as an argument to operations that decrypt data.
+#[derive(Clone)]
 pub struct DecryptHandle {
     decrypter: ArcDecrypter,
+    /// Hasher of data chunks, keyed if the repo uses keyed digests
+    hasher: hashing::ArcHasher,
 }
 
 /// A encryption handle
 ///
 /// Used as an argument to operations that encrypt data.
+#[derive(Clone)]
 pub struct EncryptHandle {
     encrypter: ArcEncrypter,
+    /// Hasher of data chunks, keyed if the repo uses keyed digests
+    hasher: hashing::ArcHasher,
+}
+
+/// Re-encryption of data chunks moved to the current generation (see
+/// `Repo::change_key`)
+pub(crate) struct Reseal {
+    decrypter: ArcDecrypter,
+    encrypter: ArcEncrypter,
 }
 
 // {{{ Repo
@@ -161,6 +660,45 @@ pub struct Repo {
     log: slog::Logger,
 
     aio: aio::AsyncIO,
+
+    /// Failures injected into every backend used by this handle
+    fault_injection: Option<FaultInjection>,
+
+    /// Retries of failed operations of every backend used by this handle
+    retry_policy: Option<RetryPolicy>,
+
+    /// Chunk cache in front of every backend used by this handle
+    chunk_cache: Option<Arc<aio::ChunkCache>>,
+
+    /// Limit of the rate data is read from the backend at
+    read_rate: Option<Rate>,
+
+    /// Limit of the rate data is written to the backend at
+    write_rate: Option<Rate>,
+
+    /// Receiver of metrics of the operations of this handle
+    metrics: Option<Arc<dyn Metrics>>,
+
+    /// Chunk fetches in progress, shared by all clones of this handle
+    fetches: Arc<InFlightFetches>,
+
+    /// Indexes of pack files, shared by all clones of this handle
+    packs: Arc<PackIndex>,
+
+    /// The repository can't be modified (eg. is on read-only media)
+    read_only: bool,
+
+    /// How long to wait for the repository lock (forever if `None`)
+    lock_wait: Option<Duration>,
+
+    /// Transforms registered with this handle, by their identifiers
+    transforms: BTreeMap<String, Arc<dyn Transform>>,
+
+    /// Key of hidden names, once unlocked (see `unlock_names`)
+    names_key: Option<Arc<NamesKey>>,
+    /// Key name records are signed with, once unlocked (see
+    /// `unlock_names`)
+    names_signer: Option<Arc<NamesSigner>>,
 }
 
 impl Repo {
@@ -169,11 +707,16 @@ impl Repo {
         pass: PassphraseFn,
     ) -> io::Result<DecryptHandle> {
         info!(self.log, "Opening read handle");
+        if self.config.keyed_digests || self.config.names_sign_key.is_some() {
+            let sec_key = self.secret_key(pass)?;
+            return self.unlock_decrypt_secret_key(&sec_key);
+        }
         let decrypter =
             self.config.encryption.decrypter(pass, &self.config.pwhash)?;
 
         Ok(DecryptHandle {
             decrypter,
+            hasher: Arc::clone(&self.hasher),
         })
     }
 
@@ -182,14 +725,157 @@ impl Repo {
         pass: PassphraseFn,
     ) -> io::Result<EncryptHandle> {
         info!(self.log, "Opening write handle");
+        // Public key is enough to encrypt, but not to calculate keyed
+        // digests
+        let hasher = if self.config.keyed_digests {
+            self.data_hasher(&self.secret_key(pass)?)
+        } else {
+            Arc::clone(&self.hasher)
+        };
         let encrypter =
             self.config.encryption.encrypter(pass, &self.config.pwhash)?;
 
-        Ok(EncryptHandle {
-            encrypter,
+        Ok(EncryptHandle { encrypter, hasher })
+    }
+
+    /// Hasher of data chunks unlocked with `sec_key`
+    fn data_hasher(&self, sec_key: &SecretKey) -> hashing::ArcHasher {
+        if self.config.keyed_digests {
+            self.config
+                .hashing
+                .to_keyed_hasher(hashing::digest_key(sec_key).as_bytes())
+        } else {
+            Arc::clone(&self.hasher)
+        }
+    }
+
+    /// Like `unlock_decrypt`, but with an `age` identity instead of the
+    /// passphrase
+    ///
+    /// Works only after `set_age_recipients` included a recipient matching
+    /// `identity`.
+    pub fn unlock_decrypt_age(
+        &self,
+        identity: &age::Identity,
+    ) -> io::Result<DecryptHandle> {
+        info!(self.log, "Opening read handle with age identity");
+        let envelope = self
+            .aio
+            .read(config::AGE_SEC_KEY_FILE.into())
+            .wait()
+            .map_err(|e| {
+                if e.kind() == io::ErrorKind::NotFound {
+                    Error::new(
+                        io::ErrorKind::NotFound,
+                        "no age recipients set for this repository",
+                    )
+                } else {
+                    e
+                }
+            })?;
+        let sec_key =
+            SecretKey::new(age::decrypt(&envelope.to_linear_vec(), identity)?);
+        self.unlock_decrypt_secret_key(&sec_key)
+    }
+
+    /// Like `unlock_decrypt`, but with the plain secret key instead of the
+    /// passphrase
+    pub fn unlock_decrypt_secret_key(
+        &self,
+        sec_key: &SecretKey,
+    ) -> io::Result<DecryptHandle> {
+        info!(self.log, "Opening read handle with secret key");
+        let decrypter = self
+            .config
+            .encryption
+            .decrypter_from_sec_key(sec_key.as_bytes())?;
+        self.check_names_sign_key(sec_key)?;
+
+        Ok(DecryptHandle {
+            decrypter,
+            hasher: self.data_hasher(sec_key),
         })
     }
 
+    /// Unlock the names of a repository hiding them (see
+    /// `settings::Repo::set_hidden_names`) or signing them (see
+    /// `settings::Repo::set_signed_names`), so they can be used with this
+    /// handle
+    ///
+    /// Until then, any operation involving hidden names fails, and so does
+    /// storing signed ones. Does nothing if the names are neither.
+    pub fn unlock_names(&mut self, sec_key: &SecretKey) -> Result<()> {
+        if !self.config.hidden_names && self.config.names_sign_key.is_none() {
+            return Ok(());
+        }
+        // fails if it's not the key of the repository
+        self.config
+            .encryption
+            .decrypter_from_sec_key(sec_key.as_bytes())?;
+        if self.config.hidden_names {
+            self.names_key = Some(Arc::new(NamesKey::new(sec_key)));
+        }
+        if self.config.names_sign_key.is_some() {
+            self.check_names_sign_key(sec_key)?;
+            self.names_signer = Some(Arc::new(NamesSigner::new(sec_key)));
+        }
+        Ok(())
+    }
+
+    /// Fail with `Error::KeyMismatch` unless name records are signed (if at
+    /// all) with the key derived from `sec_key`
+    ///
+    /// Otherwise anyone able to modify the config could put in a key of
+    /// their own, and sign records pointing names to other data.
+    fn check_names_sign_key(&self, sec_key: &SecretKey) -> Result<()> {
+        match self.config.names_sign_key {
+            Some(pub_key) if NamesSigner::new(sec_key).pub_key() != pub_key => {
+                Err(error::Error::KeyMismatch.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Plain secret key, unsealed with the passphrase
+    ///
+    /// Can be used with `unlock_decrypt_secret_key`.
+    pub fn secret_key(&self, pass: PassphraseFn) -> io::Result<SecretKey> {
+        self.config.encryption.sec_key(pass, &self.config.pwhash)
+    }
+
+    /// Allow `age` identities to unlock the repository for reading
+    ///
+    /// The secret key is encrypted for `recipients`, and stored next to the
+    /// repository config, so it can be also recovered with any `age`
+    /// tooling. Replaces previously set recipients; no recipients removes
+    /// them.
+    pub fn set_age_recipients(
+        &self,
+        pass: PassphraseFn,
+        recipients: &[age::Recipient],
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+
+        let sec_key =
+            self.config.encryption.sec_key(pass, &self.config.pwhash)?;
+
+        if recipients.is_empty() {
+            return substitute_err_not_found(
+                self.aio.remove(config::AGE_SEC_KEY_FILE.into()).wait(),
+                || (),
+            );
+        }
+
+        let envelope = age::encrypt(sec_key.as_bytes(), recipients)?;
+        self.aio
+            .write(
+                config::AGE_SEC_KEY_FILE.into(),
+                SGData::from_single(envelope),
+            )
+            .wait()
+    }
+
     fn ensure_repo_empty_or_new(aio: &AsyncIO) -> Result<()> {
         let list = aio.list(PathBuf::from(".")).wait();
 
@@ -217,11 +903,22 @@ impl Repo {
             .unwrap_or_else(|| Logger::root(slog::Discard, o!()));
 
         let backend = aio::backend_from_url(url)?;
-        let aio = aio::AsyncIO::new(backend, log.clone())?;
+        let chunk_cache = Repo::default_chunk_cache(&*backend);
+        let retry_policy = Repo::default_retry_policy(&*backend);
+        let aio = aio::AsyncIO::new(
+            Repo::cached_backend(
+                Repo::retrying_backend(backend, &retry_policy),
+                &chunk_cache,
+            ),
+            aio::IoCounters::default(),
+            log.clone(),
+        )?;
 
         Repo::ensure_repo_empty_or_new(&aio)?;
         let config = config::Repo::new_from_settings(passphrase, settings)?;
         config.write(&aio)?;
+        // so the first writes, if concurrent, don't each start one
+        Generation::gen_first().write(&aio)?;
 
         let compression = config.compression.to_engine();
         let hasher = config.hashing.to_hasher();
@@ -233,9 +930,27 @@ impl Repo {
             hasher,
             log,
             aio,
+            fault_injection: None,
+            retry_policy,
+            chunk_cache,
+            read_rate: None,
+            write_rate: None,
+            metrics: None,
+            fetches: Arc::new(InFlightFetches::default()),
+            packs: Arc::new(PackIndex::default()),
+            read_only: false,
+            lock_wait: None,
+            transforms: BTreeMap::new(),
+            names_key: None,
+            names_signer: None,
         })
     }
 
+    /// Open existing rdedup repository
+    ///
+    /// Repositories on read-only media are detected, and can still be read
+    /// (`read`, `du`, `verify`, etc.): reading never writes anything to the
+    /// repository, and no cache or temporary files are used.
     pub fn open<L>(url: &Url, log: L) -> Result<Repo>
     where
         L: Into<Option<Logger>>,
@@ -245,77 +960,803 @@ impl Repo {
             .unwrap_or_else(|| Logger::root(slog::Discard, o!()));
 
         let backend = aio::backend_from_url(url)?;
-        let aio = aio::AsyncIO::new(backend, log.clone())?;
+        let read_only = backend.read_only();
+        let chunk_cache = Repo::default_chunk_cache(&*backend);
+        let retry_policy = Repo::default_retry_policy(&*backend);
+        let aio = aio::AsyncIO::new(
+            Repo::cached_backend(
+                Repo::retrying_backend(backend, &retry_policy),
+                &chunk_cache,
+            ),
+            aio::IoCounters::default(),
+            log.clone(),
+        )?;
 
         let config = config::Repo::read(&aio)?;
+        let read_only = read_only || config.seal.is_some();
+        if read_only {
+            info!(log, "Repository is read-only");
+        }
 
         let compression = config.compression.to_engine();
         let hasher = config.hashing.to_hasher();
-        Ok(Repo {
+        let mut repo = Repo {
             url: url.clone(),
             config,
             compression,
             hasher,
             log,
             aio,
-        })
+            fault_injection: None,
+            retry_policy,
+            chunk_cache,
+            read_rate: None,
+            write_rate: None,
+            metrics: None,
+            fetches: Arc::new(InFlightFetches::default()),
+            packs: Arc::new(PackIndex::default()),
+            read_only,
+            lock_wait: None,
+            transforms: BTreeMap::new(),
+            names_key: None,
+            names_signer: None,
+        };
+        if !repo.config.replicas.is_empty() {
+            repo.aio = aio::AsyncIO::new(
+                repo.backend()?,
+                repo.aio.io_counters(),
+                repo.log.clone(),
+            )?;
+        }
+        Ok(repo)
     }
 
-    /// Change the passphrase
-    pub fn change_passphrase(
+    /// Make all backend operations of this handle misbehave
+    ///
+    /// Meant for exercising error handling of the repository and code
+    /// using it against unreliable storage.
+    pub fn inject_faults(&mut self, faults: FaultInjection) -> Result<()> {
+        self.fault_injection = Some(faults);
+        let backend = self.backend()?;
+        self.read_only = backend.read_only() || self.config.seal.is_some();
+        self.aio = aio::AsyncIO::new(
+            backend,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Retry backend operations failing with transient errors as `policy`
+    /// tells (`None` fails them right away)
+    ///
+    /// By default operations of remote backends are retried as
+    /// `RetryPolicy::default()` tells, and others aren't. Retries are
+    /// counted in `io_stats`.
+    pub fn set_retry_policy(
         &mut self,
-        old_p: PassphraseFn,
-        new_p: PassphraseFn,
+        policy: Option<RetryPolicy>,
     ) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
+        self.retry_policy = policy;
+        self.aio = aio::AsyncIO::new(
+            self.backend()?,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
 
-        if self.config.version == 0 {
-            Err(Error::new(
-                io::ErrorKind::NotFound,
-                "rdedup v0 config format not supported",
-            ))
-        } else {
-            self.config.encryption.change_passphrase(
-                old_p,
-                new_p,
-                &self.config.pwhash,
-            )?;
-            self.config.write(&self.aio)?;
-            Ok(())
-        }
+    /// Wait at most `wait` for the repository lock (forever if `None`)
+    ///
+    /// Operations needing the lock held by someone else (eg. `write`
+    /// during a `gc`) then fail with `io::ErrorKind::TimedOut`, instead of
+    /// blocking until it's released. Lockers are served in turns, so a
+    /// waiting `gc` isn't starved by `write`s started after it.
+    pub fn set_lock_wait(&mut self, wait: Option<Duration>) {
+        self.lock_wait = wait;
     }
 
-    /// Write a chunk of data to the repo.
-    fn chunk_and_write_data_thread<'a>(
-        &'a self,
-        input_data_iter: Box<dyn Iterator<Item = Vec<u8>> + Send + 'a>,
-        process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
-        aio: aio::AsyncIO,
-        data_type: DataType,
-    ) -> io::Result<DataAddress> {
-        // Note: This channel is intentionally unbounded
-        // The processing loop runs in sort of a loop (actually more of a
-        // recursive spiral). Unless this channel is unbounded it's possible
-        // that one `index-processor` will wait for `chunker` while `chunker`
-        // waits for `chunk-processor` while will `chunk-processor` waits
-        // for `index-processor`.
-        //
-        // In practice there's always less `index` data than chunk data (that's
-        // the whole point of keeping index) so this channel does not have
-        // to be bounded.
-        let (digests_tx, digests_rx) = mpsc::channel();
+    /// Make `transform` available for storing data (see
+    /// `WriteOptions::transform`), and reading data stored with it
+    ///
+    /// Replaces a transform registered before with the same identifier.
+    pub fn register_transform(
+        &mut self,
+        transform: Arc<dyn Transform>,
+    ) -> Result<()> {
+        if transform.id().is_empty() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "transform identifier can't be empty",
+            ));
+        }
+        self.transforms.insert(transform.id().to_owned(), transform);
+        Ok(())
+    }
 
-        crossbeam::scope(move |scope| {
-            let mut timer = slog_perf::TimeReporter::new_with_level(
-                "index-processor",
-                self.log.clone(),
-                Level::Debug,
-            );
-            timer.start("spawn-chunker");
+    /// Transform registered as `id`
+    fn transform(&self, id: &str) -> Result<&Arc<dyn Transform>> {
+        self.transforms.get(id).ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("transform {} is not registered", id),
+            )
+        })
+    }
 
-            scope.spawn({
-                let process_tx = process_tx.clone();
-                move |_| {
+    /// Cache up to `size` bytes of chunk data read, and which chunks exist
+    ///
+    /// Saves requests to slow (eg. remote) backends; it's used for them by
+    /// default. Zero disables the cache.
+    pub fn set_chunk_cache_size(&mut self, size: u64) -> Result<()> {
+        self.chunk_cache = if size == 0 {
+            None
+        } else {
+            Some(Arc::new(aio::ChunkCache::new(size)))
+        };
+        let backend = self.backend()?;
+        self.aio = aio::AsyncIO::new(
+            backend,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Capacity of the chunk cache, in bytes (0 if there's none)
+    fn chunk_cache_size(&self) -> u64 {
+        match self.chunk_cache {
+            Some(ref cache) => cache.capacity(),
+            None => 0,
+        }
+    }
+
+    /// Read data from the backend at most at `rate` (no limit if `None`)
+    ///
+    /// Meant for restoring without starving other users of a shared
+    /// storage. Chunks found in the chunk cache don't count.
+    pub fn set_read_rate(&mut self, rate: Option<Rate>) -> Result<()> {
+        self.read_rate = rate;
+        let backend = self.backend()?;
+        self.aio = aio::AsyncIO::new(
+            backend,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Write data to the backend at most at `rate` (no limit if `None`)
+    ///
+    /// Counts the chunks (and everything else) as stored, ie. compressed
+    /// and encrypted. See `WriteOptions::input_rate` to limit the rate
+    /// data to store is read at instead.
+    pub fn set_write_rate(&mut self, rate: Option<Rate>) -> Result<()> {
+        self.write_rate = rate;
+        let backend = self.backend()?;
+        self.aio = aio::AsyncIO::new(
+            backend,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Report counters and timings of writes, reads and backend requests
+    /// to `metrics` (nothing if `None`)
+    pub fn set_metrics(
+        &mut self,
+        metrics: Option<Arc<dyn Metrics>>,
+    ) -> Result<()> {
+        self.metrics = metrics;
+        let backend = self.backend()?;
+        self.aio = aio::AsyncIO::new(
+            backend,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Report to the metrics receiver, if any
+    fn report_metrics<F>(&self, f: F)
+    where
+        F: FnOnce(&dyn Metrics),
+    {
+        if let Some(ref metrics) = self.metrics {
+            f(&**metrics)
+        }
+    }
+
+    fn default_chunk_cache(
+        backend: &dyn aio::Backend,
+    ) -> Option<Arc<aio::ChunkCache>> {
+        if backend.remote() {
+            Some(Arc::new(aio::ChunkCache::new(aio::DEFAULT_CHUNK_CACHE_SIZE)))
+        } else {
+            None
+        }
+    }
+
+    fn default_retry_policy(backend: &dyn aio::Backend) -> Option<RetryPolicy> {
+        if backend.remote() {
+            Some(RetryPolicy::default())
+        } else {
+            None
+        }
+    }
+
+    fn retrying_backend(
+        backend: Box<dyn aio::Backend + Send + Sync>,
+        policy: &Option<RetryPolicy>,
+    ) -> Box<dyn aio::Backend + Send + Sync> {
+        match *policy {
+            Some(ref policy) => {
+                Box::new(aio::Retrying::new(backend, policy.clone()))
+            }
+            None => backend,
+        }
+    }
+
+    fn cached_backend(
+        backend: Box<dyn aio::Backend + Send + Sync>,
+        chunk_cache: &Option<Arc<aio::ChunkCache>>,
+    ) -> Box<dyn aio::Backend + Send + Sync> {
+        match *chunk_cache {
+            Some(ref cache) => {
+                Box::new(aio::Cached::new(backend, Arc::clone(cache)))
+            }
+            None => backend,
+        }
+    }
+
+    /// Backend requests made through this handle (and its clones) so far
+    pub fn io_stats(&self) -> IoStats {
+        self.aio.io_counters().get()
+    }
+
+    /// Summary of a run of `command` ending now: versions, settings and
+    /// `io_stats`
+    ///
+    /// Phases and counts are left for the caller to fill in, before
+    /// `record_run`.
+    pub fn run_summary(&self, command: &str) -> RunSummary {
+        RunSummary {
+            time: chrono::Utc::now(),
+            command: command.into(),
+            ok: true,
+            version: env!("CARGO_PKG_VERSION").into(),
+            repo_version: self.config.version,
+            settings: RunSettings {
+                chunking: run_summary::describe(&self.config.chunking),
+                hashing: run_summary::describe(&self.config.hashing),
+                compression: run_summary::describe(&self.config.compression),
+                encryption: match self.config.encryption {
+                    config::Encryption::None => "none".into(),
+                    config::Encryption::Curve25519(_) => "curve25519".into(),
+                },
+                packing: self.config.packing.is_some(),
+            },
+            phases: vec![],
+            io: self.io_stats(),
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Keep `summary` in the history of runs of the repository
+    ///
+    /// Only the latest runs are kept. Runs recorded at the same time by
+    /// others can get lost.
+    pub fn record_run(&self, summary: &RunSummary) -> Result<()> {
+        self.ensure_writable()?;
+        let mut history = RunHistory::read(&self.aio)?;
+        history.record(summary.clone());
+        history.write(&self.aio)
+    }
+
+    /// Runs recorded with `record_run`, oldest first
+    pub fn runs(&self) -> Result<Vec<RunSummary>> {
+        Ok(RunHistory::read(&self.aio)?.runs)
+    }
+
+    /// Does the repository hide names (see `unlock_names`)
+    pub fn hides_names(&self) -> bool {
+        self.config.hidden_names
+    }
+
+    /// Does the repository sign names (see `unlock_names`)
+    pub fn signs_names(&self) -> bool {
+        self.config.names_sign_key.is_some()
+    }
+
+    /// Hex encoded public key name records are signed with, if signed
+    ///
+    /// Worth keeping elsewhere, to check the repository config wasn't
+    /// replaced along with the records.
+    pub fn names_public_key(&self) -> Option<String> {
+        self.config.names_sign_key.map(hex::encode)
+    }
+
+    /// Is the repository read-only (eg. on read-only media)
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.config.seal.is_some() {
+            Err(Error::new(
+                io::ErrorKind::PermissionDenied,
+                "repository is sealed",
+            ))
+        } else if self.read_only {
+            Err(Error::new(
+                io::ErrorKind::PermissionDenied,
+                "repository is read-only",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fail unless anything stored can be removed or replaced (see
+    /// `set_append_only`)
+    fn ensure_deletable(&self) -> Result<()> {
+        self.ensure_writable()?;
+        if self.config.append_only {
+            return Err(Error::new(
+                io::ErrorKind::PermissionDenied,
+                "repository is append-only",
+            ));
+        }
+        Ok(())
+    }
+
+    fn backend(&self) -> Result<Box<dyn aio::Backend + Send + Sync>> {
+        let backend = aio::backend_from_url(&self.url)?;
+        let backend = if self.config.replicas.is_empty() {
+            backend
+        } else {
+            let replicas = self
+                .replica_urls()?
+                .iter()
+                .map(aio::backend_from_url)
+                .collect::<Result<_>>()?;
+            Box::new(aio::Replicated::new(backend, replicas))
+        };
+        let backend = match self.metrics {
+            Some(ref metrics) => {
+                Box::new(aio::Measured::new(backend, Arc::clone(metrics)))
+            }
+            None => backend,
+        };
+        let throttled = self.read_rate.is_some() || self.write_rate.is_some();
+        let backend = if throttled {
+            Box::new(aio::Throttled::new(
+                backend,
+                self.read_rate,
+                self.write_rate,
+            ))
+        } else {
+            backend
+        };
+        let backend = match self.fault_injection {
+            Some(ref faults) => {
+                Box::new(aio::Faulty::new(backend, faults.clone()))
+            }
+            None => backend,
+        };
+        let backend = Repo::retrying_backend(backend, &self.retry_policy);
+        Ok(Repo::cached_backend(backend, &self.chunk_cache))
+    }
+
+    fn replica_urls(&self) -> Result<Vec<Url>> {
+        self.config
+            .replicas
+            .iter()
+            .map(|url| {
+                Url::parse(url).map_err(|e| {
+                    Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid replica url {}: {}", url, e),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Replicate everything stored from now on to `replicas` (none stops
+    /// replicating)
+    ///
+    /// Each chunk (and name) is then written to all of them before a write
+    /// completes, and read from them if the repository itself can't provide
+    /// it. Data stored before isn't copied: `verify_replicas` reports what's
+    /// missing. Releases older than replicas can't use the repository
+    /// anymore.
+    pub fn set_replicas(&mut self, replicas: &[Url]) -> Result<()> {
+        self.ensure_writable()?;
+        for url in replicas {
+            aio::backend_from_url(url)?;
+        }
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        self.config.replicas =
+            replicas.iter().map(|url| url.to_string()).collect();
+        self.config.version =
+            self.config.version.max(self.config.required_version());
+        self.config.write(&self.aio)?;
+        self.aio = aio::AsyncIO::new(
+            self.backend()?,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Check that every chunk of `name_str` is on every replica
+    ///
+    /// Only presence is checked, not the content; errors name the
+    /// replica missing the chunk.
+    pub fn verify_replicas(&self, name_str: &str) -> Result<VerifyResults> {
+        let replicas = self
+            .replica_urls()?
+            .into_iter()
+            .map(|url| {
+                let aio = aio::AsyncIO::new(
+                    aio::backend_from_url(&url)?,
+                    aio::IoCounters::default(),
+                    self.log.clone(),
+                )?;
+                Ok((url, aio))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let gen_strs: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let data_address: DataAddress = name.into();
+        let mut digests = HashSet::new();
+        self.reachable_recursively_insert(
+            data_address.as_ref(),
+            &mut digests,
+            generations,
+        )?;
+
+        let mut results = VerifyResults {
+            scanned: digests.len(),
+            errors: vec![],
+        };
+        for digest in digests {
+            let path = match self.find_chunk(DigestRef(&digest), &gen_strs)? {
+                Some((_, ChunkLocation::File(path, _))) => path,
+                Some((_, ChunkLocation::Packed(packed))) => {
+                    (*packed.path).clone()
+                }
+                None => {
+                    let err = error::Error::ChunkNotFound(digest.clone());
+                    results.errors.push((digest, err.into()));
+                    continue;
+                }
+            };
+            for (url, aio) in &replicas {
+                match aio.read_metadata(path.clone()).wait() {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        results.errors.push((
+                            digest.clone(),
+                            Error::new(
+                                io::ErrorKind::NotFound,
+                                format!("missing on replica {}", url),
+                            ),
+                        ))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Compare the objects the repository needs with an inventory of the
+    /// storage (eg. an S3 or GCS inventory report)
+    ///
+    /// `inventory` lists paths of the objects stored, relative to the
+    /// repository. Only names and index chunks are read: data chunks are
+    /// looked for in the inventory, so even very large repositories are
+    /// audited without requesting every chunk. Objects written after the
+    /// inventory was taken are reported missing.
+    pub fn reconcile<I>(&self, inventory: I) -> Result<ReconcileResults>
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let inventory: HashSet<PathBuf> = inventory
+            .into_iter()
+            .filter(|path| {
+                path.components()
+                    .next()
+                    .and_then(|c| c.as_os_str().to_str())
+                    .map_or(false, |item| Generation::try_from(item).is_ok())
+            })
+            .collect();
+
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let gen_strs: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+        self.packs.refresh(&gen_strs, &self.aio)?;
+
+        let mut expected = HashSet::new();
+        for gen in &generations {
+            expected.insert(gen.config_path());
+            for name in Name::list(*gen, &self.name_io())? {
+                expected.insert(self.name_io().name_path(&name, *gen)?);
+            }
+        }
+
+        let mut missing = vec![];
+        for digest in self.list_reachable_chunks()? {
+            let mut found = false;
+            for gen_str in gen_strs.iter().rev() {
+                let path =
+                    self.chunk_rel_path_by_digest(DigestRef(&digest), gen_str);
+                if inventory.contains(&path) {
+                    expected.insert(parity::path(&path));
+                    expected.insert(path);
+                    found = true;
+                    break;
+                }
+                if let Some(packed) =
+                    self.packs.find(gen_str, &digest, &self.aio)?
+                {
+                    let path = (*packed.path).clone();
+                    if inventory.contains(&path) {
+                        let index = path.with_extension(pack::INDEX_EXT);
+                        expected.insert(index);
+                        expected.insert(path);
+                        found = true;
+                        break;
+                    }
+                }
+            }
+            if !found {
+                missing.push(digest);
+            }
+        }
+        missing.sort();
+
+        let mut unexpected: Vec<_> = inventory
+            .iter()
+            .filter(|path| !expected.contains(*path))
+            .cloned()
+            .collect();
+        unexpected.sort();
+
+        Ok(ReconcileResults {
+            scanned: inventory.len(),
+            missing,
+            unexpected,
+        })
+    }
+
+    /// Leftovers of interrupted operations `recover` would remove, without
+    /// removing anything
+    pub fn plan_recover(&self) -> Result<RecoverResults> {
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        self.recover_locked(true)
+    }
+
+    /// Remove leftovers of interrupted operations (eg. of a process killed,
+    /// or a machine crashed)
+    ///
+    /// They're ignored anyway, so only take space: temporary files of
+    /// writes that never completed, generations whose removal by `gc`
+    /// didn't complete, and pack files written without their index.
+    /// Nothing else is needed: locks are released by the storage when the
+    /// process holding them ends, and chunks written for names that never
+    /// got stored are removed by `gc`. The repository is locked
+    /// exclusively meanwhile, so no write in progress is taken for a
+    /// leftover.
+    pub fn recover(&self) -> Result<RecoverResults> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let results = self.recover_locked(false)?;
+        if !results.is_empty() {
+            info!(self.log, "Removed leftovers of interrupted operations";
+                  "temp-files" => results.temp_files.len(),
+                  "dead-generations" => results.dead_generations.len(),
+                  "incomplete-packs" => results.incomplete_packs.len());
+        }
+        Ok(results)
+    }
+
+    fn recover_locked(&self, dry_run: bool) -> Result<RecoverResults> {
+        let mut results = RecoverResults {
+            temp_files: self.aio.remove_temp_files(dry_run)?,
+            ..Default::default()
+        };
+
+        for path in self.aio.list(PathBuf::new()).wait()? {
+            let item = match path.file_name().and_then(|file| file.to_str()) {
+                Some(item) => item.to_owned(),
+                None => continue,
+            };
+            let gen = match Generation::try_from(&item) {
+                Ok(gen) => gen,
+                Err(_) => continue,
+            };
+            match self.aio.read_metadata(gen.config_path()).wait() {
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            if !dry_run {
+                self.aio.remove_dir_all(PathBuf::from(&item)).wait()?;
+            }
+            results.dead_generations.push(item);
+        }
+        results.dead_generations.sort();
+
+        for gen in self.read_generations()? {
+            for path in pack::incomplete_packs(&gen.to_string(), &self.aio)? {
+                if !dry_run {
+                    self.aio.remove(path.clone()).wait()?;
+                }
+                results.incomplete_packs.push(path);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Change the passphrase
+    pub fn change_passphrase(
+        &mut self,
+        old_p: PassphraseFn,
+        new_p: PassphraseFn,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+
+        if self.config.version == 0 {
+            Err(Error::new(
+                io::ErrorKind::NotFound,
+                "rdedup v0 config format not supported",
+            ))
+        } else {
+            self.config.encryption.change_passphrase(
+                old_p,
+                new_p,
+                &self.config.pwhash,
+            )?;
+            self.config.write(&self.aio)?;
+            Ok(())
+        }
+    }
+
+    /// Replace the keypair of the repository with the one of
+    /// `new_sec_key` (eg. when the secret key might have leaked), and
+    /// re-encrypt all the data for it
+    ///
+    /// The current secret key is unsealed with `pass`, and the new one is
+    /// sealed with it. Data is moved to a new generation as it's
+    /// re-encrypted, and the old generations are deleted. If interrupted,
+    /// everything stays readable (the old key is kept, sealed with the new
+    /// one): just change the key again. `age` recipients have to be set
+    /// again.
+    ///
+    /// Not supported with keyed digests, hidden or signed names, as they
+    /// depend on the key.
+    pub fn change_key(
+        &mut self,
+        pass: PassphraseFn,
+        new_sec_key: &SecretKey,
+    ) -> Result<()> {
+        self.ensure_deletable()?;
+        if self.config.keyed_digests {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "key of a repository with keyed digests can't be changed",
+            ));
+        }
+        if self.config.hidden_names {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "key of a repository with hidden names can't be changed",
+            ));
+        }
+        if self.config.names_sign_key.is_some() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "key of a repository with signed names can't be changed",
+            ));
+        }
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+
+        let sec_key = self.secret_key(pass)?;
+        self.config.encryption.change_key(
+            &sec_key,
+            new_sec_key,
+            pass,
+            &self.config.pwhash,
+        )?;
+        self.config.version =
+            self.config.version.max(self.config.required_version());
+        // it's encrypted for the recipients with the old key
+        substitute_err_not_found(
+            self.aio.remove(config::AGE_SEC_KEY_FILE.into()).wait(),
+            || (),
+        )?;
+        self.config.write(&self.aio)?;
+
+        let reseal = Reseal {
+            decrypter: self
+                .config
+                .encryption
+                .decrypter_from_sec_key(new_sec_key.as_bytes())?,
+            encrypter: self
+                .config
+                .encryption
+                .encrypter(pass, &self.config.pwhash)?,
+        };
+        let generations = self.read_generations()?;
+        if let Some(gen) = generations.last() {
+            let new_gen = gen.gen_next();
+            info!(self.log, "Re-encrypting data to new generation";
+                  "gen" => FnValue(|_| new_gen.to_string()));
+            new_gen.write(&self.aio)?;
+            // every step moves all the names of one of the generations
+            for _ in &generations {
+                self.gc_locked(0, Some(&reseal), &CancelToken::new())?;
+            }
+            if let Some(ref packing) = self.config.packing {
+                self.repack_locked(packing)?;
+            }
+        }
+
+        self.config.encryption.forget_previous_keys();
+        self.config.write(&self.aio)?;
+        self.audit(AuditEntry::new("change-key"))
+    }
+
+    /// Write a chunk of data to the repo.
+    ///
+    /// The data is chunked as `tier` says (`None` meaning the repo-wide
+    /// chunking). Returns the address of the data, along with the number of
+    /// chunks and bytes it was made of (excluding the index).
+    fn chunk_and_write_data_thread<'a>(
+        &'a self,
+        input_data_iter: InputIter<'a>,
+        tier: Option<&'a config::ChunkingTier>,
+        process_tx: work_queue::WorkSender<chunk_processor::Message>,
+        aio: aio::AsyncIO,
+        data_type: DataType,
+    ) -> io::Result<(DataAddress, u64, u64)> {
+        // Note: This channel is intentionally unbounded
+        // The processing loop runs in sort of a loop (actually more of a
+        // recursive spiral). Unless this channel is unbounded it's possible
+        // that one `index-processor` will wait for `chunker` while `chunker`
+        // waits for `chunk-processor` while will `chunk-processor` waits
+        // for `index-processor`.
+        //
+        // In practice there's always less `index` data than chunk data (that's
+        // the whole point of keeping index) so this channel does not have
+        // to be bounded.
+        let (digests_tx, digests_rx) = mpsc::channel();
+        // Lengths of the data of the chunks, in order (see `index`)
+        let (lengths_tx, lengths_rx) = mpsc::channel();
+        let index_lengths = self.config.index_lengths;
+
+        crossbeam::scope(move |scope| {
+            let mut timer = slog_perf::TimeReporter::new_with_level(
+                "index-processor",
+                self.log.clone(),
+                Level::Debug,
+            );
+            timer.start("spawn-chunker");
+
+            let chunker = scope.spawn({
+                let process_tx = process_tx.clone();
+                move |_| {
                     let mut timer = slog_perf::TimeReporter::new_with_level(
                         "chunker",
                         self.log.clone(),
@@ -324,23 +1765,43 @@ impl Repo {
 
                     let chunker = chunking::Chunker::new(
                         input_data_iter.into_iter(),
-                        self.config.chunking.to_engine(),
+                        if data_type == DataType::Index {
+                            self.config.index_chunking_engine()
+                        } else {
+                            self.config.chunking_engine_for(tier)
+                        },
                     );
 
                     let mut data = util::EnumerateU64::new(chunker);
+                    let (mut chunks, mut bytes) = (0, 0);
 
                     while let Some(i_sg) =
                         timer.start_with("rx-and-chunking", || data.next())
                     {
                         timer.start("tx");
                         let (i, sg) = i_sg;
+                        chunks += 1;
+                        bytes += sg.len() as u64;
+                        if index_lengths {
+                            let len = if data_type == DataType::Index {
+                                index::data_len(&sg.clone().to_linear_vec())
+                                    .expect("index chunk of whole records")
+                            } else {
+                                Some(sg.len() as u64)
+                            };
+                            lengths_tx.send(len).expect("lengths_rx gone");
+                        }
                         process_tx.send(chunk_processor::Message {
                             data: (i as u64, sg),
                             response_tx: digests_tx.clone(),
                             data_type,
-                        }).expect("chunk process tx channel closed")
+                        }).unwrap_or_else(|_| {
+                            panic!("chunk process queue closed")
+                        })
                     }
                     drop(digests_tx);
+                    drop(lengths_tx);
+                    (chunks, bytes)
                 }
             });
 
@@ -351,44 +1812,148 @@ impl Repo {
             let first_digest =
                 digests_rx.next().expect("At least one index digest");
 
-            if let Some(second_digest) =
+            let address = if let Some(second_digest) =
                 timer.start_with("digest-rx", || digests_rx.next())
             {
                 let mut two_first = vec![first_digest, second_digest];
-                let mut address = self.chunk_and_write_data_thread(
-                    Box::new(
-                        two_first
-                            .drain(..)
-                            .chain(digests_rx)
-                            .map(|digest| digest.0),
-                    ),
+                let mut lengths = lengths_rx.into_iter();
+                let (mut address, _, _) = self.chunk_and_write_data_thread(
+                    Box::new(two_first.drain(..).chain(digests_rx).map(
+                        move |digest| {
+                            let len = if index_lengths {
+                                lengths.next().unwrap_or(None)
+                            } else {
+                                None
+                            };
+                            Arc::new(index::record(
+                                &digest.0,
+                                len,
+                                index_lengths,
+                            ))
+                        },
+                    )),
+                    None,
                     process_tx,
                     aio.clone(),
                     DataType::Index,
                 )?;
 
                 address.index_level += 1;
-                Ok(address)
+                address
             } else {
-                Ok(DataAddress {
+                DataAddress {
                     index_level: 0,
                     digest: first_digest,
-                })
-            }
+                }
+            };
+
+            let (chunks, bytes) = chunker.join().expect("chunker panicked");
+            Ok((address, chunks, bytes))
         }).expect("chunker thread failed")
     }
 
+    /// Read ahead `input` to tell its chunking tier by the size
+    ///
+    /// Returns the tier, along with all of the `input` (including what was
+    /// read ahead).
+    fn detect_chunking_tier<'a, I>(
+        &'a self,
+        mut input: I,
+    ) -> (Option<&'a config::ChunkingTier>, InputIter<'a>)
+    where
+        I: Iterator<Item = Arc<Vec<u8>>> + Send + 'a,
+    {
+        let detection_size = self.config.chunking_tier_detection_size();
+        let mut ahead = vec![];
+        let mut size = 0;
+        while size < detection_size {
+            match input.next() {
+                Some(buf) => {
+                    size += buf.len() as u64;
+                    ahead.push(buf);
+                }
+                None => break,
+            }
+        }
+        let tier = self.config.chunking_tier_for_size(size);
+        let tier_name = tier.map_or(config::DEFAULT_CHUNKING_TIER, |t| &t.name);
+        debug!(self.log, "picked chunking tier";
+               "tier" => tier_name, "read-ahead" => size);
+        (tier, Box::new(ahead.into_iter().chain(input)))
+    }
+
     /// Number of threads to use to parallelize CPU-intense part of
     /// the workload.
     fn write_cpu_thread_num(&self) -> usize {
         num_cpus::get()
     }
 
-    fn input_reader_thread<R>(
+    /// Compression of name records, if enabled
+    fn name_compression(&self) -> Option<&config::Compression> {
+        if self.config.compress_names {
+            Some(&self.config.compression)
+        } else {
+            None
+        }
+    }
+
+    /// `sg` (compressed, unless `compressed` is false, and encrypted) as
+    /// stored in a chunk
+    fn with_chunk_header(
         &self,
-        reader: R,
-        chunker_tx: mpsc::SyncSender<Vec<u8>>,
-    ) where
+        sg: SGData,
+        data_type: DataType,
+        compressed: bool,
+    ) -> SGData {
+        if self.config.chunk_headers {
+            let header = ChunkHeader::new(&self.config, data_type);
+            if data_type.should_compress() && !compressed {
+                header.uncompressed().prepend(sg)
+            } else {
+                header.prepend(sg)
+            }
+        } else {
+            sg
+        }
+    }
+
+    /// Contents of chunk `digest` without its header, if any, and whether
+    /// they're compressed
+    fn without_chunk_header(
+        &self,
+        sg: SGData,
+        data_type: DataType,
+        digest: DigestRef,
+    ) -> Result<(SGData, bool)> {
+        if self.config.chunk_headers {
+            let (sg, uncompressed) = ChunkHeader::new(&self.config, data_type)
+                .strip(sg, &hex::encode(digest.0))?;
+            Ok((sg, data_type.should_compress() && !uncompressed))
+        } else {
+            Ok((sg, data_type.should_compress()))
+        }
+    }
+
+    /// `sg` of a data chunk as stored, and whether it's compressed (see
+    /// `config::Repo::min_compression_savings`)
+    fn compress_chunk(&self, sg: SGData) -> io::Result<(SGData, bool)> {
+        compression::compress_if_worth_it(
+            &*self.compression,
+            sg,
+            self.config.min_compression_savings,
+        )
+    }
+
+    /// Pass the data from `reader` on (at most at `options.input_rate`),
+    /// until its end or `options.cancel`
+    fn input_reader_thread<R>(
+        &self,
+        reader: R,
+        buf_size: usize,
+        chunker_tx: mpsc::SyncSender<Arc<Vec<u8>>>,
+        options: &WriteOptions,
+        stop: &CancelToken,
+    ) where
         R: Read + Send,
     {
         let mut time = TimeReporter::new_with_level(
@@ -397,10 +1962,25 @@ impl Repo {
             Level::Debug,
         );
 
-        let r2vi = ReaderVecIter::new(reader, INGRESS_BUFFER_SIZE);
+        let r2vi = ReaderVecIter::new(reader, buf_size);
         let mut while_ok = WhileOk::new(r2vi);
+        let pacer = options.input_rate.map(aio::Pacer::new);
 
         while let Some(buf) = time.start_with("input", || while_ok.next()) {
+            if let Some(ref cancel) = options.cancel {
+                if cancel.is_cancelled() {
+                    info!(self.log, "Write cancelled, not reading further");
+                    break;
+                }
+            }
+            if stop.is_cancelled() {
+                info!(self.log, "Storing failed, not reading further");
+                break;
+            }
+            if let Some(ref pacer) = pacer {
+                time.start("pace");
+                pacer.pace(buf.len() as u64);
+            }
             time.start("tx");
             chunker_tx.send(buf).expect("chunker tx channel closed")
         }
@@ -410,37 +1990,42 @@ impl Repo {
         }
     }
 
-    fn get_chunk_accessor(
-        &self,
-        decrypter: Option<ArcDecrypter>,
+    fn get_chunk_accessor<'a>(
+        &'a self,
+        dec: Option<&DecryptHandle>,
         compression: ArcCompression,
         generations: Vec<Generation>,
-    ) -> DefaultChunkAccessor {
-        DefaultChunkAccessor::new(self, decrypter, compression, generations)
+    ) -> DefaultChunkAccessor<'a> {
+        DefaultChunkAccessor::new(self, dec, compression, generations)
     }
 
     fn get_recording_chunk_accessor<'a>(
         &'a self,
         accessed: &'a mut HashSet<Vec<u8>>,
-        decrypter: Option<ArcDecrypter>,
+        dec: Option<&DecryptHandle>,
         compression: ArcCompression,
         generations: Vec<Generation>,
     ) -> RecordingChunkAccessor<'a> {
         RecordingChunkAccessor::new(
             self,
             accessed,
-            decrypter,
+            dec,
             compression,
             generations,
         )
     }
 
+    /// Delete `gen`, if `next_gen` replaced it at least `min_age_secs` ago
+    ///
+    /// Writers that started before `next_gen` was created might still be
+    /// adding data to `gen`; the grace period gives them time to finish.
     fn wipe_generation_maybe(
         &self,
         gen: Generation,
+        next_gen: Generation,
         min_age_secs: u64,
     ) -> io::Result<()> {
-        let gen_config = match gen.load_config(&self.aio) {
+        let next_gen_config = match next_gen.load_config(&self.aio) {
             Ok(c) => c,
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                 info!(
@@ -453,14 +2038,15 @@ impl Repo {
             Err(e) => return Err(e),
         };
 
-        if gen_config.created + chrono::Duration::seconds(min_age_secs as i64)
+        let replaced = next_gen_config.created;
+        if replaced + chrono::Duration::seconds(min_age_secs as i64)
             > chrono::Utc::now()
         {
             info!(
                 self.log,
-                "Generation is not old enough. Rerun GC later to finish";
+                "Generation was replaced too recently. Rerun GC later to finish";
                 "gen" => FnValue(|_| gen.to_string()),
-                "gen-created" => gen_config.created.to_rfc3339(),
+                "gen-replaced" => replaced.to_rfc3339(),
                 "now" => chrono::Utc::now().to_rfc3339(),
             );
             return Ok(());
@@ -493,6 +2079,16 @@ impl Repo {
             || (),
         )?;
 
+        substitute_err_not_found(
+            self.aio
+                .remove_dir_all(
+                    PathBuf::from(gen.to_string()).join(pack::PACK_SUBDIR),
+                )
+                .wait(),
+            || (),
+        )?;
+        self.packs.invalidate(&gen.to_string());
+
         self.aio
             .remove_dir_all(PathBuf::from(gen.to_string()))
             .wait()?;
@@ -505,23 +2101,25 @@ impl Repo {
         name_str: &str,
         cur_gen: Generation,
         generations: &[Generation],
+        reseal: Option<&Reseal>,
     ) -> io::Result<()> {
         // traverse all the chunks (both index and data)
         // and move all the chunks to the newest gen
         info!(self.log, "Updating name to current generation";
               "name" => name_str,
               "gen" => FnValue(|_| cur_gen.to_string()));
-        let name = Name::load_from_any(name_str, generations, &self.aio)?;
+        let name = Name::load_from_any(name_str, generations, &self.name_io())?;
         let data_address: DataAddress = name.into();
 
         let accessor = GenerationUpdateChunkAccessor::new(
             self,
             Arc::clone(&self.compression),
             generations.to_vec(),
+            reseal,
         );
         {
             let traverser = ReadContext::new(&accessor);
-            traverser.read_recursively(ReadRequest::new(
+            traverser.traverse(ReadRequest::new(
                 DataType::Data,
                 data_address.as_ref(),
                 None,
@@ -529,7 +2127,12 @@ impl Repo {
             ))?;
         }
 
-        Name::update_generation_to(name_str, cur_gen, generations, &self.aio)?;
+        Name::update_generation_to(
+            name_str,
+            cur_gen,
+            generations,
+            &self.name_io(),
+        )?;
 
         Ok(())
     }
@@ -549,7 +2152,7 @@ impl Repo {
             generations,
         );
         let traverser = ReadContext::new(&accessor);
-        traverser.read_recursively(ReadRequest::new(
+        traverser.traverse(ReadRequest::new(
             DataType::Data,
             da,
             None,
@@ -558,13 +2161,12 @@ impl Repo {
     }
 
     /// Return all reachable chunks
-    #[allow(dead_code)] // tests
     fn list_reachable_chunks(&self) -> Result<HashSet<Vec<u8>>> {
         let generations = self.read_generations()?;
         let mut reachable_digests = HashSet::new();
-        let all_names = Name::list_all(&generations, &self.aio)?;
+        let all_names = Name::list_all(&generations, &self.name_io())?;
         for name_str in &all_names {
-            match Name::load_from_any(name_str, &generations, &self.aio) {
+            match Name::load_from_any(name_str, &generations, &self.name_io()) {
                 Ok(name) => {
                     let data_address: DataAddress = name.into();
                     info!(self.log, "processing"; "name" => name_str);
@@ -590,26 +2192,775 @@ impl Repo {
         gen_str: &str,
     ) -> PathBuf {
         self.config.nesting.get_path(
+            self.config.nesting_width,
             Path::new(config::DATA_SUBDIR),
             digest.0,
             gen_str,
         )
     }
 
+    /// Parity file of chunk file `path` storing `sg`, if parity is enabled
+    fn parity_file(
+        &self,
+        path: &Path,
+        sg: &SGData,
+    ) -> Option<(PathBuf, SGData)> {
+        self.config.parity.as_ref().map(|parity| {
+            (
+                parity::path(path),
+                SGData::from_single(parity.encode(&sg.to_linear())),
+            )
+        })
+    }
+
+    /// Write chunk file `path`, with its parity file if enabled
+    ///
+    /// The parity file goes first, so no chunk file lacks it.
+    fn write_chunk_file(&self, path: PathBuf, sg: SGData) -> io::Result<()> {
+        if let Some((parity_path, parity)) = self.parity_file(&path, &sg) {
+            self.aio.write_idempotent(parity_path, parity).wait()?;
+        }
+        self.aio.write_idempotent(path, sg).wait()
+    }
+
+    /// Like `write_chunk_file`, but replacing the file if it exists
+    fn rewrite_chunk_file(&self, path: PathBuf, sg: SGData) -> io::Result<()> {
+        if let Some((parity_path, parity)) = self.parity_file(&path, &sg) {
+            self.aio.write(parity_path, parity).wait()?;
+        }
+        self.aio.write(path, sg).wait()
+    }
+
+    /// Find the chunk in `gen_str`, as a file or in a pack
+    fn find_chunk_in(
+        &self,
+        digest: DigestRef,
+        gen_str: &str,
+    ) -> io::Result<Option<ChunkLocation>> {
+        let path = self.chunk_rel_path_by_digest(digest, gen_str);
+        match self.aio.read_metadata(path.clone()).wait() {
+            Ok(metadata) => {
+                return Ok(Some(ChunkLocation::File(path, metadata.len)))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(self
+            .packs
+            .find(gen_str, digest.0, &self.aio)?
+            .map(ChunkLocation::Packed))
+    }
+
+    /// Find the chunk in the newest of `gen_strs` having it, with `find_in`
+    ///
+    /// Returns the index of the generation too. Packs written by others
+    /// since they were loaded are only looked at if the chunk isn't found
+    /// otherwise.
+    fn find_chunk_with<T, F>(
+        &self,
+        gen_strs: &[String],
+        find_in: F,
+    ) -> io::Result<Option<(usize, T)>>
+    where
+        F: Fn(&str) -> io::Result<Option<T>>,
+    {
+        for &refresh in &[false, true] {
+            if refresh && !self.packs.refresh(gen_strs, &self.aio)? {
+                break;
+            }
+            for (i, gen_str) in gen_strs.iter().enumerate().rev() {
+                if let Some(found) = find_in(gen_str)? {
+                    return Ok(Some((i, found)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_chunk(
+        &self,
+        digest: DigestRef,
+        gen_strs: &[String],
+    ) -> io::Result<Option<(usize, ChunkLocation)>> {
+        self.find_chunk_with(gen_strs, |gen_str| {
+            self.find_chunk_in(digest, gen_str)
+        })
+    }
+
+    fn read_chunk_at(&self, location: &ChunkLocation) -> io::Result<SGData> {
+        match *location {
+            ChunkLocation::File(ref path, _) => {
+                self.aio.read(path.clone()).wait()
+            }
+            ChunkLocation::Packed(ref packed) => self
+                .aio
+                .read_range(
+                    (*packed.path).clone(),
+                    packed.offset,
+                    packed.len,
+                )
+                .wait(),
+        }
+    }
+
+    /// Move the chunk at `location` to generation `cur_gen_str`
+    ///
+    /// Packed chunks are copied out as a chunk file, and left to be removed
+    /// with the whole pack. `data` of the chunk can be passed, if already
+    /// read.
+    fn move_chunk_to_gen(
+        &self,
+        digest: DigestRef,
+        location: &ChunkLocation,
+        data: Option<SGData>,
+        cur_gen_str: &str,
+    ) -> io::Result<()> {
+        let cur_path = self.chunk_rel_path_by_digest(digest, cur_gen_str);
+        match *location {
+            ChunkLocation::File(ref path, _) => {
+                // chunks stored before enabling parity have none
+                if self.config.parity.is_some() {
+                    substitute_err_not_found(
+                        self.aio
+                            .rename(parity::path(path), parity::path(&cur_path))
+                            .wait(),
+                        || (),
+                    )?;
+                }
+                substitute_err_not_found(
+                    self.aio.rename(path.clone(), cur_path).wait(),
+                    || (),
+                )
+            }
+            ChunkLocation::Packed(_) => {
+                let data = match data {
+                    Some(data) => data,
+                    None => self.read_chunk_at(location)?,
+                };
+                self.write_chunk_file(cur_path, data)
+            }
+        }
+    }
+
+    /// Like `move_chunk_to_gen`, but re-encrypting the data chunk at
+    /// `location` with `reseal`
+    ///
+    /// The original is left to be removed with its generation.
+    fn reseal_chunk_to_gen(
+        &self,
+        digest: DigestRef,
+        location: &ChunkLocation,
+        reseal: &Reseal,
+        cur_gen_str: &str,
+    ) -> io::Result<()> {
+        let data = self.read_chunk_at(location)?;
+        let (data, compressed) =
+            self.without_chunk_header(data, DataType::Data, digest)?;
+        let data = reseal.decrypter.decrypt(data, digest.0)?;
+        let data = reseal.encrypter.encrypt(data, digest.0)?;
+        let cur_path = self.chunk_rel_path_by_digest(digest, cur_gen_str);
+        self.rewrite_chunk_file(
+            cur_path,
+            self.with_chunk_header(data, DataType::Data, compressed),
+        )
+    }
+
+    /// List all stored names, sorted
     pub fn list_names(&self) -> io::Result<Vec<String>> {
-        let _lock = self.aio.lock_shared();
-        Name::list_all(&self.read_generations()?, &self.aio)
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let mut names =
+            Name::list_all(&self.read_generations()?, &self.name_io())?;
+        // a name can be in two generations while being moved by `gc`
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// List stored names matching `pattern`, sorted
+    ///
+    /// A pattern with any of `*`, `?` or `[` is a glob (eg.
+    /// `home-2019-??-01`), otherwise a prefix of the names.
+    pub fn list_names_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = NamePattern::new(pattern)?;
+        let mut names = self.list_names()?;
+        names.retain(|name| pattern.matches(name));
+        Ok(names)
+    }
+
+    /// Like `list_names`, but with the metadata of every name
+    pub fn list_names_stat(&self) -> io::Result<Vec<(String, NameStat)>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+
+        let mut names = Name::list_all(&generations, &self.name_io())?;
+        names.sort();
+        names.dedup();
+
+        let mut res = vec![];
+        for name_str in names {
+            let name =
+                Name::load_from_any(&name_str, &generations, &self.name_io())?;
+            let stat = NameStat::from(&name);
+            res.push((name_str, stat));
+        }
+        Ok(res)
+    }
+
+    /// Metadata of a stored name
+    pub fn stat(&self, name: &str) -> Result<NameStat> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        Ok(NameStat::from(&Name::load_from_any(
+            name,
+            &generations,
+            &self.name_io(),
+        )?))
     }
 
     /// Remove a stored name from repo
     pub fn rm(&self, name: &str) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
-        Name::remove_any(name, &self.read_generations()?, &self.aio)
+        self.ensure_deletable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let digest = Name::load_from_any(name, &generations, &self.name_io())
+            .map(|n| n.digest)
+            .ok();
+        Name::remove_any(name, &generations, &self.name_io())?;
+        let mut entry = AuditEntry::new("rm").with_name(name);
+        if let Some(digest) = digest {
+            RootIndex::remove(&digest, name, &self.name_io())?;
+            entry = entry.with_digest(&digest);
+        }
+        self.audit(entry)
+    }
+
+    /// Store another name pointing at the same data as `existing`
+    ///
+    /// No data is copied, so eg. a `latest` name can be moved to each new
+    /// backup cheaply (see `alias_with_options`).
+    pub fn alias(&self, existing: &str, alias: &str) -> Result<()> {
+        self.alias_with_options(existing, alias, &WriteOptions::default())
+    }
+
+    /// Like `alias`, with `options` used as in `write_with_options`
+    pub fn alias_with_options(
+        &self,
+        existing: &str,
+        alias: &str,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        if options.overwrite {
+            self.ensure_deletable()?;
+        }
+        // exclusive, so `gc` doesn't move the data away in the meantime
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let digest =
+            self.alias_locked(existing, alias, options, &generations)?;
+        self.audit(
+            AuditEntry::new("alias")
+                .with_name(alias)
+                .with_from(existing)
+                .with_digest(&digest),
+        )
+    }
+
+    /// Alias `existing` in the generation it's in, so `gc` moves both
+    /// names (and their data) together; returns the digest of the data
+    fn alias_locked(
+        &self,
+        existing: &str,
+        alias: &str,
+        options: &WriteOptions,
+        generations: &[Generation],
+    ) -> Result<Vec<u8>> {
+        if existing == alias {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "name and alias are the same",
+            ));
+        }
+        let (name, gen) =
+            Name::load_with_gen_from_any(
+                existing,
+                generations,
+                &self.name_io(),
+            )?;
+        if !options.overwrite {
+            if Name::exists_any(alias, generations, &self.name_io())? {
+                return Err(error::Error::NameExists(alias.into()).into());
+            }
+            name.write_as(
+                alias,
+                gen,
+                self.name_compression(),
+                &self.name_io(),
+            )?;
+        } else {
+            let old_name =
+                match Name::load_from_any(alias, generations, &self.name_io()) {
+                    Ok(old_name) => Some(old_name),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                };
+            name.write_replacing(
+                alias,
+                gen,
+                self.name_compression(),
+                &self.name_io(),
+            )?;
+            Name::remove_older(alias, gen, generations, &self.name_io())?;
+            if let Some(old_name) = old_name {
+                RootIndex::remove(&old_name.digest, alias, &self.name_io())?;
+            }
+        }
+        RootIndex::add(&name.digest, alias, &self.name_io())?;
+        Ok(name.digest)
+    }
+
+    /// Rename a stored name, without copying any data
+    pub fn rename(&self, old: &str, new: &str) -> Result<()> {
+        self.ensure_deletable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let digest = self.alias_locked(
+            old,
+            new,
+            &WriteOptions::default(),
+            &generations,
+        )?;
+        Name::remove_any(old, &generations, &self.name_io())?;
+        RootIndex::remove(&digest, old, &self.name_io())?;
+        self.audit(
+            AuditEntry::new("rename")
+                .with_name(new)
+                .with_from(old)
+                .with_digest(&digest),
+        )
+    }
+
+    /// Export stored names (all, or matching `pattern`, as in
+    /// `list_names_matching`), without their data
+    ///
+    /// See `import_names`.
+    pub fn export_names(&self, pattern: Option<&str>) -> Result<NamesExport> {
+        let names = match pattern {
+            Some(pattern) => self.list_names_matching(pattern)?,
+            None => self.list_names()?,
+        };
+
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let mut export = NamesExport::default();
+        for name_str in names {
+            let name =
+                Name::load_from_any(&name_str, &generations, &self.name_io())?;
+            export.names.insert(name_str, NameRecord::from(&name));
+        }
+        Ok(export)
+    }
+
+    /// Seal the repository: nothing in it can be modified anymore
+    ///
+    /// Writes a manifest of all the names (and the digests of their data),
+    /// signed with a key derived from the secret key (see `Seal::sign`;
+    /// `pass` is needed if encrypted). The seal is recorded in the config,
+    /// so releases not supporting it can't use the repository anymore. With
+    /// `restrict_permissions`, everything is made read-only for the backend
+    /// too (eg. file permissions of a local repository).
+    pub fn seal(
+        &mut self,
+        pass: PassphraseFn,
+        restrict_permissions: bool,
+    ) -> Result<SealManifest> {
+        self.ensure_writable()?;
+        // the manifest would list them in plain
+        if self.config.hidden_names {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "a repository with hidden names can't be sealed",
+            ));
+        }
+        let sec_key = if self.config.encryption.is_encrypted() {
+            Some(self.secret_key(pass)?)
+        } else {
+            None
+        };
+
+        let manifest = {
+            let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+            let generations = self.read_generations()?;
+            let mut manifest = SealManifest {
+                sealed: chrono::Utc::now(),
+                names: Default::default(),
+            };
+            for name_str in Name::list_all(&generations, &self.name_io())? {
+                let name =
+                    Name::load_from_any(
+                        &name_str,
+                        &generations,
+                        &self.name_io(),
+                    )?;
+                manifest.names.insert(name_str, NameRecord::from(&name));
+            }
+
+            let yaml = manifest.to_yaml();
+            let seal = Seal::sign(yaml.as_bytes(), sec_key.as_ref());
+            self.aio
+                .write(
+                    config::SEAL_MANIFEST_FILE.into(),
+                    SGData::from_single(yaml.into_bytes()),
+                )
+                .wait()?;
+            self.config.seal = Some(seal);
+            self.config.version =
+                self.config.version.max(self.config.required_version());
+            self.config.write(&self.aio)?;
+            manifest
+        };
+        self.read_only = true;
+        info!(self.log, "Repository sealed"; "names" => manifest.names.len());
+
+        if restrict_permissions {
+            self.aio.restrict_permissions()?;
+        }
+        Ok(manifest)
+    }
+
+    /// Hex encoded public key the seal manifest is signed with, if sealed
+    ///
+    /// Worth keeping elsewhere, to check the repository wasn't re-sealed.
+    pub fn seal_public_key(&self) -> Option<String> {
+        self.config
+            .seal
+            .as_ref()
+            .map(|seal| hex::encode(seal.sign_pub_key))
+    }
+
+    /// Manifest of the sealed repository, checked against its signature
+    /// and the names stored
+    ///
+    /// Fails with `InvalidData` if anything doesn't match.
+    pub fn verify_seal(&self) -> Result<SealManifest> {
+        let seal = match self.config.seal {
+            Some(ref seal) => seal,
+            None => {
+                return Err(Error::new(
+                    io::ErrorKind::NotFound,
+                    "repository is not sealed",
+                ))
+            }
+        };
+        let manifest = seal.verify(
+            &self
+                .aio
+                .read(config::SEAL_MANIFEST_FILE.into())
+                .wait()?
+                .to_linear_vec(),
+        )?;
+        if self.export_names(None)?.names != manifest.names {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                "names stored don't match the seal manifest",
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Store names exported by `export_names` from a repo with the same
+    /// data (sharing the chunk store, or a mirror)
+    ///
+    /// Nothing is imported if the top index chunk of any name is missing,
+    /// or (unless `options.overwrite`) any name already exists. The rest of
+    /// the index isn't checked; see `check_index`. Returns the number of
+    /// names imported.
+    pub fn import_names(
+        &self,
+        export: &NamesExport,
+        options: &WriteOptions,
+    ) -> Result<usize> {
+        self.ensure_writable()?;
+        if options.overwrite {
+            self.ensure_deletable()?;
+        }
+        export.validate()?;
+        if export.names.is_empty() {
+            return Ok(0);
+        }
+        // exclusive, so `gc` doesn't remove the data in the meantime
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let gen_strs: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+
+        for (name_str, record) in &export.names {
+            if self.find_chunk(DigestRef(&record.digest), &gen_strs)?.is_none()
+            {
+                return Err(Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("data of name {} not in the repository", name_str),
+                ));
+            }
+            if !options.overwrite
+                && Name::exists_any(name_str, &generations, &self.name_io())?
+            {
+                return Err(error::Error::NameExists(name_str.into()).into());
+            }
+        }
+
+        // The data can be in any generation, and `gc` moves the data of
+        // names from older generations to newer ones, not the other way
+        let gen = generations[0];
+        for (name_str, record) in &export.names {
+            let name = Name::from(record);
+            let old_name =
+                match Name::load_from_any(
+                    name_str,
+                    &generations,
+                    &self.name_io(),
+                ) {
+                    Ok(old_name) => Some(old_name),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                };
+            name.write_replacing(
+                name_str,
+                gen,
+                self.name_compression(),
+                &self.name_io(),
+            )?;
+            Name::remove_older(name_str, gen, &generations, &self.name_io())?;
+            if let Some(old_name) = old_name {
+                RootIndex::remove(
+                    &old_name.digest,
+                    name_str,
+                    &self.name_io(),
+                )?;
+            }
+            RootIndex::add(&name.digest, name_str, &self.name_io())?;
+            self.audit(
+                AuditEntry::new("import")
+                    .with_name(name_str)
+                    .with_digest(&name.digest)
+                    .with_bytes(name.size),
+            )?;
+        }
+        Ok(export.names.len())
+    }
+
+    /// Names that `prune` would keep and remove, without removing anything
+    ///
+    /// Time of a name is its creation time or, for names stored by older
+    /// versions, a date at the end of the name (`2019-01-31` or
+    /// `2019-01-31T12:00:00Z`).
+    pub fn plan_prune(&self, policy: &RetentionPolicy) -> Result<PruneResults> {
+        let mut results = PruneResults::default();
+        let mut dated = vec![];
+        for (name, stat) in self.list_names_stat()? {
+            if !policy.matches(&name) {
+                continue;
+            }
+            match stat.created.or_else(|| prune::time_from_name_suffix(&name)) {
+                Some(time) => dated.push((name, time)),
+                None => results.undated.push(name),
+            }
+        }
+        let (kept, removed) = policy.apply(dated);
+        results.kept = kept;
+        results.removed = removed;
+        Ok(results)
+    }
+
+    /// Remove names falling outside of the retention `policy`
+    ///
+    /// Space is reclaimed only by a following `gc`. A policy keeping nothing
+    /// is rejected, to avoid removing all the names by mistake.
+    pub fn prune(&self, policy: &RetentionPolicy) -> Result<PruneResults> {
+        self.ensure_deletable()?;
+        if policy.keeps_nothing() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "retention policy would remove all names",
+            ));
+        }
+        let results = self.plan_prune(policy)?;
+        for name in &results.removed {
+            info!(self.log, "pruning"; "name" => name);
+            self.rm(name)?;
+        }
+        Ok(results)
+    }
+
+    /// Is there a name stored under `name`
+    pub fn exists(&self, name: &str) -> Result<bool> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        Name::exists_any(name, &generations, &self.name_io())
+    }
+
+    /// Root digest of the data stored under a name
+    ///
+    /// Names storing identical data share the root digest.
+    pub fn root_digest(&self, name: &str) -> Result<Vec<u8>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        Ok(Name::load_from_any(name, &generations, &self.name_io())?.digest)
+    }
+
+    /// List all names storing data with given root digest
+    pub fn owners_of(&self, digest: &[u8]) -> Result<Vec<String>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        self.owners_of_locked(digest, &generations)
     }
 
+    fn owners_of_locked(
+        &self,
+        digest: &[u8],
+        generations: &[Generation],
+    ) -> Result<Vec<String>> {
+        let mut names = vec![];
+        // not indexed, see `RootIndex`
+        let indexed = if self.config.hidden_names {
+            Name::list_all(generations, &self.name_io())?
+        } else {
+            substitute_err_not_found(
+                RootIndex::list(digest, &self.aio),
+                Vec::new,
+            )?
+        };
+        for name_str in indexed {
+            match Name::load_from_any(&name_str, generations, &self.name_io()) {
+                Ok(ref name) if name.digest == digest => names.push(name_str),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Recreate the root digest index from the stored names
+    pub fn rebuild_root_index(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let generations = self.read_generations()?;
+
+        RootIndex::wipe(&self.aio)?;
+        for name_str in Name::list_all(&generations, &self.name_io())? {
+            let name =
+                Name::load_from_any(&name_str, &generations, &self.name_io())?;
+            RootIndex::add(&name.digest, &name_str, &self.name_io())?;
+        }
+        Ok(())
+    }
+
+    /// Remove data no longer reachable from any name
+    ///
+    /// A new generation is started, and everything reachable is moved to
+    /// it from the older ones, which are then deleted. Writers started
+    /// before the new generation was created might still use the old one,
+    /// so it's deleted only `min_age_secs` after that; until then, `gc`
+    /// has to be rerun to finish.
+    ///
+    /// With packing enabled, chunks moved out of packs are `repack`ed.
+    ///
+    /// See `gc_dry_run` to find out what would be removed first.
     pub fn gc(&self, min_age_secs: u64) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
+        self.gc_cancellable(min_age_secs, &CancelToken::new())
+    }
+
+    /// Like `gc`, but failing with `error::Error::Cancelled` before moving
+    /// another name once `cancel` is cancelled
+    ///
+    /// Everything is left as consistent as after any interrupted `gc`: the
+    /// next one takes over where this one stopped.
+    pub fn gc_cancellable(
+        &self,
+        min_age_secs: u64,
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        self.ensure_deletable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+
+        self.gc_locked(min_age_secs, None, cancel)?;
+        if let Some(ref packing) = self.config.packing {
+            self.repack_locked(packing)?;
+        }
+        self.audit(AuditEntry::new("gc"))
+    }
+
+    /// What `gc` would remove, without changing anything
+    ///
+    /// Unlike `gc`, fails if any name can't be read, rather than taking
+    /// the chunks of it for garbage.
+    pub fn gc_dry_run(&self) -> Result<GcDryRunResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+
+        let mut results = GcDryRunResults {
+            stored: 0,
+            unreachable: BTreeMap::new(),
+            reachable: BTreeMap::new(),
+        };
+        let mut reachable_digests = HashSet::new();
+        for name_str in Name::list_all(&generations, &self.name_io())? {
+            let name =
+                Name::load_from_any(&name_str, &generations, &self.name_io())?;
+            let data_address: DataAddress = name.into();
+            let mut digests = HashSet::new();
+            self.reachable_recursively_insert(
+                data_address.as_ref(),
+                &mut digests,
+                generations.clone(),
+            )?;
+            results.reachable.insert(name_str, digests.len());
+            reachable_digests.extend(digests);
+        }
+
+        let mut stored = HashSet::new();
+        for gen in &generations {
+            let gen_str = gen.to_string();
+            for digest in StoredChunks::new(
+                &self.aio,
+                PathBuf::from(&gen_str).join(config::DATA_SUBDIR),
+                DIGEST_SIZE,
+                self.log.clone(),
+            )? {
+                let digest = digest?;
+                if !reachable_digests.contains(&digest) {
+                    let path = self
+                        .chunk_rel_path_by_digest(DigestRef(&digest), &gen_str);
+                    let len = self.aio.read_metadata(path).wait()?.len;
+                    *results.unreachable.entry(digest.clone()).or_insert(0) +=
+                        len;
+                }
+                stored.insert(digest);
+            }
+            for (digest, packed) in self.packs.chunks(&gen_str, &self.aio)? {
+                if !reachable_digests.contains(&digest) {
+                    *results.unreachable.entry(digest.clone()).or_insert(0) +=
+                        packed.len;
+                }
+                stored.insert(digest);
+            }
+        }
+        results.stored = stored.len();
+        Ok(results)
+    }
 
+    /// One step of `gc`: move names of the oldest generation to the newest
+    /// one, or delete the oldest generation if it has none
+    ///
+    /// Data chunks are re-encrypted with `reseal` as they are moved.
+    fn gc_locked(
+        &self,
+        min_age_secs: u64,
+        reseal: Option<&Reseal>,
+        cancel: &CancelToken,
+    ) -> Result<()> {
         let generations = self.read_generations()?;
 
         if generations.is_empty() {
@@ -637,105 +2988,2099 @@ impl Repo {
             let gen_oldest = generations[0];
             let gen_cur = generations.last().unwrap();
 
-            let names = Name::list(gen_oldest, &self.aio)?;
+            let names = Name::list(gen_oldest, &self.name_io())?;
+
+            info!(self.log, "Names left in the generation to be GCed";
+                  "count" => names.len(),
+                  "gen" => FnValue(|_| gen_oldest.to_string())
+                  );
+            if names.is_empty() {
+                self.wipe_generation_maybe(
+                    gen_oldest,
+                    generations[1],
+                    min_age_secs,
+                )?;
+                return Ok(());
+            }
+            cancel.check()?;
+            self.update_name_to(&names[0], *gen_cur, &generations, reseal)?;
+        }
+    }
+
+    /// Pack files settings of the repository, if packing is enabled
+    pub fn packing(&self) -> Option<&Packing> {
+        self.config.packing.as_ref()
+    }
+
+    /// Enable packing of small chunks (or change its settings)
+    ///
+    /// Chunks stored before are packed by `repack`. Releases older than
+    /// packing can't use the repository anymore.
+    pub fn set_packing(&mut self, packing: Packing) -> Result<()> {
+        self.ensure_writable()?;
+        if !packing.valid() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid packing defined",
+            ));
+        }
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        self.config.packing = Some(packing);
+        self.config.version =
+            self.config.version.max(self.config.required_version());
+        self.config.write(&self.aio)
+    }
+
+    /// Is the repository append-only (see `set_append_only`)
+    pub fn is_append_only(&self) -> bool {
+        self.config.append_only
+    }
+
+    /// Make the repository append-only, or not anymore
+    ///
+    /// Nothing stored in an append-only repository can be removed or
+    /// replaced through the library: `rm`, `rename`, `prune`, `gc`,
+    /// `repack`, `change_key` and writes overwriting names fail, so backups
+    /// can't be destroyed by mistake or by a script gone wrong. Making it
+    /// append-only needs nothing more; undoing it takes the passphrase
+    /// (`pass`) of an encrypted repository. It's only the config that
+    /// tells it, though: anyone able to change the config can undo it, so
+    /// for the storage itself to refuse removals, use its own means too
+    /// (eg. object locks, or write-only credentials). Releases older than
+    /// append-only mode can't use the repository anymore.
+    pub fn set_append_only(
+        &mut self,
+        pass: PassphraseFn,
+        append_only: bool,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        if self.config.append_only
+            && !append_only
+            && self.config.encryption.is_encrypted()
+        {
+            // fails without the passphrase
+            self.secret_key(pass)?;
+        }
+        self.config.append_only = append_only;
+        self.config.version =
+            self.config.version.max(self.config.required_version());
+        self.config.write(&self.aio)
+    }
+
+    /// Are operations recorded in the audit log (see `set_audit_log`)
+    pub fn records_audit_log(&self) -> bool {
+        self.config.audit_log
+    }
+
+    /// Record operations in the audit log, or not anymore
+    ///
+    /// Storing, aliasing, renaming and removing names, `gc` and key changes
+    /// are recorded (see `audit_log`). Turning it off takes the passphrase
+    /// (`pass`) of an encrypted repository; entries recorded so far are
+    /// kept. Releases older than the audit log can't use the repository
+    /// anymore.
+    pub fn set_audit_log(
+        &mut self,
+        pass: PassphraseFn,
+        audit_log: bool,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        if self.config.audit_log && !audit_log {
+            if self.config.encryption.is_encrypted() {
+                // fails without the passphrase
+                self.secret_key(pass)?;
+            }
+            self.audit(AuditEntry::new("audit-log-off"))?;
+        }
+        let enabled = !self.config.audit_log && audit_log;
+        self.config.audit_log = audit_log;
+        self.config.version =
+            self.config.version.max(self.config.required_version());
+        self.config.write(&self.aio)?;
+        if enabled {
+            self.audit(AuditEntry::new("audit-log-on"))?;
+        }
+        Ok(())
+    }
+
+    /// Entries of the audit log, oldest first
+    ///
+    /// Not checked: see `verify_audit_log`.
+    pub fn audit_log(&self) -> Result<Vec<AuditEntry>> {
+        audit::read_all(&self.aio)
+    }
+
+    /// Check that no entry of the audit log was changed, removed or put
+    /// in between
+    ///
+    /// Fails with `InvalidData` naming the first entry not matching. The
+    /// latest entries could still be removed unnoticed, unless `head` is
+    /// compared with one kept elsewhere.
+    pub fn verify_audit_log(&self) -> Result<AuditVerifyResults> {
+        audit::verify(&self.aio)
+    }
+
+    /// Record `entry` in the audit log, if enabled
+    fn audit(&self, mut entry: AuditEntry) -> io::Result<()> {
+        if !self.config.audit_log {
+            return Ok(());
+        }
+        if self.config.hidden_names {
+            entry.name = None;
+            entry.from = None;
+        }
+        audit::append(&self.aio, self.lock_wait, entry)
+    }
+
+    /// Parity settings of the repository, if parity is enabled
+    pub fn parity(&self) -> Option<&Parity> {
+        self.config.parity.as_ref()
+    }
+
+    /// Enable parity files of chunk files (or change their settings)
+    ///
+    /// Only chunks stored from now on get parity. Releases older than
+    /// parity can't use the repository anymore.
+    pub fn set_parity(&mut self, parity: Parity) -> Result<()> {
+        self.ensure_writable()?;
+        if !parity.valid() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid parity defined",
+            ));
+        }
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        self.config.parity = Some(parity);
+        self.config.version =
+            self.config.version.max(self.config.required_version());
+        self.config.write(&self.aio)
+    }
+
+    /// Repair chunk `digest` using its parity file
+    ///
+    /// Returns `false` if the chunk, as stored, was intact (eg. it can't be
+    /// decrypted for other reasons). Fails if the chunk has no parity file
+    /// (eg. it's in a pack), or is damaged too much to repair.
+    pub fn repair_chunk(&self, digest: &[u8]) -> Result<bool> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        let gen_strs: Vec<_> = self
+            .read_generations()?
+            .iter()
+            .map(|gen| gen.to_string())
+            .collect();
+        self.packs.refresh(&gen_strs, &self.aio)?;
+        let path = match self.find_chunk(DigestRef(digest), &gen_strs)? {
+            Some((_, ChunkLocation::File(path, _))) => path,
+            Some((_, ChunkLocation::Packed(_))) => {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "chunk {} is in a pack, without parity",
+                        hex::encode(digest)
+                    ),
+                ))
+            }
+            None => {
+                return Err(error::Error::ChunkNotFound(digest.to_vec()).into())
+            }
+        };
+        let parity =
+            self.aio.read(parity::path(&path)).wait()?.to_linear_vec();
+        let data = self.aio.read(path.clone()).wait()?.to_linear_vec();
+        match parity::repair(&parity, &data)? {
+            Some(repaired) => {
+                self.aio
+                    .write(path, SGData::from_single(repaired))
+                    .wait()?;
+                info!(self.log, "Repaired chunk";
+                      "digest" => hex::encode(digest));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Move small chunk files of the current generation into packs
+    ///
+    /// Packs smaller than half of the pack size are merged too. Chunks of
+    /// older generations are left to `gc`.
+    pub fn repack(&self) -> Result<RepackResults> {
+        self.ensure_deletable()?;
+        let packing = self.config.packing.clone().ok_or_else(|| {
+            Error::new(io::ErrorKind::InvalidInput, "packing is not enabled")
+        })?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        self.repack_locked(&packing)
+    }
+
+    fn repack_locked(&self, packing: &Packing) -> Result<RepackResults> {
+        let mut results = RepackResults::default();
+        let gen_str = match self.read_generations()?.last() {
+            Some(gen) => gen.to_string(),
+            None => return Ok(results),
+        };
+        let writer = PackWriter::new(
+            self.aio.clone(),
+            Arc::clone(&self.hasher),
+            Arc::clone(&self.packs),
+            gen_str.clone(),
+            packing.clone(),
+            None,
+        );
+
+        let mut packed_files = vec![];
+        for digest in StoredChunks::new(
+            &self.aio,
+            PathBuf::from(&gen_str).join(config::DATA_SUBDIR),
+            DIGEST_SIZE,
+            self.log.clone(),
+        )? {
+            let digest = digest?;
+            let path =
+                self.chunk_rel_path_by_digest(DigestRef(&digest), &gen_str);
+            let len = self.aio.read_metadata(path.clone()).wait()?.len;
+            if !writer.fits(len) {
+                continue;
+            }
+            // eg. copied out of a pack of an older generation
+            if self.packs.find(&gen_str, &digest, &self.aio)?.is_none() {
+                writer.add(&digest, self.aio.read(path.clone()).wait()?)?;
+                results.chunks += 1;
+                results.bytes += len;
+            }
+            packed_files.push(path);
+        }
+
+        let merged =
+            pack::undersized_packs(&self.packs, &gen_str, packing, &self.aio)?;
+        for id in &merged {
+            for (digest, sg) in pack::read_pack(&gen_str, id, &self.aio)? {
+                writer.add(&digest, sg)?;
+            }
+        }
+        let written = writer.finish()?;
+        results.packs = written.len();
+
+        // Everything is safely in the new packs now
+        for path in packed_files {
+            substitute_err_not_found(
+                self.aio.remove(parity::path(&path)).wait(),
+                || (),
+            )?;
+            substitute_err_not_found(self.aio.remove(path).wait(), || ())?;
+        }
+        for id in merged.iter().filter(|id| !written.contains(id)) {
+            pack::remove_pack(&gen_str, id, &self.aio)?;
+            results.merged_packs += 1;
+        }
+        self.packs.invalidate(&gen_str);
+
+        info!(self.log, "Repacked";
+              "chunks" => results.chunks,
+              "merged-packs" => results.merged_packs,
+              "packs" => results.packs);
+        Ok(results)
+    }
+
+    /// Maintenance policy of the repository
+    pub fn maintenance_policy(&self) -> &MaintenancePolicy {
+        &self.config.maintenance
+    }
+
+    /// Change the maintenance policy of the repository
+    pub fn set_maintenance_policy(
+        &mut self,
+        policy: MaintenancePolicy,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_exclusive(self.lock_wait)?;
+        self.config.maintenance = policy;
+        self.config.write(&self.aio)
+    }
+
+    /// Maintenance done so far, including the recent history
+    pub fn maintenance_state(&self) -> Result<MaintenanceState> {
+        MaintenanceState::read(&self.aio)
+    }
+
+    /// Maintenance that `maintain` would do now
+    pub fn maintenance_due(&self) -> Result<MaintenanceTasks> {
+        let policy = &self.config.maintenance;
+        let state = MaintenanceState::read(&self.aio)?;
+        let mut names = self.list_names_stat()?;
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let stores_since_gc = names
+            .iter()
+            .filter_map(|(_, stat)| stat.created)
+            .filter(|created| match state.last_gc {
+                Some(last_gc) => *created > last_gc,
+                None => true,
+            })
+            .count() as u64;
+        // nothing can be removed anyway
+        let mut gc = !self.config.append_only
+            && policy.gc_every_stores != 0
+            && stores_since_gc >= policy.gc_every_stores;
+        if !gc && !self.config.append_only && policy.gc_garbage_percent != 0 {
+            gc = self.garbage_percent()? > f64::from(policy.gc_garbage_percent);
+        }
+
+        let names: Vec<_> = names.into_iter().map(|(name, _)| name).collect();
+        Ok(MaintenanceTasks {
+            gc,
+            scrub: state.scrub_due(policy, &names, chrono::Utc::now()),
+        })
+    }
+
+    /// Do the maintenance that is due according to the policy
+    ///
+    /// Scrubbing verifies data, which requires `dec`; without it, scrubbing
+    /// is skipped (and recorded as such). Returns the events recorded in
+    /// the history.
+    pub fn maintain(
+        &self,
+        dec: Option<&DecryptHandle>,
+    ) -> Result<Vec<MaintenanceEvent>> {
+        self.ensure_writable()?;
+        let tasks = self.maintenance_due()?;
+        let mut state = MaintenanceState::read(&self.aio)?;
+        let mut events = vec![];
+
+        if !tasks.scrub.is_empty() {
+            let event = match dec {
+                Some(dec) => {
+                    let mut corrupted = 0;
+                    for name in &tasks.scrub {
+                        corrupted += self.verify(name, dec)?.errors.len();
+                    }
+                    state.last_scrub = Some(chrono::Utc::now());
+                    state.last_scrubbed_name = tasks.scrub.last().cloned();
+                    MaintenanceEvent {
+                        time: chrono::Utc::now(),
+                        task: "scrub".into(),
+                        result: format!(
+                            "verified {} name(s), found {} corrupted chunk(s)",
+                            tasks.scrub.len(),
+                            corrupted
+                        ),
+                        ok: corrupted == 0,
+                    }
+                }
+                None => MaintenanceEvent {
+                    time: chrono::Utc::now(),
+                    task: "scrub".into(),
+                    result: "skipped, repository not unlocked".into(),
+                    ok: false,
+                },
+            };
+            state.record(event.clone());
+            state.write(&self.aio)?;
+            events.push(event);
+        }
+
+        if tasks.gc {
+            self.gc(self.config.maintenance.gc_grace_secs)?;
+            state.last_gc = Some(chrono::Utc::now());
+            let event = MaintenanceEvent {
+                time: chrono::Utc::now(),
+                task: "gc".into(),
+                result: "done".into(),
+                ok: true,
+            };
+            state.record(event.clone());
+            state.write(&self.aio)?;
+            events.push(event);
+        }
+
+        Ok(events)
+    }
+
+    /// Digests of all the chunks stored (in files or packs), in any of
+    /// `generations`
+    fn list_stored_chunks(
+        &self,
+        generations: &[Generation],
+    ) -> Result<HashSet<Vec<u8>>> {
+        let mut stored = HashSet::new();
+        for digest in StoredChunks::new(
+            &self.aio,
+            PathBuf::from("."),
+            DIGEST_SIZE,
+            self.log.clone(),
+        )? {
+            stored.insert(digest?);
+        }
+        for gen in generations {
+            for (digest, _) in self.packs.chunks(&gen.to_string(), &self.aio)? {
+                stored.insert(digest);
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Percent of stored chunks not reachable from any name
+    fn garbage_percent(&self) -> Result<f64> {
+        let reachable = self.list_reachable_chunks()?;
+        let stored = self.list_stored_chunks(&self.read_generations()?)?;
+        if stored.is_empty() {
+            return Ok(0.0);
+        }
+        let garbage = stored.difference(&reachable).count();
+        Ok(100.0 * garbage as f64 / stored.len() as f64)
+    }
+
+    pub fn read<W: Write>(
+        &self,
+        name_str: &str,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.read_with_options(name_str, writer, dec, &ReadOptions::default())
+    }
+
+    /// Like `read`, with `options`
+    pub fn read_with_options<W: Write>(
+        &self,
+        name_str: &str,
+        writer: &mut W,
+        dec: &DecryptHandle,
+        options: &ReadOptions,
+    ) -> Result<()> {
+        let share = options.memory_budget.map(|budget| budget / 4);
+        if let Some(share) = share {
+            if self.chunk_cache_size() > share {
+                let mut repo = self.clone();
+                repo.set_chunk_cache_size(share)?;
+                return repo.read_with_options(name_str, writer, dec, options);
+            }
+        }
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let expected_sha256 = name.sha256.clone();
+        let mut sha256 = hashing::sha256_writer();
+        let writer: Box<dyn Write> = match expected_sha256 {
+            Some(_) => Box::new(util::TeeWriter::new(writer, &mut sha256)),
+            None => Box::new(writer),
+        };
+        let mut writer: Box<dyn Write> = match name.transform {
+            Some(ref id) => self.transform(id)?.invert(writer),
+            None => writer,
+        };
+        let counter = options.progress.as_ref().map(|progress| {
+            ProgressCounter::new(Arc::clone(progress), name.size)
+        });
+        let data_address: DataAddress = name.into();
+
+        let accessor = self
+            .get_chunk_accessor(
+                Some(dec),
+                Arc::clone(&self.compression),
+                generations,
+            ).with_decoded_cache(
+                options
+                    .decoded_cache_size
+                    .unwrap_or(DEFAULT_DECODED_CACHE_SIZE)
+                    .min(share.unwrap_or(::std::u64::MAX)),
+            );
+        let progress_accessor;
+        let accessor: &dyn ChunkAccessor = match counter {
+            Some(ref counter) => {
+                progress_accessor =
+                    ProgressChunkAccessor::new(&accessor, counter);
+                &progress_accessor
+            }
+            None => &accessor,
+        };
+        let cancelling_accessor;
+        let accessor: &dyn ChunkAccessor = match options.cancel {
+            Some(ref cancel) => {
+                cancelling_accessor =
+                    CancellingChunkAccessor::new(accessor, cancel);
+                &cancelling_accessor
+            }
+            None => accessor,
+        };
+        let traverser = ReadContext::new(accessor);
+        let mut req = ReadRequest::new(
+            DataType::Data,
+            data_address.as_ref(),
+            Some(&mut *writer),
+            self.log.clone(),
+        );
+        if let Some(share) = share {
+            req = req.index_budget(share);
+        }
+        traverser.traverse(req)?;
+        writer.flush()?;
+        drop(writer);
+        if let Some(expected) = expected_sha256 {
+            if sha256.finish() != expected {
+                return Err(error::Error::DataCorrupted(name_str.into()).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the data of `name_str` as `read` does, without writing it
+    /// anywhere
+    ///
+    /// Every chunk is decrypted, decompressed and checked against its
+    /// digest, and the data (with any transform undone) is hashed whole, so
+    /// the name can be checked against the original without room to
+    /// restore it. As with `read`, data not matching the SHA-256 recorded
+    /// when it was stored (see `NameStat::sha256`) fails with
+    /// `error::Error::DataCorrupted`.
+    pub fn read_verify(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+    ) -> Result<ReadVerifyResults> {
+        let mut writer = ReadVerifyWriter {
+            size: 0,
+            digest: hashing::sha256_writer(),
+        };
+        self.read(name_str, &mut writer, dec)?;
+        Ok(ReadVerifyResults {
+            size: writer.size,
+            digest: writer.digest.finish(),
+        })
+    }
+
+    /// Read `len` bytes of data stored under `name_str`, starting at
+    /// `offset`
+    ///
+    /// Only the data chunks with data in the range are read, as far as the
+    /// index has the lengths of the data (see `settings::Repo::set_bare_index`
+    /// and `put_name`); anything else before the range has to be read to
+    /// find it. Less data is written if the range goes past the end. Names
+    /// stored with a transform are read whole, and the range is taken from
+    /// the result.
+    pub fn read_range<W: Write>(
+        &self,
+        name_str: &str,
+        offset: u64,
+        len: u64,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        if name.transform.is_some() {
+            drop(_lock);
+            let mut writer = RangeWriter::new(writer, offset, len);
+            return self.read(name_str, &mut writer, dec);
+        }
+        let data_address: DataAddress = name.into();
+
+        let accessor = self.get_chunk_accessor(
+            Some(dec),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        let traverser = ReadContext::new(&accessor);
+        traverser.traverse(
+            ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(writer),
+                self.log.clone(),
+            ).range(offset, len),
+        )?;
+        writer.flush()
+    }
+
+    /// Seekable reader of the data stored under `name_str`
+    ///
+    /// Eg. for reading single files out of a stored disk image or archive.
+    /// Only the index is read here. Names stored with a transform can't be
+    /// read with it.
+    pub fn open_reader<'a>(
+        &'a self,
+        name_str: &str,
+        dec: &'a DecryptHandle,
+    ) -> Result<NameReader<'a>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        if name.transform.is_some() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is stored with a transform", name_str),
+            ));
+        }
+        let chunks = self.list_data_chunks(name.into(), generations)?;
+        Ok(NameReader::new(self, dec, chunks))
+    }
+
+    /// Like `read`, but skip data chunks that can't be read, instead of
+    /// failing
+    ///
+    /// For salvaging what's left of damaged data. The data of the chunks
+    /// skipped is just left out (their size is not known without reading
+    /// them), so everything after them is shifted; the results tell at
+    /// which offsets data is missing. For names stored with a transform,
+    /// the offsets are in the data as stored, before undoing the
+    /// transform. Index chunks can't be skipped: the rest of the data
+    /// can't be found without them.
+    pub fn read_lossy<W: Write>(
+        &self,
+        name_str: &str,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<LossyReadResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let mut writer: Box<dyn Write> = match name.transform {
+            Some(ref id) => self.transform(id)?.invert(Box::new(writer)),
+            None => Box::new(writer),
+        };
+        let data_address: DataAddress = name.into();
+
+        let accessor = LossyChunkAccessor::new(
+            self,
+            Some(dec),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        {
+            let traverser = ReadContext::new(&accessor);
+            traverser.traverse(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut *writer),
+                self.log.clone(),
+            ))?;
+        }
+        writer.flush()?;
+        Ok(accessor.get_results())
+    }
+
+    /// Measure how fast `size` bytes of `BenchData` (generated from
+    /// `seed`) are chunked, hashed, compressed and encrypted, with the
+    /// settings of the repository
+    ///
+    /// Nothing is stored or read. The data is generated in memory first,
+    /// so it's not measured. Chunks that would deduplicate are only
+    /// chunked and hashed, as when writing.
+    pub fn bench(
+        &self,
+        size: u64,
+        seed: u64,
+        enc: &EncryptHandle,
+    ) -> Result<BenchResults> {
+        let mut data = BenchData::new(size, seed);
+        let mut bufs = vec![];
+        loop {
+            let mut buf = vec![0; INGRESS_BUFFER_SIZE];
+            let len = data.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            buf.truncate(len);
+            bufs.push(Arc::new(buf));
+        }
+
+        let mut results = BenchResults {
+            bytes: size,
+            ..Default::default()
+        };
+        let mut chunker = chunking::Chunker::new(
+            bufs.into_iter(),
+            self.config.chunking_engine(),
+        );
+        let mut digests = HashSet::new();
+        loop {
+            let start = Instant::now();
+            let sg = match chunker.next() {
+                Some(sg) => sg,
+                None => break,
+            };
+            results.chunking += start.elapsed();
+            results.chunks += 1;
+
+            let start = Instant::now();
+            let digest = enc.hasher.calculate_digest(&sg);
+            results.hashing += start.elapsed();
+            if !digests.insert(digest.clone()) {
+                continue;
+            }
+            results.unique_chunks += 1;
+            results.unique_bytes += sg.len() as u64;
+
+            let start = Instant::now();
+            let (sg, _) = self.compress_chunk(sg)?;
+            results.compression += start.elapsed();
+            results.compressed_bytes += sg.len() as u64;
+
+            let start = Instant::now();
+            enc.encrypter.encrypt(sg, &digest)?;
+            results.encryption += start.elapsed();
+        }
+        Ok(results)
+    }
+
+    /// Chunks (data and index, each counted once) and bytes of data
+    /// stored under `name_str`
+    ///
+    /// Where the index has the lengths of the data chunks (see
+    /// `settings::Repo::set_bare_index`), only the index is read.
+    /// Otherwise all the data is read (and so verified) to tell its size.
+    pub fn du(&self, name_str: &str, dec: &DecryptHandle) -> Result<DuResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let data_address: DataAddress = name.into();
+
+        if self.config.index_lengths {
+            let accessor = SizingChunkAccessor::new(
+                self,
+                Arc::clone(&self.compression),
+                generations.clone(),
+            );
+            ReadContext::new(&accessor).traverse(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                None,
+                self.log.clone(),
+            ))?;
+            if let Some(results) = accessor.get_results() {
+                return Ok(results);
+            }
+        }
+
+        let mut counter = CounterWriter::new();
+        let accessor = VerifyingChunkAccessor::new(
+            self,
+            Some(dec),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        {
+            let traverser = ReadContext::new(&accessor);
+            traverser.traverse(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut counter),
+                self.log.clone(),
+            ))?;
+        }
+        Ok(DuResults {
+            chunks: accessor.get_results().scanned,
+            bytes: counter.count,
+        })
+    }
+
+    /// Compare chunks used by two sets of names
+    ///
+    /// Sizes are of the chunks as stored (compressed and encrypted), so
+    /// `only_a` is roughly what removing all names in `names_a` would free
+    /// after `gc`, if nothing else refers to them.
+    pub fn overlap(
+        &self,
+        names_a: &[&str],
+        names_b: &[&str],
+    ) -> Result<OverlapResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        self.overlap_locked(names_a, names_b, &generations)
+    }
+
+    /// Chunks (data and index) only `name` refers to, and the bytes they
+    /// take as stored, so what removing it would free after `gc`
+    ///
+    /// All the other names are traversed to tell, as in `overlap`.
+    pub fn unique_size(&self, name: &str) -> Result<ChunkStats> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let mut others = Name::list_all(&generations, &self.name_io())?;
+        // a name can be in two generations while being moved by `gc`
+        others.sort();
+        others.dedup();
+        let others: Vec<&str> = others
+            .iter()
+            .map(String::as_str)
+            .filter(|other| *other != name)
+            .collect();
+        Ok(self.overlap_locked(&[name], &others, &generations)?.only_a)
+    }
+
+    fn overlap_locked(
+        &self,
+        names_a: &[&str],
+        names_b: &[&str],
+        generations: &[Generation],
+    ) -> Result<OverlapResults> {
+        let reachable = |names: &[&str]| -> Result<HashSet<Vec<u8>>> {
+            let mut digests = HashSet::new();
+            for name_str in names {
+                let name =
+                    Name::load_from_any(
+                        name_str,
+                        generations,
+                        &self.name_io(),
+                    )?;
+                let data_address: DataAddress = name.into();
+                self.reachable_recursively_insert(
+                    data_address.as_ref(),
+                    &mut digests,
+                    generations.to_vec(),
+                )?;
+            }
+            Ok(digests)
+        };
+        let set_a = reachable(names_a)?;
+        let set_b = reachable(names_b)?;
+
+        let mut results = OverlapResults::default();
+        for digest in set_a.union(&set_b) {
+            let stats = match (set_a.contains(digest), set_b.contains(digest)) {
+                (true, true) => &mut results.shared,
+                (true, false) => &mut results.only_a,
+                _ => &mut results.only_b,
+            };
+            stats.chunks += 1;
+            stats.bytes +=
+                self.stored_chunk_size(DigestRef(digest), generations)?;
+        }
+        Ok(results)
+    }
+
+    /// Statistics of the whole repository, eg. for capacity planning
+    ///
+    /// Only the names and the index are read, and the sizes of the stored
+    /// chunks looked up: nothing is decrypted, so no key is needed.
+    pub fn stat_repo(&self) -> Result<RepoStats> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+
+        let mut stats = RepoStats::default();
+        let accessor = ClassifyingChunkAccessor::new(
+            self,
+            Arc::clone(&self.compression),
+            generations.clone(),
+        );
+        let mut names = Name::list_all(&generations, &self.name_io())?;
+        // a name can be in two generations while being moved by `gc`
+        names.sort();
+        names.dedup();
+        for name_str in &names {
+            let name =
+                Name::load_from_any(name_str, &generations, &self.name_io())?;
+            stats.names += 1;
+            match name.size {
+                Some(size) => stats.logical_bytes += size,
+                None => stats.names_without_size += 1,
+            }
+            self.classify_reachable(name, &accessor)?;
+        }
+        let (index, data) = accessor.get_chunks();
+        stats.index_chunks = index.len();
+        stats.data_chunks = data.len();
+        for len in data.values() {
+            stats.logical_sizes.add(*len);
+        }
+
+        for (digest, len) in self.stored_chunk_sizes(&generations)? {
+            stats.stored_bytes += len;
+            if data.contains_key(&digest) {
+                stats.data_bytes += len;
+                stats.stored_sizes.add(Some(len));
+                stats.largest_chunks.push((digest, len));
+            } else if !index.contains(&digest) {
+                stats.unreachable_chunks += 1;
+            }
+        }
+        stats
+            .largest_chunks
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        stats.largest_chunks.truncate(LARGEST_CHUNKS);
+        Ok(stats)
+    }
+
+    /// Record the chunks reachable from `name` in `accessor`
+    fn classify_reachable(
+        &self,
+        name: Name,
+        accessor: &ClassifyingChunkAccessor,
+    ) -> Result<()> {
+        let size = name.size;
+        let data_address: DataAddress = name.into();
+        // data of a single chunk has no index to have its length
+        if data_address.index_level == 0 {
+            accessor.touch_data(data_address.digest.as_digest_ref(), size)?;
+        }
+        ReadContext::new(accessor).traverse(ReadRequest::new(
+            DataType::Data,
+            data_address.as_ref(),
+            None,
+            self.log.clone(),
+        ))
+    }
+
+    /// Compare the data stored under two names, eg. consecutive backups
+    ///
+    /// Only the index of the names is read, nothing is decrypted. Unlike
+    /// `overlap`, the results have also the bytes of data (as opposed to
+    /// chunks as stored) that changed.
+    pub fn diff(&self, name_a: &str, name_b: &str) -> Result<DiffResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+
+        let reachable = |name_str: &str| -> Result<(_, DataChunks)> {
+            let name =
+                Name::load_from_any(name_str, &generations, &self.name_io())?;
+            let accessor = ClassifyingChunkAccessor::new(
+                self,
+                Arc::clone(&self.compression),
+                generations.clone(),
+            );
+            self.classify_reachable(name, &accessor)?;
+            Ok(accessor.get_chunks())
+        };
+        let (index_a, data_a) = reachable(name_a)?;
+        let (index_b, data_b) = reachable(name_b)?;
+
+        let mut results = DiffResults::default();
+        let data = data_a.iter().chain(data_b.iter());
+        let index = index_a.iter().chain(index_b.iter()).map(|d| (d, &None));
+        let mut seen = HashSet::new();
+        for (digest, len) in data.chain(index) {
+            if !seen.insert(digest) {
+                continue;
+            }
+            let in_a = data_a.contains_key(digest) || index_a.contains(digest);
+            let in_b = data_b.contains_key(digest) || index_b.contains(digest);
+            let stats = match (in_a, in_b) {
+                (true, true) => &mut results.shared,
+                (true, false) => &mut results.only_a,
+                _ => &mut results.only_b,
+            };
+            stats.chunks += 1;
+            stats.bytes +=
+                self.stored_chunk_size(DigestRef(digest), &generations)?;
+            stats.data_bytes += len.unwrap_or(0);
+        }
+        Ok(results)
+    }
+
+    /// Stored chunks, with the bytes they take in all the generations
+    /// having them
+    fn stored_chunk_sizes(
+        &self,
+        generations: &[Generation],
+    ) -> io::Result<HashMap<Vec<u8>, u64>> {
+        let mut sizes = HashMap::new();
+        for gen in generations {
+            let gen_str = gen.to_string();
+            for digest in StoredChunks::new(
+                &self.aio,
+                PathBuf::from(&gen_str).join(config::DATA_SUBDIR),
+                DIGEST_SIZE,
+                self.log.clone(),
+            )? {
+                let digest = digest?;
+                let path =
+                    self.chunk_rel_path_by_digest(DigestRef(&digest), &gen_str);
+                let len = self.aio.read_metadata(path).wait()?.len;
+                *sizes.entry(digest).or_insert(0) += len;
+            }
+            for (digest, packed) in self.packs.chunks(&gen_str, &self.aio)? {
+                *sizes.entry(digest).or_insert(0) += packed.len;
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Size of the chunk as stored in the newest generation having it
+    fn stored_chunk_size(
+        &self,
+        digest: DigestRef,
+        generations: &[Generation],
+    ) -> io::Result<u64> {
+        let gen_strs: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+        match self.find_chunk(digest, &gen_strs)? {
+            Some((_, location)) => Ok(location.len()),
+            None => Err(error::Error::ChunkNotFound(digest.0.to_vec()).into()),
+        }
+    }
+
+    /// Check that the data of a name can be read back
+    ///
+    /// Every chunk of the name (index and data) is read, decrypted,
+    /// decompressed and hashed, as when restoring it, but the data is
+    /// discarded. Chunks missing or corrupted are reported in the results,
+    /// instead of failing at the first one.
+    pub fn verify(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+    ) -> Result<VerifyResults> {
+        self.verify_cancellable(name_str, dec, &CancelToken::new())
+    }
+
+    /// Like `verify`, but failing with `error::Error::Cancelled` before
+    /// reading another chunk once `cancel` is cancelled
+    pub fn verify_cancellable(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+        cancel: &CancelToken,
+    ) -> Result<VerifyResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let data_address: DataAddress = name.into();
+
+        let mut counter = CounterWriter::new();
+        let accessor = VerifyingChunkAccessor::new(
+            self,
+            Some(dec),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        {
+            let accessor = CancellingChunkAccessor::new(&accessor, cancel);
+            let traverser = ReadContext::new(&accessor);
+            traverser.traverse(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut counter),
+                self.log.clone(),
+            ))?;
+        }
+        Ok(accessor.get_results())
+    }
+
+    /// Like `verify`, but resumable
+    ///
+    /// Progress is periodically saved to a local `checkpoint` file. If it
+    /// holds an incomplete verification of the same name, it's resumed:
+    /// data chunks verified before are skipped, and errors found before are
+    /// included in the results.
+    pub fn verify_resumable(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+        checkpoint: &Path,
+    ) -> Result<VerifyResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let previous = match VerifyCheckpoint::load(checkpoint)? {
+            Some(ref c)
+                if c.name == name_str
+                    && c.digest == hex::encode(&name.digest)
+                    && !c.complete =>
+            {
+                info!(self.log, "Resuming verify"; "done" => c.done);
+                c.clone()
+            }
+            _ => VerifyCheckpoint::new(name_str, &name.digest, name.chunks),
+        };
+        let data_address: DataAddress = name.into();
+
+        let accessor = VerifyingChunkAccessor::new(
+            self,
+            Some(dec),
+            Arc::clone(&self.compression),
+            generations,
+        ).resume(
+            previous.done,
+            Box::new(|done, errors| {
+                let mut progress = previous.clone();
+                progress.done = done;
+                progress.record_errors(errors);
+                progress.save(checkpoint)
+            }),
+        );
+        {
+            let mut counter = CounterWriter::new();
+            let traverser = ReadContext::new(&accessor);
+            traverser.traverse(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut counter),
+                self.log.clone(),
+            ))?;
+        }
+
+        let mut progress = previous.clone();
+        progress.done = accessor.position();
+        progress.complete = true;
+        let results = accessor.get_results();
+        progress.record_errors(&results.errors);
+        progress.save(checkpoint)?;
+
+        let mut errors = previous.to_errors();
+        errors.extend(results.errors);
+        Ok(VerifyResults {
+            scanned: results.scanned + previous.done as usize,
+            errors,
+        })
+    }
+
+    /// Check integrity of the index of data stored under a name
+    ///
+    /// Much cheaper than `verify`: index chunks are read and verified, but
+    /// data chunks are only checked to exist. Catches broken index
+    /// structure (wrong length, missing chunks, bogus index level). Doesn't
+    /// need the secret key.
+    pub fn check_index(&self, name_str: &str) -> Result<VerifyResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        let generations = self.read_generations()?;
+
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let data_address: DataAddress = name.into();
+
+        let accessor = IndexCheckingChunkAccessor::new(
+            self,
+            Arc::clone(&self.compression),
+            generations,
+        );
+        {
+            let traverser = ReadContext::new(&accessor);
+            if let Err(e) = traverser.traverse(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                None,
+                self.log.clone(),
+            )) {
+                accessor.push_error(&data_address.digest.0, e);
+            }
+        }
+        Ok(accessor.get_results())
+    }
+
+    /// Find chunks stored, but not referred to by anything (orphans), and
+    /// chunks referred to, but not stored (dangling)
+    ///
+    /// Orphans are removed by the next `gc`; dangling chunks mean data
+    /// loss. Only the index is read, so this doesn't need the passphrase
+    /// either. Chunks referred to by index chunks that are missing (or
+    /// unreadable) can't be known, so they're reported as orphans.
+    pub fn check_refs(&self) -> Result<CheckRefsResults> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let stored = self.list_stored_chunks(&generations)?;
+
+        let mut results = CheckRefsResults {
+            orphans: vec![],
+            dangling: BTreeMap::new(),
+            errors: vec![],
+        };
+        let mut reachable = HashSet::new();
+        for name_str in Name::list_all(&generations, &self.name_io())? {
+            let name =
+                Name::load_from_any(&name_str, &generations, &self.name_io())?;
+            let data_address: DataAddress = name.into();
+            let mut digests = HashSet::new();
+            {
+                let accessor = RefCheckingChunkAccessor::new(
+                    self,
+                    &mut digests,
+                    &mut results.errors,
+                    Arc::clone(&self.compression),
+                    generations.clone(),
+                );
+                let traverser = ReadContext::new(&accessor);
+                if let Err(e) = traverser.traverse(ReadRequest::new(
+                    DataType::Data,
+                    data_address.as_ref(),
+                    None,
+                    self.log.clone(),
+                )) {
+                    accessor.push_error(&data_address.digest.0, e);
+                }
+            }
+            for digest in &digests {
+                if !stored.contains(digest) {
+                    results
+                        .dangling
+                        .entry(digest.clone())
+                        .or_default()
+                        .push(name_str.clone());
+                }
+            }
+            reachable.extend(digests);
+        }
+        results.orphans = stored.difference(&reachable).cloned().collect();
+        results.orphans.sort();
+        Ok(results)
+    }
+
+    /// Store a single chunk of data
+    ///
+    /// Low-level alternative to `write`, for building custom structures
+    /// (eg. file trees) on top of the deduplicated storage. The chunk is
+    /// compressed and encrypted like any data chunk.
+    ///
+    /// Chunks not referred to by any name are removed by `gc`, so they
+    /// should be eventually referred with `put_name`. Note that a chunk with
+    /// content identical to an index chunk of the repository (a sequence of
+    /// digests) can't be stored; prefix such data with a header.
+    pub fn put_chunk(
+        &self,
+        data: &[u8],
+        enc: &EncryptHandle,
+    ) -> Result<Digest> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations_for_writing()?;
+        self.put_chunk_locked(data, enc, &generations)
+    }
+
+    fn put_chunk_locked(
+        &self,
+        data: &[u8],
+        enc: &EncryptHandle,
+        generations: &[Generation],
+    ) -> io::Result<Digest> {
+        let digest = Digest(enc.hasher.calculate_digest_simple(data));
+        if self.move_chunk_to_current_gen(
+            digest.as_digest_ref(),
+            generations,
+        )? {
+            return Ok(digest);
+        }
+
+        let sg = SGData::from_single(data.to_vec());
+        let (sg, compressed) = self.compress_chunk(sg)?;
+        let sg = enc.encrypter.encrypt(sg, &digest.0)?;
+        let sg = self.with_chunk_header(sg, DataType::Data, compressed);
+        let path = self.chunk_rel_path_by_digest(
+            digest.as_digest_ref(),
+            &generations.last().unwrap().to_string(),
+        );
+        self.write_chunk_file(path, sg)?;
+        Ok(digest)
+    }
+
+    /// Read a single chunk stored with `put_chunk`
+    pub fn get_chunk(
+        &self,
+        digest: &Digest,
+        dec: &DecryptHandle,
+    ) -> Result<Vec<u8>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        if generations.is_empty() {
+            return Err(Error::new(
+                io::ErrorKind::NotFound,
+                "repository is empty",
+            ));
+        }
+
+        let accessor = self.get_chunk_accessor(
+            Some(dec),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        let mut data = vec![];
+        accessor.read_chunk_into(
+            digest.as_digest_ref(),
+            DataType::Data,
+            &mut data,
+        )?;
+        Ok(data)
+    }
+
+    /// Is a chunk with a given digest stored
+    pub fn has_chunk(&self, digest: &Digest) -> Result<bool> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let gen_strs: Vec<_> = self
+            .read_generations()?
+            .iter()
+            .map(|gen| gen.to_string())
+            .collect();
+        Ok(self
+            .find_chunk(digest.as_digest_ref(), &gen_strs)?
+            .is_some())
+    }
+
+    /// Digests of all the chunks (index and data) of `name_str`, sorted
+    ///
+    /// Doesn't need the passphrase; see `export_raw_chunk`.
+    pub fn chunks_of(&self, name_str: &str) -> Result<Vec<Digest>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let data_address: DataAddress = name.into();
+        let mut digests = HashSet::new();
+        self.reachable_recursively_insert(
+            data_address.as_ref(),
+            &mut digests,
+            generations,
+        )?;
+        let mut digests: Vec<_> = digests.into_iter().collect();
+        digests.sort();
+        Ok(digests.into_iter().map(Digest).collect())
+    }
+
+    /// Digests of the data chunks of `name_str`, in order
+    ///
+    /// Only the index is read, so this doesn't need the passphrase. Reading
+    /// the chunks with `get_chunk` gives the data piece by piece (eg. to
+    /// read just a part of it).
+    pub fn data_chunks(&self, name_str: &str) -> Result<Vec<Digest>> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        Ok(self
+            .list_data_chunks(name.into(), generations)?
+            .into_iter()
+            .map(|(digest, _)| digest)
+            .collect())
+    }
+
+    /// Data chunks of data at `data_address`, with their lengths if the
+    /// index has them
+    fn list_data_chunks(
+        &self,
+        data_address: DataAddress,
+        generations: Vec<Generation>,
+    ) -> io::Result<Vec<(Digest, Option<u64>)>> {
+        let accessor = ListingChunkAccessor::new(
+            self,
+            Arc::clone(&self.compression),
+            generations,
+        );
+        ReadContext::new(&accessor).traverse(ReadRequest::new(
+            DataType::Data,
+            data_address.as_ref(),
+            None,
+            self.log.clone(),
+        ))?;
+        Ok(accessor.get_chunks())
+    }
+
+    /// Chunk `digest`, as stored (encrypted)
+    ///
+    /// Along with `import_raw_chunk` and `export_names`, allows copying
+    /// data between repositories sharing the same keys and format (eg.
+    /// replicas created from a copy of the config) without the passphrase,
+    /// so by someone not trusted with the data.
+    pub fn export_raw_chunk(&self, digest: &Digest) -> Result<RawChunk> {
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let gen_strs: Vec<_> = self
+            .read_generations()?
+            .iter()
+            .map(|gen| gen.to_string())
+            .collect();
+        let location =
+            match self.find_chunk(digest.as_digest_ref(), &gen_strs)? {
+                Some((_, location)) => location,
+                None => {
+                    return Err(
+                        error::Error::ChunkNotFound(digest.0.clone()).into()
+                    )
+                }
+            };
+        let mut data = self.read_chunk_at(&location)?.to_linear_vec();
+        let header_len = if self.config.chunk_headers {
+            chunk_header::HEADER_LEN
+        } else {
+            0
+        };
+        if data.len() < header_len {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk {}: truncated", hex::encode(&digest.0)),
+            ));
+        }
+        let rest = data.split_off(header_len);
+        Ok(RawChunk {
+            header: data,
+            data: rest,
+        })
+    }
+
+    /// Store `chunk` exported with `export_raw_chunk` as `digest`
+    ///
+    /// Fails with `InvalidData` if the chunk header doesn't match the
+    /// format of this repository. Nothing else can be checked without the
+    /// keys: `verify` finds chunks that don't match their digests.
+    pub fn import_raw_chunk(
+        &self,
+        digest: &Digest,
+        chunk: &RawChunk,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        self.check_raw_chunk_format(digest.as_digest_ref(), chunk)?;
+
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations_for_writing()?;
+        if self.move_chunk_to_current_gen(
+            digest.as_digest_ref(),
+            &generations,
+        )? {
+            return Ok(());
+        }
+        let path = self.chunk_rel_path_by_digest(
+            digest.as_digest_ref(),
+            &generations.last().unwrap().to_string(),
+        );
+        let sg = SGData::from_many(vec![
+            chunk.header.clone(),
+            chunk.data.clone(),
+        ]);
+        self.write_chunk_file(path, sg)
+    }
+
+    /// Fail with `InvalidData` if `chunk` is not in the format of this
+    /// repository
+    fn check_raw_chunk_format(
+        &self,
+        digest: DigestRef,
+        chunk: &RawChunk,
+    ) -> io::Result<()> {
+        let compatible = if self.config.chunk_headers {
+            ChunkHeader::new(&self.config, DataType::Data)
+                .matches(&chunk.header)
+                || ChunkHeader::new(&self.config, DataType::Index)
+                    .matches(&chunk.header)
+        } else {
+            chunk.header.is_empty()
+        };
+        if !compatible {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "chunk {}: not in the format of this repository",
+                    hex::encode(digest.0)
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copy chunks `verify` found missing or corrupted from `other`
+    ///
+    /// `other` is a repository sharing the keys and format of this one (eg.
+    /// an offsite mirror); chunks are copied as stored, like with
+    /// `export_raw_chunk`, so no passphrase is needed. A chunk is written to
+    /// the current generation, replacing a damaged chunk file there, or
+    /// taking precedence over a damaged copy in an older one. Chunks packed
+    /// in the current generation can't be replaced. Run `verify` again to
+    /// check the copies.
+    pub fn repair_from(
+        &self,
+        other: &Repo,
+        verified: &VerifyResults,
+    ) -> Result<RepairResults> {
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations_for_writing()?;
+        let gen_strs: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+        let cur_gen_str = gen_strs.last().unwrap();
+
+        let mut results = RepairResults::default();
+        for (digest, _) in &verified.errors {
+            let digest = DigestRef(digest);
+            let res = other
+                .export_raw_chunk(&Digest(digest.0.to_vec()))
+                .and_then(|chunk| {
+                    self.check_raw_chunk_format(digest, &chunk)?;
+                    if let Some((i, ChunkLocation::Packed(_))) =
+                        self.find_chunk(digest, &gen_strs)?
+                    {
+                        if gen_strs[i] == *cur_gen_str {
+                            return Err(Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "chunk is packed in the current generation",
+                            ));
+                        }
+                    }
+                    self.rewrite_chunk_file(
+                        self.chunk_rel_path_by_digest(digest, cur_gen_str),
+                        SGData::from_many(vec![chunk.header, chunk.data]),
+                    )
+                });
+            match res {
+                Ok(()) => results.repaired.push(digest.0.to_vec()),
+                Err(e) => results.failed.push((digest.0.to_vec(), e)),
+            }
+        }
+        info!(self.log, "Repaired from another repository";
+              "repaired" => results.repaired.len(),
+              "failed" => results.failed.len());
+        Ok(results)
+    }
+
+    /// Copy the name `name_str` into `dest`, another repository (eg. with
+    /// new keys or settings), decrypting its chunks with `dec`
+    ///
+    /// The data is streamed into `dest` as with `write_with_options` (with
+    /// `enc` of `dest`): chunked, compressed and encrypted the way `dest`
+    /// does it, so it deduplicates with the data already there. The name
+    /// keeps its creation time; names stored with a transform are copied
+    /// as stored, and need the same transform registered in `dest` to be
+    /// read. `options.transform` is not allowed, for that reason.
+    pub fn copy_to(
+        &self,
+        name_str: &str,
+        dest: &Repo,
+        dec: &DecryptHandle,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+    ) -> Result<WriteStats> {
+        if options.transform.is_some() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "names are copied as stored, without applying transforms",
+            ));
+        }
+        info!(self.log, "Copying to another repository"; "name" => name_str);
+        // keep `gc` away from the chunks while they are read
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations()?;
+        let name =
+            Name::load_from_any(name_str, &generations, &self.name_io())?;
+        let chunks = self.list_data_chunks(name.clone().into(), generations)?;
+        let reader = NameReader::new(self, dec, chunks);
+        dest.write_name(name_str, reader, enc, options, Some(&name))
+    }
+
+    /// Store a name referring to chunks stored with `put_chunk`
+    ///
+    /// Reading the name returns content of all the chunks, concatenated in
+    /// order. This also protects the chunks from `gc`.
+    pub fn put_name(&self, name_str: &str, digests: &[Digest]) -> Result<()> {
+        self.ensure_writable()?;
+        validate_name(name_str)?;
+        if digests.is_empty() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "name must refer to at least one chunk",
+            ));
+        }
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations_for_writing()?;
+
+        for digest in digests {
+            if !self.move_chunk_to_current_gen(
+                digest.as_digest_ref(),
+                &generations,
+            )? {
+                return Err(
+                    error::Error::ChunkNotFound(digest.0.clone()).into()
+                );
+            }
+        }
+
+        // the chunks are not read, so their lengths are not known
+        let chunks: Vec<_> =
+            digests.iter().map(|d| (d.clone(), None)).collect();
+        let mut name: Name = self.write_index_of(&chunks, &generations)?.into();
+        name.created = Some(chrono::Utc::now());
+        name.chunks = Some(digests.len() as u64);
+        name.write_as(
+            name_str,
+            *generations.last().unwrap(),
+            self.name_compression(),
+            &self.name_io(),
+        )?;
+        RootIndex::add(&name.digest, name_str, &self.name_io())?;
+        self.audit(
+            AuditEntry::new("store")
+                .with_name(name_str)
+                .with_digest(&name.digest),
+        )
+    }
+
+    /// Index of data chunks `chunks` (already in the current generation),
+    /// with their lengths if known
+    ///
+    /// Same index structure as built by `write`.
+    fn write_index_of(
+        &self,
+        chunks: &[(Digest, Option<u64>)],
+        generations: &[Generation],
+    ) -> io::Result<DataAddress> {
+        let cur_gen_str = generations.last().unwrap().to_string();
+        let lengths = self.config.index_lengths;
+        let mut level: Vec<(Vec<u8>, Option<u64>)> =
+            chunks.iter().map(|(d, len)| (d.0.clone(), *len)).collect();
+        let mut index_level = 0;
+        while level.len() > 1 {
+            let records: Vec<_> = level
+                .iter()
+                .map(|(digest, len)| index::record(digest, *len, lengths))
+                .collect();
+            let chunker = chunking::Chunker::new(
+                Some(records.concat()).into_iter(),
+                self.config.index_chunking_engine(),
+            );
+            level = chunker
+                .map(|sg| {
+                    let digest = self.hasher.calculate_digest(&sg);
+                    let len = if lengths {
+                        index::data_len(&sg.clone().to_linear_vec())?
+                    } else {
+                        None
+                    };
+                    let path = self.chunk_rel_path_by_digest(
+                        DigestRef(&digest),
+                        &cur_gen_str,
+                    );
+                    let sg =
+                        self.with_chunk_header(sg, DataType::Index, false);
+                    self.write_chunk_file(path, sg)?;
+                    Ok((digest, len))
+                })
+                .collect::<io::Result<_>>()?;
+            index_level += 1;
+        }
+        Ok(DataAddress {
+            digest: Digest(level.pop().unwrap().0),
+            index_level,
+        })
+    }
+
+    /// Store names from chunk files listed in `manifest`
+    ///
+    /// Meant for migrating from other content-addressed backup tools: the
+    /// chunk files (paths relative to `dir`) are stored with `put_chunk`,
+    /// each one only once, and the names with `put_name`.
+    pub fn import_chunks(
+        &self,
+        dir: &Path,
+        manifest: &ImportManifest,
+        enc: &EncryptHandle,
+    ) -> Result<ImportResults> {
+        self.ensure_writable()?;
+        let mut results = ImportResults::default();
+        let mut imported: HashMap<&Path, Digest> = HashMap::new();
+        for (name_str, paths) in &manifest.names {
+            let mut digests = Vec::with_capacity(paths.len());
+            for path in paths {
+                if !imported.contains_key(path.as_path()) {
+                    let data = fs::read(dir.join(path))?;
+                    let digest = self.put_chunk(&data, enc)?;
+                    results.chunks += 1;
+                    results.bytes += data.len() as u64;
+                    imported.insert(path, digest);
+                }
+                digests.push(imported[path.as_path()].clone());
+            }
+            info!(self.log, "importing"; "name" => name_str.as_str());
+            self.put_name(name_str, &digests)?;
+            results.names += 1;
+        }
+        Ok(results)
+    }
+
+    /// Store the saves of branches `branches` (all of them if empty) of
+    /// the bup repository at `dir`, as trees (see `write_tree`)
+    ///
+    /// For migrating from bup without restoring the data first. Every save
+    /// is stored as the branch and the time of the save in UTC (eg.
+    /// `home-2019-01-31T10:00:00Z`, as `prune` expects), with its files
+    /// chunked again like by `write_tree`; files already imported with
+    /// an earlier save are not read again. Saves already imported are
+    /// skipped, so an interrupted import can be resumed, and new saves
+    /// imported later. The metadata bup keeps apart (in `.bupm` files) is
+    /// not imported: files get permissions from git (0644 or 0755), and
+    /// the time of the save as their modification time.
+    #[cfg(feature = "with-deflate")]
+    pub fn import_bup(
+        &self,
+        dir: &Path,
+        branches: &[&str],
+        enc: &EncryptHandle,
+    ) -> Result<ImportResults> {
+        use std::collections::hash_map::Entry;
+
+        info!(self.log, "Importing from bup"; "dir" => %dir.display());
+        self.ensure_writable()?;
+        let bup = bup::BupRepo::open(dir)?;
+        let mut results = ImportResults::default();
+        // chunks of the files imported, by their bup object
+        let mut imported: HashMap<bup::ObjectId, Vec<(Digest, Option<u64>)>> =
+            HashMap::new();
+        for save in bup.saves(branches)? {
+            let name_str = save.name();
+            let _lock = self.aio.lock_shared(self.lock_wait)?;
+            let generations = self.read_generations_for_writing()?;
+            if Name::exists_any(&name_str, &generations, &self.name_io())? {
+                continue;
+            }
+            info!(self.log, "importing"; "name" => &name_str);
+            let mut index = TreeIndex::default();
+            let mut data_digests = vec![];
+            for (file, mut entry) in bup.walk(&save)? {
+                if let Some(file) = file {
+                    if let Entry::Vacant(vacant) = imported.entry(file.id) {
+                        let blobs = bup.blobs(&file)?;
+                        let tier = self.config.chunking_tier_for_size(
+                            blobs.len() as u64 * bup::AVERAGE_BLOB_SIZE,
+                        );
+                        let mut chunks = vec![];
+                        self.put_tree_file(
+                            bup.reader(&blobs),
+                            tier,
+                            &mut entry,
+                            &mut chunks,
+                            enc,
+                            &generations,
+                        )?;
+                        results.chunks += blobs.len();
+                        results.bytes += entry.size;
+                        vacant.insert(chunks);
+                    }
+                    let chunks = &imported[&file.id];
+                    entry.first_chunk = data_digests.len() as u64;
+                    entry.chunks = chunks.len() as u64;
+                    entry.size = chunks.iter().filter_map(|c| c.1).sum();
+                    data_digests.extend(chunks.iter().cloned());
+                }
+                index.entries.push(entry);
+            }
+            self.put_tree_name(
+                &name_str,
+                &index,
+                data_digests,
+                save.time,
+                enc,
+                &generations,
+            )?;
+            results.names += 1;
+        }
+        Ok(results)
+    }
+
+    /// Store the directory tree at `dir` as `name_str`
+    ///
+    /// Files are chunked one by one, so each of them deduplicates on its
+    /// own; paths, permissions, modification times and symlinks are kept
+    /// in the tree index (see `tree_index`). Other special files (devices,
+    /// sockets, ...) are skipped, and symlinks are not followed. Reading
+    /// the name with `read` gives the index, followed by the content of all
+    /// the files; `read_tree` restores the tree.
+    pub fn write_tree(
+        &self,
+        name_str: &str,
+        dir: &Path,
+        enc: &EncryptHandle,
+    ) -> Result<TreeIndex> {
+        info!(self.log, "Writing tree"; "name" => name_str,
+              "dir" => %dir.display());
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+        let generations = self.read_generations_for_writing()?;
+        if Name::exists_any(name_str, &generations, &self.name_io())? {
+            return Err(error::Error::NameExists(name_str.into()).into());
+        }
+
+        let mut index = TreeIndex::default();
+        let mut data_digests = vec![];
+        for (path, mut entry) in tree::walk(dir, &self.log)? {
+            if entry.kind == EntryKind::File {
+                let at_path = |e| error::Error::at_path(path.clone(), e);
+                let file = fs::File::open(&path).map_err(at_path)?;
+                // chunked as a stream of the size of the file
+                let tier = self.config.chunking_tier_for_size(entry.size);
+                self.put_tree_file(
+                    file,
+                    tier,
+                    &mut entry,
+                    &mut data_digests,
+                    enc,
+                    &generations,
+                ).map_err(at_path)?;
+            }
+            index.entries.push(entry);
+        }
+        self.put_tree_name(
+            name_str,
+            &index,
+            data_digests,
+            chrono::Utc::now(),
+            enc,
+            &generations,
+        )?;
+        Ok(index)
+    }
+
+    /// Chunk and store the data of file `entry` of a tree from `reader`
+    ///
+    /// The chunks are appended to `data_digests`; the size and chunks of
+    /// `entry` are set.
+    fn put_tree_file<R: Read>(
+        &self,
+        reader: R,
+        tier: Option<&config::ChunkingTier>,
+        entry: &mut TreeEntry,
+        data_digests: &mut Vec<(Digest, Option<u64>)>,
+        enc: &EncryptHandle,
+        generations: &[Generation],
+    ) -> io::Result<()> {
+        let mut input =
+            WhileOk::new(ReaderVecIter::new(reader, INGRESS_BUFFER_SIZE));
+        entry.first_chunk = data_digests.len() as u64;
+        entry.size = 0;
+        for sg in chunking::Chunker::new(
+            &mut input,
+            self.config.chunking_engine_for(tier),
+        ) {
+            let data = sg.to_linear_vec();
+            entry.size += data.len() as u64;
+            let digest = self.put_chunk_locked(&data, enc, generations)?;
+            data_digests.push((digest, Some(data.len() as u64)));
+        }
+        if let Some(e) = input.finish() {
+            return Err(e);
+        }
+        entry.chunks = data_digests.len() as u64 - entry.first_chunk;
+        Ok(())
+    }
+
+    /// Store `name_str`, the tree `index` with the chunks of its files
+    fn put_tree_name(
+        &self,
+        name_str: &str,
+        index: &TreeIndex,
+        data_digests: Vec<(Digest, Option<u64>)>,
+        created: chrono::DateTime<chrono::Utc>,
+        enc: &EncryptHandle,
+        generations: &[Generation],
+    ) -> io::Result<()> {
+        let index_data = index.to_yaml().into_bytes();
+        let index_len = index_data.len() as u64;
+        let mut digests = chunking::Chunker::new(
+            Some(index_data).into_iter(),
+            self.config.chunking_engine(),
+        ).map(|sg| {
+            let data = sg.to_linear_vec();
+            let digest = self.put_chunk_locked(&data, enc, generations)?;
+            Ok((digest, Some(data.len() as u64)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+        let index_chunks = digests.len() as u64;
+        digests.extend(data_digests);
+
+        let mut name: Name = self.write_index_of(&digests, generations)?.into();
+        name.created = Some(created);
+        name.size = Some(index_len + index.size());
+        name.chunks = Some(digests.len() as u64);
+        name.tree = Some(index_chunks);
+        name.write_as(
+            name_str,
+            *generations.last().unwrap(),
+            self.name_compression(),
+            &self.name_io(),
+        )?;
+        RootIndex::add(&name.digest, name_str, &self.name_io())?;
+        self.audit(
+            AuditEntry::new("store")
+                .with_name(name_str)
+                .with_digest(&name.digest)
+                .with_bytes(name.size),
+        )
+    }
 
-            info!(self.log, "Names left in the generation to be GCed";
-                  "count" => names.len(),
-                  "gen" => FnValue(|_| gen_oldest.to_string())
-                  );
-            if names.is_empty() {
-                self.wipe_generation_maybe(gen_oldest, min_age_secs)?;
-                return Ok(());
-            }
-            self.update_name_to(&names[0], *gen_cur, &generations)?;
+    /// Index of tree `name_str` stored with `write_tree`
+    pub fn tree_index(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+    ) -> Result<TreeIndex> {
+        let (index_chunks, _) = self.tree_chunks(name_str)?;
+        let mut data = vec![];
+        for digest in &index_chunks {
+            data.extend(self.get_chunk(digest, dec)?);
         }
+        TreeIndex::from_yaml(&data)
     }
 
-    pub fn read<W: Write>(
+    /// Chunks of the index, and of the files of tree `name_str`
+    fn tree_chunks(
+        &self,
+        name_str: &str,
+    ) -> Result<(Vec<Digest>, Vec<Digest>)> {
+        let index_chunks = {
+            let _lock = self.aio.lock_shared(self.lock_wait)?;
+            let generations = self.read_generations()?;
+            Name::load_from_any(name_str, &generations, &self.name_io())?.tree
+        };
+        let index_chunks = index_chunks.ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a tree: {}", name_str),
+            )
+        })?;
+        let mut chunks = self.data_chunks(name_str)?;
+        let file_chunks = chunks.split_off(index_chunks as usize);
+        Ok((chunks, file_chunks))
+    }
+
+    /// Write the content of file `path` of tree `name_str` to `writer`
+    pub fn read_tree_file<W: Write>(
         &self,
         name_str: &str,
+        path: &str,
         writer: &mut W,
         dec: &DecryptHandle,
     ) -> Result<()> {
-        let _lock = self.aio.lock_shared();
-
-        let generations = self.read_generations()?;
-
-        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
-        let data_address: DataAddress = name.into();
-
-        let accessor = self.get_chunk_accessor(
-            Some(Arc::clone(&dec.decrypter)),
-            Arc::clone(&self.compression),
-            generations,
-        );
-        let traverser = ReadContext::new(&accessor);
-        traverser.read_recursively(ReadRequest::new(
-            DataType::Data,
-            data_address.as_ref(),
-            Some(writer),
-            self.log.clone(),
-        ))
+        let index = self.tree_index(name_str, dec)?;
+        let entry = match index.entry(path) {
+            Some(entry) if entry.kind == EntryKind::File => entry,
+            _ => {
+                return Err(Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such file in the tree: {}", path),
+                ))
+            }
+        };
+        let (_, file_chunks) = self.tree_chunks(name_str)?;
+        self.write_tree_file(entry, &file_chunks, writer, dec)
     }
 
-    pub fn du(&self, name_str: &str, dec: &DecryptHandle) -> Result<DuResults> {
-        let _lock = self.aio.lock_shared();
-
-        let generations = self.read_generations()?;
-        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
-        let data_address: DataAddress = name.into();
-
-        let mut counter = CounterWriter::new();
-        let accessor = VerifyingChunkAccessor::new(
-            self,
-            Some(Arc::clone(&dec.decrypter)),
-            Arc::clone(&self.compression),
-            generations,
-        );
-        {
-            let traverser = ReadContext::new(&accessor);
-            traverser.read_recursively(ReadRequest::new(
-                DataType::Data,
-                data_address.as_ref(),
-                Some(&mut counter),
-                self.log.clone(),
-            ))?;
+    fn write_tree_file<W: Write>(
+        &self,
+        entry: &TreeEntry,
+        file_chunks: &[Digest],
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        let first = entry.first_chunk as usize;
+        let chunks = file_chunks
+            .get(first..first + entry.chunks as usize)
+            .ok_or_else(|| {
+                Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunks of {} missing in the tree", entry.path),
+                )
+            })?;
+        for digest in chunks {
+            writer.write_all(&self.get_chunk(digest, dec)?)?;
         }
-        Ok(DuResults {
-            chunks: accessor.get_results().scanned,
-            bytes: counter.count,
-        })
+        Ok(())
     }
 
-    pub fn verify(
+    /// Restore tree `name_str` (stored with `write_tree`) into `dest`
+    ///
+    /// Only the entries at `paths`, or in directories at them, are
+    /// restored (everything if empty), along with the directories leading
+    /// to them. Existing files are overwritten.
+    pub fn read_tree(
         &self,
         name_str: &str,
+        dest: &Path,
+        paths: &[&str],
         dec: &DecryptHandle,
-    ) -> Result<VerifyResults> {
-        let _lock = self.aio.lock_shared();
+    ) -> Result<()> {
+        info!(self.log, "Reading tree"; "name" => name_str,
+              "dest" => %dest.display());
+        let index = self.tree_index(name_str, dec)?;
+        let entries = index.select(paths)?;
+        let (_, file_chunks) = self.tree_chunks(name_str)?;
 
-        let generations = self.read_generations()?;
+        fs::create_dir_all(dest)?;
+        let mut dirs = vec![];
+        for entry in entries {
+            let path = tree::dest_path(dest, entry)?;
+            let at_path = |e| error::Error::at_path(path.clone(), e);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(at_path)?;
+            }
+            match entry.kind {
+                EntryKind::Dir => {
+                    fs::create_dir_all(&path).map_err(at_path)?;
+                    dirs.push((path, entry));
+                }
+                EntryKind::File => {
+                    let mut file = fs::File::create(&path).map_err(at_path)?;
+                    self.write_tree_file(entry, &file_chunks, &mut file, dec)?;
+                    drop(file);
+                    tree::set_attributes(&path, entry).map_err(at_path)?;
+                }
+                EntryKind::Symlink => {
+                    tree::symlink(&path, entry, &self.log).map_err(at_path)?
+                }
+            }
+        }
+        for (path, entry) in dirs.into_iter().rev() {
+            tree::set_attributes(&path, entry)
+                .map_err(|e| error::Error::at_path(path.clone(), e))?;
+        }
+        Ok(())
+    }
 
-        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
-        let data_address: DataAddress = name.into();
+    /// Make sure a chunk is in the current generation, if it's stored at all
+    fn move_chunk_to_current_gen(
+        &self,
+        digest: DigestRef,
+        generations: &[Generation],
+    ) -> io::Result<bool> {
+        let gen_strs: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+        match self.find_chunk(digest, &gen_strs)? {
+            Some((i, location)) => {
+                if i != gen_strs.len() - 1 {
+                    self.move_chunk_to_gen(
+                        digest,
+                        &location,
+                        None,
+                        gen_strs.last().unwrap(),
+                    )?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
-        let mut counter = CounterWriter::new();
-        let accessor = VerifyingChunkAccessor::new(
-            self,
-            Some(Arc::clone(&dec.decrypter)),
-            Arc::clone(&self.compression),
-            generations,
-        );
-        {
-            let traverser = ReadContext::new(&accessor);
-            traverser.read_recursively(ReadRequest::new(
-                DataType::Data,
-                data_address.as_ref(),
-                Some(&mut counter),
-                self.log.clone(),
-            ))?;
+    /// `AsyncIO` to access name records with
+    fn name_io(&self) -> NameIO<'_> {
+        NameIO::new(
+            &self.aio,
+            &self.config,
+            self.names_key.as_ref().map(|key| &**key),
+            self.names_signer.as_ref().map(|signer| &**signer),
+        )
+    }
+
+    /// Like `read_generations`, but creates the first one if there's none
+    fn read_generations_for_writing(&self) -> io::Result<Vec<Generation>> {
+        let mut generations = self.read_generations()?;
+
+        if generations.is_empty() {
+            let gen_first = Generation::gen_first();
+            gen_first.write(&self.aio)?;
+            generations.push(gen_first);
         }
-        Ok(accessor.get_results())
+        Ok(generations)
     }
 
     fn read_generations(&self) -> io::Result<Vec<Generation>> {
@@ -748,6 +5093,10 @@ impl Repo {
             .filter(|&item| {
                 item != config::CONFIG_YML_FILE
                     && item != config::LOCK_FILE
+                    && item != config::LOCK_QUEUE_FILE
+                    && item != config::AGE_SEC_KEY_FILE
+                    && item != ROOT_INDEX_SUBDIR
+                    && item != config::AUDIT_SUBDIR
                     && !item.ends_with(".yml")
             })
             .filter_map(|item| match Generation::try_from(item) {
@@ -780,6 +5129,11 @@ impl Repo {
         Ok(list)
     }
 
+    /// Store data from `reader` as `name_str`
+    ///
+    /// The name has to be a single file name: names that are empty, `.`,
+    /// `..` or contain a path separator are rejected with
+    /// `error::Error::InvalidName`.
     pub fn write<R>(
         &self,
         name_str: &str,
@@ -789,16 +5143,164 @@ impl Repo {
     where
         R: Read + Send,
     {
-        info!(self.log, "Writing data"; "name" => name_str);
-        let _lock = self.aio.lock_shared();
+        self.write_with_options(name_str, reader, enc, &WriteOptions::default())
+    }
 
-        let mut generations = self.read_generations()?;
+    /// Like `write`, with `options`
+    pub fn write_with_options<R>(
+        &self,
+        name_str: &str,
+        reader: R,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.write_name(name_str, reader, enc, options, None)
+    }
 
-        if generations.is_empty() {
-            let gen_first = Generation::gen_first();
-            gen_first.write(&self.aio)?;
-            generations.push(gen_first);
+    /// Like `write_with_options`, unless data with the root digest
+    /// `expected_digest` (see `root_digest`) is stored already
+    ///
+    /// For retried jobs that know the digest of their data from an earlier
+    /// run: if `name_str` stores it already, nothing is done; if another
+    /// name does, `name_str` is stored as an alias of it (see `alias`).
+    /// Neither reads `reader`, and `WriteStats::unchanged_from` tells the
+    /// name found. Otherwise the data is written as usual, and stored even
+    /// if its digest turns out different (eg. after the chunking settings
+    /// changed).
+    pub fn write_if_absent<R>(
+        &self,
+        name_str: &str,
+        expected_digest: &[u8],
+        reader: R,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.ensure_writable()?;
+        validate_name(name_str)?;
+        {
+            let _lock = self.aio.lock_shared(self.lock_wait)?;
+            let generations = self.read_generations_for_writing()?;
+            // the name itself first
+            let mut owners =
+                self.owners_of_locked(expected_digest, &generations)?;
+            owners.retain(|owner| {
+                Name::load_from_any(owner, &generations, &self.name_io())
+                    .map(|owner| owner.transform == options.transform)
+                    .unwrap_or(false)
+            });
+            let existing = owners
+                .iter()
+                .find(|owner| *owner == name_str)
+                .or_else(|| owners.first());
+            if let Some(existing) = existing {
+                info!(self.log, "Data already stored, not reading it";
+                      "name" => name_str, "same-as" => existing);
+                if existing != name_str {
+                    self.alias_locked(
+                        existing,
+                        name_str,
+                        options,
+                        &generations,
+                    )?;
+                    self.audit(
+                        AuditEntry::new("alias")
+                            .with_name(name_str)
+                            .with_from(existing)
+                            .with_digest(expected_digest),
+                    )?;
+                }
+                return Ok(WriteStats {
+                    new_chunks: 0,
+                    new_bytes: 0,
+                    unchanged_from: Some(existing.clone()),
+                });
+            }
+        }
+        self.write_with_options(name_str, reader, enc, options)
+    }
+
+    /// Store several names over one chunk processor
+    ///
+    /// `f` gets a `WriteSession` to write the names with, from as many
+    /// threads as it likes; eg. to store lots of small streams without
+    /// starting and stopping the processing of chunks for every one of
+    /// them. All the writes use `options`. Once `f` returns, the names
+    /// written are stored, in the order they were written; none is if `f`
+    /// fails. The statistics are of all the names together
+    /// (`unchanged_from` is not set).
+    pub fn write_session<F>(
+        &self,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+        f: F,
+    ) -> Result<WriteStats>
+    where
+        F: FnOnce(&WriteSession) -> Result<()>,
+    {
+        info!(self.log, "Starting write session");
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        self.check_write_options(options)?;
+        let generations = self.read_generations_for_writing()?;
+        let (aio, packer, processor) =
+            self.write_pipeline(enc, options, &generations)?;
+        let stats = aio.stats();
+
+        let names = crossbeam::scope(|scope| {
+            let session = WriteSession::new(
+                self,
+                options,
+                &generations,
+                processor.spawn(scope, &options.threads),
+                aio,
+                processor.stop_token().clone(),
+            );
+            f(&session).map(|()| session.into_names())
+        }).expect("non-joined thread panicked (chunk processor?)");
+
+        // why writes stopped, if they did
+        processor
+            .check_stored()
+            .map_err(|e| self.no_space(e, &stats))?;
+        let names = names.map_err(|e| self.no_space(e, &stats))?;
+        if let Some(ref packer) = packer {
+            packer.finish().map_err(|e| self.no_space(e, &stats))?;
+        }
+        for (name_str, name) in names {
+            self.store_written_name(&name_str, name, options, &generations)
+                .map_err(|e| self.no_space(e, &stats))?;
         }
+        Ok(stats.get_stats())
+    }
+
+    /// `write_with_options`; with the data (as stored, transformed already)
+    /// and metadata of the name `source`, when it's a copy
+    fn write_name<R>(
+        &self,
+        name_str: &str,
+        reader: R,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+        source: Option<&Name>,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        info!(self.log, "Writing data"; "name" => name_str);
+        self.ensure_writable()?;
+        let _lock = self.aio.lock_shared(self.lock_wait)?;
+
+        // Fail early, before anything is started
+        self.check_write_options(options)?;
+        let generations = self.read_generations_for_writing()?;
+        self.check_new_name(name_str, options, &generations)?;
 
         let mut timer = slog_perf::TimeReporter::new_with_level(
             "write",
@@ -806,46 +5308,189 @@ impl Repo {
             Level::Info,
         );
         timer.start("write");
-        let num_threads = num_cpus::get();
-        let (chunker_tx, chunker_rx) =
-            mpsc::sync_channel(self.write_cpu_thread_num());
+        let (aio, packer, processor) =
+            self.write_pipeline(enc, options, &generations)?;
+        let stats = aio.stats();
+
+        let name = crossbeam::scope(|scope| {
+            let process_tx = processor.spawn(scope, &options.threads);
+            self.write_stream(
+                reader,
+                options,
+                process_tx,
+                aio,
+                processor.stop_token(),
+            )
+        }).expect("non-joined thread panicked (chunk processor?)");
+
+        // why the write stopped, if it did
+        processor
+            .check_stored()
+            .map_err(|e| self.no_space(e, &stats))?;
+        let mut name = name.map_err(|e| self.no_space(e, &stats))?;
+        if let Some(ref packer) = packer {
+            packer.finish().map_err(|e| self.no_space(e, &stats))?;
+        }
+        if let Some(source) = source {
+            name.created = source.created;
+            name.transform = source.transform.clone();
+            name.sha256 = source.sha256.clone();
+        }
+        let unchanged_from = self
+            .store_written_name(name_str, name, options, &generations)
+            .map_err(|e| self.no_space(e, &stats))?;
+        Ok(WriteStats {
+            unchanged_from,
+            ..stats.get_stats()
+        })
+    }
+
+    /// `e` of a write, as `error::Error::NoSpace` if the storage is full
+    ///
+    /// The chunks stored before are left for `gc` to remove.
+    fn no_space(&self, e: io::Error, stats: &AsyncIOThreadShared) -> io::Error {
+        if !error::is_storage_full(&e) {
+            return e;
+        }
+        if let Some(&error::Error::NoSpace { .. }) = error::Error::of(&e) {
+            return e;
+        }
+        warn!(self.log, "Storage full"; "error" => %e);
+        error::Error::NoSpace {
+            written: stats.get_stats().new_bytes,
+        }.into()
+    }
+
+    /// Check `options` of a write, before anything is started
+    fn check_write_options(&self, options: &WriteOptions) -> io::Result<()> {
+        if let Some(ref tier) = options.chunking_tier {
+            self.config.chunking_tier(tier)?;
+        }
+        if options.read_buffer_size == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "read buffer size can't be zero",
+            ));
+        }
+        if let Some(ref id) = options.transform {
+            self.transform(id)?;
+        }
+        if options.overwrite {
+            self.ensure_deletable()?;
+        }
+        Ok(())
+    }
+
+    /// Check `name_str` can be written with `options`
+    fn check_new_name(
+        &self,
+        name_str: &str,
+        options: &WriteOptions,
+        generations: &[Generation],
+    ) -> io::Result<()> {
+        validate_name(name_str)?;
+        if !options.overwrite
+            && Name::exists_any(name_str, generations, &self.name_io())?
+        {
+            return Err(error::Error::NameExists(name_str.into()).into());
+        }
+        Ok(())
+    }
 
-        let backend = backend_from_url(&self.url)?;
-        let aio = aio::AsyncIO::new(backend, self.log.clone())?;
+    /// Chunk processor (and pack writer, if packing) storing the chunks
+    /// of writes with `options`
+    fn write_pipeline(
+        &self,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+        generations: &[Generation],
+    ) -> io::Result<(aio::AsyncIO, Option<Arc<PackWriter>>, ChunkProcessor)>
+    {
+        let aio = aio::AsyncIO::new(
+            self.backend()?,
+            self.aio.io_counters(),
+            self.log.clone(),
+        )?;
 
         let stats = aio.stats();
+        let packer = self.config.packing.clone().map(|packing| {
+            Arc::new(PackWriter::new(
+                self.aio.clone(),
+                Arc::clone(&self.hasher),
+                Arc::clone(&self.packs),
+                generations.last().unwrap().to_string(),
+                packing,
+                Some(stats.clone()),
+            ).with_verify_writes(options.verify_writes))
+        });
+
+        let processor = ChunkProcessor::new(
+            self.clone(),
+            aio.clone(),
+            enc,
+            Arc::clone(&self.compression),
+            generations,
+            packer.clone(),
+        ).with_progress(options.progress.as_ref().map(|progress| {
+            Arc::new(ProgressCounter::new(Arc::clone(progress), None))
+        }))
+        .with_verify_writes(options.verify_writes);
+        Ok((aio, packer, processor))
+    }
 
-        // mpmc queue used  as spmc fan-out
-        let (process_tx, process_rx) = crossbeam_channel::bounded(num_threads);
+    /// Chunk the data from `reader` into `process_tx`, returning the name
+    /// with its address
+    ///
+    /// The chunks are stored once the chunk processor is done with them.
+    fn write_stream<R>(
+        &self,
+        reader: R,
+        options: &WriteOptions,
+        process_tx: work_queue::WorkSender<chunk_processor::Message>,
+        aio: aio::AsyncIO,
+        stop: &CancelToken,
+    ) -> io::Result<Name>
+    where
+        R: Read + Send,
+    {
+        let read_buffer_size =
+            options.read_buffer_size.unwrap_or(INGRESS_BUFFER_SIZE);
+        let mut sha256 = hashing::sha256_writer();
+        let reader = util::TeeReader::new(reader, &mut sha256);
+        let reader: Box<dyn Read + Send> = match options.transform {
+            Some(ref id) => self.transform(id)?.apply(Box::new(reader)),
+            None => Box::new(reader),
+        };
+        let (chunker_tx, chunker_rx) =
+            mpsc::sync_channel(self.write_cpu_thread_num());
 
         let data_address = crossbeam::scope(|scope| {
-            scope.spawn(move |_| self.input_reader_thread(reader, chunker_tx));
-
-            for _ in 0..num_threads {
-                let process_rx = process_rx.clone();
-                let aio = aio.clone();
-                let encrypter = Arc::clone(&enc.encrypter);
-                let compression = Arc::clone(&self.compression);
-                let hasher = Arc::clone(&self.hasher);
-                let generations = generations.clone();
-                scope.spawn(move |_| {
-                    let processor = ChunkProcessor::new(
-                        self.clone(),
-                        process_rx,
-                        aio,
-                        encrypter,
-                        compression,
-                        hasher,
-                        generations,
-                    );
-                    processor.run();
-                });
-            }
-            drop(process_rx);
+            scope.spawn(move |_| {
+                self.input_reader_thread(
+                    reader,
+                    read_buffer_size,
+                    chunker_tx,
+                    options,
+                    stop,
+                )
+            });
 
             let chunk_and_write = scope.spawn(move |_| {
+                let input: InputIter = if options.tar {
+                    Box::new(tar::TarBoundaries::new(chunker_rx.into_iter()))
+                } else {
+                    Box::new(chunker_rx.into_iter())
+                };
+                let (tier, input) = match options.chunking_tier {
+                    Some(ref tier) => (
+                        self.config.chunking_tier(tier).expect("checked"),
+                        Box::new(input) as InputIter,
+                    ),
+                    None => self.detect_chunking_tier(input),
+                };
                 self.chunk_and_write_data_thread(
-                    Box::new(chunker_rx.into_iter()),
+                    input,
+                    tier,
                     process_tx,
                     aio,
                     DataType::Data,
@@ -853,7 +5498,7 @@ impl Repo {
             });
 
             chunk_and_write.join()
-        }).expect("non-joined thread panicked (chunk processor?)");
+        }).expect("non-joined thread panicked (input reader?)");
 
         let data_address = data_address.map_err(|e| {
             if let Some(io_e) = e.downcast_ref::<io::Error>() {
@@ -863,9 +5508,122 @@ impl Repo {
             }
         })?;
 
-        let name: Name = data_address?.into();
-        name.write_as(name_str, *generations.last().unwrap(), &self.aio)?;
-        Ok(stats.get_stats())
+        let (data_address, chunks, size) = data_address?;
+        // the data was cut short, see `input_reader_thread`
+        if let Some(ref cancel) = options.cancel {
+            cancel.check()?;
+        }
+        if stop.is_cancelled() {
+            return Err(Error::new(
+                io::ErrorKind::Other,
+                "storing the data failed",
+            ));
+        }
+        let mut name: Name = data_address.into();
+        name.created = Some(chrono::Utc::now());
+        name.size = Some(size);
+        name.chunks = Some(chunks);
+        name.transform = options.transform.clone();
+        name.sha256 = Some(sha256.finish());
+        Ok(name)
+    }
+
+    /// Store `name` (with all its chunks stored) as `name_str`, as
+    /// `options` tell; returns the name already storing the same data, if
+    /// any
+    fn store_written_name(
+        &self,
+        name_str: &str,
+        name: Name,
+        options: &WriteOptions,
+        generations: &[Generation],
+    ) -> io::Result<Option<String>> {
+        // the name itself first, when storing the same data again
+        let mut owners = self.owners_of_locked(&name.digest, generations)?;
+        // same data read back differently
+        owners.retain(|owner| {
+            Name::load_from_any(owner, generations, &self.name_io())
+                .map(|owner| owner.transform == name.transform)
+                .unwrap_or(false)
+        });
+        let unchanged_from = owners
+            .iter()
+            .find(|owner| *owner == name_str)
+            .or_else(|| owners.first())
+            .cloned();
+        if let Some(ref existing) = unchanged_from {
+            info!(self.log, "Data unchanged";
+                  "name" => name_str, "same-as" => existing);
+            match options.if_unchanged {
+                IfUnchanged::Store => {}
+                IfUnchanged::Skip => return Ok(unchanged_from),
+                IfUnchanged::Alias => {
+                    if existing != name_str {
+                        self.alias_locked(
+                            existing,
+                            name_str,
+                            options,
+                            generations,
+                        )?;
+                        self.audit(
+                            AuditEntry::new("alias")
+                                .with_name(name_str)
+                                .with_from(existing)
+                                .with_digest(&name.digest),
+                        )?;
+                    }
+                    return Ok(unchanged_from);
+                }
+            }
+        }
+
+        let cur_gen = *generations.last().unwrap();
+        if options.overwrite {
+            let old_name =
+                match Name::load_from_any(
+                    name_str,
+                    generations,
+                    &self.name_io(),
+                ) {
+                    Ok(old_name) => Some(old_name),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                };
+            name.write_replacing(
+                name_str,
+                cur_gen,
+                self.name_compression(),
+                &self.name_io(),
+            )?;
+            Name::remove_older(
+                name_str,
+                cur_gen,
+                generations,
+                &self.name_io(),
+            )?;
+            if let Some(old_name) = old_name {
+                RootIndex::remove(
+                    &old_name.digest,
+                    name_str,
+                    &self.name_io(),
+                )?;
+            }
+        } else {
+            name.write_as(
+                name_str,
+                cur_gen,
+                self.name_compression(),
+                &self.name_io(),
+            )?;
+        }
+        RootIndex::add(&name.digest, name_str, &self.name_io())?;
+        self.audit(
+            AuditEntry::new("store")
+                .with_name(name_str)
+                .with_digest(&name.digest)
+                .with_bytes(name.size),
+        )?;
+        Ok(unchanged_from)
     }
 }
 // }}}
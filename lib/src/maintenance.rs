@@ -0,0 +1,178 @@
+//! Automatic repository maintenance
+//!
+//! The policy is a part of the repo config. What was done, and when, is
+//! kept in a separate file, so `Repo::maintain` can be run often (eg. from
+//! cron) and only do what is due.
+use aio;
+use chrono::prelude::*;
+use config::MAINTENANCE_YML_FILE;
+use serde_yaml;
+use std::io;
+use util::*;
+use SGData;
+
+/// Events kept in the maintenance history
+const MAX_HISTORY: usize = 100;
+
+fn default_gc_grace_secs() -> u64 {
+    86400
+}
+
+/// What `Repo::maintain` does, and when
+///
+/// Zero disables given trigger.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenancePolicy {
+    /// `gc` after this many names were stored since the last one
+    #[serde(default)]
+    pub gc_every_stores: u64,
+    /// `gc` when more than this percent of stored chunks is unreachable
+    #[serde(default)]
+    pub gc_garbage_percent: u32,
+    /// Grace time of the `gc`, in seconds
+    #[serde(default = "default_gc_grace_secs")]
+    pub gc_grace_secs: u64,
+    /// Verify this percent of the names a day (all of them in turns)
+    #[serde(default)]
+    pub scrub_daily_percent: u32,
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        MaintenancePolicy {
+            gc_every_stores: 0,
+            gc_garbage_percent: 0,
+            gc_grace_secs: default_gc_grace_secs(),
+            scrub_daily_percent: 0,
+        }
+    }
+}
+
+impl MaintenancePolicy {
+    pub(crate) fn is_default(&self) -> bool {
+        *self == MaintenancePolicy::default()
+    }
+}
+
+/// Maintenance task done by `Repo::maintain`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceEvent {
+    #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
+    pub time: DateTime<Utc>,
+    /// `gc` or `scrub`
+    pub task: String,
+    pub result: String,
+    /// False if the task was skipped, or found a problem
+    pub ok: bool,
+}
+
+/// Maintenance done so far
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MaintenanceState {
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_rfc3339_opt",
+        deserialize_with = "from_rfc3339_opt"
+    )]
+    pub last_gc: Option<DateTime<Utc>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_rfc3339_opt",
+        deserialize_with = "from_rfc3339_opt"
+    )]
+    pub last_scrub: Option<DateTime<Utc>>,
+    /// Last name verified; the next scrub continues after it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_scrubbed_name: Option<String>,
+    /// Latest events, oldest first
+    #[serde(default)]
+    pub history: Vec<MaintenanceEvent>,
+}
+
+impl MaintenanceState {
+    pub(crate) fn read(aio: &aio::AsyncIO) -> io::Result<Self> {
+        let data = match aio.read(MAINTENANCE_YML_FILE.into()).wait() {
+            Ok(data) => data.to_linear_vec(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(MaintenanceState::default())
+            }
+            Err(e) => return Err(e),
+        };
+        serde_yaml::from_slice(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse yaml: {}", e),
+            )
+        })
+    }
+
+    pub(crate) fn write(&self, aio: &aio::AsyncIO) -> io::Result<()> {
+        let serialized =
+            serde_yaml::to_string(self).expect("yaml serialization failed");
+        aio.write(
+            MAINTENANCE_YML_FILE.into(),
+            SGData::from_single(serialized.into_bytes()),
+        ).wait()
+    }
+
+    pub(crate) fn record(&mut self, event: MaintenanceEvent) {
+        self.history.push(event);
+        if self.history.len() > MAX_HISTORY {
+            let excess = self.history.len() - MAX_HISTORY;
+            self.history.drain(..excess);
+        }
+    }
+
+    /// Names (`names` must be sorted) to verify at `now`
+    ///
+    /// `scrub_daily_percent` of the names for each whole day since the
+    /// last scrub, at least one.
+    pub(crate) fn scrub_due(
+        &self,
+        policy: &MaintenancePolicy,
+        names: &[String],
+        now: DateTime<Utc>,
+    ) -> Vec<String> {
+        if policy.scrub_daily_percent == 0 || names.is_empty() {
+            return vec![];
+        }
+        let days = match self.last_scrub {
+            Some(last_scrub) => (now - last_scrub).num_days().max(0) as u64,
+            None => 1,
+        };
+        if days == 0 {
+            return vec![];
+        }
+        let percent = f64::from(policy.scrub_daily_percent) * days as f64;
+        let count = (names.len() as f64 * percent / 100.0).ceil() as usize;
+        let count = count.max(1).min(names.len());
+
+        let start = match self.last_scrubbed_name {
+            Some(ref last) => names.iter().position(|name| name > last),
+            None => None,
+        }.unwrap_or(0);
+        names
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Maintenance due, as returned by `Repo::maintenance_due`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaintenanceTasks {
+    pub gc: bool,
+    /// Names to verify
+    pub scrub: Vec<String>,
+}
+
+impl MaintenanceTasks {
+    pub fn is_empty(&self) -> bool {
+        !self.gc && self.scrub.is_empty()
+    }
+}
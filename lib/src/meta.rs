@@ -0,0 +1,127 @@
+//! Export and import of names, without their data
+//!
+//! Only the name records (the digest of the data and its metadata) are
+//! moved, so it works only between repos that have the same data: sharing
+//! a chunk store, or mirrors of each other.
+use chrono::prelude::*;
+use name::{validate_name, Name};
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::{io, str};
+use util::*;
+use DIGEST_SIZE;
+
+/// A stored name, as exported
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NameRecord {
+    /// Digest of the top index chunk of the data
+    #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
+    pub digest: Vec<u8>,
+    pub index_level: u32,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_rfc3339_opt",
+        deserialize_with = "from_rfc3339_opt"
+    )]
+    pub created: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<u64>,
+    /// Identifier of the `Transform` the data was stored with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<String>,
+    /// SHA-256 of the whole data, before any transform
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_hex_opt",
+        deserialize_with = "from_hex_opt"
+    )]
+    pub sha256: Option<Vec<u8>>,
+    /// Chunks of the tree index, for trees (see `Repo::write_tree`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<u64>,
+}
+
+impl From<&Name> for NameRecord {
+    fn from(name: &Name) -> Self {
+        NameRecord {
+            digest: name.digest.clone(),
+            index_level: name.index_level,
+            created: name.created,
+            size: name.size,
+            chunks: name.chunks,
+            transform: name.transform.clone(),
+            sha256: name.sha256.clone(),
+            tree: name.tree,
+        }
+    }
+}
+
+impl From<&NameRecord> for Name {
+    fn from(record: &NameRecord) -> Self {
+        Name {
+            digest: record.digest.clone(),
+            index_level: record.index_level,
+            created: record.created,
+            size: record.size,
+            chunks: record.chunks,
+            transform: record.transform.clone(),
+            sha256: record.sha256.clone(),
+            tree: record.tree,
+            sealed_name: None,
+            signature: None,
+        }
+    }
+}
+
+/// Names exported by `Repo::export_names`
+///
+/// Stored as YAML:
+///
+/// ```norust
+/// names:
+///   home-2019-01-31:
+///     digest: abcdef...
+///     index_level: 1
+///     created: 2019-01-31T12:00:00Z
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NamesExport {
+    pub names: BTreeMap<String, NameRecord>,
+}
+
+impl NamesExport {
+    /// Check the records before importing anything
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        for (name, record) in &self.names {
+            validate_name(name)?;
+            if record.digest.len() != DIGEST_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("name {}: digest has wrong size", name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("yaml serialization failed")
+    }
+}
+
+impl str::FromStr for NamesExport {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        serde_yaml::from_str(s).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse exported names: {}", e),
+            )
+        })
+    }
+}
@@ -0,0 +1,68 @@
+//! Instrumentation of writes and reads, see `Repo::set_metrics`
+use std::fmt;
+use std::time::Duration;
+use DataType;
+
+/// Kind of a request made to the backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BackendOp {
+    Read,
+    /// Read of a part of a file (eg. of a chunk in a pack file)
+    ReadRange,
+    Write,
+    ReadMetadata,
+    List,
+    Remove,
+    Rename,
+}
+
+impl BackendOp {
+    /// Name of the request, eg. for metric labels
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BackendOp::Read => "read",
+            BackendOp::ReadRange => "read_range",
+            BackendOp::Write => "write",
+            BackendOp::ReadMetadata => "read_metadata",
+            BackendOp::List => "list",
+            BackendOp::Remove => "remove",
+            BackendOp::Rename => "rename",
+        }
+    }
+}
+
+/// Receiver of counters and timings of the operations of a `Repo`, eg. to
+/// export them to Prometheus or statsd
+///
+/// Every method does nothing by default, so only what's of interest has to
+/// be implemented. Called from the threads doing the work, so they should
+/// return quickly (eg. just update atomic counters).
+pub trait Metrics: Send + Sync {
+    /// Chunk stored, of `bytes` bytes as stored (compressed and
+    /// encrypted)
+    fn chunk_written(&self, _data_type: DataType, _bytes: u64) {}
+
+    /// Chunk of `bytes` bytes not stored, as it already was
+    fn dedup_hit(&self, _data_type: DataType, _bytes: u64) {}
+
+    /// Chunk of `bytes_in` bytes compressed to `bytes_out` bytes
+    fn compressed(&self, _bytes_in: u64, _bytes_out: u64) {}
+
+    /// Chunk of `bytes_in` bytes decompressed to `bytes_out` bytes
+    fn decompressed(&self, _bytes_in: u64, _bytes_out: u64) {}
+
+    /// Chunk encrypted, taking `time`
+    fn encrypted(&self, _time: Duration) {}
+
+    /// Chunk decrypted, taking `time`
+    fn decrypted(&self, _time: Duration) {}
+
+    /// Request to the backend finished after `time`, successfully or not
+    fn backend_request(&self, _op: BackendOp, _time: Duration, _ok: bool) {}
+}
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
@@ -1,5 +1,6 @@
 // {{{ use
 use Name;
+use DIGEST_SIZE;
 // }}}
 
 // {{{ DataAddress & DataAddressRef
@@ -48,15 +49,31 @@ impl From<Name> for DataAddress {
 // }}}
 
 // {{{ Digest & DigestRef
-#[derive(Clone)]
-pub(crate) struct Digest(pub(crate) Vec<u8>);
+/// Digest of a chunk stored in the `Repo`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Digest(pub(crate) Vec<u8>);
 
 impl Digest {
+    /// Digest from its binary form (eg. as returned by `as_ref`)
+    pub fn from_slice(bytes: &[u8]) -> Option<Digest> {
+        if bytes.len() == DIGEST_SIZE {
+            Some(Digest(bytes.to_vec()))
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn as_digest_ref(&self) -> DigestRef {
         DigestRef(self.0.as_slice())
     }
 }
 
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 #[derive(Copy, Clone)]
 pub(crate) struct DigestRef<'a>(pub(crate) &'a [u8]);
 // }}}
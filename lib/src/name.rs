@@ -1,7 +1,15 @@
 use aio;
+use chrono::prelude::*;
+use config;
+use error::Error;
+use glob;
+use hidden_name::NamesKey;
 use serde_yaml;
+use signed_name::{self, NamesSigner};
+use sodiumoxide::crypto::sign;
 use std::io;
-use std::path::PathBuf;
+use std::ops::Deref;
+use std::path::{self, Path, PathBuf};
 use util::*;
 use SGData;
 use DIGEST_SIZE;
@@ -9,11 +17,274 @@ use {DataAddress, DataAddressRef, Generation};
 
 pub(crate) const NAME_SUBDIR: &'static str = "name";
 
-#[derive(Serialize, Deserialize)]
+/// Base names Windows reserves for devices, in any case and with any
+/// extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+    "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// `AsyncIO` to access name records with
+///
+/// In repositories with `portable_names` (see `config::Repo`), names are
+/// stored under file names that are valid on every platform (see
+/// `encode_portable`). In ones with `hidden_names`, under HMACs of the
+/// names (see `hidden_name`), so `key` is needed to access any.
+pub(crate) struct NameIO<'a> {
+    aio: &'a aio::AsyncIO,
+    portable: bool,
+    hidden: bool,
+    key: Option<&'a NamesKey>,
+    /// Key records are checked with, in repositories signing them (see
+    /// `signed_name`)
+    sign_pub_key: Option<sign::PublicKey>,
+    signer: Option<&'a NamesSigner>,
+}
+
+impl<'a> NameIO<'a> {
+    pub(crate) fn new(
+        aio: &'a aio::AsyncIO,
+        config: &config::Repo,
+        key: Option<&'a NamesKey>,
+        signer: Option<&'a NamesSigner>,
+    ) -> Self {
+        NameIO {
+            aio,
+            portable: config.portable_names,
+            hidden: config.hidden_names,
+            key,
+            sign_pub_key: config.names_sign_key,
+            signer,
+        }
+    }
+
+    /// Names are hidden (see `hidden_name`)
+    pub(crate) fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Key of hidden names, failing if not unlocked
+    fn key(&self) -> io::Result<&'a NamesKey> {
+        self.key.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "names are hidden, unlock them with the secret key first",
+            )
+        })
+    }
+
+    /// Key records are signed with, failing if not unlocked
+    fn signer(&self) -> io::Result<&'a NamesSigner> {
+        self.signer.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "names are signed, unlock them with the secret key first",
+            )
+        })
+    }
+
+    /// File name (without the extension) of the record of `name`
+    fn file_stem(&self, name: &str) -> io::Result<String> {
+        validate_name(name)?;
+        Ok(if self.hidden {
+            self.key()?.file_stem(name)
+        } else if self.portable {
+            encode_portable(name)
+        } else {
+            name.to_string()
+        })
+    }
+
+    /// Path of the record of `name` in `gen`
+    pub(crate) fn name_path(
+        &self,
+        name: &str,
+        gen: Generation,
+    ) -> io::Result<PathBuf> {
+        let mut path: PathBuf = gen.to_string().into();
+        path.push(NAME_SUBDIR);
+        path.push(self.file_stem(name)? + ".yml");
+        Ok(path)
+    }
+
+    /// Name stored in the record at `path`
+    fn name_at(&self, path: &Path) -> io::Result<String> {
+        let stem = path
+            .file_stem()
+            .unwrap_or_else(|| panic!("malformed name: {:?}", path))
+            .to_string_lossy();
+        if self.hidden {
+            let key = self.key()?;
+            let record = self.aio.read(path.to_owned()).wait()?;
+            let record = Name::from_record(&record.to_linear_vec())?;
+            key.open(record.sealed_name.as_ref().map(|s| &s[..]), &stem)
+        } else if self.portable {
+            Ok(decode_portable(&stem))
+        } else {
+            Ok(stem.to_string())
+        }
+    }
+}
+
+impl<'a> Deref for NameIO<'a> {
+    type Target = aio::AsyncIO;
+
+    fn deref(&self) -> &aio::AsyncIO {
+        self.aio
+    }
+}
+
+/// Fail with `Error::InvalidName` unless `name` is a single file name
+///
+/// So names given by users can't get out of the directory of the records
+/// (eg. `../../etc/cron.d/x`): names with path separators (of the platform)
+/// or NUL, empty names and dot segments (`.` and `..`) are rejected.
+pub(crate) fn validate_name(name: &str) -> io::Result<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains('\0')
+        || name.chars().any(path::is_separator)
+    {
+        return Err(Error::InvalidName(name.into()).into());
+    }
+    Ok(())
+}
+
+/// File name (without the extension) to store `name` under, on any
+/// platform
+///
+/// Characters Windows doesn't allow in file names (and `%` itself) are
+/// percent-encoded, and so are trailing dots and spaces (that Windows
+/// strips) and the first character of reserved device names (`CON`,
+/// `aux.tar`, ...). Names without any of them are stored unchanged.
+fn encode_portable(name: &str) -> String {
+    let base = name.split('.').next().unwrap_or("");
+    let reserved = RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base));
+    let trailing = name.len() - name.trim_end_matches(&['.', ' '][..]).len();
+
+    let mut encoded = String::with_capacity(name.len());
+    for (i, c) in name.char_indices() {
+        let escape = match c {
+            '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*' | '%' => true,
+            c if c < ' ' => true,
+            _ => (reserved && i == 0) || i >= name.len() - trailing,
+        };
+        if escape {
+            encoded.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+/// Name stored under `file_name` by `encode_portable`
+fn decode_portable(file_name: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = file_name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) =
+                (hex(bytes[i + 1]), hex(bytes[i + 2]))
+            {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Start of compressed name records; plain ones are YAML text
+///
+/// Followed by the length (`u32`, little endian) of the YAML serialized
+/// `config::Compression` used, the compression config itself, and the
+/// compressed YAML text of the record.
+const COMPRESSED_RECORD_MAGIC: &[u8] = b"\0rdedup-name\n";
+
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct Name {
     #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
     pub(crate) digest: Vec<u8>,
     pub(crate) index_level: u32,
+    // Metadata below is missing in names written by older versions
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_rfc3339_opt",
+        deserialize_with = "from_rfc3339_opt"
+    )]
+    pub(crate) created: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) chunks: Option<u64>,
+    /// Identifier of the `Transform` the data was stored with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transform: Option<String>,
+    /// SHA-256 of the whole data, as written (before any transform),
+    /// checked when it's read back whole
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_hex_opt",
+        deserialize_with = "from_hex_opt"
+    )]
+    pub(crate) sha256: Option<Vec<u8>>,
+    /// Chunks of the tree index at the start of the data, for trees stored
+    /// with `Repo::write_tree`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tree: Option<u64>,
+    /// The name itself, sealed, in repositories hiding names (see
+    /// `hidden_name`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sealed_name: Option<String>,
+    /// Signature of the record, in repositories signing them (see
+    /// `signed_name`)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_base64_opt",
+        deserialize_with = "from_base64_opt"
+    )]
+    pub(crate) signature: Option<sign::Signature>,
+}
+
+/// Metadata of a stored name
+///
+/// Fields are `None` for names stored by older versions of rdedup, that
+/// didn't record them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameStat {
+    /// Time the name was stored
+    pub created: Option<DateTime<Utc>>,
+    /// Size of the stored data, in bytes
+    pub size: Option<u64>,
+    /// Number of data chunks the stored data was split into
+    pub chunks: Option<u64>,
+    /// SHA-256 of the stored data, as `sha256sum` prints it for the
+    /// original
+    pub sha256: Option<Vec<u8>>,
+}
+
+impl From<&Name> for NameStat {
+    fn from(name: &Name) -> Self {
+        NameStat {
+            created: name.created,
+            size: name.size,
+            chunks: name.chunks,
+            sha256: name.sha256.clone(),
+        }
+    }
 }
 
 // TODO: I am very displeased with myself how this
@@ -26,43 +297,47 @@ impl Name {
     pub(crate) fn remove(
         name: &str,
         gen: Generation,
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<()> {
-        let path = Name::path(name, gen);
+        let path = aio.name_path(name, gen)?;
         aio.remove(path).wait()
     }
 
+    /// Remove the name from all the generations it's in
     pub(crate) fn remove_any(
         name: &str,
         gens: &[Generation],
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<()> {
+        let mut found = false;
         for gen in gens.iter().rev() {
             match Name::remove(name, *gen, aio) {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                res => return res,
+                Err(e) => return Err(e),
+                Ok(()) => found = true,
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        if found {
+            Ok(())
+        } else {
+            Err(Error::NameNotFound(name.into()).into())
+        }
     }
 
     pub(crate) fn update_generation_to(
         name: &str,
         cur_generation: Generation,
         gens: &[Generation],
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<()> {
-        let dst_path = Name::path(name, cur_generation);
+        let dst_path = aio.name_path(name, cur_generation)?;
         for gen in gens.iter().rev() {
             if *gen == cur_generation {
                 continue;
             }
 
-            let src_path = Name::path(name, *gen);
+            let src_path = aio.name_path(name, *gen)?;
 
             match aio.rename(src_path, dst_path.clone()).wait() {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
@@ -72,44 +347,29 @@ impl Name {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
-    }
-
-    pub(crate) fn path(name: &str, gen: Generation) -> PathBuf {
-        let mut path: PathBuf = gen.to_string().into();
-        path.push(NAME_SUBDIR);
-        path.push(name.to_string() + ".yml");
-        path
+        Err(Error::NameNotFound(name.into()).into())
     }
 
     /// List all names
     pub(crate) fn list(
         gen: Generation,
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<Vec<String>> {
+        if aio.hidden {
+            aio.key()?;
+        }
         let list = substitute_err_not_found(
             aio.list(PathBuf::from(gen.to_string()).join(NAME_SUBDIR))
                 .wait(),
             || vec![],
         )?;
 
-        Ok(list
-            .iter()
-            .map(|e| {
-                e.file_stem()
-                    .expect(&format!("malformed name: {:?}", e))
-                    .to_string_lossy()
-                    .to_string()
-            })
-            .collect())
+        list.iter().map(|path| aio.name_at(path)).collect()
     }
 
     pub fn list_all(
         gens: &[Generation],
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<Vec<String>> {
         let mut res = vec![];
 
@@ -120,73 +380,188 @@ impl Name {
         Ok(res)
     }
 
+    /// Store the name in `gen`, compressed with `compression` (if any)
     pub fn write_as(
         &self,
         name: &str,
         gen: Generation,
-        aio: &aio::AsyncIO,
+        compression: Option<&config::Compression>,
+        aio: &NameIO,
+    ) -> io::Result<()> {
+        if aio.read(aio.name_path(name, gen)?).wait().is_ok() {
+            return Err(Error::NameExists(name.into()).into());
+        }
+
+        self.write_replacing(name, gen, compression, aio)
+    }
+
+    /// Like `write_as`, but atomically replaces the name in `gen`, if any
+    pub(crate) fn write_replacing(
+        &self,
+        name: &str,
+        gen: Generation,
+        compression: Option<&config::Compression>,
+        aio: &NameIO,
     ) -> io::Result<()> {
-        let serialized_str =
+        let path = aio.name_path(name, gen)?;
+        let mut record = self.clone();
+        if aio.hidden {
+            record.sealed_name = Some(aio.key()?.seal(name));
+        }
+        if aio.sign_pub_key.is_some() {
+            record.signature = Some(aio.signer()?.sign(name, &record));
+        }
+        aio.write(path, record.to_record(compression)?).wait()
+    }
+
+    fn to_record(
+        &self,
+        compression: Option<&config::Compression>,
+    ) -> io::Result<SGData> {
+        let serialized =
             serde_yaml::to_string(self).expect("yaml serialization failed");
+        let compression = match compression {
+            Some(compression) => compression,
+            None => return Ok(SGData::from_single(serialized.into_bytes())),
+        };
 
-        let path = Name::path(name, gen);
+        let header = serde_yaml::to_string(compression)
+            .expect("yaml serialization failed");
+        let compressed = compression
+            .to_engine()
+            .compress(SGData::from_single(serialized.into_bytes()))?;
 
-        if aio.read(path.clone()).wait().is_ok() {
-            return Err(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                "name already exists",
-            ));
+        let mut record = COMPRESSED_RECORD_MAGIC.to_vec();
+        let header_len = header.len() as u32;
+        for b in 0..4 {
+            record.push((header_len >> (8 * b)) as u8);
+        }
+        record.extend_from_slice(header.as_bytes());
+        record.extend_from_slice(&compressed.to_linear_vec());
+        Ok(SGData::from_single(record))
+    }
+
+    /// Parse a name record, compressed or not
+    fn from_record(record: &[u8]) -> io::Result<Self> {
+        let invalid =
+            |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if !record.starts_with(COMPRESSED_RECORD_MAGIC) {
+            return serde_yaml::from_slice(record).map_err(|e| {
+                invalid(format!("couldn't parse yaml: {}", e))
+            });
         }
 
-        aio.write(path, SGData::from_single(serialized_str.into_bytes()))
-            .wait()?;
+        let rest = &record[COMPRESSED_RECORD_MAGIC.len()..];
+        if rest.len() < 4 {
+            return Err(invalid("truncated name record".into()));
+        }
+        let header_len = (0..4)
+            .fold(0usize, |len, b| len | (rest[b] as usize) << (8 * b));
+        let rest = &rest[4..];
+        if rest.len() < header_len {
+            return Err(invalid("truncated name record".into()));
+        }
+        let compression: config::Compression =
+            serde_yaml::from_slice(&rest[..header_len]).map_err(|e| {
+                invalid(format!("unsupported name record compression: {}", e))
+            })?;
+        let serialized = compression
+            .to_engine()
+            .decompress(SGData::from_single(rest[header_len..].to_vec()))?
+            .to_linear_vec();
+
+        serde_yaml::from_slice(&serialized).map_err(|e| {
+            invalid(format!("couldn't parse yaml: {}", e))
+        })
+    }
+
+    /// Remove the name from all generations except `keep_gen`
+    pub(crate) fn remove_older(
+        name: &str,
+        keep_gen: Generation,
+        gens: &[Generation],
+        aio: &NameIO,
+    ) -> io::Result<()> {
+        for gen in gens.iter().filter(|gen| **gen != keep_gen) {
+            match Name::remove(name, *gen, aio) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                res => res?,
+            }
+        }
         Ok(())
     }
 
     pub fn load_from(
         name: &str,
         gen: Generation,
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<Self> {
-        let path = Name::path(name, gen);
+        let path = aio.name_path(name, gen)?;
 
-        let config_data = aio.read(path).wait()?;
-        let config_data = config_data.to_linear_vec();
-
-        let name: Name = serde_yaml::from_reader(config_data.as_slice())
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("couldn't parse yaml: {}", e.to_string()),
-                )
-            })?;
+        let record = aio.read(path.clone()).wait()?.to_linear_vec();
+        let loaded = Name::from_record(&record)?;
+        if aio.hidden {
+            // checks the record wasn't moved from another name
+            let stem = path.file_stem().expect("name path").to_string_lossy();
+            let sealed = loaded.sealed_name.as_ref().map(|s| &s[..]);
+            aio.key()?.open(sealed, &stem)?;
+        }
+        if let Some(ref pub_key) = aio.sign_pub_key {
+            signed_name::verify(pub_key, name, &loaded)?;
+        }
 
-        if name.digest.len() != DIGEST_SIZE {
+        if loaded.digest.len() != DIGEST_SIZE {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!("parsed digest has wrong size: {}", name.digest.len()),
+                format!(
+                    "parsed digest has wrong size: {}",
+                    loaded.digest.len()
+                ),
             ));
         }
 
-        Ok(name)
+        Ok(loaded)
+    }
+
+    /// Is the name stored in any of `gens`
+    pub(crate) fn exists_any(
+        name: &str,
+        gens: &[Generation],
+        aio: &NameIO,
+    ) -> io::Result<bool> {
+        for gen in gens.iter().rev() {
+            match aio.read_metadata(aio.name_path(name, *gen)?).wait() {
+                Ok(_) => return Ok(true),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
     }
 
     pub(crate) fn load_from_any(
         name: &str,
         gens: &[Generation],
-        aio: &aio::AsyncIO,
+        aio: &NameIO,
     ) -> io::Result<Self> {
+        Name::load_with_gen_from_any(name, gens, aio).map(|(name, _)| name)
+    }
+
+    /// Like `load_from_any`, but also return the generation the name is in
+    pub(crate) fn load_with_gen_from_any(
+        name: &str,
+        gens: &[Generation],
+        aio: &NameIO,
+    ) -> io::Result<(Self, Generation)> {
         for gen in gens.iter().rev() {
             match Name::load_from(name, *gen, aio) {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                res => return res,
+                res => return res.map(|name| (name, *gen)),
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(Error::NameNotFound(name.into()).into())
     }
 }
 
@@ -195,6 +570,14 @@ impl<'a> From<DataAddressRef<'a>> for Name {
         Name {
             digest: da.digest.0.into(),
             index_level: da.index_level,
+            created: None,
+            size: None,
+            chunks: None,
+            transform: None,
+            sha256: None,
+            tree: None,
+            sealed_name: None,
+            signature: None,
         }
     }
 }
@@ -204,6 +587,45 @@ impl From<DataAddress> for Name {
         Name {
             digest: da.digest.0,
             index_level: da.index_level,
+            created: None,
+            size: None,
+            chunks: None,
+            transform: None,
+            sha256: None,
+            tree: None,
+            sealed_name: None,
+            signature: None,
+        }
+    }
+}
+
+/// Prefix or glob pattern selecting names
+pub(crate) enum NamePattern {
+    Prefix(String),
+    Glob(glob::Pattern),
+}
+
+impl NamePattern {
+    pub(crate) fn new(pattern: &str) -> io::Result<Self> {
+        if !pattern.contains(&['*', '?', '['][..]) {
+            return Ok(NamePattern::Prefix(pattern.into()));
+        }
+        glob::Pattern::new(pattern)
+            .map(NamePattern::Glob)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid name pattern: {}", e),
+                )
+            })
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        match *self {
+            NamePattern::Prefix(ref prefix) => {
+                name.starts_with(prefix.as_str())
+            }
+            NamePattern::Glob(ref glob) => glob.matches(name),
         }
     }
 }
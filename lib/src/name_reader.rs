@@ -0,0 +1,158 @@
+//! Seekable reader of the data of a name, see `Repo::open_reader`
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use {DecryptHandle, Digest, Repo};
+
+/// Chunks kept in memory, for reads going back and forth a little
+const CACHED_CHUNKS: usize = 4;
+
+/// Reader of the data of a name, returned by `Repo::open_reader`
+///
+/// Reads and seeks only read the chunks with the data asked for, as far
+/// as the index has the lengths of the chunks (see
+/// `settings::Repo::set_bare_index`). Otherwise the chunks before the data
+/// are read once, to find it.
+pub struct NameReader<'a> {
+    repo: &'a Repo,
+    dec: &'a DecryptHandle,
+    chunks: Vec<Digest>,
+    /// Offsets of the chunks as far as known, and the end of the last of
+    /// them
+    starts: Vec<u64>,
+    position: u64,
+    /// Chunks read recently, most recent last
+    cache: VecDeque<(usize, Vec<u8>)>,
+}
+
+impl<'a> NameReader<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        dec: &'a DecryptHandle,
+        chunks: Vec<(Digest, Option<u64>)>,
+    ) -> Self {
+        let mut starts = vec![0];
+        for len in chunks.iter().map(|&(_, len)| len) {
+            match len {
+                Some(len) => {
+                    let start = *starts.last().unwrap();
+                    starts.push(start + len);
+                }
+                None => break,
+            }
+        }
+        NameReader {
+            repo,
+            dec,
+            chunks: chunks.into_iter().map(|(digest, _)| digest).collect(),
+            starts,
+            position: 0,
+            cache: VecDeque::new(),
+        }
+    }
+
+    /// Size of the data, reading the chunks with unknown lengths
+    pub fn size(&mut self) -> io::Result<u64> {
+        self.chunk_at(::std::u64::MAX)?;
+        Ok(*self.starts.last().unwrap())
+    }
+
+    /// Data of chunk `i`
+    fn chunk(&mut self, i: usize) -> io::Result<&[u8]> {
+        match self.cache.iter().position(|&(j, _)| j == i) {
+            Some(pos) => {
+                let cached = self.cache.remove(pos).unwrap();
+                self.cache.push_back(cached);
+            }
+            None => {
+                let data = self.repo.get_chunk(&self.chunks[i], self.dec)?;
+                if i + 1 == self.starts.len() {
+                    let start = self.starts[i];
+                    self.starts.push(start + data.len() as u64);
+                } else if self.starts[i + 1] - self.starts[i]
+                    != data.len() as u64
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "length of a chunk doesn't match the index",
+                    ));
+                }
+                if self.cache.len() == CACHED_CHUNKS {
+                    self.cache.pop_front();
+                }
+                self.cache.push_back((i, data));
+            }
+        }
+        Ok(&self.cache.back().unwrap().1)
+    }
+
+    /// Index of the chunk containing `offset`, or `None` past the end
+    fn chunk_at(&mut self, offset: u64) -> io::Result<Option<usize>> {
+        if offset < *self.starts.last().unwrap() {
+            // the first start past `offset` (comparing never equal)
+            let after = self
+                .starts
+                .binary_search_by(|&start| {
+                    if start <= offset {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                })
+                .unwrap_err();
+            return Ok(Some(after - 1));
+        }
+        // read on, until the end of a chunk is past `offset`
+        while self.starts.len() <= self.chunks.len() {
+            let i = self.starts.len() - 1;
+            self.chunk(i)?;
+            if offset < self.starts[i + 1] {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Read for NameReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let i = match self.chunk_at(self.position)? {
+            Some(i) => i,
+            None => return Ok(0),
+        };
+        let from = (self.position - self.starts[i]) as usize;
+        let chunk = self.chunk(i)?;
+        let len = buf.len().min(chunk.len() - from);
+        buf[..len].copy_from_slice(&chunk[from..from + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a> Seek for NameReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.position, delta),
+            SeekFrom::End(delta) => (self.size()?, delta),
+        };
+        let position = if delta < 0 {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        } else {
+            base.checked_add(delta as u64)
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
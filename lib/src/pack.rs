@@ -0,0 +1,510 @@
+//! Pack files: many small chunks stored in a single file
+//!
+//! Chunks are stored as they are (compressed and encrypted), one after
+//! another, in `<gen>/pack/<id>.pack`. `<id>.idx` lists the digest, offset
+//! and length of each of them. The index is written after the pack, so a
+//! pack without one is incomplete and ignored; `<id>` is the digest of the
+//! index.
+//!
+//! Packs are never modified: `Repo::repack` writes new ones and removes
+//! the old, and `gc` copies reachable chunks out of packs of old
+//! generations, like it moves chunk files.
+use aio;
+use hashing::ArcHasher;
+use hex;
+use sgdata::SGData;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use util::*;
+use DIGEST_SIZE;
+
+pub(crate) const PACK_SUBDIR: &str = "pack";
+const PACK_EXT: &str = "pack";
+pub(crate) const INDEX_EXT: &str = "idx";
+const INDEX_MAGIC: &[u8] = b"rdidx001";
+/// Digest, offset and length
+const INDEX_ENTRY_SIZE: usize = DIGEST_SIZE + 8 + 8;
+
+fn default_pack_size() -> u64 {
+    32 * 1024 * 1024
+}
+
+fn default_max_chunk_size() -> u64 {
+    1024 * 1024
+}
+
+/// Pack files settings of a repository
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Packing {
+    /// Pack files are closed once they reach this size
+    #[serde(default = "default_pack_size")]
+    pub pack_size: u64,
+    /// Only chunks up to this size (as stored) are packed
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: u64,
+}
+
+impl Default for Packing {
+    fn default() -> Self {
+        Packing {
+            pack_size: default_pack_size(),
+            max_chunk_size: default_max_chunk_size(),
+        }
+    }
+}
+
+impl Packing {
+    pub(crate) fn valid(&self) -> bool {
+        self.max_chunk_size > 0 && self.max_chunk_size <= self.pack_size
+    }
+}
+
+/// Summary of a `Repo::repack`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RepackResults {
+    /// Chunk files moved into packs
+    pub chunks: usize,
+    /// Their size, in bytes
+    pub bytes: u64,
+    /// Undersized packs merged into new ones
+    pub merged_packs: usize,
+    /// Packs written
+    pub packs: usize,
+}
+
+/// Location of a chunk in a pack
+#[derive(Clone, Debug)]
+pub(crate) struct PackedChunk {
+    pub(crate) path: Arc<PathBuf>,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+fn pack_dir(gen_str: &str) -> PathBuf {
+    PathBuf::from(gen_str).join(PACK_SUBDIR)
+}
+
+fn parse_index(data: &[u8]) -> io::Result<Vec<(Vec<u8>, u64, u64)>> {
+    if !data.starts_with(INDEX_MAGIC)
+        || (data.len() - INDEX_MAGIC.len()) / INDEX_ENTRY_SIZE
+            * INDEX_ENTRY_SIZE
+            != data.len() - INDEX_MAGIC.len()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed pack index",
+        ));
+    }
+    Ok(data[INDEX_MAGIC.len()..]
+        .chunks(INDEX_ENTRY_SIZE)
+        .map(|entry| {
+            let (digest, location) = entry.split_at(DIGEST_SIZE);
+            let mut offset = [0u8; 8];
+            let mut len = [0u8; 8];
+            offset.copy_from_slice(&location[..8]);
+            len.copy_from_slice(&location[8..]);
+            (
+                digest.to_vec(),
+                u64::from_be_bytes(offset),
+                u64::from_be_bytes(len),
+            )
+        })
+        .collect())
+}
+
+/// Packs of one generation
+#[derive(Default)]
+struct GenPacks {
+    /// Pack id and size
+    packs: HashMap<String, u64>,
+    chunks: HashMap<Vec<u8>, PackedChunk>,
+}
+
+impl GenPacks {
+    fn load(gen_str: &str, aio: &aio::AsyncIO) -> io::Result<Self> {
+        let mut gen_packs = GenPacks::default();
+        let list = substitute_err_not_found(
+            aio.list(pack_dir(gen_str)).wait(),
+            Vec::new,
+        )?;
+        for path in list {
+            if path.extension() != Some(INDEX_EXT.as_ref()) {
+                continue;
+            }
+            let id = match path.file_stem() {
+                Some(id) => id.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let index = aio
+                .read(pack_dir(gen_str).join(path.file_name().unwrap()))
+                .wait()?;
+            let entries = parse_index(&index.to_linear_vec())?;
+            gen_packs.insert(gen_str, &id, entries);
+        }
+        Ok(gen_packs)
+    }
+
+    fn insert(
+        &mut self,
+        gen_str: &str,
+        id: &str,
+        entries: Vec<(Vec<u8>, u64, u64)>,
+    ) {
+        let path = Arc::new(
+            pack_dir(gen_str).join(id).with_extension(PACK_EXT),
+        );
+        let mut size = 0;
+        for (digest, offset, len) in entries {
+            size = size.max(offset + len);
+            self.chunks.insert(
+                digest,
+                PackedChunk {
+                    path: Arc::clone(&path),
+                    offset,
+                    len,
+                },
+            );
+        }
+        self.packs.insert(id.into(), size);
+    }
+}
+
+/// Indexes of packs of all generations, loaded on demand
+///
+/// Shared by all clones of a `Repo`. Packs written by others after a
+/// generation was loaded are only seen after `refresh`.
+#[derive(Default)]
+pub(crate) struct PackIndex {
+    gens: Mutex<HashMap<String, GenPacks>>,
+}
+
+impl PackIndex {
+    fn with_gen<T, F>(
+        &self,
+        gen_str: &str,
+        aio: &aio::AsyncIO,
+        f: F,
+    ) -> io::Result<T>
+    where
+        F: FnOnce(&GenPacks) -> T,
+    {
+        let mut gens = self.gens.lock().unwrap();
+        if !gens.contains_key(gen_str) {
+            let gen_packs = GenPacks::load(gen_str, aio)?;
+            gens.insert(gen_str.into(), gen_packs);
+        }
+        Ok(f(&gens[gen_str]))
+    }
+
+    /// Where `digest` is packed in `gen_str`, if it is
+    pub(crate) fn find(
+        &self,
+        gen_str: &str,
+        digest: &[u8],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<Option<PackedChunk>> {
+        self.with_gen(gen_str, aio, |gen_packs| {
+            gen_packs.chunks.get(digest).cloned()
+        })
+    }
+
+    /// All chunks packed in `gen_str`
+    pub(crate) fn chunks(
+        &self,
+        gen_str: &str,
+        aio: &aio::AsyncIO,
+    ) -> io::Result<Vec<(Vec<u8>, PackedChunk)>> {
+        self.with_gen(gen_str, aio, |gen_packs| {
+            gen_packs
+                .chunks
+                .iter()
+                .map(|(digest, packed)| (digest.clone(), packed.clone()))
+                .collect()
+        })
+    }
+
+    /// Ids and sizes of packs in `gen_str`
+    fn packs(
+        &self,
+        gen_str: &str,
+        aio: &aio::AsyncIO,
+    ) -> io::Result<Vec<(String, u64)>> {
+        self.with_gen(gen_str, aio, |gen_packs| {
+            gen_packs
+                .packs
+                .iter()
+                .map(|(id, size)| (id.clone(), *size))
+                .collect()
+        })
+    }
+
+    fn insert(
+        &self,
+        gen_str: &str,
+        id: &str,
+        entries: Vec<(Vec<u8>, u64, u64)>,
+    ) {
+        if let Some(gen_packs) = self.gens.lock().unwrap().get_mut(gen_str) {
+            gen_packs.insert(gen_str, id, entries);
+        }
+    }
+
+    /// Forget what's loaded for `gen_str`
+    pub(crate) fn invalidate(&self, gen_str: &str) {
+        self.gens.lock().unwrap().remove(gen_str);
+    }
+
+    /// Reload indexes of `gen_strs` if there are any new packs
+    ///
+    /// Returns `false` if nothing changed.
+    pub(crate) fn refresh(
+        &self,
+        gen_strs: &[String],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<bool> {
+        let mut changed = false;
+        for gen_str in gen_strs {
+            let list = substitute_err_not_found(
+                aio.list(pack_dir(gen_str)).wait(),
+                Vec::new,
+            )?;
+            let ids: HashSet<String> = list
+                .iter()
+                .filter(|path| path.extension() == Some(INDEX_EXT.as_ref()))
+                .filter_map(|path| path.file_stem())
+                .map(|id| id.to_string_lossy().to_string())
+                .collect();
+            let mut gens = self.gens.lock().unwrap();
+            let up_to_date = gens.get(gen_str).map(|gen_packs| {
+                gen_packs.packs.len() == ids.len()
+                    && ids.iter().all(|id| gen_packs.packs.contains_key(id))
+            });
+            if up_to_date != Some(true) {
+                gens.insert(gen_str.clone(), GenPacks::load(gen_str, aio)?);
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Chunks waiting to be written in a pack
+#[derive(Default)]
+struct Pending {
+    chunks: Vec<(Vec<u8>, SGData)>,
+    digests: HashSet<Vec<u8>>,
+    size: u64,
+}
+
+/// Writes chunks into packs of one generation
+///
+/// Can be used by many threads at once. Chunks added are only stored once
+/// the pack they are in is written: when it gets full, or on `finish`.
+pub(crate) struct PackWriter {
+    aio: aio::AsyncIO,
+    hasher: ArcHasher,
+    index: Arc<PackIndex>,
+    gen_str: String,
+    packing: Packing,
+    /// Stats to record added chunks in, as if they were written alone
+    stats: Option<aio::AsyncIOThreadShared>,
+    pending: Mutex<Pending>,
+    written: Mutex<Vec<String>>,
+    /// Read packs back after writing them, see `WriteOptions::verify_writes`
+    verify_writes: bool,
+}
+
+impl PackWriter {
+    pub(crate) fn new(
+        aio: aio::AsyncIO,
+        hasher: ArcHasher,
+        index: Arc<PackIndex>,
+        gen_str: String,
+        packing: Packing,
+        stats: Option<aio::AsyncIOThreadShared>,
+    ) -> Self {
+        PackWriter {
+            aio,
+            hasher,
+            index,
+            gen_str,
+            packing,
+            stats,
+            pending: Mutex::new(Pending::default()),
+            written: Mutex::new(vec![]),
+            verify_writes: false,
+        }
+    }
+
+    pub(crate) fn with_verify_writes(mut self, verify_writes: bool) -> Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    /// Should a chunk of `len` bytes (as stored) be packed
+    pub(crate) fn fits(&self, len: u64) -> bool {
+        len <= self.packing.max_chunk_size
+    }
+
+    pub(crate) fn add(&self, digest: &[u8], sg: SGData) -> io::Result<()> {
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            if !pending.digests.insert(digest.to_vec()) {
+                return Ok(());
+            }
+            pending.size += sg.len() as u64;
+            pending.chunks.push((digest.to_vec(), sg));
+            if pending.size < self.packing.pack_size {
+                return Ok(());
+            }
+            mem::replace(&mut *pending, Pending::default())
+        };
+        self.write_pack(full.chunks)
+    }
+
+    /// Write the pack with the chunks added so far
+    ///
+    /// Returns ids of all the packs written.
+    pub(crate) fn finish(&self) -> io::Result<Vec<String>> {
+        let mut pending = self.pending.lock().unwrap();
+        let rest = mem::replace(&mut *pending, Pending::default());
+        drop(pending);
+        if !rest.chunks.is_empty() {
+            self.write_pack(rest.chunks)?;
+        }
+        Ok(self.written.lock().unwrap().clone())
+    }
+
+    fn write_pack(&self, chunks: Vec<(Vec<u8>, SGData)>) -> io::Result<()> {
+        let mut index = INDEX_MAGIC.to_vec();
+        let mut entries = Vec::with_capacity(chunks.len());
+        let mut data = SGData::empty();
+        let mut offset = 0u64;
+        for (digest, sg) in chunks {
+            let len = sg.len() as u64;
+            index.extend_from_slice(&digest);
+            index.extend_from_slice(&offset.to_be_bytes());
+            index.extend_from_slice(&len.to_be_bytes());
+            if let Some(ref stats) = self.stats {
+                stats.record_write(len);
+            }
+            entries.push((digest, offset, len));
+            for part in sg.as_parts() {
+                data.push_arcref(part.clone());
+            }
+            offset += len;
+        }
+
+        let id = hex::encode(self.hasher.calculate_digest_simple(&index));
+        let path = pack_dir(&self.gen_str).join(&id);
+        let index = SGData::from_single(index);
+        if self.verify_writes {
+            self.aio
+                .write_verified(path.with_extension(PACK_EXT), data)?;
+            self.aio
+                .write_verified(path.with_extension(INDEX_EXT), index)?;
+        } else {
+            self.aio
+                .write_idempotent(path.with_extension(PACK_EXT), data)
+                .wait()?;
+            self.aio
+                .write_idempotent(path.with_extension(INDEX_EXT), index)
+                .wait()?;
+        }
+        self.index.insert(&self.gen_str, &id, entries);
+        self.written.lock().unwrap().push(id);
+        Ok(())
+    }
+}
+
+/// Packs in `gen_str` worth merging: smaller than half of the pack size,
+/// if there's more than one
+pub(crate) fn undersized_packs(
+    index: &PackIndex,
+    gen_str: &str,
+    packing: &Packing,
+    aio: &aio::AsyncIO,
+) -> io::Result<Vec<String>> {
+    let undersized: Vec<_> = index
+        .packs(gen_str, aio)?
+        .into_iter()
+        .filter(|&(_, size)| size < packing.pack_size / 2)
+        .map(|(id, _)| id)
+        .collect();
+    Ok(if undersized.len() > 1 {
+        undersized
+    } else {
+        vec![]
+    })
+}
+
+/// Read whole pack `id` of `gen_str`, split into chunks
+pub(crate) fn read_pack(
+    gen_str: &str,
+    id: &str,
+    aio: &aio::AsyncIO,
+) -> io::Result<Vec<(Vec<u8>, SGData)>> {
+    let path = pack_dir(gen_str).join(id);
+    let index = aio.read(path.with_extension(INDEX_EXT)).wait()?;
+    let data = aio.read(path.with_extension(PACK_EXT)).wait()?;
+    let data = data.to_linear_vec();
+    parse_index(&index.to_linear_vec())?
+        .into_iter()
+        .map(|(digest, offset, len)| {
+            let end = offset.saturating_add(len);
+            if end > data.len() as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("pack {} is truncated", id),
+                ));
+            }
+            let chunk = data[offset as usize..end as usize].to_vec();
+            Ok((digest, SGData::from_single(chunk)))
+        })
+        .collect()
+}
+
+/// Packs of `gen_str` without an index (of writes that never completed)
+pub(crate) fn incomplete_packs(
+    gen_str: &str,
+    aio: &aio::AsyncIO,
+) -> io::Result<Vec<PathBuf>> {
+    let list = substitute_err_not_found(
+        aio.list(pack_dir(gen_str)).wait(),
+        Vec::new,
+    )?;
+    let names: HashSet<_> =
+        list.iter().filter_map(|path| path.file_name()).collect();
+    let mut incomplete: Vec<_> = list
+        .iter()
+        .filter(|path| path.extension() == Some(PACK_EXT.as_ref()))
+        .filter_map(|path| path.file_name())
+        .filter(|name| {
+            let index = PathBuf::from(name).with_extension(INDEX_EXT);
+            !names.contains(index.as_os_str())
+        })
+        .map(|name| pack_dir(gen_str).join(name))
+        .collect();
+    incomplete.sort();
+    Ok(incomplete)
+}
+
+/// Remove pack `id` of `gen_str`, index first
+pub(crate) fn remove_pack(
+    gen_str: &str,
+    id: &str,
+    aio: &aio::AsyncIO,
+) -> io::Result<()> {
+    let path = pack_dir(gen_str).join(id);
+    substitute_err_not_found(
+        aio.remove(path.with_extension(INDEX_EXT)).wait(),
+        || (),
+    )?;
+    substitute_err_not_found(
+        aio.remove(path.with_extension(PACK_EXT)).wait(),
+        || (),
+    )
+}
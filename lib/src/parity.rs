@@ -0,0 +1,293 @@
+//! Parity of chunk files, for repairing them
+//!
+//! With parity enabled, every chunk stored in a file gets a parity file
+//! next to it (`<digest>.par`). The chunk, as stored, is split into
+//! `data_shards` shards, and `parity_shards` Reed-Solomon parity shards
+//! are calculated from them, so any `parity_shards` shards of the chunk
+//! can be rebuilt from the rest. Hashes of all the shards tell which ones
+//! are damaged:
+//!
+//! ```norust
+//! magic "rdpr" | version | data shards | parity shards | chunk length
+//! | shard hashes | header hash | parity shards
+//! ```
+//!
+//! The length is 8 bytes (little endian), hashes are truncated SHA-256
+//! (8 bytes each); the header hash covers everything before it. Chunks in
+//! packs have no parity.
+use hashing::{Hasher, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"rdpr";
+const VERSION: u8 = 1;
+const EXT: &str = "par";
+const HASH_LEN: usize = 8;
+/// Magic, version, shard counts and chunk length
+const PREFIX_LEN: usize = 4 + 3 + 8;
+
+/// Parity settings of a repository, see `Repo::set_parity`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Parity {
+    /// Shards each chunk is split into
+    pub data_shards: u8,
+    /// Parity shards calculated from them: how many damaged shards can be
+    /// rebuilt
+    pub parity_shards: u8,
+}
+
+impl Default for Parity {
+    fn default() -> Self {
+        Parity {
+            data_shards: 8,
+            parity_shards: 2,
+        }
+    }
+}
+
+impl Parity {
+    pub(crate) fn valid(&self) -> bool {
+        self.data_shards > 0
+            && self.parity_shards > 0
+            && usize::from(self.data_shards) + usize::from(self.parity_shards)
+                <= 256
+    }
+
+    /// Parity file of `data`, a chunk as stored
+    pub(crate) fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let k = usize::from(self.data_shards);
+        let m = usize::from(self.parity_shards);
+        let shards = split(data, k);
+        let gf = Gf::new();
+        let parity: Vec<Vec<u8>> = (k..k + m)
+            .map(|i| combine(&gf, &row(&gf, i, k), &shards))
+            .collect();
+
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&[
+            VERSION,
+            self.data_shards,
+            self.parity_shards,
+        ]);
+        file.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        for shard in shards.iter().chain(&parity) {
+            file.extend_from_slice(&hash(shard));
+        }
+        let header_hash = hash(&file);
+        file.extend_from_slice(&header_hash);
+        for shard in &parity {
+            file.extend_from_slice(shard);
+        }
+        file
+    }
+}
+
+/// Parity file of chunk file `path`
+pub(crate) fn path(chunk_path: &Path) -> PathBuf {
+    chunk_path.with_extension(EXT)
+}
+
+/// `data`, a chunk as stored, repaired using its parity file `parity`
+///
+/// Returns `None` if `data` is intact. Fails with `InvalidData` if too
+/// many shards are damaged, or the parity file itself is.
+pub(crate) fn repair(
+    parity: &[u8],
+    data: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if parity.len() < PREFIX_LEN
+        || &parity[..MAGIC.len()] != MAGIC
+        || parity[MAGIC.len()] != VERSION
+    {
+        return Err(invalid("not a valid parity file"));
+    }
+    let k = usize::from(parity[5]);
+    let m = usize::from(parity[6]);
+    let mut len = [0u8; 8];
+    len.copy_from_slice(&parity[7..PREFIX_LEN]);
+    let len = u64::from_le_bytes(len) as usize;
+    let header_len = PREFIX_LEN + (k + m) * HASH_LEN;
+    if k == 0
+        || parity.len() < header_len + HASH_LEN
+        || parity[header_len..header_len + HASH_LEN]
+            != hash(&parity[..header_len])[..]
+    {
+        return Err(invalid("parity file is damaged"));
+    }
+    let shard_len = shard_len(len, k);
+    if parity.len() != header_len + HASH_LEN + m * shard_len {
+        return Err(invalid("parity file is damaged"));
+    }
+    if len == 0 {
+        return Ok(if data.is_empty() { None } else { Some(vec![]) });
+    }
+    let hashes: Vec<&[u8]> =
+        parity[PREFIX_LEN..header_len].chunks(HASH_LEN).collect();
+    let parity_shards = parity[header_len + HASH_LEN..]
+        .chunks(shard_len)
+        .map(|shard| shard.to_vec());
+
+    let mut resized = data.to_vec();
+    resized.resize(len, 0);
+    let shards: Vec<Vec<u8>> = split(&resized, k)
+        .into_iter()
+        .chain(parity_shards)
+        .collect();
+    let good: Vec<usize> = (0..k + m)
+        .filter(|&i| hash(&shards[i])[..] == *hashes[i])
+        .collect();
+    if good.iter().take_while(|&&i| i < k).count() == k {
+        // only the length is wrong, if anything
+        return Ok(if data.len() == len { None } else { Some(resized) });
+    }
+    if good.len() < k {
+        return Err(invalid("too many shards damaged to repair"));
+    }
+
+    let gf = Gf::new();
+    let chosen = &good[..k];
+    let matrix: Vec<Vec<u8>> = chosen.iter().map(|&i| row(&gf, i, k)).collect();
+    let inverse = invert(&gf, matrix);
+    let available: Vec<Vec<u8>> =
+        chosen.iter().map(|&i| shards[i].clone()).collect();
+    let mut repaired = vec![];
+    for (j, coefficients) in inverse.iter().enumerate() {
+        let shard = combine(&gf, coefficients, &available);
+        if hash(&shard)[..] != *hashes[j] {
+            return Err(invalid("repaired shard doesn't match its hash"));
+        }
+        repaired.extend_from_slice(&shard);
+    }
+    repaired.truncate(len);
+    Ok(Some(repaired))
+}
+
+fn hash(data: &[u8]) -> Vec<u8> {
+    Sha256.calculate_digest_simple(data)[..HASH_LEN].to_vec()
+}
+
+fn shard_len(len: usize, k: usize) -> usize {
+    (len + k - 1) / k
+}
+
+/// `data` split into `k` shards of the same length, padded with zeros
+fn split(data: &[u8], k: usize) -> Vec<Vec<u8>> {
+    let shard_len = shard_len(data.len(), k);
+    (0..k)
+        .map(|i| {
+            let start = (i * shard_len).min(data.len());
+            let end = ((i + 1) * shard_len).min(data.len());
+            let mut shard = data[start..end].to_vec();
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect()
+}
+
+/// Coefficients of shard `i` in terms of the `k` data shards
+///
+/// Data shards are themselves; parity shards are rows of a Cauchy matrix,
+/// so any `k` rows are linearly independent.
+fn row(gf: &Gf, i: usize, k: usize) -> Vec<u8> {
+    (0..k)
+        .map(|j| {
+            if i < k {
+                u8::from(i == j)
+            } else {
+                gf.inv(i as u8 ^ j as u8)
+            }
+        })
+        .collect()
+}
+
+/// Sum of `shards` multiplied by `coefficients`
+fn combine(gf: &Gf, coefficients: &[u8], shards: &[Vec<u8>]) -> Vec<u8> {
+    let mut result = vec![0u8; shards.first().map_or(0, |s| s.len())];
+    for (&c, shard) in coefficients.iter().zip(shards) {
+        let table = gf.mul_table(c);
+        for (r, &b) in result.iter_mut().zip(shard) {
+            *r ^= table[usize::from(b)];
+        }
+    }
+    result
+}
+
+/// Inverse of square, invertible `matrix` (Gauss-Jordan elimination)
+fn invert(gf: &Gf, mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|i| (0..n).map(|j| u8::from(i == j)).collect())
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n)
+            .find(|&r| matrix[r][col] != 0)
+            .expect("matrix not invertible");
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = gf.inv(matrix[col][col]);
+        for j in 0..n {
+            matrix[col][j] = gf.mul(matrix[col][j], scale);
+            inverse[col][j] = gf.mul(inverse[col][j], scale);
+        }
+        for r in (0..n).filter(|&r| r != col) {
+            let factor = matrix[r][col];
+            if factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                matrix[r][j] ^= gf.mul(factor, matrix[col][j]);
+                inverse[r][j] ^= gf.mul(factor, inverse[col][j]);
+            }
+        }
+    }
+    inverse
+}
+
+/// Arithmetic in GF(2^8), with the polynomial 0x11d
+struct Gf {
+    exp: [u8; 510],
+    log: [u8; 256],
+}
+
+impl Gf {
+    fn new() -> Self {
+        let mut gf = Gf {
+            exp: [0; 510],
+            log: [0; 256],
+        };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.exp[i + 255] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[usize::from(self.log[usize::from(a)])
+            + usize::from(self.log[usize::from(b)])]
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        assert!(a != 0);
+        self.exp[255 - usize::from(self.log[usize::from(a)])]
+    }
+
+    /// Products of `c` and every byte
+    fn mul_table(&self, c: u8) -> [u8; 256] {
+        let mut table = [0; 256];
+        for (b, product) in table.iter_mut().enumerate() {
+            *product = self.mul(c, b as u8);
+        }
+        table
+    }
+}
@@ -0,0 +1,146 @@
+//! Progress of long writes and reads, see `WriteOptions::progress` and
+//! `ReadOptions::progress`
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use {ChunkAccessor, DataType, DigestRef, Repo};
+
+/// Progress of a write or a read, so far
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProgressInfo {
+    /// Bytes of data processed
+    pub bytes: u64,
+    /// Bytes of all the data, if known (when reading)
+    pub total: Option<u64>,
+    /// Data chunks processed
+    pub chunks: u64,
+    /// Data chunks that were already stored (when writing)
+    pub dedup_hits: u64,
+}
+
+/// Receiver of progress updates, after every data chunk
+///
+/// Called from the threads processing the chunks, so it should return
+/// quickly (eg. rendering a progress bar only every now and then).
+pub trait Progress: Send + Sync {
+    fn update(&self, info: &ProgressInfo);
+}
+
+impl<F> Progress for F
+where
+    F: Fn(&ProgressInfo) + Send + Sync,
+{
+    fn update(&self, info: &ProgressInfo) {
+        self(info)
+    }
+}
+
+impl fmt::Debug for dyn Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Counts of an operation, shared by its threads
+pub(crate) struct ProgressCounter {
+    progress: Arc<dyn Progress>,
+    total: Option<u64>,
+    bytes: AtomicU64,
+    chunks: AtomicU64,
+    dedup_hits: AtomicU64,
+}
+
+impl ProgressCounter {
+    pub(crate) fn new(progress: Arc<dyn Progress>, total: Option<u64>) -> Self {
+        ProgressCounter {
+            progress,
+            total,
+            bytes: AtomicU64::new(0),
+            chunks: AtomicU64::new(0),
+            dedup_hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Data chunk of `len` bytes processed
+    pub(crate) fn chunk_done(&self, len: u64, dedup_hit: bool) {
+        let bytes = self.bytes.fetch_add(len, Ordering::SeqCst) + len;
+        let chunks = self.chunks.fetch_add(1, Ordering::SeqCst) + 1;
+        let dedup_hits = if dedup_hit {
+            self.dedup_hits.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            self.dedup_hits.load(Ordering::SeqCst)
+        };
+        self.progress.update(&ProgressInfo {
+            bytes,
+            total: self.total,
+            chunks,
+            dedup_hits,
+        })
+    }
+}
+
+/// `ChunkAccessor` counting the data chunks read by another one
+pub(crate) struct ProgressChunkAccessor<'a> {
+    inner: &'a dyn ChunkAccessor,
+    counter: &'a ProgressCounter,
+}
+
+impl<'a> ProgressChunkAccessor<'a> {
+    pub(crate) fn new(
+        inner: &'a dyn ChunkAccessor,
+        counter: &'a ProgressCounter,
+    ) -> Self {
+        ProgressChunkAccessor { inner, counter }
+    }
+}
+
+impl<'a> ChunkAccessor for ProgressChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.inner.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        if data_type != DataType::Data {
+            return self.inner.read_chunk_into(digest, data_type, writer);
+        }
+        let mut writer = CountingWriter { inner: writer, count: 0 };
+        self.inner.read_chunk_into(digest, data_type, &mut writer)?;
+        self.counter.chunk_done(writer.count, false);
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.inner.touch(digest)
+    }
+
+    fn touch_data(
+        &self,
+        digest: DigestRef,
+        len: Option<u64>,
+    ) -> io::Result<()> {
+        self.inner.touch_data(digest, len)
+    }
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    count: u64,
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,121 @@
+//! Retention policies for periodically stored names (backups)
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, Utc};
+use std::collections::HashSet;
+
+/// Which names to keep when pruning
+///
+/// A name is kept if any of the rules keeps it. Calendar rules keep the
+/// newest name of each of the last N days/weeks/months (in UTC) that have
+/// any. `Default` keeps nothing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    /// Only consider names starting with this prefix; others are kept
+    pub prefix: Option<String>,
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+/// Names kept and removed by pruning
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PruneResults {
+    pub kept: Vec<String>,
+    pub removed: Vec<String>,
+    /// Names considered, but with no known time, which are always kept
+    pub undated: Vec<String>,
+}
+
+/// Last `len` bytes of `s`, if it has them
+fn suffix(s: &str, len: usize) -> Option<&str> {
+    if s.len() >= len && s.is_char_boundary(s.len() - len) {
+        Some(&s[s.len() - len..])
+    } else {
+        None
+    }
+}
+
+/// Time encoded at the end of the name, like `home-2019-01-31` or
+/// `home-2019-01-31T12:00:00Z`
+pub(crate) fn time_from_name_suffix(name: &str) -> Option<DateTime<Utc>> {
+    if let Some(time) = suffix(name.trim_end_matches('Z'), 19).and_then(|s| {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S").ok()
+    }) {
+        return Some(DateTime::from_utc(time, Utc));
+    }
+    suffix(name, 10)
+        .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok())
+        .map(|date| DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+}
+
+impl RetentionPolicy {
+    /// Would the policy remove all the names it considers
+    pub fn keeps_nothing(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        self.prefix
+            .as_ref()
+            .map(|prefix| name.starts_with(prefix.as_str()))
+            .unwrap_or(true)
+    }
+
+    /// Split `names` (with their time) into kept and removed ones
+    pub(crate) fn apply(
+        &self,
+        mut names: Vec<(String, DateTime<Utc>)>,
+    ) -> (Vec<String>, Vec<String>) {
+        // newest first
+        names.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut keep = HashSet::new();
+        for (name, _) in names.iter().take(self.keep_last) {
+            keep.insert(name.clone());
+        }
+        keep_periodic(&names, self.keep_daily, &mut keep, |t| {
+            (t.year(), t.ordinal())
+        });
+        keep_periodic(&names, self.keep_weekly, &mut keep, |t| {
+            let week = t.iso_week();
+            (week.year(), week.week())
+        });
+        keep_periodic(&names, self.keep_monthly, &mut keep, |t| {
+            (t.year(), t.month())
+        });
+
+        let (kept, removed): (Vec<_>, Vec<_>) =
+            names.into_iter().partition(|(name, _)| keep.contains(name));
+        (
+            kept.into_iter().map(|(name, _)| name).collect(),
+            removed.into_iter().map(|(name, _)| name).collect(),
+        )
+    }
+}
+
+/// Keep the newest name in each of the last `count` periods
+fn keep_periodic<F>(
+    names: &[(String, DateTime<Utc>)],
+    count: usize,
+    keep: &mut HashSet<String>,
+    period: F,
+) where
+    F: Fn(&DateTime<Utc>) -> (i32, u32),
+{
+    let mut last_period = None;
+    let mut kept = 0;
+    for (name, time) in names {
+        if kept == count {
+            break;
+        }
+        let cur_period = Some(period(time));
+        if cur_period != last_period {
+            keep.insert(name.clone());
+            last_period = cur_period;
+            kept += 1;
+        }
+    }
+}
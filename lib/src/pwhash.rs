@@ -36,6 +36,15 @@ impl SodiumOxide {
         }
     }
 
+    /// Interactive strength, with a given salt (eg. to produce
+    /// reproducible output)
+    pub(crate) fn new_interactive_with_salt(salt: pwhash::Salt) -> Self {
+        Self {
+            salt,
+            ..Self::new_interactive()
+        }
+    }
+
     pub(crate) fn new_sensitive() -> Self {
         Self {
             ops_limit: pwhash::OPSLIMIT_SENSITIVE.0 as u64,
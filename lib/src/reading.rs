@@ -1,152 +1,152 @@
 //! Primitives used for reading the chunked data stored in the `Repo`
 // {{{ use and mod
+use decoded_cache::DecodedCache;
+use error;
+use hashing::{ArcHasher, DigestWriter};
 use hex;
+use in_flight::Fetched;
+use sgdata::SGData;
 use slog::{FnValue, Logger};
-use std;
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
 use Generation;
-use VerifyResults;
-use {ArcCompression, ArcDecrypter};
-use {DataAddressRef, DataType, Digest, DigestRef, Error, Repo, DIGEST_SIZE};
+use {DuResults, LossyReadResults, SkippedChunk, VerifyResults};
+use {ArcCompression, ArcDecrypter, DecryptHandle};
+use {ChunkLocation, DataAddressRef, DataType, Digest, DigestRef, Error, Repo};
+use Reseal;
+use index;
+use MAX_INDEX_LEVEL;
 // }}}
 
-/// Translates index stream into data stream
-///
-/// This type implements `io::Write` and interprets what's written to it as a
-/// stream of digests.
-///
-/// For every digest written to it, it will access the corresponding chunk and
-/// write it into `writer` that it wraps.
-struct IndexTranslator<'a, 'b> {
-    writer: Option<&'b mut dyn Write>,
-    digest_buf: Digest,
+/// Information specific to a given read operation
+/// of a data in the Repo
+pub(crate) struct ReadRequest<'a> {
+    data_address: DataAddressRef<'a>,
     data_type: DataType,
-    read_context: &'a ReadContext<'a>,
+    writer: Option<&'a mut dyn Write>,
+    /// Only the data from the first, up to the second offset
+    range: Option<(u64, u64)>,
+    /// Most bytes of index records to hold at once
+    index_budget: Option<u64>,
     log: Logger,
 }
 
-impl<'a, 'b> IndexTranslator<'a, 'b> {
+impl<'a> ReadRequest<'a> {
     pub(crate) fn new(
-        writer: Option<&'b mut dyn Write>,
         data_type: DataType,
-        read_context: &'a ReadContext<'a>,
+        data_address: DataAddressRef<'a>,
+        writer: Option<&'a mut dyn Write>,
         log: Logger,
     ) -> Self {
-        IndexTranslator {
+        ReadRequest {
             data_type,
-            digest_buf: Digest(Vec::with_capacity(DIGEST_SIZE)),
-            read_context,
+            data_address,
             writer,
+            range: None,
+            index_budget: None,
             log,
         }
     }
-}
 
-impl<'a, 'b> Write for IndexTranslator<'a, 'b> {
-    fn write(&mut self, mut bytes: &[u8]) -> io::Result<usize> {
-        assert!(!bytes.is_empty());
+    /// Read only `len` bytes of the data, starting at `offset`
+    ///
+    /// Data chunks (and whole index chunks) outside of the range are
+    /// skipped, where the index has their lengths.
+    pub(crate) fn range(mut self, offset: u64, len: u64) -> Self {
+        self.range = Some((offset, offset.saturating_add(len)));
+        self
+    }
 
-        let total_len = bytes.len();
-        loop {
-            let has_already = self.digest_buf.0.len();
-            if (has_already + bytes.len()) < DIGEST_SIZE {
-                self.digest_buf.0.extend_from_slice(bytes);
+    /// Fail with `error::Error::MemoryBudgetExceeded` if the index records
+    /// waiting to be read take more than `bytes`
+    ///
+    /// They're at most one index chunk of every level of the index.
+    pub(crate) fn index_budget(mut self, bytes: u64) -> Self {
+        self.index_budget = Some(bytes);
+        self
+    }
+}
 
-                trace!(self.log, "left with a buffer";
-                       "digest" => FnValue(|_| hex::encode(&self.digest_buf.0)),
-                       );
-                return Ok(total_len);
-            }
+/// Digests of one index level (with lengths of their data, if known),
+/// waiting to be read
+struct IndexLevel {
+    /// Digests to read, in order
+    pending: VecDeque<(Vec<u8>, Option<u64>)>,
+    /// Index data read so far, that doesn't make a whole record yet
+    ///
+    /// Index chunk edges don't have to align with records (see `index`).
+    partial: Vec<u8>,
+}
 
-            let &mut IndexTranslator {
-                ref mut digest_buf,
-                data_type,
-                ref mut writer,
-                read_context,
-                ..
-            } = self;
-            let needs = DIGEST_SIZE - has_already;
-
-            if digest_buf.0.is_empty() {
-                let digest = &bytes[..needs];
-                debug_assert_eq!(digest.len(), DIGEST_SIZE);
-                bytes = &bytes[needs..];
-
-                read_context.read_recursively(ReadRequest::new(
-                    data_type,
-                    DataAddressRef {
-                        digest: DigestRef(digest),
-                        index_level: 0,
-                    },
-                    writer.as_mut().map(|w| w as &mut dyn io::Write),
-                    self.log.clone(),
-                ))?;
-            } else {
-                digest_buf.0.extend_from_slice(&bytes[..needs]);
-                debug_assert_eq!(digest_buf.0.len(), DIGEST_SIZE);
-                bytes = &bytes[needs..];
-
-                let res = read_context.read_recursively(ReadRequest::new(
-                    data_type,
-                    DataAddressRef {
-                        digest: digest_buf.as_digest_ref(),
-                        index_level: 0,
-                    },
-                    writer.as_mut().map(|w| w as &mut dyn io::Write),
-                    self.log.clone(),
-                ));
-                digest_buf.0.clear();
-                res?;
-            }
+impl IndexLevel {
+    fn new() -> Self {
+        IndexLevel {
+            pending: VecDeque::new(),
+            partial: vec![],
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    /// Move all the whole records from `partial` to `pending`
+    fn take_records(&mut self, record_size: usize) {
+        let whole = self.partial.len() - self.partial.len() % record_size;
+        self.pending.extend(
+            self.partial[..whole]
+                .chunks(record_size)
+                .map(index::parse_record),
+        );
+        self.partial.drain(..whole);
     }
-}
 
-impl<'a, 'b> Drop for IndexTranslator<'a, 'b> {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            debug_assert_eq!(self.digest_buf.0.len(), 0);
-        }
+    /// Bytes of records held, about
+    fn buffered(&self, record_size: usize) -> u64 {
+        (self.pending.len() * record_size + self.partial.len()) as u64
     }
 }
 
-/// Information specific to a given read operation
-/// of a data in the Repo
-pub(crate) struct ReadRequest<'a> {
-    data_address: DataAddressRef<'a>,
-    data_type: DataType,
-    writer: Option<&'a mut dyn Write>,
-    log: Logger,
+/// Writer passing on only `len` bytes, after skipping `skip` bytes
+pub(crate) struct RangeWriter<'a> {
+    inner: &'a mut dyn Write,
+    skip: u64,
+    len: u64,
+    /// Bytes written to it so far, including the ones not passed on
+    written: u64,
 }
 
-impl<'a> ReadRequest<'a> {
-    pub(crate) fn new(
-        data_type: DataType,
-        data_address: DataAddressRef<'a>,
-        writer: Option<&'a mut dyn Write>,
-        log: Logger,
-    ) -> Self {
-        ReadRequest {
-            data_type,
-            data_address,
-            writer,
-            log,
+impl<'a> RangeWriter<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Write, skip: u64, len: u64) -> Self {
+        RangeWriter {
+            inner,
+            skip,
+            len,
+            written: 0,
         }
     }
 }
 
+impl<'a> Write for RangeWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = self.skip.saturating_sub(self.written);
+        let end = (self.skip + self.len).saturating_sub(self.written);
+        let part = &buf[(start.min(buf.len() as u64) as usize)
+            ..(end.min(buf.len() as u64) as usize)];
+        self.inner.write_all(part)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// Read Context
 ///
 /// Information about the `Repo` that is open for reaading
 pub(crate) struct ReadContext<'a> {
-    /// Writer to write the data to; `None` will discard the data
     accessor: &'a dyn ChunkAccessor,
 }
 
@@ -155,56 +155,132 @@ impl<'a> ReadContext<'a> {
         ReadContext { accessor }
     }
 
-    fn on_index(&self, mut req: ReadRequest) -> io::Result<()> {
-        trace!(req.log, "Traversing index";
-               "digest" => FnValue(|_| hex::encode(req.data_address.digest.0)),
-               );
-
-        let mut translator = IndexTranslator::new(
-            req.writer.take(),
-            req.data_type,
-            self,
-            req.log.clone(),
-        );
+    /// Read all the data chunks of `req`, in order
+    ///
+    /// Data stored with `index_level` N is a stream of digests of data
+    /// chunks, stored as a stream of index chunks with `index_level` N-1,
+    /// and so on, down to the single chunk of `index_level` 0. The tree is
+    /// walked depth-first with an explicit stack of levels, so the depth
+    /// of the call stack doesn't depend on the data.
+    ///
+    /// With a `range`, chunks before it are skipped (or, where the index
+    /// doesn't have their lengths, read but not written), and the traversal
+    /// stops at its end.
+    pub(crate) fn traverse(&self, req: ReadRequest) -> io::Result<()> {
+        let ReadRequest {
+            data_address,
+            data_type,
+            mut writer,
+            range,
+            index_budget,
+            log,
+        } = req;
+        let record_size =
+            index::record_size(self.accessor.repo().config.index_lengths);
+        let root = data_address.digest;
 
-        let da = DataAddressRef {
-            digest: req.data_address.digest,
-            index_level: req.data_address.index_level - 1,
-        };
-        let req = ReadRequest::new(
-            DataType::Index,
-            da,
-            Some(&mut translator),
-            req.log,
-        );
-        self.read_recursively(req)
-    }
-
-    fn on_data(&self, mut req: ReadRequest) -> io::Result<()> {
-        trace!(req.log, "Traversing data";
-               "digest" => FnValue(|_| hex::encode(req.data_address.digest.0)),
-               );
-        if let Some(writer) = req.writer.take() {
-            self.accessor.read_chunk_into(
-                req.data_address.digest,
-                req.data_type,
-                writer,
-            )
-        } else {
-            self.accessor.touch(req.data_address.digest)
+        if data_address.index_level > MAX_INDEX_LEVEL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "index level {} of {} exceeds the limit of {}",
+                    data_address.index_level,
+                    hex::encode(root.0),
+                    MAX_INDEX_LEVEL
+                ),
+            ));
         }
-    }
 
-    pub(crate) fn read_recursively(&self, req: ReadRequest) -> io::Result<()> {
-        trace!(req.log, "Reading recursively";
-               "digest" => FnValue(|_| hex::encode(req.data_address.digest.0)),
-               );
+        // `levels[n]` are digests of chunks `n` levels above the data
+        let mut levels: Vec<_> = (0..=data_address.index_level)
+            .map(|_| IndexLevel::new())
+            .collect();
+        levels
+            .last_mut()
+            .unwrap()
+            .pending
+            .push_back((root.0.to_vec(), None));
 
-        if req.data_address.index_level == 0 {
-            self.on_data(req)
-        } else {
-            self.on_index(req)
+        // Offset in the data of the next digest
+        let mut position = 0u64;
+
+        // Always continue with the lowest level, to go depth-first
+        while let Some(n) = levels.iter().position(|l| !l.pending.is_empty())
+        {
+            let (digest, len) = levels[n].pending.pop_front().unwrap();
+            let digest = DigestRef(&digest);
+
+            if let Some((start, end)) = range {
+                if position >= end {
+                    return Ok(());
+                }
+                if let Some(len) = len {
+                    if position + len <= start {
+                        position += len;
+                        continue;
+                    }
+                }
+            }
+
+            if n == 0 {
+                trace!(log, "Traversing data";
+                       "digest" => FnValue(|_| hex::encode(digest.0)),
+                       );
+                match (&mut writer, range) {
+                    (Some(ref mut writer), Some((start, end))) => {
+                        let mut range_writer = RangeWriter::new(
+                            &mut **writer,
+                            start.saturating_sub(position),
+                            end - position.max(start),
+                        );
+                        self.accessor.read_chunk_into(
+                            digest,
+                            data_type,
+                            &mut range_writer,
+                        )?;
+                        position += range_writer.written;
+                    }
+                    (Some(ref mut writer), None) => self
+                        .accessor
+                        .read_chunk_into(digest, data_type, &mut **writer)?,
+                    (None, _) => self.accessor.touch_data(digest, len)?,
+                }
+            } else {
+                trace!(log, "Traversing index";
+                       "digest" => FnValue(|_| hex::encode(digest.0)),
+                       "level" => n,
+                       );
+                let lower = &mut levels[n - 1];
+                self.accessor.read_chunk_into(
+                    digest,
+                    DataType::Index,
+                    &mut lower.partial,
+                )?;
+                lower.take_records(record_size);
+                if let Some(budget) = index_budget {
+                    let buffered: u64 =
+                        levels.iter().map(|l| l.buffered(record_size)).sum();
+                    if buffered > budget {
+                        return Err(error::Error::MemoryBudgetExceeded {
+                            digest: root.0.into(),
+                            held: buffered,
+                        }
+                        .into());
+                    }
+                }
+            }
         }
+
+        if levels.iter().any(|l| !l.partial.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "index of {} is not a multiple of the record size",
+                    hex::encode(root.0)
+                ),
+            ));
+        }
+        Ok(())
     }
 }
 
@@ -221,6 +297,16 @@ pub(crate) trait ChunkAccessor {
     ) -> io::Result<()>;
 
     fn touch(&self, _digest: DigestRef) -> io::Result<()>;
+
+    /// Data chunk traversed without reading it, of `len` bytes if the index
+    /// has the length
+    fn touch_data(
+        &self,
+        digest: DigestRef,
+        _len: Option<u64>,
+    ) -> io::Result<()> {
+        self.touch(digest)
+    }
 }
 
 /// `ChunkAccessor` that just reads the chunks as requested, without doing
@@ -228,121 +314,267 @@ pub(crate) trait ChunkAccessor {
 pub(crate) struct DefaultChunkAccessor<'a> {
     repo: &'a Repo,
     decrypter: Option<ArcDecrypter>,
+    /// Hasher of data chunks (see `DecryptHandle`)
+    data_hasher: ArcHasher,
     compression: ArcCompression,
     gen_strings: Vec<String>,
+    /// Data chunks decoded recently, if caching them
+    decoded: Option<RefCell<DecodedCache>>,
 }
 
 impl<'a> DefaultChunkAccessor<'a> {
     pub(crate) fn new(
         repo: &'a Repo,
-        decrypter: Option<ArcDecrypter>,
+        dec: Option<&DecryptHandle>,
         compression: ArcCompression,
         generations: Vec<Generation>,
     ) -> Self {
         DefaultChunkAccessor {
             repo,
-            decrypter,
+            decrypter: dec.map(|dec| Arc::clone(&dec.decrypter)),
+            data_hasher: dec
+                .map(|dec| Arc::clone(&dec.hasher))
+                .unwrap_or_else(|| Arc::clone(&repo.hasher)),
             compression,
             gen_strings: generations.iter().map(|g| g.to_string()).collect(),
+            decoded: None,
         }
     }
+
+    /// Keep up to `capacity` bytes of the data chunks decoded, to pass them
+    /// on again without reading them (zero disables that)
+    pub(crate) fn with_decoded_cache(mut self, capacity: u64) -> Self {
+        self.decoded = if capacity == 0 {
+            None
+        } else {
+            Some(RefCell::new(DecodedCache::new(capacity)))
+        };
+        self
+    }
 }
 
-impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
-    fn repo(&self) -> &Repo {
-        self.repo
+impl<'a> DefaultChunkAccessor<'a> {
+    /// Data of a chunk, verified before it's returned
+    fn read_chunk(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+    ) -> io::Result<SGData> {
+        let mut data = vec![];
+        self.decode_chunk_into(digest, data_type, &mut data)?;
+        Ok(SGData::from_single(data))
     }
 
-    fn read_chunk_into(
+    /// Decompress a chunk into `writer`, verifying it on the way
+    ///
+    /// Only the stored (compressed) chunk is kept in memory, the
+    /// decompressed data is passed on in small pieces. So a corrupted chunk
+    /// is noticed only after its data was written.
+    fn decode_chunk_into(
         &self,
         digest: DigestRef,
         data_type: DataType,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
-        let mut data = None;
-        let cur_gen_str = self.gen_strings.last().unwrap();
-        let mut data_gen_str = None;
+        // fetched once for all the concurrent reads of it, but decrypted by
+        // each with its own key
+        let (data, compressed) =
+            self.repo.fetches.fetch(digest.0, data_type, || {
+                self.fetch_chunk(digest, data_type)
+            })?;
+        let data = self.decrypt_chunk(data, digest, data_type)?;
 
-        for gen_str in self.gen_strings.iter().rev() {
-            let path = self.repo.chunk_rel_path_by_digest(digest, gen_str);
-            match self.repo.aio.read(path).wait() {
-                Ok(d) => {
-                    data = Some(d);
-                    data_gen_str = Some(gen_str);
-                    break;
-                }
-                Err(_e) => {}
+        let hasher = if data_type == DataType::Data {
+            &self.data_hasher
+        } else {
+            &self.repo.hasher
+        };
+        let cache = match self.decoded {
+            Some(ref cache) if data_type == DataType::Data => Some(cache),
+            _ => None,
+        };
+        let mut writer = VerifyingWriter {
+            inner: writer,
+            digest: hasher.digest_writer(),
+            len: 0,
+            copy: cache.map(|_| vec![]),
+            copy_limit: cache.map_or(0, |cache| cache.borrow().capacity()),
+        };
+
+        if compressed {
+            let len = data.len() as u64;
+            self.compression.decompress_into(data, &mut writer)?;
+            self.repo.report_metrics(|metrics| {
+                metrics.decompressed(len, writer.len)
+            });
+        } else {
+            for part in data.as_parts() {
+                writer.write_all(part)?;
             }
         }
 
-        if data.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
-            ));
+        let vec_result = writer.digest.finish();
+        if vec_result != digest.0 {
+            Err(error::Error::ChunkCorrupted {
+                digest: digest.0.into(),
+                reason: format!("data read: {}", hex::encode(vec_result)),
+            }.into())
+        } else {
+            if let (Some(cache), Some(copy)) = (cache, writer.copy) {
+                cache.borrow_mut().insert(digest.0, copy);
+            }
+            Ok(())
         }
+    }
 
-        let data_gen_str = data_gen_str.unwrap();
+    /// Read a chunk, still encrypted and compressed (unless it's stored
+    /// uncompressed, see the flag returned)
+    fn fetch_chunk(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+    ) -> io::Result<Fetched> {
+        let cur_gen_str = self.gen_strings.last().unwrap();
+
+        let found = self.repo.find_chunk_with(&self.gen_strings, |gen_str| {
+            let path = self.repo.chunk_rel_path_by_digest(digest, gen_str);
+            if let Ok(data) = self.repo.aio.read(path.clone()).wait() {
+                let len = data.len() as u64;
+                return Ok(Some((ChunkLocation::File(path, len), data)));
+            }
+            let location =
+                match self.repo.packs.find(gen_str, digest.0, &self.repo.aio)? {
+                    Some(packed) => ChunkLocation::Packed(packed),
+                    None => return Ok(None),
+                };
+            // pack might have been just repacked; look again after refresh
+            Ok(self
+                .repo
+                .read_chunk_at(&location)
+                .ok()
+                .map(|data| (location, data)))
+        })?;
 
-        if cur_gen_str != data_gen_str {
-            let data_gen_path =
-                self.repo.chunk_rel_path_by_digest(digest, data_gen_str);
-            let cur_gen_path =
-                self.repo.chunk_rel_path_by_digest(digest, cur_gen_str);
+        let (data_gen, (location, data)) = match found {
+            Some(found) => found,
+            None => {
+                return Err(error::Error::ChunkNotFound(digest.0.into()).into())
+            }
+        };
 
-            // `rename` is best effort
+        // Read-only repository can't be modified; the chunk will be moved
+        // by the next `gc` run on a writable copy instead
+        if cur_gen_str != &self.gen_strings[data_gen] && !self.repo.read_only
+        {
+            // moving is best effort
             //
             // Should we fail if we're GCing, and we want to make sure
             // everything reachable has been moved? Well, if it wa
-            let res = self
-                .repo
-                .aio
-                .rename(data_gen_path.clone(), cur_gen_path.clone())
-                .wait();
+            let res = self.repo.move_chunk_to_gen(
+                digest,
+                &location,
+                Some(data.clone()),
+                cur_gen_str,
+            );
             if let Err(e) = res {
-                if e.kind() != io::ErrorKind::NotFound {
-                    warn!(self.repo.log, "Couldn't move chunk to the current generation";
-                          "src-path" => data_gen_path.display(),
-                          "dst-path" => cur_gen_path.display(),
-                          "err" => %e);
-                    return Err(e);
-                }
+                warn!(self.repo.log, "Couldn't move chunk to the current generation";
+                      "digest" => FnValue(|_| hex::encode(digest.0)),
+                      "src-gen" => &self.gen_strings[data_gen],
+                      "dst-gen" => cur_gen_str,
+                      "err" => %e);
+                return Err(e);
             }
         }
 
-        let data = data.unwrap();
-        let data = if data_type.should_encrypt() {
-            self.decrypter
-                .as_ref()
-                .expect("Decrypter expected")
-                .decrypt(data, digest.0)?
-        } else {
-            data
-        };
+        self.repo.without_chunk_header(data, data_type, digest)
+    }
 
-        let data = if data_type.should_compress() {
-            self.compression.decompress(data)?
-        } else {
-            data
-        };
+    /// Decrypt a chunk `fetch_chunk` read, with the key of this reader
+    fn decrypt_chunk(
+        &self,
+        data: SGData,
+        digest: DigestRef,
+        data_type: DataType,
+    ) -> io::Result<SGData> {
+        if !data_type.should_encrypt() {
+            return Ok(data);
+        }
+        let start = Instant::now();
+        let data = self
+            .decrypter
+            .as_ref()
+            .expect("Decrypter expected")
+            .decrypt(data, digest.0)?;
+        self.repo
+            .report_metrics(|metrics| metrics.decrypted(start.elapsed()));
+        Ok(data)
+    }
+}
 
-        let vec_result = self.repo.hasher.calculate_digest(&data);
+/// Writer passing the data on, while calculating its digest
+struct VerifyingWriter<'a> {
+    inner: &'a mut dyn Write,
+    digest: Box<dyn DigestWriter>,
+    /// Bytes written so far
+    len: u64,
+    /// Copy of the data written, if it's going to be cached
+    copy: Option<Vec<u8>>,
+    /// Data longer than this isn't copied, as it couldn't be cached
+    copy_limit: u64,
+}
 
-        if vec_result != digest.0 {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "{} corrupted, data read: {}",
-                    hex::encode(digest.0),
-                    hex::encode(vec_result)
-                ),
-            ))
+impl<'a> Write for VerifyingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.digest.write_all(&buf[..len])?;
+        let fits = match self.copy {
+            Some(ref copy) => (copy.len() + len) as u64 <= self.copy_limit,
+            None => false,
+        };
+        if fits {
+            if let Some(ref mut copy) = self.copy {
+                copy.extend_from_slice(&buf[..len]);
+            }
         } else {
-            for part in data.as_parts() {
-                writer.write_all(&*part)?;
+            self.copy = None;
+        }
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.repo
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        if data_type == DataType::Data {
+            if let Some(ref cache) = self.decoded {
+                let cached = cache.borrow_mut().get(digest.0);
+                if let Some(data) = cached {
+                    return writer.write_all(&data);
+                }
             }
-            Ok(())
+            return self.decode_chunk_into(digest, data_type, writer);
+        }
+        // records of a corrupted index chunk would send the traversal to
+        // garbage digests, so it's verified before any are passed on
+        let data = self.read_chunk(digest, data_type)?;
+
+        for part in data.as_parts() {
+            writer.write_all(part)?;
         }
+        Ok(())
     }
 
     fn touch(&self, _digest: DigestRef) -> io::Result<()> {
@@ -363,14 +595,14 @@ impl<'a> RecordingChunkAccessor<'a> {
     pub(crate) fn new(
         repo: &'a Repo,
         accessed: &'a mut HashSet<Vec<u8>>,
-        decrypter: Option<ArcDecrypter>,
+        dec: Option<&DecryptHandle>,
         compression: ArcCompression,
         generations: Vec<Generation>,
     ) -> Self {
         RecordingChunkAccessor {
             raw: DefaultChunkAccessor::new(
                 repo,
-                decrypter,
+                dec,
                 compression,
                 generations,
             ),
@@ -400,6 +632,222 @@ impl<'a> ChunkAccessor for RecordingChunkAccessor<'a> {
     }
 }
 
+/// `ChunkAccessor` listing the data chunks (with their lengths, if the
+/// index has them), in order, without reading them
+pub(crate) struct ListingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    chunks: RefCell<Vec<(Digest, Option<u64>)>>,
+}
+
+impl<'a> ListingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        ListingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                None,
+                compression,
+                generations,
+            ),
+            chunks: RefCell::new(vec![]),
+        }
+    }
+
+    pub(crate) fn get_chunks(self) -> Vec<(Digest, Option<u64>)> {
+        self.chunks.into_inner()
+    }
+}
+
+impl<'a> ChunkAccessor for ListingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        debug_assert_eq!(data_type, DataType::Index);
+        self.raw.read_chunk_into(digest, data_type, writer)
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.touch_data(digest, None)
+    }
+
+    fn touch_data(
+        &self,
+        digest: DigestRef,
+        len: Option<u64>,
+    ) -> io::Result<()> {
+        self.chunks.borrow_mut().push((Digest(digest.0.into()), len));
+        Ok(())
+    }
+}
+
+/// `ChunkAccessor` adding up the lengths of the data chunks, as the index
+/// has them, without reading the data chunks
+///
+/// Every chunk is counted once.
+pub(crate) struct SizingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    accessed: RefCell<HashSet<Vec<u8>>>,
+    bytes: Cell<u64>,
+    /// Data chunks without lengths in the index were found
+    incomplete: Cell<bool>,
+}
+
+impl<'a> SizingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        SizingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                None,
+                compression,
+                generations,
+            ),
+            accessed: RefCell::new(HashSet::new()),
+            bytes: Cell::new(0),
+            incomplete: Cell::new(false),
+        }
+    }
+
+    /// Chunks and bytes of data, unless the index doesn't have all the
+    /// lengths
+    pub(crate) fn get_results(self) -> Option<DuResults> {
+        if self.incomplete.get() {
+            return None;
+        }
+        Some(DuResults {
+            chunks: self.accessed.borrow().len(),
+            bytes: self.bytes.get(),
+        })
+    }
+}
+
+impl<'a> ChunkAccessor for SizingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        debug_assert_eq!(data_type, DataType::Index);
+        self.accessed.borrow_mut().insert(digest.0.into());
+        self.raw.read_chunk_into(digest, data_type, writer)
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.touch_data(digest, None)
+    }
+
+    fn touch_data(
+        &self,
+        digest: DigestRef,
+        len: Option<u64>,
+    ) -> io::Result<()> {
+        if self.accessed.borrow_mut().insert(digest.0.into()) {
+            match len {
+                Some(len) => self.bytes.set(self.bytes.get() + len),
+                None => self.incomplete.set(true),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Called periodically during verification with the number of data chunks
+/// verified so far, and errors found
+pub(crate) type VerifyProgressFn<'a> =
+    Box<dyn Fn(u64, &[(Vec<u8>, Error)]) -> io::Result<()> + 'a>;
+
+/// Data chunks verified between calls to `VerifyProgressFn`
+const VERIFY_PROGRESS_INTERVAL: u64 = 1024;
+
+/// Data chunks, with their lengths if the index has them
+pub(crate) type DataChunks = HashMap<Vec<u8>, Option<u64>>;
+
+/// `ChunkAccessor` recording the chunks reachable, index and data ones
+/// apart, without reading the data chunks
+///
+/// Data chunks are recorded with their lengths, if the index has them.
+pub(crate) struct ClassifyingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    index: RefCell<HashSet<Vec<u8>>>,
+    data: RefCell<DataChunks>,
+}
+
+impl<'a> ClassifyingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        ClassifyingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                None,
+                compression,
+                generations,
+            ),
+            index: RefCell::new(HashSet::new()),
+            data: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Index chunks, and data chunks with their lengths
+    pub(crate) fn get_chunks(self) -> (HashSet<Vec<u8>>, DataChunks) {
+        (self.index.into_inner(), self.data.into_inner())
+    }
+}
+
+impl<'a> ChunkAccessor for ClassifyingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        debug_assert_eq!(data_type, DataType::Index);
+        self.index.borrow_mut().insert(digest.0.into());
+        self.raw.read_chunk_into(digest, data_type, writer)
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.touch_data(digest, None)
+    }
+
+    fn touch_data(
+        &self,
+        digest: DigestRef,
+        len: Option<u64>,
+    ) -> io::Result<()> {
+        let mut data = self.data.borrow_mut();
+        let known = data.entry(digest.0.into()).or_insert(None);
+        if known.is_none() {
+            *known = len;
+        }
+        Ok(())
+    }
+}
+
 /// `ChunkAccessor` that verifies the chunks
 /// that are accessed
 ///
@@ -408,27 +856,55 @@ pub(crate) struct VerifyingChunkAccessor<'a> {
     raw: DefaultChunkAccessor<'a>,
     accessed: RefCell<HashSet<Vec<u8>>>,
     errors: RefCell<Vec<(Vec<u8>, Error)>>,
+    /// Data chunks to skip, as they were verified before
+    skip: u64,
+    /// Data chunks encountered so far
+    position: Cell<u64>,
+    /// `position` at the last `progress` call
+    last_progress: Cell<u64>,
+    progress: Option<VerifyProgressFn<'a>>,
 }
 
 impl<'a> VerifyingChunkAccessor<'a> {
     pub(crate) fn new(
         repo: &'a Repo,
-        decrypter: Option<ArcDecrypter>,
+        dec: Option<&DecryptHandle>,
         compression: ArcCompression,
         generations: Vec<Generation>,
     ) -> Self {
         VerifyingChunkAccessor {
             raw: DefaultChunkAccessor::new(
                 repo,
-                decrypter,
+                dec,
                 compression,
                 generations,
             ),
             accessed: RefCell::new(HashSet::new()),
             errors: RefCell::new(Vec::new()),
+            skip: 0,
+            position: Cell::new(0),
+            last_progress: Cell::new(0),
+            progress: None,
         }
     }
 
+    /// Skip first `skip` data chunks, and report progress with `progress`
+    pub(crate) fn resume(
+        mut self,
+        skip: u64,
+        progress: VerifyProgressFn<'a>,
+    ) -> Self {
+        self.skip = skip;
+        self.last_progress.set(skip);
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Data chunks encountered so far, including the skipped ones
+    pub(crate) fn position(&self) -> u64 {
+        self.position.get()
+    }
+
     pub(crate) fn get_results(self) -> VerifyResults {
         VerifyResults {
             scanned: self.accessed.borrow().len(),
@@ -448,19 +924,32 @@ impl<'a> ChunkAccessor for VerifyingChunkAccessor<'a> {
         data_type: DataType,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
-        {
-            let mut accessed = self.accessed.borrow_mut();
-            if accessed.contains(digest.0) {
+        if data_type == DataType::Data {
+            let position = self.position.get();
+            self.position.set(position + 1);
+            if position < self.skip {
                 return Ok(());
             }
-            accessed.insert(digest.0.into());
+            if position - self.last_progress.get() >= VERIFY_PROGRESS_INTERVAL {
+                self.last_progress.set(position);
+                if let Some(ref progress) = self.progress {
+                    progress(position, &self.errors.borrow())?;
+                }
+            }
+        }
+
+        // Index chunks have to be always read, to keep the stream of
+        // digests intact
+        let is_new = self.accessed.borrow_mut().insert(digest.0.into());
+        if !is_new && data_type == DataType::Data {
+            return Ok(());
         }
         let res = self.raw.read_chunk_into(digest, data_type, writer);
 
-        if res.is_err() {
-            self.errors
-                .borrow_mut()
-                .push((digest.0.into(), res.err().unwrap()));
+        if let Err(e) = res {
+            if is_new {
+                self.errors.borrow_mut().push((digest.0.into(), e));
+            }
         }
         Ok(())
     }
@@ -470,10 +959,243 @@ impl<'a> ChunkAccessor for VerifyingChunkAccessor<'a> {
     }
 }
 
+/// `ChunkAccessor` that checks the index structure
+///
+/// Index chunks are read and verified, while data chunks are only checked
+/// for presence. As index chunks are never encrypted, this doesn't need the
+/// secret key.
+pub(crate) struct IndexCheckingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    index_chunks: RefCell<usize>,
+    data_chunks: RefCell<HashSet<Vec<u8>>>,
+    errors: RefCell<Vec<(Vec<u8>, Error)>>,
+}
+
+impl<'a> IndexCheckingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        IndexCheckingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                None,
+                compression,
+                generations,
+            ),
+            index_chunks: RefCell::new(0),
+            data_chunks: RefCell::new(HashSet::new()),
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn push_error(&self, digest: &[u8], e: Error) {
+        self.errors.borrow_mut().push((digest.into(), e));
+    }
+
+    pub(crate) fn get_results(self) -> VerifyResults {
+        VerifyResults {
+            scanned: self.index_chunks.into_inner()
+                + self.data_chunks.borrow().len(),
+            errors: self.errors.into_inner(),
+        }
+    }
+}
+
+impl<'a> ChunkAccessor for IndexCheckingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        debug_assert_eq!(data_type, DataType::Index);
+        *self.index_chunks.borrow_mut() += 1;
+        let res = self.raw.read_chunk_into(digest, data_type, writer);
+
+        if let Err(e) = res {
+            self.push_error(digest.0, e);
+        }
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        if !self.data_chunks.borrow_mut().insert(digest.0.into()) {
+            return Ok(());
+        }
+
+        let found = self
+            .raw
+            .repo
+            .find_chunk(digest, &self.raw.gen_strings)
+            .unwrap_or(None)
+            .is_some();
+        if !found {
+            self.push_error(
+                digest.0,
+                error::Error::ChunkNotFound(digest.0.into()).into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `ChunkAccessor` recording all the chunks referred to, whether they can
+/// be read or not
+///
+/// Index chunks that can't be read are skipped (along with everything they
+/// refer to); the ones that are there, but not readable, are recorded as
+/// errors.
+pub(crate) struct RefCheckingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    accessed: RefCell<&'a mut HashSet<Vec<u8>>>,
+    errors: RefCell<&'a mut Vec<(Vec<u8>, Error)>>,
+}
+
+impl<'a> RefCheckingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        accessed: &'a mut HashSet<Vec<u8>>,
+        errors: &'a mut Vec<(Vec<u8>, Error)>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        RefCheckingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                None,
+                compression,
+                generations,
+            ),
+            accessed: RefCell::new(accessed),
+            errors: RefCell::new(errors),
+        }
+    }
+
+    pub(crate) fn push_error(&self, digest: &[u8], e: Error) {
+        self.errors.borrow_mut().push((digest.into(), e));
+    }
+}
+
+impl<'a> ChunkAccessor for RefCheckingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        debug_assert_eq!(data_type, DataType::Index);
+        self.touch(digest)?;
+        match self.raw.read_chunk_into(digest, data_type, writer) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => self.push_error(digest.0, e),
+            Ok(()) => {}
+        }
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.accessed.borrow_mut().insert(digest.0.into());
+        Ok(())
+    }
+}
+
+/// `ChunkAccessor` skipping data chunks that can't be read
+///
+/// The data chunks skipped are recorded, with the position in the data
+/// where they're missing. Index chunks still have to be readable, as
+/// without them the rest of the data can't be found.
+pub(crate) struct LossyChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    /// Bytes of data written so far
+    position: Cell<u64>,
+    skipped: RefCell<Vec<SkippedChunk>>,
+}
+
+impl<'a> LossyChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        dec: Option<&DecryptHandle>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        LossyChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                dec,
+                compression,
+                generations,
+            ),
+            position: Cell::new(0),
+            skipped: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn get_results(self) -> LossyReadResults {
+        LossyReadResults {
+            bytes: self.position.get(),
+            skipped: self.skipped.into_inner(),
+        }
+    }
+}
+
+impl<'a> ChunkAccessor for LossyChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        if data_type == DataType::Index {
+            return self.raw.read_chunk_into(digest, data_type, writer);
+        }
+        let data = match self.raw.read_chunk(digest, data_type) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!(self.raw.repo.log, "Skipping chunk";
+                      "digest" => FnValue(|_| hex::encode(digest.0)),
+                      "offset" => self.position.get(),
+                      "err" => %e);
+                self.skipped.borrow_mut().push(SkippedChunk {
+                    offset: self.position.get(),
+                    digest: digest.0.into(),
+                    error: e,
+                });
+                return Ok(());
+            }
+        };
+        for part in data.as_parts() {
+            writer.write_all(part)?;
+        }
+        self.position.set(self.position.get() + data.len() as u64);
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef) -> io::Result<()> {
+        self.raw.touch(digest)
+    }
+}
+
 /// `ChunkAccessor` that update accessed chunks
 /// to the latest generation
+///
+/// With `reseal`, data chunks are re-encrypted as they are moved.
 pub(crate) struct GenerationUpdateChunkAccessor<'a> {
     raw: DefaultChunkAccessor<'a>,
+    reseal: Option<&'a Reseal>,
 }
 
 impl<'a> GenerationUpdateChunkAccessor<'a> {
@@ -481,6 +1203,7 @@ impl<'a> GenerationUpdateChunkAccessor<'a> {
         repo: &'a Repo,
         compression: ArcCompression,
         generations: Vec<Generation>,
+        reseal: Option<&'a Reseal>,
     ) -> Self {
         GenerationUpdateChunkAccessor {
             raw: DefaultChunkAccessor::new(
@@ -489,6 +1212,7 @@ impl<'a> GenerationUpdateChunkAccessor<'a> {
                 compression,
                 generations,
             ),
+            reseal,
         }
     }
 }
@@ -508,50 +1232,39 @@ impl<'a> ChunkAccessor for GenerationUpdateChunkAccessor<'a> {
     }
 
     fn touch(&self, digest: DigestRef) -> io::Result<()> {
-        let cur_gen_str = self.raw.gen_strings.last().unwrap();
-        let mut data_gen_str = None;
-
-        for gen_str in self.raw.gen_strings.iter().rev() {
-            let path = self.raw.repo.chunk_rel_path_by_digest(digest, gen_str);
-            match self.raw.repo.aio.read_metadata(path).wait() {
-                Ok(_metadata) => {
-                    data_gen_str = Some(gen_str);
-                    break;
-                }
-                Err(_e) => {}
-            }
-        }
-
-        if data_gen_str.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
-            ));
-        }
+        let repo = self.raw.repo;
+        let gen_strings = &self.raw.gen_strings;
+        let cur_gen_str = gen_strings.last().unwrap();
 
-        let data_gen_str = data_gen_str.unwrap();
-
-        if cur_gen_str != data_gen_str {
-            let data_gen_path =
-                self.raw.repo.chunk_rel_path_by_digest(digest, data_gen_str);
-            let cur_gen_path =
-                self.raw.repo.chunk_rel_path_by_digest(digest, cur_gen_str);
+        let (data_gen, location) = match repo.find_chunk(digest, gen_strings)? {
+            Some(found) => found,
+            None => {
+                return Err(error::Error::ChunkNotFound(digest.0.into()).into())
+            }
+        };
 
-            // `rename` is best effort
-            let res = self
-                .raw
-                .repo
-                .aio
-                .rename(data_gen_path.clone(), cur_gen_path.clone())
-                .wait();
+        if cur_gen_str != &gen_strings[data_gen] {
+            let res = match self.reseal {
+                Some(reseal) => repo.reseal_chunk_to_gen(
+                    digest,
+                    &location,
+                    reseal,
+                    cur_gen_str,
+                ),
+                None => repo.move_chunk_to_gen(
+                    digest,
+                    &location,
+                    None,
+                    cur_gen_str,
+                ),
+            };
             if let Err(e) = res {
-                if e.kind() != io::ErrorKind::NotFound {
-                    warn!(self.raw.repo.log, "Couldn't move chunk to the current generation";
-                          "src-path" => data_gen_path.display(),
-                          "dst-path" => cur_gen_path.display(),
-                          "err" => %e);
-                    return Err(e);
-                }
+                warn!(repo.log, "Couldn't move chunk to the current generation";
+                      "digest" => FnValue(|_| hex::encode(digest.0)),
+                      "src-gen" => &gen_strings[data_gen],
+                      "dst-gen" => cur_gen_str,
+                      "err" => %e);
+                return Err(e);
             }
         }
         Ok(())
@@ -0,0 +1,84 @@
+//! Index of names by the root digest of data they point to
+//!
+//! Every name has an (empty) entry file `roots/<digest>/<name>`, so finding
+//! all names sharing given data is a single listing, instead of reading
+//! every name file. Each writer only ever adds its own entries, so no
+//! coordination between concurrent writers is needed.
+//!
+//! Name files remain the source of truth: the index can always be rebuilt
+//! from them.
+//!
+//! Repositories hiding names (see `hidden_name`) have no index, as it would
+//! reveal them.
+use aio;
+use hex;
+use name::{validate_name, NameIO};
+use std::io;
+use std::path::PathBuf;
+use util::*;
+use SGData;
+
+pub(crate) const ROOT_INDEX_SUBDIR: &str = "roots";
+
+pub(crate) struct RootIndex;
+
+impl RootIndex {
+    fn dir(digest: &[u8]) -> PathBuf {
+        PathBuf::from(ROOT_INDEX_SUBDIR).join(hex::encode(digest))
+    }
+
+    fn path(digest: &[u8], name: &str) -> io::Result<PathBuf> {
+        validate_name(name)?;
+        Ok(RootIndex::dir(digest).join(name))
+    }
+
+    pub(crate) fn add(
+        digest: &[u8],
+        name: &str,
+        aio: &NameIO,
+    ) -> io::Result<()> {
+        if aio.hidden() {
+            return Ok(());
+        }
+        aio.write(RootIndex::path(digest, name)?, SGData::empty())
+            .wait()
+    }
+
+    pub(crate) fn remove(
+        digest: &[u8],
+        name: &str,
+        aio: &NameIO,
+    ) -> io::Result<()> {
+        if aio.hidden() {
+            return Ok(());
+        }
+        substitute_err_not_found(
+            aio.remove(RootIndex::path(digest, name)?).wait(),
+            || (),
+        )
+    }
+
+    /// List names indexed under `digest`
+    ///
+    /// Entries might be stale (eg. after a crash), so callers should
+    /// double-check against the name files.
+    pub(crate) fn list(
+        digest: &[u8],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<Vec<String>> {
+        let list = aio.list(RootIndex::dir(digest)).wait()?;
+
+        Ok(list
+            .iter()
+            .filter_map(|e| e.file_name())
+            .map(|e| e.to_string_lossy().to_string())
+            .collect())
+    }
+
+    pub(crate) fn wipe(aio: &aio::AsyncIO) -> io::Result<()> {
+        substitute_err_not_found(
+            aio.remove_dir_all(PathBuf::from(ROOT_INDEX_SUBDIR)).wait(),
+            || (),
+        )
+    }
+}
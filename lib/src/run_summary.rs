@@ -0,0 +1,139 @@
+//! Summaries of runs, kept in the repository
+//!
+//! Recording them is opt-in (eg. `rdedup --summary`). The latest ones are
+//! kept in a file at the top of the repository, so performance of a run
+//! can be compared with earlier runs on the same repository, with the
+//! settings and versions they were made with.
+use aio;
+use chrono::prelude::*;
+use config::RUNS_YML_FILE;
+use serde::Serialize;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::io;
+use util::*;
+use {IoStats, SGData};
+
+/// Runs kept in the history
+const MAX_RUNS: usize = 100;
+
+/// Settings of the repository a run was made with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RunSettings {
+    /// Eg. `bup chunk_bits=17`
+    pub chunking: String,
+    pub hashing: String,
+    /// Eg. `zstd level=1`
+    pub compression: String,
+    pub encryption: String,
+    #[serde(default)]
+    pub packing: bool,
+}
+
+/// Time spent in a phase of a run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RunPhase {
+    pub name: String,
+    pub secs: f64,
+}
+
+/// Summary of a run, see `Repo::run_summary`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RunSummary {
+    /// When the run finished
+    #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
+    pub time: DateTime<Utc>,
+    /// What was run (eg. `store`)
+    pub command: String,
+    pub ok: bool,
+    /// Version of `rdedup-lib`
+    pub version: String,
+    /// Version of the repository format
+    pub repo_version: u32,
+    pub settings: RunSettings,
+    /// Phases of the run, in order
+    #[serde(default)]
+    pub phases: Vec<RunPhase>,
+    /// Backend requests made, and data transferred
+    #[serde(default)]
+    pub io: IoStats,
+    /// Results of the command (eg. `new_chunks` of `store`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl RunSummary {
+    /// Duration of the whole run, in seconds
+    pub fn secs(&self) -> f64 {
+        self.phases.iter().map(|phase| phase.secs).sum()
+    }
+
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("yaml serialization failed")
+    }
+}
+
+/// Setting serialized as `type key=value...`
+pub(crate) fn describe<T: Serialize>(setting: &T) -> String {
+    let map = match serde_yaml::to_value(setting) {
+        Ok(serde_yaml::Value::Mapping(map)) => map,
+        _ => return String::new(),
+    };
+    let scalar = |value: &serde_yaml::Value| match *value {
+        serde_yaml::Value::String(ref s) => s.clone(),
+        serde_yaml::Value::Number(ref n) => n.to_string(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        _ => "?".into(),
+    };
+    let type_key = serde_yaml::Value::String("type".into());
+    let mut parts: Vec<_> =
+        map.get(&type_key).map(&scalar).into_iter().collect();
+    for (key, value) in map.iter() {
+        if *key != type_key {
+            parts.push(format!("{}={}", scalar(key), scalar(value)));
+        }
+    }
+    parts.join(" ")
+}
+
+/// Latest runs recorded, oldest first
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct RunHistory {
+    #[serde(default)]
+    pub(crate) runs: Vec<RunSummary>,
+}
+
+impl RunHistory {
+    pub(crate) fn read(aio: &aio::AsyncIO) -> io::Result<Self> {
+        let data = match aio.read(RUNS_YML_FILE.into()).wait() {
+            Ok(data) => data.to_linear_vec(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(RunHistory::default())
+            }
+            Err(e) => return Err(e),
+        };
+        serde_yaml::from_slice(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse yaml: {}", e),
+            )
+        })
+    }
+
+    pub(crate) fn write(&self, aio: &aio::AsyncIO) -> io::Result<()> {
+        let serialized =
+            serde_yaml::to_string(self).expect("yaml serialization failed");
+        aio.write(
+            RUNS_YML_FILE.into(),
+            SGData::from_single(serialized.into_bytes()),
+        ).wait()
+    }
+
+    pub(crate) fn record(&mut self, summary: RunSummary) {
+        self.runs.push(summary);
+        if self.runs.len() > MAX_RUNS {
+            let excess = self.runs.len() - MAX_RUNS;
+            self.runs.drain(..excess);
+        }
+    }
+}
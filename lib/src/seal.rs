@@ -0,0 +1,101 @@
+//! Sealing of finished repositories
+//!
+//! Nothing in a sealed repository can be modified anymore. It comes with a
+//! manifest of everything stored in it (every name, and the digest of its
+//! data), signed when sealing.
+use chrono::prelude::*;
+use hashing::{Hasher, Hmac};
+use meta::NameRecord;
+use secret_key::SecretKey;
+use serde_yaml;
+use sha2;
+use sodiumoxide::crypto::sign;
+use sodiumoxide::utils::memzero;
+use std::collections::BTreeMap;
+use std::io;
+use util::*;
+
+/// Names stored in a sealed repository, as of sealing it
+///
+/// Stored as YAML, like `NamesExport`:
+///
+/// ```norust
+/// sealed: 2019-01-31T12:00:00Z
+/// names:
+///   home-2019-01-31:
+///     digest: abcdef...
+///     index_level: 1
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SealManifest {
+    #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
+    pub sealed: DateTime<Utc>,
+    pub names: BTreeMap<String, NameRecord>,
+}
+
+impl SealManifest {
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("yaml serialization failed")
+    }
+}
+
+/// Seal of a repository, recorded in its config
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Seal {
+    /// Key the manifest is signed with
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub sign_pub_key: sign::PublicKey,
+    /// Signature of the manifest file contents
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub signature: sign::Signature,
+}
+
+impl Seal {
+    /// Sign `manifest` (the manifest file contents)
+    ///
+    /// The signing key is derived from `sec_key`, so anyone with the
+    /// passphrase can tell the seal was made with it. Unencrypted
+    /// repositories are signed with a one-time key instead.
+    pub(crate) fn sign(manifest: &[u8], sec_key: Option<&SecretKey>) -> Self {
+        let (sign_pub_key, sign_sec_key) = match sec_key {
+            Some(sec_key) => {
+                let mut seed = Hmac::<sha2::Sha256>::new(sec_key.as_bytes())
+                    .calculate_digest_simple(b"rdedup seal");
+                let keypair = sign::keypair_from_seed(
+                    &sign::Seed::from_slice(&seed).expect("seed from digest"),
+                );
+                memzero(&mut seed);
+                keypair
+            }
+            None => sign::gen_keypair(),
+        };
+        Seal {
+            sign_pub_key,
+            signature: sign::sign_detached(manifest, &sign_sec_key),
+        }
+    }
+
+    /// Parse `manifest` (the manifest file contents), checking it against
+    /// the signature
+    pub(crate) fn verify(&self, manifest: &[u8]) -> io::Result<SealManifest> {
+        if !sign::verify_detached(&self.signature, manifest, &self.sign_pub_key)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "seal manifest doesn't match its signature",
+            ));
+        }
+        serde_yaml::from_slice(manifest).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse seal manifest: {}", e),
+            )
+        })
+    }
+}
@@ -0,0 +1,111 @@
+//! Plain (unsealed) repository secret key
+//!
+//! Allows unlocking the repository for reading without the passphrase, eg.
+//! in unattended restores. Anyone holding it can read all the data, so it
+//! should be treated like the passphrase itself.
+//!
+//! The key is cleared from memory when dropped, and the text it was parsed
+//! from when possible. Hex text of the key is only produced when printing
+//! it (`Display`).
+use hex;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::utils::memzero;
+use std::path::Path;
+use std::{fmt, fs, io, str};
+use util::clear_string;
+
+/// Repository secret key, hex encoded in its text form
+pub struct SecretKey(Vec<u8>);
+
+impl SecretKey {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        SecretKey(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// New random key, eg. of an additional recipient of the chunks
+    pub fn generate() -> Self {
+        let (_, sec_key) = box_::gen_keypair();
+        SecretKey(sec_key.0.to_vec())
+    }
+
+    /// Hex encoded public key matching this key
+    pub fn public_key(&self) -> String {
+        let sec_key = box_::SecretKey::from_slice(&self.0)
+            .expect("secret key in a wrong format");
+        hex::encode(sec_key.public_key().0)
+    }
+
+    /// Read secret key from a file
+    ///
+    /// On Unix, the file must not be accessible by the group or other users.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        check_permissions(&fs::metadata(path)?, path)?;
+        SecretKey::from_hex_string(fs::read_to_string(path)?)
+    }
+
+    /// Like `parse`, but clears `s` afterwards
+    pub fn from_hex_string(s: String) -> io::Result<Self> {
+        let res = s.parse();
+        clear_string(s);
+        res
+    }
+}
+
+#[cfg(unix)]
+fn check_permissions(metadata: &fs::Metadata, path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "secret key file `{}` is accessible by other users (mode {:o})",
+                path.display(),
+                mode & 0o777
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_metadata: &fs::Metadata, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl str::FromStr for SecretKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid secret key")
+        };
+        let key = SecretKey(hex::decode(s.trim()).map_err(|_| invalid())?);
+        if key.0.len() != box_::SECRETKEYBYTES {
+            return Err(invalid());
+        }
+        Ok(key)
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // byte by byte, not to leave a copy in a temporary `String`
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        memzero(&mut self.0);
+    }
+}
@@ -1,6 +1,10 @@
 //! Settings: options that user can pick
 
 use config;
+use hex;
+use pack::Packing;
+use parity::Parity;
+use sodiumoxide::crypto::box_;
 use std::io;
 
 #[derive(Clone)]
@@ -103,10 +107,19 @@ impl Nesting {
     }
 }
 
+#[derive(Clone)]
+pub(crate) struct NestingWidth(pub(crate) u8);
+impl Default for NestingWidth {
+    fn default() -> Self {
+        NestingWidth(config::DEFAULT_NESTING_WIDTH)
+    }
+}
+
 #[derive(Clone)]
 pub enum Hashing {
     Sha256,
     Blake2b,
+    Sha512Trunc256,
 }
 
 impl Hashing {
@@ -114,6 +127,7 @@ impl Hashing {
         match *self {
             Hashing::Sha256 => config::Hashing::Sha256,
             Hashing::Blake2b => config::Hashing::Blake2b,
+            Hashing::Sha512Trunc256 => config::Hashing::Sha512Trunc256,
         }
     }
 }
@@ -131,8 +145,25 @@ pub struct Repo {
     pub(crate) compression: Compression,
     pub(crate) compression_level: i32,
     pub(crate) chunking: Chunking,
+    pub(crate) chunk_size_bounds: config::ChunkSizeBounds,
+    pub(crate) chunking_tiers: Vec<config::ChunkingTier>,
     pub(crate) nesting: Nesting,
+    pub(crate) nesting_width: NestingWidth,
     pub(crate) hashing: Hashing,
+    pub(crate) keyed_digests: bool,
+    pub(crate) hidden_names: bool,
+    pub(crate) signed_names: bool,
+    pub(crate) append_only: bool,
+    pub(crate) audit_log: bool,
+    pub(crate) digest_nonces: bool,
+    pub(crate) compress_names: bool,
+    pub(crate) min_compression_savings: Option<u8>,
+    pub(crate) bare_chunks: bool,
+    pub(crate) bare_index: bool,
+    pub(crate) raw_names: bool,
+    pub(crate) recipients: Vec<box_::PublicKey>,
+    pub(crate) packing: Option<Packing>,
+    pub(crate) parity: Option<Parity>,
 }
 
 impl Repo {
@@ -166,6 +197,132 @@ impl Repo {
         Ok(())
     }
 
+    /// Key digests of data chunks with a secret derived from the repository
+    /// secret key
+    ///
+    /// Without the key, a chunk of known content can't be looked up in the
+    /// repository, so nobody with just read access to it can confirm that
+    /// a specific file is stored. Writing then requires the passphrase too.
+    /// Requires encryption.
+    pub fn set_keyed_digests(&mut self, keyed: bool) {
+        self.keyed_digests = keyed;
+    }
+
+    /// Store names under HMACs of them, keyed with a secret derived from the
+    /// repository secret key, with the names sealed inside the records
+    ///
+    /// Nobody with just read access to the repository can then tell the
+    /// names (eg. hostnames or dates), but using any name (even listing
+    /// them) requires the secret key (see `Repo::unlock_names`). Requires
+    /// encryption, and can't be used with recipients.
+    pub fn set_hidden_names(&mut self, hidden: bool) {
+        self.hidden_names = hidden;
+    }
+
+    /// Sign name records with a key derived from the repository secret key
+    ///
+    /// Records are checked against the signature whenever they're loaded,
+    /// so nobody with just write access to the repository can point a name
+    /// at other data unnoticed. Storing names then requires the secret key
+    /// (see `Repo::unlock_names`). Requires encryption.
+    pub fn set_signed_names(&mut self, signed: bool) {
+        self.signed_names = signed;
+    }
+
+    /// Refuse removing or replacing anything stored (see
+    /// `Repo::set_append_only`)
+    pub fn set_append_only(&mut self, append_only: bool) {
+        self.append_only = append_only;
+    }
+
+    /// Record operations in the audit log (see `Repo::set_audit_log`)
+    pub fn set_audit_log(&mut self, audit_log: bool) {
+        self.audit_log = audit_log;
+    }
+
+    /// Derive nonces of chunks from their digests, instead of random ones
+    ///
+    /// Releases before repository format version 8 support only these, so
+    /// can still use the repository.
+    pub fn set_digest_nonces(&mut self, digest_nonces: bool) {
+        self.digest_nonces = digest_nonces;
+    }
+
+    /// Compress name records (the digest of the data, and its metadata)
+    /// like the data
+    ///
+    /// Name records stay unencrypted: operations like `gc` or `ls` don't
+    /// need the passphrase.
+    pub fn set_compress_names(&mut self, compress: bool) {
+        self.compress_names = compress;
+    }
+
+    /// Store data chunks uncompressed, if compressing them saves less than
+    /// `percent` of their size
+    ///
+    /// Saves decompressing data that doesn't compress anyway (eg. media
+    /// files) on restore; data that looks random isn't even tried, saving
+    /// compressing it too. Requires chunk headers.
+    pub fn set_min_compression_savings(
+        &mut self,
+        percent: u8,
+    ) -> super::Result<()> {
+        if percent > 100 {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compression savings can't be more than 100%",
+            ));
+        }
+        self.min_compression_savings = Some(percent);
+        Ok(())
+    }
+
+    /// Store chunks without a header identifying their format
+    ///
+    /// Releases before repository format version 10 support only these, so
+    /// can still use the repository.
+    pub fn set_bare_chunks(&mut self, bare: bool) {
+        self.bare_chunks = bare;
+    }
+
+    /// Store index records as just the digests, without the length of the
+    /// data they refer to
+    ///
+    /// Releases before repository format version 16 support only these, so
+    /// can still use the repository. Reading a range of data then has to
+    /// read all of it up to the range.
+    pub fn set_bare_index(&mut self, bare: bool) {
+        self.bare_index = bare;
+    }
+
+    /// Store names under file names as they are, instead of encoding the
+    /// characters some platforms (Windows) don't allow in them
+    ///
+    /// Releases before repository format version 17 support only these, so
+    /// can still use the repository.
+    pub fn set_raw_names(&mut self, raw: bool) {
+        self.raw_names = raw;
+    }
+
+    /// Seal chunks also for the holder of the secret key matching
+    /// `pub_key` (hex encoded, see `SecretKey::public_key`)
+    ///
+    /// Eg. an offline recovery key: the secret key can then be used to read
+    /// the data instead of the passphrase. Can't be used with keyed digests.
+    pub fn add_recipient(&mut self, pub_key: &str) -> io::Result<()> {
+        let pub_key = hex::decode(pub_key.trim())
+            .ok()
+            .and_then(|bytes| box_::PublicKey::from_slice(&bytes))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid recipient public key",
+                )
+            })?;
+        self.recipients.push(pub_key);
+        Ok(())
+    }
+
     pub fn use_bup_chunking(&mut self, bits: Option<u32>) -> super::Result<()> {
         let bits = bits.unwrap_or(config::DEFAULT_BUP_CHUNK_BITS);
         let chunking = config::Chunking::Bup { chunk_bits: bits };
@@ -214,6 +371,97 @@ impl Repo {
         Ok(())
     }
 
+    pub fn use_fixed_chunking(
+        &mut self,
+        bits: Option<u32>,
+    ) -> super::Result<()> {
+        let bits = bits.unwrap_or(config::DEFAULT_BUP_CHUNK_BITS);
+        let chunking = config::Chunking::Fixed { chunk_bits: bits };
+
+        if !chunking.valid() {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid chunking algorithm defined",
+            ));
+        }
+        self.chunking = Chunking(chunking);
+        Ok(())
+    }
+
+    /// Set minimum and maximum size of chunks, in bytes
+    ///
+    /// `None` leaves given side unbounded.
+    pub fn set_chunk_size_bounds(
+        &mut self,
+        min_size: Option<u64>,
+        max_size: Option<u64>,
+    ) -> super::Result<()> {
+        let bounds = config::ChunkSizeBounds { min_size, max_size };
+
+        if !bounds.valid() {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid chunk size bounds defined",
+            ));
+        }
+        self.chunk_size_bounds = bounds;
+        Ok(())
+    }
+
+    /// Chunk streams of at least `min_stream_size` bytes differently
+    ///
+    /// The chunking algorithm and chunk size bounds of `tier` are used for
+    /// them (its other settings are ignored). The tier can also be picked
+    /// explicitly by `name`, with `WriteOptions::chunking_tier`.
+    pub fn add_chunking_tier(
+        &mut self,
+        name: &str,
+        min_stream_size: u64,
+        tier: &Repo,
+    ) -> super::Result<()> {
+        let tier = config::ChunkingTier {
+            name: name.into(),
+            min_stream_size,
+            chunking: tier.chunking.0,
+            chunk_size_bounds: tier.chunk_size_bounds,
+        };
+
+        if !tier.valid()
+            || self.chunking_tiers.iter().any(|t| t.name == tier.name)
+        {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid chunking tier defined",
+            ));
+        }
+        self.chunking_tiers.push(tier);
+        Ok(())
+    }
+
+    /// Store small chunks in pack files, instead of a file each
+    pub fn set_packing(&mut self, packing: Packing) -> super::Result<()> {
+        if !packing.valid() {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid packing defined",
+            ));
+        }
+        self.packing = Some(packing);
+        Ok(())
+    }
+
+    /// Write a parity file next to every chunk file, see `Repo::repair`
+    pub fn set_parity(&mut self, parity: Parity) -> super::Result<()> {
+        if !parity.valid() {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid parity defined",
+            ));
+        }
+        self.parity = Some(parity);
+        Ok(())
+    }
+
     pub fn set_nesting(&mut self, level: u8) -> super::Result<()> {
         if level > 31 {
             return Err(super::Error::new(
@@ -224,4 +472,21 @@ impl Repo {
         self.nesting = Nesting(level);
         Ok(())
     }
+
+    /// Name directory levels of nesting after `width` hex digits of the
+    /// digests (2 by default)
+    ///
+    /// Eg. one level of 3 digits suits small repositories better than two of
+    /// 2, and more levels ones with hundreds of millions of chunks. Releases
+    /// before repository format version 19 support only 2.
+    pub fn set_nesting_width(&mut self, width: u8) -> super::Result<()> {
+        if !(1..=config::MAX_NESTING_WIDTH).contains(&width) {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "nesting width must be between 1 and 4",
+            ));
+        }
+        self.nesting_width = NestingWidth(width);
+        Ok(())
+    }
 }
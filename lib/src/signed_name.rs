@@ -0,0 +1,86 @@
+//! Name records signed, so they can't be changed unnoticed
+//!
+//! Anyone with write access to a repository could otherwise point a name
+//! at other data (eg. by swapping the digest in its record), and restores
+//! would follow it. In repositories with `names_sign_key` (see
+//! `config::Repo`), every record carries a signature of the name and the
+//! record, made with a key derived from the repository secret key, and is
+//! checked against the public key in the config whenever it's loaded. So
+//! storing names requires the secret key (see `Repo::unlock_names`), while
+//! reading them doesn't. Unlocking the repository for reading checks the
+//! public key in the config is the one derived from the secret key, so it
+//! can't be replaced along with the records.
+use error::Error;
+use hashing::{Hasher, Hmac};
+use name::Name;
+use secret_key::SecretKey;
+use serde_yaml;
+use sha2;
+use sodiumoxide::crypto::sign;
+use sodiumoxide::utils::memzero;
+use std::io;
+
+/// Key name records are signed with, derived from the repository secret
+/// key
+pub(crate) struct NamesSigner {
+    pub_key: sign::PublicKey,
+    sec_key: sign::SecretKey,
+}
+
+impl NamesSigner {
+    pub(crate) fn new(sec_key: &SecretKey) -> Self {
+        let mut seed = Hmac::<sha2::Sha256>::new(sec_key.as_bytes())
+            .calculate_digest_simple(b"rdedup names sign");
+        let (pub_key, sec_key) = sign::keypair_from_seed(
+            &sign::Seed::from_slice(&seed).expect("seed from digest"),
+        );
+        memzero(&mut seed);
+        NamesSigner { pub_key, sec_key }
+    }
+
+    pub(crate) fn pub_key(&self) -> sign::PublicKey {
+        self.pub_key
+    }
+
+    /// Signature of `record`, stored as `name`
+    pub(crate) fn sign(&self, name: &str, record: &Name) -> sign::Signature {
+        sign::sign_detached(&signed_bytes(name, record), &self.sec_key)
+    }
+}
+
+/// Check `record`, stored as `name`, against its signature
+pub(crate) fn verify(
+    pub_key: &sign::PublicKey,
+    name: &str,
+    record: &Name,
+) -> io::Result<()> {
+    let valid = match record.signature {
+        Some(ref signature) => sign::verify_detached(
+            signature,
+            &signed_bytes(name, record),
+            pub_key,
+        ),
+        None => false,
+    };
+    if !valid {
+        return Err(Error::BadSignature(name.into()).into());
+    }
+    Ok(())
+}
+
+/// What's signed: the name, and the record without the signature
+///
+/// With the name, records can't be swapped between names unnoticed.
+fn signed_bytes(name: &str, record: &Name) -> Vec<u8> {
+    let mut unsigned = record.clone();
+    unsigned.signature = None;
+    let mut bytes = b"rdedup name\0".to_vec();
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(
+        serde_yaml::to_string(&unsigned)
+            .expect("yaml serialization failed")
+            .as_bytes(),
+    );
+    bytes
+}
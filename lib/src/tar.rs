@@ -0,0 +1,166 @@
+//! Chunk edges at file boundaries of tar streams, see `WriteOptions::tar`
+//!
+//! A tar stream is a sequence of 512 byte headers, each followed by the
+//! content of the file (padded to 512 bytes). Chunking headers and
+//! contents apart makes files deduplicate regardless of their order in
+//! the archive, or of changes to their metadata. The data is stored as it
+//! is; only the chunk edges change.
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Iterator over buffers of a tar stream, with empty buffers before and
+/// after every header (see `chunking::Chunker`)
+///
+/// Once the end of the archive (or anything that's not a tar header) is
+/// found, the rest of the data is passed as it is.
+pub(crate) struct TarBoundaries<I> {
+    input: I,
+    /// Bytes of content (with padding) left before the next header
+    content_left: u64,
+    /// Bytes of the next header read so far
+    header: Vec<u8>,
+    /// Still following the archive
+    in_archive: bool,
+    output: VecDeque<Arc<Vec<u8>>>,
+}
+
+impl<I> TarBoundaries<I> {
+    pub(crate) fn new(input: I) -> Self {
+        TarBoundaries {
+            input,
+            content_left: 0,
+            header: Vec::with_capacity(BLOCK_SIZE),
+            in_archive: true,
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Split `buf` into `output`
+    fn split(&mut self, buf: Arc<Vec<u8>>) {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let rest = &buf[offset..];
+            if !self.in_archive {
+                self.push(&buf, offset, buf.len());
+                return;
+            }
+            if self.content_left > 0 {
+                let len = (self.content_left.min(rest.len() as u64)) as usize;
+                self.push(&buf, offset, offset + len);
+                self.content_left -= len as u64;
+                offset += len;
+                continue;
+            }
+
+            let len = (BLOCK_SIZE - self.header.len()).min(rest.len());
+            self.header.extend_from_slice(&rest[..len]);
+            offset += len;
+            if self.header.len() < BLOCK_SIZE {
+                continue;
+            }
+            let header = std::mem::replace(
+                &mut self.header,
+                Vec::with_capacity(BLOCK_SIZE),
+            );
+            // the end of the archive too, so the last file is chunked apart
+            self.output.push_back(Arc::new(vec![]));
+            match content_len(&header) {
+                Some(len) => {
+                    self.output.push_back(Arc::new(header));
+                    self.output.push_back(Arc::new(vec![]));
+                    let block = BLOCK_SIZE as u64;
+                    self.content_left = (len + block - 1) / block * block;
+                }
+                None => {
+                    self.in_archive = false;
+                    self.output.push_back(Arc::new(header));
+                }
+            }
+        }
+    }
+
+    /// `buf[start..end]`, without copying the whole of it
+    fn push(&mut self, buf: &Arc<Vec<u8>>, start: usize, end: usize) {
+        self.output.push_back(if start == 0 && end == buf.len() {
+            Arc::clone(buf)
+        } else {
+            Arc::new(buf[start..end].to_vec())
+        });
+    }
+}
+
+impl<I> Iterator for TarBoundaries<I>
+where
+    I: Iterator<Item = Arc<Vec<u8>>>,
+{
+    type Item = Arc<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            match self.input.next() {
+                Some(buf) => self.split(buf),
+                None => {
+                    // a header cut short
+                    if !self.header.is_empty() {
+                        let header =
+                            std::mem::replace(&mut self.header, vec![]);
+                        self.output.push_back(Arc::new(header));
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
+
+/// Length of the content following tar `header`, if it's a valid header
+fn content_len(header: &[u8]) -> Option<u64> {
+    let checksum = parse_number(&header[148..156])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            // the checksum field itself counts as spaces
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+    if sum != checksum {
+        return None;
+    }
+    match header[156] {
+        // links, devices, directories and FIFOs have no content
+        b'1'..=b'6' => Some(0),
+        _ => parse_number(&header[124..136]),
+    }
+}
+
+/// Octal number (or base-256, for large ones) of a header field
+fn parse_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return Some(
+            field[1..]
+                .iter()
+                .fold(u64::from(field[0] & 0x7f), |n, &b| {
+                    (n << 8) | u64::from(b)
+                }),
+        );
+    }
+    let digits: Vec<u8> = field
+        .iter()
+        .cloned()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| b != 0 && b != b' ')
+        .collect();
+    if digits.is_empty() {
+        return None;
+    }
+    let digits = std::str::from_utf8(&digits).ok()?;
+    u64::from_str_radix(digits, 8).ok()
+}
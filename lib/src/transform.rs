@@ -0,0 +1,31 @@
+//! Reversible transforms of the data stored
+//!
+//! A transform is applied to the data before it's chunked, and undone when
+//! reading it back, eg. to reorder columns of database dumps so more of
+//! them deduplicates, or to scrub timestamps that change on every dump.
+//! Transforms are registered with the `Repo` handle (see
+//! `Repo::register_transform`), and the identifier of the one used is
+//! recorded with the name: reading it requires the same transform
+//! registered.
+use std::io::{Read, Write};
+
+/// Reversible filter of the data stored
+pub trait Transform: Send + Sync {
+    /// Identifier recorded with the names stored with it
+    ///
+    /// Has to change whenever the output does (eg. `columns-v2`), so names
+    /// stored before are still read back right.
+    fn id(&self) -> &str;
+
+    /// `reader` of the data being stored, transformed
+    fn apply<'a>(
+        &self,
+        reader: Box<dyn Read + Send + 'a>,
+    ) -> Box<dyn Read + Send + 'a>;
+
+    /// Writer undoing `apply`, of the data being read, to `writer`
+    ///
+    /// Everything has to be written to `writer` by the time the returned
+    /// writer is flushed.
+    fn invert<'a>(&self, writer: Box<dyn Write + 'a>) -> Box<dyn Write + 'a>;
+}
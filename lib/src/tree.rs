@@ -0,0 +1,284 @@
+//! Directory trees stored under a name, see `Repo::write_tree`
+//!
+//! Every file is chunked on its own, so files deduplicate as well as if
+//! each was stored separately (unlike in a tar stream). The data of the
+//! name is the tree index, followed by the chunks of all the files in
+//! order. The index is YAML, with chunks of files counted from the end of
+//! the index (the number of chunks of the index itself is kept in the
+//! name record):
+//!
+//! ```norust
+//! entries:
+//!   - path: etc
+//!     kind: dir
+//!     mode: 493
+//!     mtime: "2019-01-31T10:00:00+00:00"
+//!   - path: etc/hosts
+//!     kind: file
+//!     mode: 420
+//!     mtime: "2019-01-31T10:00:00+00:00"
+//!     size: 220
+//!     first_chunk: 0
+//!     chunks: 1
+//!   - path: etc/localtime
+//!     kind: symlink
+//!     mode: 511
+//!     mtime: "2019-01-31T10:00:00+00:00"
+//!     target: /usr/share/zoneinfo/UTC
+//! ```
+use chrono::prelude::*;
+use filetime::{self, FileTime};
+use serde_yaml;
+use slog::Logger;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use util::*;
+use walkdir::WalkDir;
+
+/// Type of a `TreeEntry`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+/// File, directory or symlink of a tree
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TreeEntry {
+    /// Path relative to the root of the tree, `/` separated
+    pub path: String,
+    pub kind: EntryKind,
+    /// Permission bits (Unix mode, without the file type)
+    pub mode: u32,
+    #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
+    pub mtime: DateTime<Utc>,
+    /// Size of a file, in bytes
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: u64,
+    /// First chunk of a file, counted from the end of the index
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub first_chunk: u64,
+    /// Chunks of a file
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub chunks: u64,
+    /// Target of a symlink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Index of a tree stored with `Repo::write_tree`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TreeIndex {
+    /// Entries of the tree, every directory before its contents
+    pub entries: Vec<TreeEntry>,
+}
+
+impl TreeIndex {
+    /// Entry at `path`
+    pub fn entry(&self, path: &str) -> Option<&TreeEntry> {
+        let path = path.trim_matches('/');
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Entries at any of `paths`, or in directories at any of them (all
+    /// the entries if `paths` is empty)
+    pub fn select(&self, paths: &[&str]) -> io::Result<Vec<&TreeEntry>> {
+        if paths.is_empty() {
+            return Ok(self.entries.iter().collect());
+        }
+        let paths: Vec<_> =
+            paths.iter().map(|path| path.trim_matches('/')).collect();
+        if let Some(missing) =
+            paths.iter().find(|&&path| self.entry(path).is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no such path in the tree: {}", missing),
+            ));
+        }
+        Ok(self
+            .entries
+            .iter()
+            .filter(|entry| {
+                paths.iter().any(|path| {
+                    entry.path == *path
+                        || entry.path.starts_with(&format!("{}/", path))
+                })
+            })
+            .collect())
+    }
+
+    /// Total size of the files, in bytes
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("yaml serialization failed")
+    }
+
+    pub(crate) fn from_yaml(data: &[u8]) -> io::Result<Self> {
+        serde_yaml::from_slice(data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse tree index: {}", e),
+            )
+        })
+    }
+}
+
+/// Entries of the tree at `root`, with their paths in the file system
+///
+/// Symlinks are not followed. Other special files (devices, sockets, ...)
+/// are skipped.
+pub(crate) fn walk(
+    root: &Path,
+    log: &Logger,
+) -> io::Result<Vec<(PathBuf, TreeEntry)>> {
+    let mut entries = vec![];
+    let walk = WalkDir::new(root)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for dir_entry in walk {
+        let dir_entry = dir_entry.map_err(io::Error::from)?;
+        let path = dir_entry.path();
+        let rel_path = path.strip_prefix(root).expect("under the root");
+        let rel_path = utf8(rel_path)?
+            .split(std::path::MAIN_SEPARATOR)
+            .collect::<Vec<_>>()
+            .join("/");
+        let metadata = dir_entry.metadata().map_err(io::Error::from)?;
+        let file_type = metadata.file_type();
+        let (kind, target) = if file_type.is_dir() {
+            (EntryKind::Dir, None)
+        } else if file_type.is_file() {
+            (EntryKind::File, None)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            (EntryKind::Symlink, Some(utf8(&target)?.to_owned()))
+        } else {
+            warn!(log, "Skipping special file"; "path" => %path.display());
+            continue;
+        };
+        let mtime = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        entries.push((
+            path.to_owned(),
+            TreeEntry {
+                path: rel_path,
+                kind,
+                mode: mode(&metadata),
+                mtime: mtime.into(),
+                size: if kind == EntryKind::File {
+                    metadata.len()
+                } else {
+                    0
+                },
+                first_chunk: 0,
+                chunks: 0,
+                target,
+            },
+        ));
+    }
+    Ok(entries)
+}
+
+fn utf8(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("path is not valid UTF-8: {}", path.display()),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Where to restore `entry` in `dest`
+///
+/// Fails for paths that would end up outside of `dest` (`..`, absolute).
+pub(crate) fn dest_path(dest: &Path, entry: &TreeEntry) -> io::Result<PathBuf> {
+    let path = Path::new(&entry.path);
+    if entry.path.is_empty()
+        || !path
+            .components()
+            .all(|component| match component {
+                Component::Normal(_) => true,
+                _ => false,
+            })
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid path in the tree: {}", entry.path),
+        ));
+    }
+    Ok(dest.join(path))
+}
+
+/// Create symlink `entry` at `path`
+#[cfg(unix)]
+pub(crate) fn symlink(
+    path: &Path,
+    entry: &TreeEntry,
+    _log: &Logger,
+) -> io::Result<()> {
+    let target = entry.target.as_ref().map_or("", |t| t.as_str());
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if !metadata.is_dir() => fs::remove_file(path)?,
+        _ => {}
+    }
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn symlink(
+    path: &Path,
+    _entry: &TreeEntry,
+    log: &Logger,
+) -> io::Result<()> {
+    warn!(log, "Skipping symlink"; "path" => %path.display());
+    Ok(())
+}
+
+/// Set the modification time and the mode of `entry` restored at `path`
+///
+/// Directories get them after their contents were restored, so they're
+/// not changed by it (and read-only ones can be written to).
+pub(crate) fn set_attributes(path: &Path, entry: &TreeEntry) -> io::Result<()> {
+    let mtime = FileTime::from_system_time(entry.mtime.into());
+    filetime::set_file_mtime(path, mtime)?;
+    fs::set_permissions(path, permissions(path, entry.mode)?)
+}
+
+#[cfg(unix)]
+fn permissions(_path: &Path, mode: u32) -> io::Result<fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn permissions(path: &Path, mode: u32) -> io::Result<fs::Permissions> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    Ok(permissions)
+}
@@ -1,3 +1,4 @@
+use sodiumoxide::utils::memzero;
 use std::io;
 
 mod serde;
@@ -9,6 +10,12 @@ pub(crate) use self::whileok::*;
 mod readerveciter;
 pub(crate) use self::readerveciter::*;
 
+/// Overwrite `s` (eg. a passphrase) with zeros before freeing it
+pub(crate) fn clear_string(s: String) {
+    let mut bytes = s.into_bytes();
+    memzero(&mut bytes);
+}
+
 /// Writer that counts how many bytes were written to it
 pub struct CounterWriter {
     pub count: u64,
@@ -31,6 +38,51 @@ impl io::Write for CounterWriter {
     }
 }
 
+/// Reader passing everything read from `reader` to `copy` too
+pub(crate) struct TeeReader<R, C> {
+    reader: R,
+    copy: C,
+}
+
+impl<R: io::Read, C: io::Write> TeeReader<R, C> {
+    pub(crate) fn new(reader: R, copy: C) -> Self {
+        TeeReader { reader, copy }
+    }
+}
+
+impl<R: io::Read, C: io::Write> io::Read for TeeReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.copy.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// Writer writing everything to `writer`, and to `copy` too
+pub(crate) struct TeeWriter<W, C> {
+    writer: W,
+    copy: C,
+}
+
+impl<W: io::Write, C: io::Write> TeeWriter<W, C> {
+    pub(crate) fn new(writer: W, copy: C) -> Self {
+        TeeWriter { writer, copy }
+    }
+}
+
+impl<W: io::Write, C: io::Write> io::Write for TeeWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.copy.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.copy.flush()?;
+        self.writer.flush()
+    }
+}
+
 /// Substitute Err(NotFound) with something else
 ///
 /// Many places in the code ignore `NotFound`, so this function makes it
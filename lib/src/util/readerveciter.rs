@@ -1,12 +1,25 @@
+use std::collections::VecDeque;
 use std::io;
+use std::sync::Arc;
 
-/// Reader (iterator) returning owned vectors
+/// Buffers tracked for reuse, at most (all but one still shared, so
+/// tracking them holds no memory)
+const MAX_POOLED: usize = 256;
+
+/// Reader (iterator) returning shared vectors
+///
+/// Wraps `R : io::Read` and yields `Arc<Vec<u8>>` filled with data read
+/// from `R` (only the last one can be shorter than `buf_size`).
 ///
-/// Wraps `R : io::Read` and yields `Vec<u8>` with
-/// data read from `R`.
+/// Buffers are recycled: once one of them returned is not shared anymore
+/// (eg. all the chunks of it were stored), it's filled again instead of
+/// allocating a new one. Any other buffer not shared is freed, so at most a
+/// buffer is held here alone, however long an older one stays shared.
 pub struct ReaderVecIter<R: io::Read> {
     reader: R,
     buf_size: usize,
+    /// Buffers returned, oldest first
+    pool: VecDeque<Arc<Vec<u8>>>,
 }
 
 impl<R> ReaderVecIter<R>
@@ -17,7 +30,52 @@ where
         ReaderVecIter {
             reader,
             buf_size,
+            pool: VecDeque::new(),
+        }
+    }
+
+    /// Buffer to fill next: the oldest one not shared anymore, if any
+    fn take_buf(&mut self) -> Arc<Vec<u8>> {
+        let mut reused = None;
+        self.pool.retain(|buf| {
+            if Arc::strong_count(buf) > 1 {
+                return true;
+            }
+            if reused.is_none() {
+                reused = Some(Arc::clone(buf));
+            }
+            false
+        });
+        if self.pool.len() >= MAX_POOLED {
+            self.pool.pop_front();
         }
+        reused.unwrap_or_else(|| Arc::new(vec![]))
+    }
+
+    /// Bytes of the buffers held here alone
+    #[cfg(test)]
+    pub(crate) fn pooled_bytes(&self) -> usize {
+        self.pool
+            .iter()
+            .filter(|buf| Arc::strong_count(buf) == 1)
+            .map(|buf| buf.capacity())
+            .sum()
+    }
+
+    /// Read into `buf` until it's full or the end of the data
+    fn fill(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.resize(self.buf_size, 0);
+        let mut len = 0;
+        while len < buf.len() {
+            match self.reader.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        buf.truncate(len);
+        Ok(())
     }
 }
 
@@ -25,19 +83,18 @@ impl<R> Iterator for ReaderVecIter<R>
 where
     R: io::Read,
 {
-    type Item = io::Result<Vec<u8>>;
+    type Item = io::Result<Arc<Vec<u8>>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buf: Vec<u8> = vec![0u8; self.buf_size];
-        match self.reader.read(&mut buf) {
-            Ok(len) => {
-                if len == 0 {
-                    return None;
-                }
-                buf.truncate(len);
-                Some(Ok(buf))
-            }
-            Err(e) => Some(Err(e)),
+        let mut buf = self.take_buf();
+        if let Err(e) = self.fill(Arc::get_mut(&mut buf).expect("not shared"))
+        {
+            return Some(Err(e));
+        }
+        if buf.is_empty() {
+            return None;
         }
+        self.pool.push_back(Arc::clone(&buf));
+        Some(Ok(buf))
     }
 }
@@ -4,6 +4,7 @@ use crypto::pwhash;
 use hex::{self, FromHex, FromHexError};
 use serde::Deserialize;
 use std::io;
+use sodiumoxide::crypto::sign;
 use {base64, box_, secretbox, serde};
 
 pub trait MyTryFromBytes: Sized {
@@ -47,6 +48,30 @@ impl MyTryFromBytes for pwhash::Salt {
     }
 }
 
+impl MyTryFromBytes for sign::PublicKey {
+    type Err = io::Error;
+    fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
+        sign::PublicKey::from_slice(slice).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive signing PublicKey from invalid binary data",
+            )
+        })
+    }
+}
+
+impl MyTryFromBytes for sign::Signature {
+    type Err = io::Error;
+    fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
+        sign::Signature::from_slice(slice).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive Signature from invalid binary data",
+            )
+        })
+    }
+}
+
 impl MyTryFromBytes for Vec<u8> {
     type Err = io::Error;
     fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
@@ -80,6 +105,30 @@ where
     serializer.serialize_str(&base64::encode(key.as_ref()))
 }
 
+pub fn from_base64_opt<'d, T, D>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'d>,
+    T: MyTryFromBytes,
+{
+    from_base64(deserializer).map(Some)
+}
+
+pub fn as_base64_opt<T, S>(
+    key: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: serde::Serializer,
+{
+    match *key {
+        Some(ref key) => as_base64(key, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
 pub fn from_hex<'d, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     D: serde::Deserializer<'d>,
@@ -106,6 +155,28 @@ where
     serializer.serialize_str(&hex::encode(key))
 }
 
+pub fn from_hex_opt<'d, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'d>,
+    T: MyTryFromBytes,
+{
+    from_hex(deserializer).map(Some)
+}
+
+pub fn as_hex_opt<T, S>(
+    key: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: serde::Serializer,
+{
+    match *key {
+        Some(ref key) => as_hex(key, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
 pub fn from_rfc3339<'d, D>(
     deserializer: D,
 ) -> Result<chrono::DateTime<Utc>, D::Error>
@@ -130,3 +201,25 @@ where
 {
     serializer.serialize_str(&key.to_rfc3339())
 }
+
+pub fn from_rfc3339_opt<'d, D>(
+    deserializer: D,
+) -> Result<Option<chrono::DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'d>,
+{
+    from_rfc3339(deserializer).map(Some)
+}
+
+pub fn as_rfc3339_opt<S>(
+    key: &Option<chrono::DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match *key {
+        Some(ref key) => as_rfc3339(key, serializer),
+        None => serializer.serialize_none(),
+    }
+}
@@ -0,0 +1,106 @@
+//! Progress of a `verify` of a name, persisted so it can be resumed
+//!
+//! Checkpoints are kept in a local file outside of the repository (which
+//! might be read-only), chosen by the user.
+use hex;
+use serde_yaml;
+use std::fs;
+use std::io;
+use std::path::Path;
+use Error;
+
+/// Chunk that failed verification
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VerifyCheckpointError {
+    pub digest: String,
+    pub error: String,
+}
+
+/// Verification progress of a name
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VerifyCheckpoint {
+    pub name: String,
+    /// Root digest of the name, to detect if it was stored again
+    pub digest: String,
+    /// Data chunks verified so far (in order)
+    pub done: u64,
+    /// Total number of data chunks, if known
+    pub total: Option<u64>,
+    pub errors: Vec<VerifyCheckpointError>,
+    pub complete: bool,
+}
+
+impl VerifyCheckpoint {
+    pub(crate) fn new(name: &str, digest: &[u8], total: Option<u64>) -> Self {
+        VerifyCheckpoint {
+            name: name.into(),
+            digest: hex::encode(digest),
+            done: 0,
+            total,
+            errors: vec![],
+            complete: false,
+        }
+    }
+
+    /// Load the checkpoint from `path`, if it exists
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        serde_yaml::from_slice(&data).map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse verify checkpoint: {}", e),
+            )
+        })
+    }
+
+    /// Store the checkpoint in `path`, replacing it atomically
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let serialized =
+            serde_yaml::to_string(self).expect("yaml serialization failed");
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serialized)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Progress in percents, if the total is known
+    pub fn percent(&self) -> Option<f64> {
+        if self.complete {
+            return Some(100.0);
+        }
+        self.total.map(|total| {
+            if total == 0 {
+                100.0
+            } else {
+                100.0 * self.done as f64 / total as f64
+            }
+        })
+    }
+
+    pub(crate) fn record_errors(&mut self, errors: &[(Vec<u8>, Error)]) {
+        self.errors.extend(errors.iter().map(|(digest, e)| {
+            VerifyCheckpointError {
+                digest: hex::encode(digest),
+                error: e.to_string(),
+            }
+        }));
+    }
+
+    /// Errors recorded in the checkpoint
+    pub(crate) fn to_errors(&self) -> Vec<(Vec<u8>, Error)> {
+        self.errors
+            .iter()
+            .map(|e| {
+                (
+                    hex::decode(&e.digest).unwrap_or_default(),
+                    Error::new(io::ErrorKind::InvalidData, e.error.clone()),
+                )
+            })
+            .collect()
+    }
+}
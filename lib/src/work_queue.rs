@@ -0,0 +1,176 @@
+//! Work-stealing queue feeding chunk processors
+//!
+//! Chunks vary wildly in size, so processing times do too. Every processor
+//! takes chunks in batches into its own local queue, and processors that
+//! run out of work steal chunks from the others: a processor stuck on a
+//! giant chunk doesn't hold up the small chunks queued behind it.
+//!
+//! Like a bounded channel, sending blocks while too many chunks are queued,
+//! and receiving returns `None` after all senders are gone.
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+struct State {
+    /// Items sent, but not received yet
+    queued: usize,
+    senders: usize,
+    receivers: usize,
+}
+
+struct Shared<T> {
+    injector: Injector<T>,
+    stealers: Vec<Stealer<T>>,
+    capacity: usize,
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// Queue of `capacity` items for `receivers` receivers
+pub(crate) fn bounded<T>(
+    receivers: usize,
+    capacity: usize,
+) -> (WorkSender<T>, Vec<WorkReceiver<T>>) {
+    let workers: Vec<_> = (0..receivers).map(|_| Worker::new_fifo()).collect();
+    let shared = Arc::new(Shared {
+        injector: Injector::new(),
+        stealers: workers.iter().map(Worker::stealer).collect(),
+        capacity: capacity.max(1),
+        state: Mutex::new(State {
+            queued: 0,
+            senders: 1,
+            receivers,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    });
+
+    let receivers = workers
+        .into_iter()
+        .enumerate()
+        .map(|(index, local)| WorkReceiver {
+            shared: Arc::clone(&shared),
+            local,
+            index,
+        })
+        .collect();
+
+    (WorkSender { shared }, receivers)
+}
+
+pub(crate) struct WorkSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> WorkSender<T> {
+    /// Queue `item`, waiting for room for it
+    ///
+    /// Fails (returning `item`) if all the receivers are gone.
+    pub(crate) fn send(&self, item: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.queued >= self.shared.capacity && state.receivers > 0 {
+            state = self.shared.not_full.wait(state).unwrap();
+        }
+        if state.receivers == 0 {
+            return Err(item);
+        }
+        state.queued += 1;
+        self.shared.injector.push(item);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for WorkSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        WorkSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for WorkSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+pub(crate) struct WorkReceiver<T> {
+    shared: Arc<Shared<T>>,
+    local: Worker<T>,
+    /// Position of `local` in `shared.stealers`
+    index: usize,
+}
+
+impl<T> WorkReceiver<T> {
+    /// Take the next item, waiting for one
+    ///
+    /// `None` after all senders are gone and the queue is empty.
+    pub(crate) fn recv(&self) -> Option<T> {
+        loop {
+            if let Some(item) = self.find() {
+                let mut state = self.shared.state.lock().unwrap();
+                state.queued -= 1;
+                self.shared.not_full.notify_one();
+                return Some(item);
+            }
+
+            let state = self.shared.state.lock().unwrap();
+            if state.queued == 0 {
+                if state.senders == 0 {
+                    return None;
+                }
+                drop(self.shared.not_empty.wait(state).unwrap());
+            } else {
+                // items are being moved between queues by other receivers
+                drop(state);
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Take an item from the local queue, or steal one
+    fn find(&self) -> Option<T> {
+        if let Some(item) = self.local.pop() {
+            return Some(item);
+        }
+
+        loop {
+            let mut retry = false;
+            match self.shared.injector.steal_batch_and_pop(&self.local) {
+                Steal::Success(item) => return Some(item),
+                Steal::Retry => retry = true,
+                Steal::Empty => {}
+            }
+            for (i, stealer) in self.shared.stealers.iter().enumerate() {
+                if i == self.index {
+                    continue;
+                }
+                match stealer.steal() {
+                    Steal::Success(item) => return Some(item),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+}
+
+impl<T> Drop for WorkReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            self.shared.not_full.notify_all();
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Names written over one chunk processor, see `Repo::write_session`
+use aio::AsyncIO;
+use chunk_processor::Message;
+use error;
+use generation::Generation;
+use name::Name;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use work_queue::WorkSender;
+use {CancelToken, Repo, WriteOptions};
+
+/// Writes sharing one chunk processor, given to the closure passed to
+/// `Repo::write_session`
+///
+/// `write` can be called from several threads at once: every stream is
+/// read and chunked in the thread writing it, while the chunks of all of
+/// them are compressed, encrypted and stored by the same threads, and
+/// deduplicated against each other.
+pub struct WriteSession<'a> {
+    repo: &'a Repo,
+    options: &'a WriteOptions,
+    generations: &'a [Generation],
+    process_tx: WorkSender<Message>,
+    aio: AsyncIO,
+    /// See `ChunkProcessor::stop_token`
+    stop: CancelToken,
+    /// Names written so far, in order
+    names: Mutex<Vec<(String, Name)>>,
+}
+
+impl<'a> WriteSession<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        options: &'a WriteOptions,
+        generations: &'a [Generation],
+        process_tx: WorkSender<Message>,
+        aio: AsyncIO,
+        stop: CancelToken,
+    ) -> Self {
+        WriteSession {
+            repo,
+            options,
+            generations,
+            process_tx,
+            aio,
+            stop,
+            names: Mutex::new(vec![]),
+        }
+    }
+
+    /// Store data from `reader` as `name_str`, like `Repo::write_with_options`
+    /// with the options of the session
+    ///
+    /// The name is stored once the session is over. Writing the same name
+    /// twice in a session fails with `error::Error::NameExists`.
+    pub fn write<R>(&self, name_str: &str, reader: R) -> io::Result<()>
+    where
+        R: Read + Send,
+    {
+        info!(self.repo.log, "Writing data"; "name" => name_str);
+        self.repo
+            .check_new_name(name_str, self.options, self.generations)?;
+        self.check_not_written(name_str)?;
+        let name = self.repo.write_stream(
+            reader,
+            self.options,
+            self.process_tx.clone(),
+            self.aio.clone(),
+            &self.stop,
+        )?;
+        let mut names = self.names.lock().unwrap();
+        // written concurrently in the meantime
+        Self::check_not_in(&names, name_str)?;
+        names.push((name_str.to_owned(), name));
+        Ok(())
+    }
+
+    fn check_not_written(&self, name_str: &str) -> io::Result<()> {
+        Self::check_not_in(&self.names.lock().unwrap(), name_str)
+    }
+
+    fn check_not_in(
+        names: &[(String, Name)],
+        name_str: &str,
+    ) -> io::Result<()> {
+        if names.iter().any(|(name, _)| name == name_str) {
+            return Err(error::Error::NameExists(name_str.into()).into());
+        }
+        Ok(())
+    }
+
+    /// Names written, ending the session
+    pub(crate) fn into_names(self) -> Vec<(String, Name)> {
+        self.names.into_inner().unwrap()
+    }
+}
@@ -114,6 +114,10 @@ impl SGData {
             0 => vec![],
             1 => {
                 let e = self.0.pop().unwrap();
+                // the part can be just a slice of the underlying data
+                if e.len() != e.owner().len() {
+                    return e.to_vec();
+                }
                 Arc::try_unwrap(e.into_inner())
                     .unwrap_or_else(|a| a.as_ref().clone())
             }
@@ -17,8 +17,9 @@
 //!  * cloud backends are WIP
 //!  * incremental, scalable garbage collection
 //!  * variety of supported algorithms:
-//!    * chunking: fastcdc, gear, bup
-//!    * hashing: blake2b, sha256
+//!    * chunking: fastcdc, gear, bup, fixed
+//!    * hashing: blake2b (default), sha256, sha512-256 (prefer it over
+//!      sha256 on 64-bit CPUs - it's noticeably faster)
 //!    * compression: zstd, deflate, xz2, bzip2, none
 //!    * encryption: curve25519, none
 //!    * very easy to add new ones
@@ -47,6 +48,12 @@
 //! Cloud storage integrations are missing. The architecture to support it is
 //! mostly implemented, but the actual backends are not.
 //!
+//! Storage classes (eg. S3 Standard for *names*, Infrequent Access for data
+//! chunks) can't be chosen per object yet: the local backend writes plain
+//! files, and B2 sets the storage policy per bucket, not per object. Index
+//! and data chunks are stored together (in `chunk/`), so a backend able to
+//! apply a class would need to be told the chunk type on writes, too.
+//!
 //! ## Installation
 //!
 //! If you have `cargo` installed:
@@ -72,6 +79,40 @@
 //!
 //! [building-issues]: http://bit.ly/2ypLPtJ
 //!
+//! ### Restore-only binary
+//!
+//! `rdedup-restore` can only read from an existing *repo* (`load`, `list`,
+//! `verify`, `check-index`). For rescue ISOs and initramfs images a small,
+//! static build can be made with:
+//!
+//! ```norust
+//! CARGO_PROFILE_RELEASE_OPT_LEVEL=z CARGO_PROFILE_RELEASE_DEBUG=false \
+//! CARGO_PROFILE_RELEASE_CODEGEN_UNITS=1 CARGO_PROFILE_RELEASE_PANIC=abort \
+//! cargo build --release --target x86_64-unknown-linux-musl \
+//!   --no-default-features --features with-bzip2,with-deflate,with-xz2,with-zstd \
+//!   --bin rdedup-restore
+//! strip target/x86_64-unknown-linux-musl/release/rdedup-restore
+//! ```
+//!
+//! This leaves out the `b2` backend (and its TLS dependencies). Keep all the
+//! compression features, unless it's known which ones the *repo* uses.
+//!
+//! The `CARGO_PROFILE_RELEASE_*` settings (optimizing for size) need Rust 1.43
+//! or newer; older toolchains ignore them, and build a larger binary.
+//!
+//! #### FUSE
+//!
+//! `rdedup mount` is available when built with the `with-fuse` feature (Linux
+//! only):
+//!
+//! ```norust
+//! cargo install rdedup --features with-fuse
+//! ```
+//!
+//! Mounting as a regular user needs `fusermount` (from `fuse` or `fuse3`
+//! packages). The feature needs Rust 1.69 or newer, for the
+//! [fuser](https://crates.io/crates/fuser) crate it's built on.
+//!
 //! ## Usage
 //!
 //! See `rdedup -h` for help.
@@ -79,17 +120,320 @@
 //! Rdedup always operates on a *repo*, that you provide as an argument
 //! (eg. `--dir <DIR>`), or via environment variable (eg. `RDEDUP_DIR`).
 //!
+//! With `--io-report` (eg. `rdedup --io-report store <name>`), the number of
+//! backend requests of each type, failed and retried ones, and bytes
+//! transferred are printed when the command ends - useful to find out why
+//! an operation was slow, or expensive on a cloud backend.
+//!
+//! With `--summary` (eg. `rdedup --summary store <name>`), the versions
+//! and settings a command was run with, how long it took, and what it did
+//! are printed when it ends, and kept in the *repo* (`runs.yml`, the latest
+//! 100 runs): `rdedup runs` lists them, so eg. a `store` that got slower
+//! can be compared with earlier ones. Nothing is recorded without it.
+//!
+//! With remote backends, the chunks read (up to 64M), and which chunks
+//! exist, are cached in memory, to avoid a round-trip for each of them;
+//! `--chunk-cache <size>` changes the size (`0` disables the cache).
+//!
+//! Nothing is cached on disk: the caches live in memory only, so a
+//! stolen machine doesn't leak chunk graphs of the *repo*, and there's
+//! no local cache to encrypt or clear. The checkpoints of `verify` are
+//! the only local files kept, in a directory given explicitly.
+//!
+//! Operations of remote backends failing with errors likely to go away (eg.
+//! a timeout, or a connection reset) are retried, up to 5 attempts, waiting
+//! longer before each one, so a hiccup of the network does not fail a long
+//! `store`; `--retries <attempts>` changes the number (`1` disables them).
+//!
+//! Commands started while the *repo* is locked by another one (eg. `store`
+//! during a `gc`) wait for it to finish, in turns, so a `gc` is not starved
+//! by `store`s started after it. With `--wait-for-lock <seconds>` (eg. in
+//! overlapping cron jobs) a command gives up and fails instead, if it can't
+//! get the lock in time.
+//!
 //! Supported commands:
 //!
 //! * `rdedup init` - create a new *repo*.
 //!   * `rdedup init --help` for repository configuration options.
+//!   * `rdedup init --encryption none` to store the data unencrypted (eg.
+//!     when the *repo* is on an already encrypted disk), saving the overhead
+//!     of encrypting every chunk. No passphrase is needed for anything.
+//!   * `rdedup init --pack-size 32M` to store small chunks in pack files,
+//!     instead of a file each (fewer files, eg. for cloud storage).
+//!   * `rdedup init --parity 8:2` to write a parity file next to each chunk
+//!     file (25% more space, for 8:2), so chunks with up to 2 of their 8
+//!     pieces damaged (eg. bit rot on flaky media) can be repaired with
+//!     `rdedup verify --repair`. Chunks in pack files have no parity.
+//!   * `rdedup init --chunking-tier large:64M:fastcdc:1M` to chunk data of
+//!     at least 64M (eg. disk images) into bigger chunks, while keeping the
+//!     default ones for small data. The tier is picked by the size of the
+//!     data, or explicitly with `rdedup store --chunking-tier large <name>`.
+//!   * `rdedup init --nesting 1 --nesting-width 3` to spread chunk files
+//!     over one level of directories named after the first 3 hex digits of
+//!     their digests, instead of two levels of 2 (`ab/cd/<digest>`). Fewer
+//!     levels suit small *repos*, more ones with hundreds of millions of
+//!     chunks. Widths other than 2 need repo version 19.
+//!   * `rdedup init --keyed-digests` to key digests of the data with a
+//!     secret derived from the *repo* secret key, so nobody with just access
+//!     to the *repo* can tell if it contains a known file (though sizes of
+//!     the stored chunks can still hint at it). Storing data then requires
+//!     the passphrase too.
+//!   * `rdedup init --hide-names` to store *names* under keyed hashes of
+//!     them, with the *names* themselves encrypted, so nobody with just
+//!     access to the *repo* can tell them (eg. hostnames or dates). Every
+//!     command using *names* (even `rdedup ls` and `rdedup gc`) then asks for
+//!     the passphrase (or takes `RDEDUP_SECRET`). Not supported with
+//!     `--recipient-key`; `rdedup change-key` and `rdedup seal` can't be used
+//!     on the *repo*.
+//!   * `rdedup init --sign-names` to sign records of the *names* with a key
+//!     derived from the *repo* secret key, checked whenever they are read, so
+//!     nobody with just write access to the *repo* can point a *name* at
+//!     other data unnoticed. Commands storing *names* (eg. `rdedup store`
+//!     and `rdedup rename`) then ask for the passphrase (or take
+//!     `RDEDUP_SECRET`); `rdedup change-key` can't be used on the *repo*.
+//!     The signing key is printed; keep it elsewhere to tell the *repo*
+//!     config wasn't replaced too. Needs repo version 20.
+//!   * `rdedup init --digest-nonces` to derive nonces of the encrypted
+//!     chunks from their digests, instead of storing a random one with each
+//!     chunk, so releases older than repo version 8 can use the *repo*
+//!     (together with `--bare-chunks`).
+//!   * `rdedup init --compress-names` to compress records of the *names*
+//!     (the data they point to, and their metadata) like the data. They
+//!     are not encrypted, as eg. `rdedup gc` doesn't ask for the passphrase.
+//!   * `rdedup init --min-compression-savings <percent>` to store chunks
+//!     that compression shrinks by less than `<percent>` uncompressed
+//!     (flagged in their header), saving the effort of decompressing eg.
+//!     media files on restore. Data that looks random is not even tried.
+//!     Needs repo version 23.
+//!   * `rdedup init --bare-chunks` to store chunks without the small header
+//!     identifying their format (compression, encryption), checked before
+//!     reading them, so releases older than repo version 10 can use the
+//!     *repo*.
+//!   * `rdedup init --bare-index` to store just the digests in the index,
+//!     without the lengths of the data they refer to, so releases older than
+//!     repo version 16 can use the *repo*. Loading a range of data then has
+//!     to read all of the data before it.
+//!   * `rdedup init --raw-names` to store names as file names as they are,
+//!     so releases older than repo version 17 can use the *repo*. Otherwise
+//!     characters Windows doesn't allow in file names (eg. `:`), trailing
+//!     dots and spaces and reserved names (eg. `con`) are percent-encoded,
+//!     so the *repo* can be used (and copied) on any platform.
+//!   * `rdedup init --recipient-key <public-key>` to seal the data also for
+//!     the holder of another key (eg. an offline recovery key), generated
+//!     with `rdedup gen-key`. Its secret key can be used instead of the
+//!     passphrase for reading, like the *repo* one (see `RDEDUP_SECRET`).
+//!     Can be given multiple times, but not with `--keyed-digests` or
+//!     `--hide-names`.
 //! * `rdedup store <name>` - store data from standard input under a given
 //!   *name*.
+//!   * `rdedup store --overwrite <name>` to atomically replace an existing
+//!     *name* (eg. `latest`).
+//!   * `rdedup store --if-unchanged skip <name>` to not store the *name* if
+//!     the same data is already stored (eg. nothing changed since the last
+//!     backup), or `--if-unchanged alias` to store it as an alias of the
+//!     existing *name*. `unchanged` is reported either way.
+//!   * `rdedup store --progress <name>` to print progress (bytes and chunks
+//!     stored so far, and how many of them were already in the *repo*) to
+//!     standard error every second.
+//!   * `rdedup store --read-buffer 4M <name>` to read the data in bigger
+//!     pieces (128K by default), eg. from spinning disks or NFS.
+//!   * `tar c ... | rdedup store --tar <name>` to chunk the files of a tar
+//!     archive apart, so they deduplicate no matter their order in the
+//!     archive (or changes to their metadata). Data that's not a tar archive
+//!     is stored as usual.
+//!   * `rdedup store --max-input-rate 50M --max-write-rate 10M <name>` to
+//!     read the data at most at 50MB per second, and write at most 10MB per
+//!     second (as compressed and encrypted) to the *repo*, eg. so a backup
+//!     doesn't starve a live server of I/O.
+//!   * `rdedup store --verify-writes <name>` to check every new chunk:
+//!     that it decompresses to its digest before it's encrypted, and that it
+//!     reads back from the *repo* as it was written. Catches corruption (eg.
+//!     by faulty memory or disks) at backup time, instead of at restore.
+//! * `rdedup import-chunks <dir>` - store *names* from chunk files made by
+//!   another (content-addressed) backup tool, without chunking them again.
+//!   `<dir>/manifest.yml` (or `--manifest <path>`) lists chunk files of
+//!   each *name*, eg. `names: {home: [ab/abcd..., 01/0123...]}`.
+//! * `rdedup import-bup <dir> [<branch>...]` - store the saves of a bup
+//!   repository (eg. `~/.bup`) as trees (see `store-tree`), without restoring
+//!   them first. Every save becomes a *name* of its branch and time, like
+//!   `home-2019-01-31T10:00:00Z`; saves already imported are skipped, so it
+//!   can be run again for new ones. The metadata bup keeps apart (owners,
+//!   times, ...) is not imported.
 //! * `rdedup load <name>` - load data stored under given *name* and write it
 //!   to standard output.
+//!   * `rdedup load --max-rate 50M --burst 200M <name>` to read at most 50MB
+//!     per second (on average) from the *repo*, eg. so a restore doesn't
+//!     starve other users of a shared storage.
+//!   * `rdedup load --skip-corrupt <name>` to salvage damaged data: data
+//!     chunks that can't be read are left out (not zero-filled, as their
+//!     size isn't known), and listed with the offsets where data is missing.
+//!     Fails at the end if anything was skipped.
+//!   * `rdedup load --progress <name>` to print progress (bytes and chunks
+//!     loaded so far, out of the size of the data) to standard error every
+//!     second.
+//!   * `rdedup load --decoded-cache 64M <name>` to keep more (default: 16M,
+//!     `0` disables it) of the data decoded, so chunks the data has many
+//!     times (eg. blocks of zeros) are read and decrypted only once.
+//!   * `rdedup load --memory-budget 256M <name>` to keep the memory the
+//!     restore takes within about that much, shrinking the caches to fit and
+//!     failing if the index of the data takes more than a quarter of it. The
+//!     data and index are read as a stream, holding only one index chunk of
+//!     every level of the index at once.
+//!   * `rdedup load --offset 1G --length 4M <name>` to load just a range of
+//!     the data, reading only the chunks with data in it.
+//! * `rdedup store-tree <name> <dir>` - store a directory tree, with every
+//!   file chunked on its own (so files deduplicate as well as if stored one
+//!   by one), keeping paths, permissions, modification times and symlinks.
+//!   * `rdedup load-tree <name> <dir> [<path>...]` to restore it (or just
+//!     given files and directories of it) into `<dir>`.
+//!   * `rdedup ls-tree <name>` to list its files, and
+//!     `rdedup cat-tree <name> <path>` to write one of them to standard
+//!     output.
+//! * `rdedup mount <dir>` - make all the *names* readable as files in `<dir>`
+//!   (Linux only, read-only), until unmounted with `fusermount -u <dir>` (or
+//!   `umount <dir>`). Data is read from the *repo* only when it's read from
+//!   the files, so eg. a stored disk image can be loop-mounted from it. In a
+//!   *repo* created with `--bare-index`, reading them from the start (eg.
+//!   with `cp`) is much faster.
+//!   Needs `rdedup` built with the `with-fuse` feature.
 //! * `rdedup rm <name>` - remove the given *name*.
-//! * `rdedup ls` - list all stored names.
+//! * `rdedup rename <old> <new>` - rename a *name*.
+//! * `rdedup alias <name> <alias>` - store another *name* pointing at the
+//!   same data, without copying it.
+//!   * `rdedup alias --overwrite <name> latest` to move a `latest` *name*
+//!     to the newest backup.
+//! * `rdedup copy --to <repo> <name>...` - copy *names* into another *repo*
+//!   (URL or directory), eg. one with new keys or settings. The data is
+//!   decrypted, then chunked, compressed and encrypted the way the other
+//!   *repo* does it, so it deduplicates with what's already there; *names*
+//!   keep their creation time. `--overwrite` replaces existing *names*.
+//! * `rdedup meta export [<pattern>] > names.yml` and
+//!   `rdedup meta import < names.yml` - move *names* (and their metadata)
+//!   between *repos* with the same data (sharing the chunk store, or mirrors
+//!   of each other), without copying any data. Import fails if the data of a
+//!   *name* is missing; `--overwrite` replaces existing *names*.
+//! * `rdedup ls [<pattern>]` - list all stored names (in order), or only
+//!   these starting with, or matching glob (eg. `'home-2019-*'`) *pattern*.
+//!   * `rdedup ls -l` to also show their creation time, size and number
+//!     of chunks.
+//! * `rdedup stat <name>...` - show creation time, size, number of chunks
+//!   and SHA-256 of the data of given *names*. The SHA-256 is recorded when
+//!   storing, and checked whenever the data is read back whole.
+//! * `rdedup stat-repo` - show statistics of the whole *repo*: *names*,
+//!   data and index chunks (and chunks left for `gc`), bytes stored, bytes
+//!   of data under all the *names*, average chunk size and the ratio of the
+//!   two (deduplication and compression together). Nothing is decrypted.
+//!   * `rdedup stat-repo --histogram` to show also how many data chunks are
+//!     of each size (rounded up to a power of two), as stored and before
+//!     compression, and the largest chunks. Eg. most chunks of the maximum
+//!     size mean the chunking finds few edges in the data.
 //! * `rdedup gc` - remove any no longer reachable data.
+//!   * `rdedup gc --dry-run` to only show the unreachable chunks (and their
+//!     size), and how many chunks each *name* reaches.
+//! * `rdedup recover` - remove leftovers of interrupted runs (eg. killed, or
+//!   of a crashed machine): temporary files of unfinished writes, generations
+//!   an interrupted `gc` didn't finish removing, and pack files without an
+//!   index. `rdedup recover --dry-run` only lists them. Locks need no
+//!   cleanup: they're released when the process holding them ends.
+//! * `rdedup repack` - move chunk files into pack files, and merge small
+//!   packs (`gc` does it too, on a *repo* with packing).
+//!   * `rdedup repack --pack-size 32M` to enable packing on an existing
+//!     *repo*.
+//! * `rdedup maintain` - do the maintenance (`gc`, verification of some
+//!   *names*) that is due according to the *repo* maintenance policy, set
+//!   with `rdedup maintenance-policy`. Meant to be run regularly (eg. daily
+//!   from `cron`); `rdedup maintain --history` shows what it did.
+//! * `rdedup prune --keep-daily 7 --keep-weekly 4 ...` - remove *names*
+//!   falling outside of a retention policy (by creation time, or a date at
+//!   the end of the *name*), optionally followed by `gc` with `--gc`.
+//!   * `rdedup prune --dry-run ...` to only show what would be removed.
+//! * `rdedup du <name>...` - show how much data given *names* use. Only the
+//!   index is read, unless the *repo* was created with `--bare-index`.
+//!   * `rdedup du --unique <name>...` to show instead how many chunks (and
+//!     bytes, as stored) no other *name* uses: what removing the *name*
+//!     would free after `gc`. All the other *names* are read to tell.
+//! * `rdedup overlap --set-a <name>,... --set-b <name>,...` - show how many
+//!   chunks (and stored bytes) two sets of *names* share, and how many are
+//!   used only by one of them.
+//! * `rdedup diff <name-a> <name-b>` - show how much the data of two
+//!   *names* (eg. consecutive backups) differs: chunks (with stored bytes,
+//!   and bytes of data in them) shared, and used only by one of them. Only
+//!   the index is read.
+//! * `rdedup bench [--size 256M] [--seed 0]` - measure how fast generated
+//!   data (the same for the same seed, partially duplicated and partially
+//!   compressible) is chunked, hashed, compressed and encrypted with the
+//!   settings of the *repo*, per CPU core. Nothing is stored.
+//! * `rdedup verify <name>...` - check integrity of data stored under
+//!   given *names*.
+//!   * `rdedup verify --checkpoint-dir <dir> <name>...` to save progress, and
+//!     resume interrupted verification; `rdedup verify-status <dir>` shows
+//!     the progress.
+//!   * `rdedup verify --repair <name>...` to repair corrupted chunks using
+//!     their parity files (see `init --parity`).
+//!   * `rdedup verify --repair-from <repo> <name>...` to copy missing and
+//!     corrupted chunks from another *repo* with the same keys (eg. an
+//!     offsite mirror, as a URL or a directory), and verify again.
+//! * `rdedup digest <name>...` - read the data of *names* whole, checking
+//!   every chunk as `load` does, without writing it anywhere, and print its
+//!   SHA-256 in the format of `sha256sum`, to compare with the original
+//!   (eg. in nightly checks, without scratch space for a restore).
+//! * `rdedup check-index [<name>...]` - quickly check integrity of the index
+//!   of given (by default: all) *names*, without reading the data.
+//! * `rdedup check-refs` - list stored chunks no *name* refers to (orphans,
+//!   left for `gc`), and chunks *names* refer to that are missing (dangling,
+//!   meaning data loss), with the *names* affected. Reads only the index.
+//! * `rdedup reconcile --inventory <report.csv> [--prefix <prefix>]` - compare
+//!   chunks needed by all the *names* with an S3 or GCS inventory report (CSV,
+//!   decompressed) of the storage, reporting missing chunks and objects no
+//!   *name* needs. Only *names* and index chunks are read, so it's cheap even
+//!   for huge *repos* in cloud storage. `<prefix>` is where the *repo* is in
+//!   the bucket. Objects stored after the report was made show up as missing.
+//! * `rdedup age-recipients <recipient>...` - allow [age][age] identities
+//!   to unlock the *repo* for reading, with `--age-identity <file>`.
+//!   `ssh-ed25519` public keys (quoted, as one argument) work as recipients,
+//!   and their (unencrypted) OpenSSH private keys as identities.
+//! * `rdedup replicas <url>...` - write new chunks and *names* to the given
+//!   replicas (URLs or directories, eg. on another disk or a remote storage)
+//!   too, before a write completes. Reads fall back to the replicas. Data
+//!   stored before isn't copied; no replicas stops replicating.
+//!   * `rdedup verify --replicas <name>...` checks that all the data of
+//!     *names* is on every replica too.
+//! * `rdedup append-only on` - refuse removing or replacing anything stored:
+//!   `rm`, `rename`, `prune`, `gc`, `repack`, `change-key` and overwriting
+//!   *names* fail (and `maintain` skips `gc`), so backups can't be destroyed
+//!   by mistake or by a script gone wrong. `rdedup append-only off` allows it
+//!   again, asking for the passphrase. Also `rdedup init --append-only`.
+//!   Only the config tells it: for the storage itself to refuse removals
+//!   (eg. against ransomware), use its own means too, such as object locks
+//!   or credentials that can't delete. Needs repo version 21.
+//! * `rdedup audit-log on` - record every `store`, `alias`, `rename`, `rm`,
+//!   `gc` and `change-key` in an audit log in the *repo*: time, operation,
+//!   *name* (not in repos hiding *names*), digest and size of the data.
+//!   `rdedup audit-log` prints it; `rdedup audit-log --verify` checks that no
+//!   entry was changed, removed or put in between (each one carries the
+//!   SHA-256 of the previous one) and prints the digest of the latest one:
+//!   keep it elsewhere, to notice the latest entries removed too. Entries of
+//!   processes writing at the same time can break the chain. `rdedup
+//!   audit-log off` stops recording, asking for the passphrase. Also `rdedup
+//!   init --audit-log`. Needs repo version 22.
+//! * `rdedup secret-key` - print the plain secret key, usable with
+//!   `RDEDUP_SECRET`/`RDEDUP_SECRET_FILE` instead of the passphrase.
+//! * `rdedup gen-key` - generate a key for `rdedup init --recipient-key`:
+//!   print the secret key, and its public key to standard error.
+//! * `rdedup change-key` - replace the key the data is encrypted with (eg.
+//!   when the secret key might have leaked), and re-encrypt everything for the
+//!   new one, still sealed with the passphrase. If interrupted, everything
+//!   stays readable; run it again to finish. [age][age] recipients have to be
+//!   set again. Not supported with `--keyed-digests`, `--hide-names` or
+//!   `--sign-names`.
+//! * `rdedup seal` - make the *repo* read-only for good (eg. when archiving
+//!   a finished project), writing a manifest of all *names* and their data,
+//!   signed with a key derived from the *repo* secret key. The signing key
+//!   is printed; keep it elsewhere to tell the seal is genuine.
+//!   * `rdedup seal --restrict-permissions` to also make everything
+//!     read-only for the storage (file permissions of a local *repo*).
+//!   * `rdedup verify-seal` checks the signature and the *names* stored
+//!     against the manifest, and prints it.
 //!
 //!
 //! In combination with [rdup][rdup] this can be used to store and restore your
@@ -111,6 +455,15 @@
 //! While it's not advised, if `RDEDUP_PASSPHRASE` is defined, it will be used
 //! instead of interactively asking user for password.
 //!
+//! # `RDEDUP_SECRET` environment variable
+//!
+//! For reading, the hex encoded secret key (as printed by `rdedup
+//! secret-key`, or `rdedup gen-key` for a recipient key) can be used
+//! instead of the passphrase. It's taken from `RDEDUP_SECRET`, or from the
+//! file `RDEDUP_SECRET_FILE` points to. The file must not be readable by
+//! the group or other users.
+//!
+//! [age]: https://age-encryption.org
 //! [bup]: https://github.com/bup/bup/
 //! [rdup]: https://github.com/miekg/rdup
 //! [syncthing]: https://syncthing.net
@@ -121,6 +474,10 @@
 
 extern crate clap;
 extern crate hex;
+#[cfg(feature = "with-fuse")]
+extern crate fuser;
+#[cfg(feature = "with-fuse")]
+extern crate libc;
 extern crate rdedup_lib as lib;
 extern crate rpassword;
 #[macro_use]
@@ -129,31 +486,37 @@ extern crate slog_async;
 extern crate slog_term;
 extern crate url;
 
-use clap::{Arg, SubCommand};
+use clap::{Arg, ArgMatches, SubCommand};
 use lib::settings;
-use lib::Repo;
+use lib::{age, NameStat, Repo, SizeHistogram, VerifyCheckpoint};
 use slog::Drain;
-use std::error::Error;
-use std::{env, io, process};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io, process};
 use url::Url;
 
 use std::str::FromStr;
 
-// Url parse with `io::Result` shortcut
-fn parse_url(s: &str) -> io::Result<Url> {
-    Url::parse(s).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("URI parsing error : {}", e.description()),
-        )
-    })
-}
-
 #[derive(Clone)]
 struct Options {
     url: Url,
     debug_level: u32,
     settings: settings::Repo,
+    /// Repo opened by the command, for the IO report
+    repo: Option<Repo>,
+    /// How long opening `repo` took, for the run summary
+    open_time: Duration,
+    /// Results of the command, for the run summary
+    counts: BTreeMap<String, u64>,
+    /// Chunk cache size, instead of the default for the backend
+    chunk_cache_size: Option<u64>,
+    /// How long to wait for the repository lock (forever if `None`)
+    lock_wait: Option<Duration>,
+    /// Attempts of backend operations, instead of the default
+    retries: Option<u32>,
 }
 
 impl Options {
@@ -162,6 +525,12 @@ impl Options {
             url,
             debug_level: 0,
             settings: settings::Repo::new(),
+            repo: None,
+            open_time: Duration::from_secs(0),
+            counts: BTreeMap::new(),
+            chunk_cache_size: None,
+            lock_wait: None,
+            retries: None,
         }
     }
 
@@ -202,25 +571,66 @@ impl Options {
             .expect("wrong compression");
     }
 
-    fn set_chunking(&mut self, s: &str, chunk_size: Option<u32>) {
-        match s {
-            "bup" => self
-                .settings
-                .use_bup_chunking(chunk_size)
-                .expect("wrong chunking settings"),
-            "gear" => self
-                .settings
-                .use_gear_chunking(chunk_size)
-                .expect("wrong chunking settings"),
-            "fastcdc" => self
-                .settings
-                .use_fastcdc_chunking(chunk_size)
-                .expect("wrong chunking settings"),
-            _ => {
-                eprintln!("unsupported encryption: {}", s);
-                process::exit(-1);
+    fn open_repo(&mut self, log: slog::Logger) -> io::Result<Repo> {
+        let start = Instant::now();
+        let mut repo = Repo::open(&self.url, log)?;
+        self.open_time = start.elapsed();
+        if let Some(size) = self.chunk_cache_size {
+            repo.set_chunk_cache_size(size)?;
+        }
+        repo.set_lock_wait(self.lock_wait);
+        match self.retries {
+            Some(1) => repo.set_retry_policy(None)?,
+            Some(max_attempts) => {
+                repo.set_retry_policy(Some(lib::RetryPolicy {
+                    max_attempts,
+                    ..lib::RetryPolicy::default()
+                }))?
             }
-        };
+            None => {}
+        }
+        if repo.hides_names() {
+            util::unlock_names(&mut repo)?;
+        }
+        self.repo = Some(repo.clone());
+        Ok(repo)
+    }
+
+    /// Like `open_repo`, for commands storing names (unlocked if signed)
+    fn open_repo_storing_names(
+        &mut self,
+        log: slog::Logger,
+    ) -> io::Result<Repo> {
+        let mut repo = self.open_repo(log)?;
+        if repo.signs_names() && !repo.hides_names() {
+            util::unlock_names(&mut repo)?;
+            self.repo = Some(repo.clone());
+        }
+        Ok(repo)
+    }
+
+    fn set_chunking(&mut self, s: &str, chunk_size: Option<u32>) {
+        use_chunking(&mut self.settings, s, chunk_size);
+    }
+
+    fn add_chunking_tier(&mut self, s: &str) {
+        let (name, min_stream_size, scheme, chunk_size) =
+            parse_chunking_tier(s).expect("invalid chunking tier");
+        let mut tier = settings::Repo::new();
+        use_chunking(&mut tier, scheme, Some(chunk_size.trailing_zeros()));
+        self.settings
+            .add_chunking_tier(name, min_stream_size, &tier)
+            .expect("invalid chunking tier");
+    }
+
+    fn set_chunk_size_bounds(
+        &mut self,
+        min_size: Option<u64>,
+        max_size: Option<u64>,
+    ) {
+        self.settings
+            .set_chunk_size_bounds(min_size, max_size)
+            .expect("invalid chunk size bounds");
     }
 
     fn set_hashing(&mut self, s: &str) {
@@ -233,6 +643,10 @@ impl Options {
                 .settings
                 .set_hashing(lib::settings::Hashing::Blake2b)
                 .expect("wrong hashing settings"),
+            "sha512-256" => self
+                .settings
+                .set_hashing(lib::settings::Hashing::Sha512Trunc256)
+                .expect("wrong hashing settings"),
             _ => {
                 eprintln!("unsupported hashing: {}", s);
                 process::exit(-1);
@@ -243,8 +657,151 @@ impl Options {
     fn set_nesting(&mut self, level: u8) {
         self.settings.set_nesting(level).expect("invalid nesting");
     }
+
+    fn set_packing(&mut self, packing: lib::Packing) {
+        self.settings.set_packing(packing).expect("invalid packing");
+    }
+}
+
+fn use_chunking(
+    settings: &mut settings::Repo,
+    s: &str,
+    chunk_size: Option<u32>,
+) {
+    match s {
+        "bup" => settings
+            .use_bup_chunking(chunk_size)
+            .expect("wrong chunking settings"),
+        "gear" => settings
+            .use_gear_chunking(chunk_size)
+            .expect("wrong chunking settings"),
+        "fastcdc" => settings
+            .use_fastcdc_chunking(chunk_size)
+            .expect("wrong chunking settings"),
+        "fixed" => settings
+            .use_fixed_chunking(chunk_size)
+            .expect("wrong chunking settings"),
+        _ => {
+            eprintln!("unsupported encryption: {}", s);
+            process::exit(-1);
+        }
+    };
+}
+
+/// Parse `NAME:MIN_STREAM_SIZE:SCHEME:CHUNK_SIZE`
+fn parse_chunking_tier(s: &str) -> Option<(&str, u64, &str, u64)> {
+    let parts: Vec<_> = s.split(':').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    Some((
+        parts[0],
+        util::parse_size(parts[1])?,
+        parts[2],
+        util::parse_size(parts[3])?,
+    ))
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_chunking_tier(s: String) -> Result<(), String> {
+    match parse_chunking_tier(&s) {
+        Some((_, _, scheme, _))
+            if ["bup", "gear", "fastcdc", "fixed"].contains(&scheme) =>
+        {
+            Ok(())
+        }
+        _ => Err("chunking tier must be NAME:MIN_SIZE:SCHEME:CHUNK_SIZE \
+                  (eg. 'large:64M:fastcdc:1M')"
+            .into()),
+    }
+}
+
+/// Copy chunks of `name` found damaged (`results`) from `other`, until
+/// verification finds nothing new to copy
+///
+/// Index chunks copied can refer to chunks not verified before.
+fn repair_from_other(
+    repo: &Repo,
+    other: &Repo,
+    name: &str,
+    dec: &lib::DecryptHandle,
+    mut results: lib::VerifyResults,
+) -> io::Result<()> {
+    let mut attempted = HashSet::new();
+    loop {
+        results
+            .errors
+            .retain(|(digest, _)| !attempted.contains(digest));
+        if results.errors.is_empty() {
+            return Ok(());
+        }
+        attempted.extend(results.errors.iter().map(|(d, _)| d.clone()));
+        let repaired = repo.repair_from(other, &results)?;
+        for digest in &repaired.repaired {
+            println!("chunk {} - copied", hex::encode(digest));
+        }
+        for (digest, e) in &repaired.failed {
+            println!("chunk {} - can't copy: {}", hex::encode(digest), e);
+        }
+        if repaired.repaired.is_empty() {
+            return Ok(());
+        }
+        results = repo.verify(name, dec)?;
+        println!(
+            "found {} missing and {} corrupted chunk(s) after copying",
+            results.missing().len(),
+            results.corrupted().len()
+        );
+        for err in &results.errors {
+            println!("chunk {} - {}", hex::encode(&err.0), err.1);
+        }
+    }
+}
+
+/// Parse `DATA_SHARDS:PARITY_SHARDS`
+fn parse_parity(s: &str) -> Option<lib::Parity> {
+    let mut parts = s.split(':');
+    let parity = lib::Parity {
+        data_shards: parts.next()?.parse().ok()?,
+        parity_shards: parts.next()?.parse().ok()?,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(parity)
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_parity(s: String) -> Result<(), String> {
+    match parse_parity(&s) {
+        Some(ref parity)
+            if parity.data_shards > 0 && parity.parity_shards > 0 =>
+        {
+            Ok(())
+        }
+        _ => Err("parity must be DATA_SHARDS:PARITY_SHARDS (eg. '8:2')".into()),
+    }
+}
+
+/// Packing from `--pack-size` and `--max-packed-chunk`, if given
+fn packing_from_args(matches: &ArgMatches) -> Option<lib::Packing> {
+    let size = |arg| {
+        matches
+            .value_of(arg)
+            .map(|s| util::parse_size(s).expect("Invalid size option"))
+    };
+    size("PACK_SIZE").map(|pack_size| {
+        let max_chunk_size = size("MAX_PACKED_CHUNK")
+            .unwrap_or_else(|| lib::Packing::default().max_chunk_size);
+        lib::Packing {
+            pack_size,
+            max_chunk_size: max_chunk_size.min(pack_size),
+        }
+    })
 }
 
+#[cfg(feature = "with-fuse")]
+mod mount;
 mod util;
 use util::{read_new_passphrase, read_passphrase};
 
@@ -255,6 +812,33 @@ fn validate_chunk_size(s: String) -> Result<(), String> {
         .ok_or_else(|| "Can't parse a human readable byte-size value".into())
 }
 
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_seed(s: String) -> Result<(), String> {
+    u64::from_str(&s)
+        .map(|_| ())
+        .map_err(|_| "seed must be a number".into())
+}
+
+fn validate_min_compression_savings(s: String) -> Result<(), String> {
+    match u8::from_str(&s) {
+        Ok(percent) if percent <= 100 => Ok(()),
+        _ => Err("compression savings must be a percentage".into()),
+    }
+}
+
+fn validate_retries(s: String) -> Result<(), String> {
+    match u32::from_str(&s) {
+        Ok(attempts) if attempts >= 1 => Ok(()),
+        _ => Err("retries must be a number of attempts, at least 1".into()),
+    }
+}
+
+fn validate_lock_wait(s: String) -> Result<(), String> {
+    u64::from_str(&s)
+        .map(|_| ())
+        .map_err(|_| "lock wait time must be a number of seconds".into())
+}
+
 #[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
 fn validate_nesting(s: String) -> Result<(), String> {
     let msg = "nesting must be an integer between 0 and 31";
@@ -268,6 +852,13 @@ fn validate_nesting(s: String) -> Result<(), String> {
     Ok(())
 }
 
+fn validate_nesting_width(s: String) -> Result<(), String> {
+    match u8::from_str(s.as_str()) {
+        Ok(width) if (1..=4).contains(&width) => Ok(()),
+        _ => Err("nesting width must be an integer between 1 and 4".into()),
+    }
+}
+
 fn create_logger(verbosity: u32, timing_verbosity: u32) -> slog::Logger {
     match (verbosity, timing_verbosity) {
         (0, 0) => slog::Logger::root(slog::Discard, o!()),
@@ -319,8 +910,210 @@ fn create_logger(verbosity: u32, timing_verbosity: u32) -> slog::Logger {
     }
 }
 
+fn checkpoint_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.yml", name))
+}
+
+fn print_verify_status(dir: &Path) -> io::Result<()> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    paths.sort();
+
+    for path in paths {
+        if path.extension() != Some(OsStr::new("yml")) {
+            continue;
+        }
+        let checkpoint = match VerifyCheckpoint::load(&path)? {
+            Some(checkpoint) => checkpoint,
+            None => continue,
+        };
+        let progress = match checkpoint.percent() {
+            Some(percent) => format!("{:.1}%", percent),
+            None => format!("{} chunk(s)", checkpoint.done),
+        };
+        println!(
+            "{}: {}{}, {} corrupted chunk(s)",
+            checkpoint.name,
+            progress,
+            if checkpoint.complete { " (complete)" } else { "" },
+            checkpoint.errors.len()
+        );
+    }
+    Ok(())
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn print_summary(summary: &lib::RunSummary) {
+    let phases: Vec<_> = summary
+        .phases
+        .iter()
+        .map(|phase| format!("{} {:.3}s", phase.name, phase.secs))
+        .collect();
+    eprintln!(
+        "summary: {} {} in {:.3}s ({})",
+        summary.command,
+        if summary.ok { "done" } else { "failed" },
+        summary.secs(),
+        phases.join(", ")
+    );
+    eprintln!(
+        "summary: rdedup {}, repository version {}",
+        summary.version, summary.repo_version
+    );
+    let settings = &summary.settings;
+    eprintln!(
+        "summary: chunking {}, hashing {}, compression {}, encryption {}{}",
+        settings.chunking,
+        settings.hashing,
+        settings.compression,
+        settings.encryption,
+        if settings.packing { ", packing" } else { "" }
+    );
+    eprintln!(
+        "summary: {} request(s), {} bytes down, {} bytes up",
+        summary.io.requests(),
+        summary.io.bytes_read,
+        summary.io.bytes_written
+    );
+    if !summary.counts.is_empty() {
+        let counts: Vec<_> = summary
+            .counts
+            .iter()
+            .map(|(name, count)| format!("{} {}", name, count))
+            .collect();
+        eprintln!("summary: {}", counts.join(", "));
+    }
+}
+
+/// Print (and keep in the repository) the summary of running `command`
+fn record_summary(
+    repo: &Repo,
+    command: &str,
+    options: &Options,
+    run_time: Duration,
+    ok: bool,
+) {
+    let mut summary = repo.run_summary(command);
+    summary.ok = ok;
+    summary.phases = vec![
+        lib::RunPhase {
+            name: "open".into(),
+            secs: secs(options.open_time),
+        },
+        lib::RunPhase {
+            name: command.into(),
+            secs: secs(run_time) - secs(options.open_time),
+        },
+    ];
+    summary.counts = options.counts.clone();
+    print_summary(&summary);
+    if repo.is_read_only() {
+        return;
+    }
+    if let Err(e) = repo.record_run(&summary) {
+        eprintln!("summary: not recorded: {}", e);
+    }
+}
+
+/// Line describing a name, with unknown (old) metadata shown as `-`
+fn print_io_report(stats: &lib::IoStats) {
+    eprintln!(
+        "io: {} request(s), {} error(s), {} retried",
+        stats.requests(),
+        stats.errors,
+        stats.retries
+    );
+    eprintln!(
+        "io: {} read(s), {} range read(s), {} write(s), {} metadata read(s)",
+        stats.reads, stats.range_reads, stats.writes, stats.metadata_reads
+    );
+    eprintln!(
+        "io: {} list(s), {} remove(s), {} rename(s)",
+        stats.lists, stats.removes, stats.renames
+    );
+    eprintln!(
+        "io: {} bytes down, {} bytes up",
+        stats.bytes_read, stats.bytes_written
+    );
+    if let Some(ratio) = stats.cache_hit_ratio() {
+        eprintln!(
+            "io: {:.1}% cache hit ratio ({} hit(s), {} miss(es))",
+            ratio * 100.0,
+            stats.cache_hits,
+            stats.cache_misses
+        );
+    }
+}
+
+/// Print `stored` and `logical` chunk sizes side by side, by bucket
+fn print_size_histogram(stored: &SizeHistogram, logical: &SizeHistogram) {
+    println!("data chunk sizes: stored, logical");
+    let buckets: BTreeSet<_> =
+        stored.buckets.keys().chain(logical.buckets.keys()).collect();
+    for bucket in buckets {
+        let count = |histogram: &SizeHistogram| {
+            histogram.buckets.get(bucket).cloned().unwrap_or(0)
+        };
+        println!(
+            "  <= {}: {}, {}",
+            1u64 << bucket,
+            count(stored),
+            count(logical)
+        );
+    }
+    if logical.unknown > 0 {
+        println!("  unknown: -, {}", logical.unknown);
+    }
+    println!("  max: {}, {}", stored.max, logical.max);
+}
+
+/// Metadata of a name, tab separated; with the SHA-256 of the data too if
+/// `sha256`
+fn format_stat(name: &str, stat: &NameStat, sha256: bool) -> String {
+    fn or_unknown<T: ToString>(v: Option<T>) -> String {
+        v.map(|v| v.to_string()).unwrap_or_else(|| "-".into())
+    }
+
+    let mut line = format!(
+        "{}\t{}\t{}\t",
+        or_unknown(stat.created.map(|t| t.to_rfc3339())),
+        or_unknown(stat.size),
+        or_unknown(stat.chunks),
+    );
+    if sha256 {
+        line += &or_unknown(stat.sha256.as_ref().map(hex::encode));
+        line += "\t";
+    }
+    line + name
+}
+
+/// Entry of a tree, like `ls -l` shows it
+fn format_tree_entry(entry: &lib::TreeEntry) -> String {
+    let (kind, target) = match entry.kind {
+        lib::EntryKind::Dir => ('d', String::new()),
+        lib::EntryKind::File => ('-', String::new()),
+        lib::EntryKind::Symlink => (
+            'l',
+            format!(" -> {}", entry.target.as_ref().map_or("", |t| &**t)),
+        ),
+    };
+    format!(
+        "{}{:04o}\t{}\t{}\t{}{}",
+        kind,
+        entry.mode,
+        entry.mtime.to_rfc3339(),
+        entry.size,
+        entry.path,
+        target
+    )
+}
+
 fn run() -> io::Result<()> {
-    let matches = clap::App::new("rdedup")
+    let app = clap::App::new("rdedup")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Dawid Ciężarkiewicz <dpc@dpc.pw>")
         .about("Data deduplication toolkit")
@@ -328,16 +1121,40 @@ fn run() -> io::Result<()> {
              .help("Path to rdedup repository. Override `RDEDUP_DIR` environment variable"))
         .arg(Arg::with_name("REPO_URI").short("u").long("repo").takes_value(true).value_name("URI").conflicts_with("REPO_DIR")
              .help("Rdedup repository URI. Overrides the `RDEDUP_URI` environment variable"))
+        .arg(Arg::with_name("AGE_IDENTITY").long("age-identity").takes_value(true).value_name("PATH")
+             .help("Unlock repository with an age identity file, instead of the passphrase"))
         .arg(Arg::with_name("VERBOSE").short("v").multiple(true).help("Increase debugging level for general messages"))
         .arg(Arg::with_name("VERBOSE_TIMINGS").short("t").multiple(true).help("Increase debugging level for timings"))
+        .arg(Arg::with_name("CHUNK_CACHE").long("chunk-cache").takes_value(true).value_name("N").validator(validate_chunk_size)
+             .help("Cache up to N bytes of chunks in memory (default: 64M for remote backends, 0 otherwise)"))
+        .arg(Arg::with_name("WAIT_FOR_LOCK").long("wait-for-lock").takes_value(true).value_name("SECONDS").validator(validate_lock_wait)
+             .help("Fail if the repository stays locked by another command for SECONDS (instead of waiting forever)"))
+        .arg(Arg::with_name("RETRIES").long("retries").takes_value(true).value_name("ATTEMPTS").validator(validate_retries)
+             .help("Attempt backend operations failing with transient errors up to ATTEMPTS times (default: 5 for remote backends, 1 otherwise)"))
+        .arg(Arg::with_name("IO_REPORT").long("io-report").help("Print backend requests made, and data transferred, at the end"))
+        .arg(Arg::with_name("SUMMARY").long("summary")
+             .help("Print a summary of the run (versions, settings, timings and stats) at the end, and keep it in the repository"))
         .subcommand(SubCommand::with_name("init").display_order(0)
                     .about("Create a new repository")
                     .arg(Arg::with_name("PWHASH").long("pwhash").takes_value(true).value_name("STRENGTH").possible_values(&["strong", "interactive", "weak"])
                          .default_value("strong").help("Set pwhash strength"))
-                    .arg(Arg::with_name("CHUNKING").long("chunking").takes_value(true).value_name("SCHEME").possible_values(&["bup", "gear", "fastcdc"])
+                    .arg(Arg::with_name("CHUNKING").long("chunking").takes_value(true).value_name("SCHEME").possible_values(&["bup", "gear", "fastcdc", "fixed"])
                          .default_value("fastcdc").help("Set chunking scheme"))
                     .arg(Arg::with_name("CHUNK_SIZE").long("chunk-size").takes_value(true).value_name("N").validator(validate_chunk_size)
                          .default_value("128K").help("Set average chunk size"))
+                    .arg(Arg::with_name("CHUNK_MIN_SIZE").long("chunk-min-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Set minimum chunk size"))
+                    .arg(Arg::with_name("CHUNK_MAX_SIZE").long("chunk-max-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Set maximum chunk size"))
+                    .arg(Arg::with_name("CHUNKING_TIER").long("chunking-tier").takes_value(true).multiple(true).number_of_values(1)
+                         .value_name("NAME:MIN_SIZE:SCHEME:CHUNK_SIZE").validator(validate_chunking_tier)
+                         .help("Chunk data of at least MIN_SIZE differently (eg. 'large:64M:fastcdc:1M')"))
+                    .arg(Arg::with_name("PACK_SIZE").long("pack-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Store small chunks in pack files of this size, instead of a file each"))
+                    .arg(Arg::with_name("MAX_PACKED_CHUNK").long("max-packed-chunk").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .requires("PACK_SIZE").help("Set maximum size of chunks stored in pack files (default: 1M)"))
+                    .arg(Arg::with_name("PARITY").long("parity").takes_value(true).value_name("DATA_SHARDS:PARITY_SHARDS").validator(validate_parity)
+                         .help("Write parity files of chunk files, to repair up to PARITY_SHARDS damaged shards of each (eg. '8:2')"))
                     .arg(Arg::with_name("ENCRYPTION").long("encryption").takes_value(true).value_name("SCHEME").possible_values(&["curve25519", "none"])
                          .default_value("curve25519").help("Set encryption scheme"))
                     .arg(Arg::with_name("COMPRESSION").long("compression").takes_value(true).value_name("SCHEME")
@@ -348,76 +1165,298 @@ fn run() -> io::Result<()> {
                                                    \"smaller\""))
                     .arg(Arg::with_name("NESTING").long("nesting").takes_value(true).value_name("N").validator(validate_nesting)
                          .default_value("2").help("Set level of folder nesting"))
-                    .arg(Arg::with_name("HASHING").long("hashing").takes_value(true).value_name("SCHEME").possible_values(&["sha256", "blake2b"])
-                         .default_value("blake2b").help("Set hashing scheme")))
+                    .arg(Arg::with_name("NESTING_WIDTH").long("nesting-width").takes_value(true).value_name("DIGITS").validator(validate_nesting_width)
+                         .default_value("2").help("Set hex digits of the digest naming each folder level"))
+                    .arg(Arg::with_name("HASHING").long("hashing").takes_value(true).value_name("SCHEME").possible_values(&["sha256", "blake2b", "sha512-256"])
+                         .default_value("blake2b").help("Set hashing scheme"))
+                    .arg(Arg::with_name("KEYED_DIGESTS").long("keyed-digests")
+                         .help("Key digests of the data, so nobody can tell if a known file is stored"))
+                    .arg(Arg::with_name("HIDE_NAMES").long("hide-names")
+                         .help("Store names encrypted, under keyed hashes of them, so nobody can tell them"))
+                    .arg(Arg::with_name("APPEND_ONLY").long("append-only")
+                         .help("Refuse removing or replacing anything stored (see append-only)"))
+                    .arg(Arg::with_name("AUDIT_LOG").long("audit-log")
+                         .help("Record operations in an audit log (see audit-log)"))
+                    .arg(Arg::with_name("SIGN_NAMES").long("sign-names")
+                         .help("Sign name records, so nobody can point names at other data unnoticed"))
+                    .arg(Arg::with_name("DIGEST_NONCES").long("digest-nonces")
+                         .help("Derive nonces from digests, so older releases can use the repo"))
+                    .arg(Arg::with_name("COMPRESS_NAMES").long("compress-names")
+                         .help("Compress name records like the data"))
+                    .arg(Arg::with_name("MIN_COMPRESSION_SAVINGS").long("min-compression-savings").takes_value(true).value_name("PERCENT").validator(validate_min_compression_savings)
+                         .help("Store chunks compression shrinks by less than PERCENT uncompressed"))
+                    .arg(Arg::with_name("BARE_CHUNKS").long("bare-chunks")
+                         .help("Store chunks without a format header, so older releases can use the repo"))
+                    .arg(Arg::with_name("BARE_INDEX").long("bare-index")
+                         .help("Store index records without data lengths, so older releases can use the repo"))
+                    .arg(Arg::with_name("RAW_NAMES").long("raw-names")
+                         .help("Store names as file names without encoding them for Windows, so older releases can use the repo"))
+                    .arg(Arg::with_name("RECIPIENT_KEY").long("recipient-key").takes_value(true).multiple(true).number_of_values(1)
+                         .value_name("PUBLIC_KEY").help("Seal data also for the holder of given key (as printed by gen-key)")))
         .subcommand(SubCommand::with_name("store").about("Store data to repository").display_order(1)
+                    .arg(Arg::with_name("OVERWRITE").long("overwrite").help("Replace the name if it already exists"))
+                    .arg(Arg::with_name("CHUNKING_TIER").long("chunking-tier").takes_value(true).value_name("TIER")
+                         .help("Use given chunking tier (or 'default'), instead of picking one by the data size"))
+                    .arg(Arg::with_name("IF_UNCHANGED").long("if-unchanged").takes_value(true).value_name("ACTION")
+                         .possible_values(&["store", "skip", "alias"]).default_value("store")
+                         .help("What to do if the same data is already stored under another name (or this one)"))
+                    .arg(Arg::with_name("READ_BUFFER").long("read-buffer").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Read the data in pieces of N bytes (default: 128K)"))
+                    .arg(Arg::with_name("PROGRESS").long("progress")
+                         .help("Print progress to standard error, every second"))
+                    .arg(Arg::with_name("TAR").long("tar")
+                         .help("Data is a tar archive: chunk files in it apart, so they deduplicate regardless of their order"))
+                    .arg(Arg::with_name("MAX_INPUT_RATE").long("max-input-rate").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Read the data at most at N bytes per second (eg. '50M')"))
+                    .arg(Arg::with_name("MAX_WRITE_RATE").long("max-write-rate").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Write at most N bytes per second to the repository (eg. '10M')"))
+                    .arg(Arg::with_name("VERIFY_WRITES").long("verify-writes")
+                         .help("Read every new chunk back after writing it, and fail if it's not as written"))
                     .arg(Arg::with_name("NAME").required(true).help("Name to store to")))
+        .subcommand(SubCommand::with_name("import-chunks").about("Store names from chunk files made by another tool, without chunking them again")
+                    .arg(Arg::with_name("MANIFEST").long("manifest").takes_value(true).value_name("PATH")
+                         .help("Manifest listing chunk files of each name (default: DIR/manifest.yml)"))
+                    .arg(Arg::with_name("DIR").required(true).help("Directory with the chunk files")))
+        .subcommand(SubCommand::with_name("store-tree").about("Store a directory tree, chunking every file on its own")
+                    .arg(Arg::with_name("NAME").required(true).help("Name to store to"))
+                    .arg(Arg::with_name("DIR").required(true).help("Directory to store")))
         .subcommand(SubCommand::with_name("load").about("Load data from repository").display_order(2)
+                    .arg(Arg::with_name("MAX_RATE").long("max-rate").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Read at most N bytes per second from the repository (eg. '50M')"))
+                    .arg(Arg::with_name("BURST").long("burst").takes_value(true).value_name("N").validator(validate_chunk_size).requires("MAX_RATE")
+                         .help("Allow reading N bytes at once after a pause, above the --max-rate (default: one second worth)"))
+                    .arg(Arg::with_name("SKIP_CORRUPT").long("skip-corrupt")
+                         .help("Leave out data that can't be read (eg. corrupted) and continue, instead of failing"))
+                    .arg(Arg::with_name("PROGRESS").long("progress").conflicts_with_all(&["SKIP_CORRUPT", "OFFSET", "LENGTH"])
+                         .help("Print progress to standard error, every second"))
+                    .arg(Arg::with_name("DECODED_CACHE").long("decoded-cache").takes_value(true).value_name("N").validator(validate_chunk_size).conflicts_with_all(&["SKIP_CORRUPT", "OFFSET", "LENGTH"])
+                         .help("Keep up to N bytes of the data decoded, to read chunks repeated in it only once (default: 16M)"))
+                    .arg(Arg::with_name("MEMORY_BUDGET").long("memory-budget").takes_value(true).value_name("N").validator(validate_chunk_size).conflicts_with_all(&["SKIP_CORRUPT", "OFFSET", "LENGTH"])
+                         .help("Keep the memory taken by caches and the index within about N bytes, failing if the index doesn't fit"))
+                    .arg(Arg::with_name("OFFSET").long("offset").takes_value(true).value_name("N").validator(validate_chunk_size).conflicts_with("SKIP_CORRUPT")
+                         .help("Load data starting at byte N (eg. '1G')"))
+                    .arg(Arg::with_name("LENGTH").long("length").takes_value(true).value_name("N").validator(validate_chunk_size).conflicts_with("SKIP_CORRUPT")
+                         .help("Load at most N bytes"))
                     .arg(Arg::with_name("NAME").required(true).help("Name to load from")))
-        .subcommand(SubCommand::with_name("list").visible_alias("ls").about("List names stored in the repository").display_order(3))
+        .subcommand(SubCommand::with_name("load-tree").about("Restore a directory tree stored with store-tree")
+                    .arg(Arg::with_name("NAME").required(true).help("Name to load from"))
+                    .arg(Arg::with_name("DIR").required(true).help("Directory to restore into"))
+                    .arg(Arg::with_name("PATH").multiple(true).help("Only restore these files or directories of the tree")))
+        .subcommand(SubCommand::with_name("cat-tree").about("Write a file of a tree stored with store-tree to standard output")
+                    .arg(Arg::with_name("NAME").required(true).help("Name of the tree"))
+                    .arg(Arg::with_name("PATH").required(true).help("Path of the file in the tree")))
+        .subcommand(SubCommand::with_name("ls-tree").about("List files of a tree stored with store-tree")
+                    .arg(Arg::with_name("NAME").required(true).help("Name of the tree")))
+        .subcommand(SubCommand::with_name("list").visible_alias("ls").about("List names stored in the repository").display_order(3)
+                    .arg(Arg::with_name("LONG").short("l").long("long").help("Show creation time, size and number of chunks"))
+                    .arg(Arg::with_name("PATTERN").help("Only list names starting with PATTERN, or matching it if it's a glob (eg. 'home-*-01')")))
+        .subcommand(SubCommand::with_name("stat").about("Show metadata of stored name(s)")
+                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to show")))
+        .subcommand(SubCommand::with_name("stat-repo").about("Show statistics of the whole repository (chunks, sizes, deduplication)")
+                    .arg(Arg::with_name("HISTOGRAM").long("histogram")
+                         .help("Show also how data chunk sizes are distributed, and the largest chunks")))
         .subcommand(SubCommand::with_name("remove").visible_alias("rm").about("Remove name(s) stored in the repository").display_order(4)
                     .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to remove")))
+        .subcommand(SubCommand::with_name("rename").visible_alias("mv").about("Rename a name stored in the repository")
+                    .arg(Arg::with_name("OLD").required(true).help("Name to rename"))
+                    .arg(Arg::with_name("NEW").required(true).help("New name")))
+        .subcommand(SubCommand::with_name("alias").about("Store another name pointing at the same data, without copying it")
+                    .arg(Arg::with_name("OVERWRITE").long("overwrite").help("Replace the alias if it already exists"))
+                    .arg(Arg::with_name("NAME").required(true).help("Existing name"))
+                    .arg(Arg::with_name("ALIAS").required(true).help("Name to store")))
+        .subcommand(SubCommand::with_name("copy").about("Copy names into another repository, re-encrypting and re-chunking them with its keys and settings")
+                    .arg(Arg::with_name("TO").long("to").takes_value(true).value_name("REPO").required(true)
+                         .help("Repository to copy to (URL or directory)"))
+                    .arg(Arg::with_name("OVERWRITE").long("overwrite").help("Replace names that already exist there"))
+                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to copy")))
+        .subcommand(SubCommand::with_name("meta").about("Move names between repos with the same data (shared chunk store, mirrors), without the data")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(SubCommand::with_name("export").about("Write names (and their metadata) to standard output")
+                                .arg(Arg::with_name("PATTERN").help("Only export names starting with PATTERN, or matching it if it's a glob")))
+                    .subcommand(SubCommand::with_name("import").about("Store names exported with `meta export`, read from standard input")
+                                .arg(Arg::with_name("OVERWRITE").long("overwrite").help("Replace names that already exist"))))
         .subcommand(SubCommand::with_name("change_passphrase").visible_alias("chpasswd")
                     .about("Change the passphrase protecting the encryption key (if any)"))
+        .subcommand(SubCommand::with_name("change-key")
+                    .about("Replace the encryption key (eg. if it might have leaked), re-encrypting all the data"))
+        .subcommand(SubCommand::with_name("age-recipients")
+                    .about("Allow age identities to unlock the repository (no recipients revokes access)")
+                    .arg(Arg::with_name("RECIPIENT").multiple(true).help("age recipients (age1... or ssh-ed25519 public keys)")))
+        .subcommand(SubCommand::with_name("replicas")
+                    .about("Write new data to replicas too (no replicas stops replicating)")
+                    .arg(Arg::with_name("REPLICA").multiple(true).help("Replica URLs or directories")))
+        .subcommand(SubCommand::with_name("append-only")
+                    .about("Refuse removing or replacing anything stored, or allow it again (asks for the passphrase)")
+                    .arg(Arg::with_name("MODE").required(true).possible_values(&["on", "off"]).help("on or off")))
+        .subcommand(SubCommand::with_name("audit-log")
+                    .about("Show the audit log, or start or stop recording operations in it (stopping asks for the passphrase)")
+                    .arg(Arg::with_name("MODE").possible_values(&["on", "off"]).help("on or off"))
+                    .arg(Arg::with_name("VERIFY").long("verify").conflicts_with("MODE")
+                         .help("Check that no entry was changed, removed or put in between, and print the digest of the latest one")))
+        .subcommand(SubCommand::with_name("secret-key")
+                    .about("Print the plain secret key, usable instead of the passphrase for reading"))
+        .subcommand(SubCommand::with_name("gen-key")
+                    .about("Generate a key for init --recipient-key: print the secret key, and the public key to stderr"))
+        .subcommand(SubCommand::with_name("seal").about("Make the repository read-only for good, with a signed manifest of the names stored")
+                    .arg(Arg::with_name("RESTRICT_PERMISSIONS").long("restrict-permissions")
+                         .help("Make everything read-only for the storage too (eg. file permissions)")))
+        .subcommand(SubCommand::with_name("verify-seal")
+                    .about("Check the seal of the repository, and print its manifest"))
+        .subcommand(SubCommand::with_name("prune").about("Remove names falling outside of a retention policy")
+                    .arg(Arg::with_name("PREFIX").long("prefix").takes_value(true).value_name("PREFIX")
+                         .help("Only prune names starting with PREFIX"))
+                    .arg(Arg::with_name("KEEP_LAST").long("keep-last").takes_value(true).value_name("N").default_value("0")
+                         .help("Keep N newest names"))
+                    .arg(Arg::with_name("KEEP_DAILY").long("keep-daily").takes_value(true).value_name("N").default_value("0")
+                         .help("Keep the newest name of each of the last N days"))
+                    .arg(Arg::with_name("KEEP_WEEKLY").long("keep-weekly").takes_value(true).value_name("N").default_value("0")
+                         .help("Keep the newest name of each of the last N weeks"))
+                    .arg(Arg::with_name("KEEP_MONTHLY").long("keep-monthly").takes_value(true).value_name("N").default_value("0")
+                         .help("Keep the newest name of each of the last N months"))
+                    .arg(Arg::with_name("DRY_RUN").long("dry-run").short("n")
+                         .help("Only show what would be removed"))
+                    .arg(Arg::with_name("GC").long("gc").conflicts_with("DRY_RUN")
+                         .help("Garbage collect afterwards"))
+                    .arg(Arg::with_name("GRACE_TIME").long("grace").takes_value(true).value_name("SECONDS").default_value("86400")
+                         .help("Set grace time in seconds of the garbage collection")))
+        .subcommand(SubCommand::with_name("maintenance-policy").about("Show or change what `maintain` does (0 disables a trigger)")
+                    .arg(Arg::with_name("GC_EVERY_STORES").long("gc-every-stores").takes_value(true).value_name("N")
+                         .help("Garbage collect after N stores"))
+                    .arg(Arg::with_name("GC_GARBAGE_PERCENT").long("gc-garbage-percent").takes_value(true).value_name("PERCENT")
+                         .help("Garbage collect when more than PERCENT of chunks is unreachable"))
+                    .arg(Arg::with_name("GC_GRACE_TIME").long("gc-grace").takes_value(true).value_name("SECONDS")
+                         .help("Set grace time in seconds of the garbage collection"))
+                    .arg(Arg::with_name("SCRUB_DAILY_PERCENT").long("scrub-daily-percent").takes_value(true).value_name("PERCENT")
+                         .help("Verify PERCENT of the names a day")))
+        .subcommand(SubCommand::with_name("runs").about("Show runs recorded with --summary")
+                    .arg(Arg::with_name("YAML").long("yaml").help("Print the whole summaries, as YAML")))
+        .subcommand(SubCommand::with_name("maintain").about("Do the maintenance that is due, according to the maintenance policy")
+                    .arg(Arg::with_name("DRY_RUN").long("dry-run").short("n").help("Only show what is due"))
+                    .arg(Arg::with_name("HISTORY").long("history").conflicts_with("DRY_RUN").help("Show maintenance done recently")))
         .subcommand(SubCommand::with_name("gc").about("Garbage collect unreferenced chunks")
                     .arg(Arg::with_name("GRACE_TIME").long("grace").takes_value(true).value_name("SECONDS").default_value("86400")
-                         .help("Set grace time in seconds")))
+                         .help("Set grace time in seconds"))
+                    .arg(Arg::with_name("DRY_RUN").long("dry-run").short("n")
+                         .help("Only show the chunks that are unreachable, and how many chunks each name reaches")))
+        .subcommand(SubCommand::with_name("recover").about("Remove leftovers of interrupted runs (temporary files, half-removed generations, packs without index)")
+                    .arg(Arg::with_name("DRY_RUN").long("dry-run").short("n").help("Only show the leftovers")))
+        .subcommand(SubCommand::with_name("repack").about("Move small chunk files into pack files, and merge small packs")
+                    .arg(Arg::with_name("PACK_SIZE").long("pack-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Enable packing (or change the pack size) first"))
+                    .arg(Arg::with_name("MAX_PACKED_CHUNK").long("max-packed-chunk").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .requires("PACK_SIZE").help("Set maximum size of chunks stored in pack files (default: 1M)")))
         .subcommand(SubCommand::with_name("verify").about("Verify integrity of data stored in the repository")
+                    .arg(Arg::with_name("CHECKPOINT_DIR").long("checkpoint-dir").takes_value(true).value_name("DIR")
+                         .help("Save progress in DIR, and resume interrupted verification from it"))
+                    .arg(Arg::with_name("REPLICAS").long("replicas")
+                         .help("Check that all the chunks are on every replica too"))
+                    .arg(Arg::with_name("REPAIR").long("repair")
+                         .help("Repair corrupted chunks using their parity files"))
+                    .arg(Arg::with_name("REPAIR_FROM").long("repair-from").takes_value(true).value_name("REPO")
+                         .help("Copy missing and corrupted chunks from another repository with the same keys (URL or directory)"))
                     .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to verify")))
+        .subcommand(SubCommand::with_name("digest").about("Print the SHA-256 of the data of names, as sha256sum does for the original, reading it whole without writing it anywhere")
+                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to read")))
+        .subcommand(SubCommand::with_name("verify-status").about("Show progress of verifications saved with `verify --checkpoint-dir`")
+                    .arg(Arg::with_name("CHECKPOINT_DIR").required(true).help("Checkpoint directory")))
+        .subcommand(SubCommand::with_name("check-index").about("Check integrity of the index only (cheap, doesn't need the passphrase)")
+                    .arg(Arg::with_name("NAME").multiple(true).help("Names to check (all by default)")))
+        .subcommand(SubCommand::with_name("check-refs").about("Find chunks not referred to by any name, and referred to but missing (doesn't need the passphrase)"))
+        .subcommand(SubCommand::with_name("reconcile").about("Compare the chunks needed with an inventory report of the storage (S3 or GCS CSV)")
+                    .arg(Arg::with_name("INVENTORY").long("inventory").takes_value(true).value_name("FILE").required(true)
+                         .help("Inventory report (decompressed CSV)"))
+                    .arg(Arg::with_name("PREFIX").long("prefix").takes_value(true).value_name("PREFIX").default_value("")
+                         .help("Location of the repository in the bucket")))
         .subcommand(SubCommand::with_name("du").about("Calculate disk usage due to the data stored for a set of names")
+                    .arg(Arg::with_name("UNIQUE").long("unique")
+                         .help("Count only chunks no other name uses, with the bytes they take as stored (what removing the name would free)"))
                     .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to check")))
-        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
-        .get_matches();
-
-    let url: Url = if let Some(loc) = matches.value_of_os("REPO_URI") {
-        let s = loc.to_os_string().into_string().map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("URI not valid UTF-8 string"),
-            )
-        })?;
-        parse_url(&s)?
-    } else if let Some(dir) = matches.value_of_os("REPO_DIR") {
-        Url::from_file_path(dir).map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("URI parsing error: {}", dir.to_string_lossy()),
-            )
-        })?
-    } else if let Some(loc) = env::var_os("RDEDUP_URI") {
-        if env::var_os("RDEDUP_DIR").is_some() {
-            eprintln!(
-                "Can't use both RDEDUP_REPOSITORY and RDEDUP_DIR  at the same time"
-            );
-            process::exit(-1);
+        .subcommand(SubCommand::with_name("bench").about("Measure how fast generated data is chunked, hashed, compressed and encrypted with the settings of the repository")
+                    .arg(Arg::with_name("SIZE").long("size").takes_value(true).value_name("N").validator(validate_chunk_size).default_value("256M")
+                         .help("Bytes of data to generate"))
+                    .arg(Arg::with_name("SEED").long("seed").takes_value(true).value_name("N").validator(validate_seed).default_value("0")
+                         .help("Generate the data from this seed (the same data for the same seed)")))
+        .subcommand(SubCommand::with_name("diff").about("Show how much the data of two names (eg. consecutive backups) differs")
+                    .arg(Arg::with_name("NAME_A").required(true).help("First name"))
+                    .arg(Arg::with_name("NAME_B").required(true).help("Second name")))
+        .subcommand(SubCommand::with_name("overlap").about("Show chunks shared between two sets of names, and exclusive to each of them")
+                    .arg(Arg::with_name("SET_A").long("set-a").takes_value(true).value_name("NAMES").required(true).use_delimiter(true)
+                         .help("Comma separated names of the first set"))
+                    .arg(Arg::with_name("SET_B").long("set-b").takes_value(true).value_name("NAMES").required(true).use_delimiter(true)
+                         .help("Comma separated names of the second set")))
+        .subcommand(SubCommand::with_name("format-vectors")
+                    .about("Print canonical test vectors of the repository format"))
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp);
+    #[cfg(feature = "with-fuse")]
+    let app = app.subcommand(SubCommand::with_name("mount").about("Mount names as read-only files in a directory (until unmounted)")
+                             .arg(Arg::with_name("DIR").required(true).help("Directory to mount at")));
+    #[cfg(feature = "with-deflate")]
+    let app = app.subcommand(SubCommand::with_name("import-bup").about("Store the saves of a bup repository as trees, without restoring them first")
+                             .arg(Arg::with_name("DIR").required(true).help("bup repository (eg. ~/.bup)"))
+                             .arg(Arg::with_name("BRANCH").multiple(true).help("Branches to import (all by default)")));
+    let matches = app.get_matches();
+
+    // Does not need a repository
+    match matches.subcommand() {
+        ("format-vectors", Some(_matches)) => {
+            print!("{}", lib::format_vectors()?.to_yaml());
+            return Ok(());
         }
+        ("gen-key", Some(_matches)) => {
+            let sec_key = lib::SecretKey::generate();
+            eprintln!("public key: {}", sec_key.public_key());
+            println!("{}", sec_key);
+            return Ok(());
+        }
+        ("verify-status", Some(matches)) => {
+            let dir = matches.value_of_os("CHECKPOINT_DIR").expect("dir");
+            return print_verify_status(Path::new(dir));
+        }
+        _ => {}
+    }
 
-        let s = loc.to_os_string().into_string().map_err(|_e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("URI not valid UTF-8 string"),
-            )
-        })?;
-        parse_url(&s)?
-    } else if let Some(dir) = env::var_os("RDEDUP_DIR") {
-        Url::from_file_path(&dir).map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("URI parsing error: {}", dir.to_string_lossy()),
-            )
-        })?
-    } else {
-        eprintln!("Repository location not specified");
-        process::exit(-1);
-    };
+    let url = util::repo_url(&matches)?;
 
     let mut options = Options::new(url);
+    options.chunk_cache_size = matches
+        .value_of("CHUNK_CACHE")
+        .map(|s| util::parse_size(s).expect("Invalid chunk cache size"));
+    options.lock_wait = matches.value_of("WAIT_FOR_LOCK").map(|s| {
+        Duration::from_secs(u64::from_str(s).expect("Invalid lock wait time"))
+    });
+    options.retries = matches
+        .value_of("RETRIES")
+        .map(|s| u32::from_str(s).expect("Invalid number of retries"));
+    let age_identity = matches.value_of_os("AGE_IDENTITY");
 
     let log = create_logger(
         matches.occurrences_of("VERBOSE") as u32,
         matches.occurrences_of("VERBOSE_TIMINGS") as u32,
     );
 
+    let start = Instant::now();
+    let res = run_command(&matches, &mut options, age_identity, log);
+    if matches.is_present("SUMMARY") {
+        if let Some(ref repo) = options.repo {
+            let command = matches.subcommand_name().expect("subcommand");
+            let ok = res.is_ok();
+            record_summary(repo, command, &options, start.elapsed(), ok);
+        }
+    }
+    if matches.is_present("IO_REPORT") {
+        if let Some(ref repo) = options.repo {
+            print_io_report(&repo.io_stats());
+        }
+    }
+    res
+}
+
+fn run_command(
+    matches: &ArgMatches,
+    options: &mut Options,
+    age_identity: Option<&OsStr>,
+    log: slog::Logger,
+) -> io::Result<()> {
     match matches.subcommand() {
         ("init", Some(matches)) => {
             options.set_chunking(
@@ -429,6 +1468,14 @@ fn run() -> io::Result<()> {
                     })
                     .map(|u| u.trailing_zeros()),
             );
+            options.set_chunk_size_bounds(
+                matches.value_of("CHUNK_MIN_SIZE").map(|s| {
+                    util::parse_size(s).expect("Invalid chunk min size option")
+                }),
+                matches.value_of("CHUNK_MAX_SIZE").map(|s| {
+                    util::parse_size(s).expect("Invalid chunk max size option")
+                }),
+            );
             options.set_encryption(matches.value_of("ENCRYPTION").unwrap());
             options.settings.set_pwhash(settings::PWHash::from(
                 matches.value_of("PWHASH").unwrap(),
@@ -441,43 +1488,290 @@ fn run() -> io::Result<()> {
             options.set_nesting(
                 u8::from_str(matches.value_of("NESTING").unwrap()).unwrap(),
             );
+            options
+                .settings
+                .set_nesting_width(
+                    u8::from_str(matches.value_of("NESTING_WIDTH").unwrap())
+                        .unwrap(),
+                )
+                .expect("invalid nesting width");
             options.set_hashing(matches.value_of("HASHING").unwrap());
-            let _ = Repo::init(
+            options
+                .settings
+                .set_keyed_digests(matches.is_present("KEYED_DIGESTS"));
+            options
+                .settings
+                .set_hidden_names(matches.is_present("HIDE_NAMES"));
+            options
+                .settings
+                .set_signed_names(matches.is_present("SIGN_NAMES"));
+            options
+                .settings
+                .set_append_only(matches.is_present("APPEND_ONLY"));
+            options
+                .settings
+                .set_audit_log(matches.is_present("AUDIT_LOG"));
+            options
+                .settings
+                .set_digest_nonces(matches.is_present("DIGEST_NONCES"));
+            options
+                .settings
+                .set_compress_names(matches.is_present("COMPRESS_NAMES"));
+            let min_savings = matches.value_of("MIN_COMPRESSION_SAVINGS");
+            if let Some(percent) = min_savings {
+                options
+                    .settings
+                    .set_min_compression_savings(
+                        u8::from_str(percent).expect("checked"),
+                    )
+                    .expect("invalid compression savings");
+            }
+            options
+                .settings
+                .set_bare_chunks(matches.is_present("BARE_CHUNKS"));
+            options
+                .settings
+                .set_bare_index(matches.is_present("BARE_INDEX"));
+            options
+                .settings
+                .set_raw_names(matches.is_present("RAW_NAMES"));
+            let pub_keys = matches.values_of("RECIPIENT_KEY");
+            for pub_key in pub_keys.into_iter().flatten() {
+                options.settings.add_recipient(pub_key)?;
+            }
+            for tier in matches.values_of("CHUNKING_TIER").into_iter().flatten()
+            {
+                options.add_chunking_tier(tier);
+            }
+            if let Some(packing) = packing_from_args(matches) {
+                options.set_packing(packing);
+            }
+            if let Some(parity) = matches.value_of("PARITY") {
+                options
+                    .settings
+                    .set_parity(parse_parity(parity).expect("validated"))
+                    .expect("invalid parity");
+            }
+            let repo = Repo::init(
                 &options.url,
                 &|| util::read_new_passphrase(),
-                options.settings,
+                options.settings.clone(),
                 log,
             )?;
+            if let Some(key) = repo.names_public_key() {
+                println!("names signing key: {}", key);
+            }
+            options.repo = Some(repo);
         }
         ("store", Some(matches)) => {
             let name = matches.value_of("NAME").expect("name agument missing");
-            let repo = Repo::open(&options.url, log)?;
+            let mut repo = options.open_repo_storing_names(log)?;
+            let write_rate = util::rate_from_arg(matches, "MAX_WRITE_RATE");
+            repo.set_write_rate(write_rate)?;
             let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
-            let stats = repo.write(name, &mut io::stdin(), &enc)?;
+            let write_options = lib::WriteOptions {
+                overwrite: matches.is_present("OVERWRITE"),
+                chunking_tier: matches
+                    .value_of("CHUNKING_TIER")
+                    .map(|tier| tier.to_owned()),
+                if_unchanged: match matches.value_of("IF_UNCHANGED") {
+                    Some("skip") => lib::IfUnchanged::Skip,
+                    Some("alias") => lib::IfUnchanged::Alias,
+                    _ => lib::IfUnchanged::Store,
+                },
+                // no transforms are registered by the command line tool
+                transform: None,
+                threads: Default::default(),
+                read_buffer_size: matches
+                    .value_of("READ_BUFFER")
+                    .and_then(util::parse_size)
+                    .map(|size| size as usize),
+                tar: matches.is_present("TAR"),
+                progress: util::progress_from_args(matches),
+                cancel: None,
+                input_rate: util::rate_from_arg(matches, "MAX_INPUT_RATE"),
+                verify_writes: matches.is_present("VERIFY_WRITES"),
+            };
+            let stats = repo.write_with_options(
+                name,
+                &mut io::stdin(),
+                &enc,
+                &write_options,
+            )?;
             println!("{} new chunks", stats.new_chunks);
             println!("{} new bytes", stats.new_bytes);
+            options
+                .counts
+                .insert("new_chunks".into(), stats.new_chunks as u64);
+            options.counts.insert("new_bytes".into(), stats.new_bytes);
+            if let Some(existing) = stats.unchanged_from {
+                println!("unchanged (same data as {})", existing);
+            }
+        }
+        ("import-chunks", Some(matches)) => {
+            let dir = Path::new(matches.value_of_os("DIR").expect("dir"));
+            let manifest = match matches.value_of_os("MANIFEST") {
+                Some(path) => PathBuf::from(path),
+                None => dir.join("manifest.yml"),
+            };
+            let manifest = lib::ImportManifest::load(&manifest)?;
+            let repo = options.open_repo_storing_names(log)?;
+            let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
+            let results = repo.import_chunks(dir, &manifest, &enc)?;
+            println!("{} names", results.names);
+            println!("{} chunks", results.chunks);
+            println!("{} bytes", results.bytes);
+        }
+        #[cfg(feature = "with-deflate")]
+        ("import-bup", Some(matches)) => {
+            let dir = Path::new(matches.value_of_os("DIR").expect("dir"));
+            let branches: Vec<_> = matches
+                .values_of("BRANCH")
+                .map(|branches| branches.collect())
+                .unwrap_or_default();
+            let repo = options.open_repo_storing_names(log)?;
+            let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
+            let results = repo.import_bup(dir, &branches, &enc)?;
+            println!("{} names", results.names);
+            println!("{} chunks", results.chunks);
+            println!("{} bytes", results.bytes);
+        }
+        ("store-tree", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("name");
+            let dir = Path::new(matches.value_of_os("DIR").expect("dir"));
+            let repo = options.open_repo_storing_names(log)?;
+            let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
+            let index = repo.write_tree(name, dir, &enc)?;
+            println!("{} entries", index.entries.len());
+            println!("{} bytes", index.size());
+        }
+        ("load-tree", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("name");
+            let dir = Path::new(matches.value_of_os("DIR").expect("dir"));
+            let paths: Vec<_> = matches
+                .values_of("PATH")
+                .map(|paths| paths.collect())
+                .unwrap_or_default();
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            repo.read_tree(name, dir, &paths, &dec)?;
+        }
+        ("cat-tree", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("name");
+            let path = matches.value_of("PATH").expect("path");
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            repo.read_tree_file(name, path, &mut io::stdout(), &dec)?;
+        }
+        ("ls-tree", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("name");
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            for entry in repo.tree_index(name, &dec)?.entries {
+                println!("{}", format_tree_entry(&entry));
+            }
         }
         ("load", Some(matches)) => {
             let name = matches.value_of("NAME").expect("name agument missing");
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| util::read_passphrase())?;
-            repo.read(name, &mut io::stdout(), &dec)?;
+            let mut repo = options.open_repo(log)?;
+            repo.set_read_rate(util::read_rate_from_args(matches))?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            util::load(matches, &repo, name, &dec)?;
         }
         ("change_passphrase", Some(_matches)) => {
-            let mut repo = Repo::open(&options.url, log)?;
+            let mut repo = options.open_repo(log)?;
             repo.change_passphrase(&|| read_passphrase(), &|| {
                 read_new_passphrase()
             })?;
         }
+        ("change-key", Some(_matches)) => {
+            let mut repo = options.open_repo(log)?;
+            repo.change_key(
+                &|| read_passphrase(),
+                &lib::SecretKey::generate(),
+            )?;
+        }
         ("remove", Some(matches)) => {
-            let repo = Repo::open(&options.url, log)?;
+            let repo = options.open_repo(log)?;
             for name in matches.values_of("NAME").expect("names missing") {
                 repo.rm(name)?;
             }
         }
+        ("rename", Some(matches)) => {
+            let repo = options.open_repo_storing_names(log)?;
+            repo.rename(
+                matches.value_of("OLD").expect("old name missing"),
+                matches.value_of("NEW").expect("new name missing"),
+            )?;
+        }
+        ("alias", Some(matches)) => {
+            let repo = options.open_repo_storing_names(log)?;
+            let write_options = lib::WriteOptions {
+                overwrite: matches.is_present("OVERWRITE"),
+                ..Default::default()
+            };
+            repo.alias_with_options(
+                matches.value_of("NAME").expect("name missing"),
+                matches.value_of("ALIAS").expect("alias missing"),
+                &write_options,
+            )?;
+        }
+        ("copy", Some(matches)) => {
+            let mut dest = Repo::open(
+                &util::replica_url(matches.value_of("TO").expect("to"))?,
+                log.clone(),
+            )?;
+            if dest.hides_names() || dest.signs_names() {
+                util::unlock_names(&mut dest)?;
+            }
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            let enc = dest.unlock_encrypt(&|| util::read_passphrase())?;
+            let write_options = lib::WriteOptions {
+                overwrite: matches.is_present("OVERWRITE"),
+                ..Default::default()
+            };
+            for name in matches.values_of("NAME").expect("values") {
+                let stats =
+                    repo.copy_to(name, &dest, &dec, &enc, &write_options)?;
+                println!(
+                    "{}: {} new chunks, {} new bytes",
+                    name, stats.new_chunks, stats.new_bytes
+                );
+            }
+        }
+        ("meta", Some(matches)) => match matches.subcommand() {
+            ("export", Some(matches)) => {
+                let repo = options.open_repo(log)?;
+                let export = repo.export_names(matches.value_of("PATTERN"))?;
+                println!("{}", export.to_yaml());
+            }
+            ("import", Some(matches)) => {
+                let mut yaml = String::new();
+                io::stdin().read_to_string(&mut yaml)?;
+                let export: lib::NamesExport = yaml.parse()?;
+                let repo = options.open_repo_storing_names(log)?;
+                let write_options = lib::WriteOptions {
+                    overwrite: matches.is_present("OVERWRITE"),
+                    ..Default::default()
+                };
+                let count = repo.import_names(&export, &write_options)?;
+                println!("{} names imported", count);
+            }
+            _ => panic!("Unrecognized subcommand"),
+        },
+        ("du", Some(matches)) if matches.is_present("UNIQUE") => {
+            let repo = options.open_repo(log)?;
+
+            for name in matches.values_of("NAME").expect("names missing") {
+                let result = repo.unique_size(name)?;
+                println!("{} chunks", result.chunks);
+                println!("{} bytes", result.bytes);
+            }
+        }
         ("du", Some(matches)) => {
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
 
             for name in matches.values_of("NAME").expect("names missing") {
                 let result = repo.du(name, &dec)?;
@@ -485,32 +1779,574 @@ fn run() -> io::Result<()> {
                 println!("{} bytes", result.bytes);
             }
         }
+        ("bench", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
+            let size = matches
+                .value_of("SIZE")
+                .and_then(util::parse_size)
+                .expect("validated");
+            let seed = matches
+                .value_of("SEED")
+                .and_then(|s| s.parse().ok())
+                .expect("validated");
+            let results = repo.bench(size, seed, &enc)?;
+            println!(
+                "{} bytes: {} chunks, {} unique ({} bytes, {} compressed)",
+                results.bytes,
+                results.chunks,
+                results.unique_chunks,
+                results.unique_bytes,
+                results.compressed_bytes
+            );
+            let mb_per_sec = |rate: f64| rate / 1_000_000.0;
+            println!(
+                "chunking: {:.1} MB/s",
+                mb_per_sec(results.chunking_rate())
+            );
+            println!(
+                "hashing: {:.1} MB/s",
+                mb_per_sec(results.hashing_rate())
+            );
+            println!(
+                "compression: {:.1} MB/s",
+                mb_per_sec(results.compression_rate())
+            );
+            println!(
+                "encryption: {:.1} MB/s",
+                mb_per_sec(results.encryption_rate())
+            );
+        }
+        ("age-recipients", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let recipients = matches
+                .values_of("RECIPIENT")
+                .into_iter()
+                .flatten()
+                .map(age::Recipient::parse)
+                .collect::<io::Result<Vec<_>>>()?;
+            repo.set_age_recipients(&|| read_passphrase(), &recipients)?;
+        }
+        ("replicas", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            let replicas = matches
+                .values_of("REPLICA")
+                .into_iter()
+                .flatten()
+                .map(util::replica_url)
+                .collect::<io::Result<Vec<_>>>()?;
+            repo.set_replicas(&replicas)?;
+        }
+        ("append-only", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            repo.set_append_only(
+                &|| util::read_passphrase(),
+                matches.value_of("MODE") == Some("on"),
+            )?;
+        }
+        ("audit-log", Some(matches)) => {
+            if let Some(mode) = matches.value_of("MODE") {
+                let mut repo = options.open_repo(log)?;
+                repo.set_audit_log(&|| util::read_passphrase(), mode == "on")?;
+                return Ok(());
+            }
+            let repo = options.open_repo(log)?;
+            if matches.is_present("VERIFY") {
+                let results = repo.verify_audit_log()?;
+                println!("{} entries ok", results.entries);
+                if let Some(head) = results.head {
+                    println!("head: {}", hex::encode(head));
+                }
+                return Ok(());
+            }
+            for entry in repo.audit_log()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    entry.seq,
+                    entry.time.to_rfc3339(),
+                    entry.operation,
+                    match (entry.from, entry.name) {
+                        (Some(from), Some(name)) => {
+                            format!("{} -> {}", from, name)
+                        }
+                        (_, name) => name.unwrap_or_else(|| "-".into()),
+                    },
+                    entry
+                        .digest
+                        .map(hex::encode)
+                        .unwrap_or_else(|| "-".into()),
+                    entry
+                        .bytes
+                        .map(|bytes| bytes.to_string())
+                        .unwrap_or_else(|| "-".into()),
+                );
+            }
+        }
+        ("secret-key", Some(_matches)) => {
+            let repo = options.open_repo(log)?;
+            println!("{}", repo.secret_key(&|| read_passphrase())?);
+        }
+        ("seal", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            let manifest = repo.seal(
+                &|| read_passphrase(),
+                matches.is_present("RESTRICT_PERMISSIONS"),
+            )?;
+            println!("sealed {} name(s)", manifest.names.len());
+            println!(
+                "signing key: {}",
+                repo.seal_public_key().expect("sealed")
+            );
+        }
+        ("verify-seal", Some(_matches)) => {
+            let repo = options.open_repo(log)?;
+            let manifest = repo.verify_seal()?;
+            eprintln!(
+                "signing key: {}",
+                repo.seal_public_key().expect("sealed")
+            );
+            println!("{}", manifest.to_yaml());
+        }
+        ("prune", Some(matches)) => {
+            let keep = |arg| {
+                usize::from_str(matches.value_of(arg).unwrap())
+                    .expect("invalid number of names to keep")
+            };
+            let policy = lib::RetentionPolicy {
+                prefix: matches.value_of("PREFIX").map(|p| p.to_owned()),
+                keep_last: keep("KEEP_LAST"),
+                keep_daily: keep("KEEP_DAILY"),
+                keep_weekly: keep("KEEP_WEEKLY"),
+                keep_monthly: keep("KEEP_MONTHLY"),
+            };
+            let repo = options.open_repo(log)?;
+
+            let dry_run = matches.is_present("DRY_RUN");
+            let results = if dry_run {
+                repo.plan_prune(&policy)?
+            } else {
+                repo.prune(&policy)?
+            };
+            for name in &results.removed {
+                if dry_run {
+                    println!("would remove {}", name);
+                } else {
+                    println!("removed {}", name);
+                }
+            }
+            for name in &results.undated {
+                println!("kept {} (unknown time)", name);
+            }
+            if matches.is_present("GC") {
+                let grace_secs = u64::from_str(
+                    matches.value_of("GRACE_TIME").unwrap(),
+                ).expect("invalid grace time");
+                repo.gc(grace_secs)?;
+            }
+        }
+        ("maintenance-policy", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            let mut policy = repo.maintenance_policy().clone();
+            let value = |arg| {
+                matches
+                    .value_of(arg)
+                    .map(|v| u64::from_str(v).expect("invalid number"))
+            };
+            let percent = |arg| {
+                matches
+                    .value_of(arg)
+                    .map(|v| u32::from_str(v).expect("invalid percent"))
+            };
+
+            let args = [
+                "GC_EVERY_STORES",
+                "GC_GARBAGE_PERCENT",
+                "GC_GRACE_TIME",
+                "SCRUB_DAILY_PERCENT",
+            ];
+            if args.iter().any(|arg| matches.is_present(arg)) {
+                if let Some(n) = value("GC_EVERY_STORES") {
+                    policy.gc_every_stores = n;
+                }
+                if let Some(p) = percent("GC_GARBAGE_PERCENT") {
+                    policy.gc_garbage_percent = p;
+                }
+                if let Some(secs) = value("GC_GRACE_TIME") {
+                    policy.gc_grace_secs = secs;
+                }
+                if let Some(p) = percent("SCRUB_DAILY_PERCENT") {
+                    policy.scrub_daily_percent = p;
+                }
+                repo.set_maintenance_policy(policy)?;
+            } else {
+                println!("gc every stores: {}", policy.gc_every_stores);
+                println!("gc garbage percent: {}", policy.gc_garbage_percent);
+                println!("gc grace: {}", policy.gc_grace_secs);
+                println!("scrub daily percent: {}", policy.scrub_daily_percent);
+            }
+        }
+        ("runs", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            for run in repo.runs()? {
+                if matches.is_present("YAML") {
+                    print!("{}", run.to_yaml());
+                    println!();
+                } else {
+                    println!(
+                        "{}\t{}\t{}\t{:.3}s",
+                        run.time.to_rfc3339(),
+                        run.command,
+                        if run.ok { "ok" } else { "failed" },
+                        run.secs()
+                    );
+                }
+            }
+        }
+        ("maintain", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            if matches.is_present("HISTORY") {
+                for event in repo.maintenance_state()?.history {
+                    println!(
+                        "{}\t{}\t{}",
+                        event.time.to_rfc3339(),
+                        event.task,
+                        event.result
+                    );
+                }
+                return Ok(());
+            }
+
+            let tasks = repo.maintenance_due()?;
+            if matches.is_present("DRY_RUN") {
+                if tasks.gc {
+                    println!("gc");
+                }
+                for name in &tasks.scrub {
+                    println!("scrub {}", name);
+                }
+                return Ok(());
+            }
+            let dec = if tasks.scrub.is_empty() {
+                None
+            } else {
+                Some(util::unlock_decrypt(&repo, age_identity)?)
+            };
+            let events = repo.maintain(dec.as_ref())?;
+            for event in &events {
+                println!("{}: {}", event.task, event.result);
+            }
+            if events.iter().any(|event| !event.ok) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "maintenance found problems",
+                ));
+            }
+        }
         ("gc", Some(matches)) => {
             let grace_secs = u64::from_str(
                 matches.value_of("GRACE_TIME").unwrap(),
             ).expect("invalid grace time");
-            let repo = Repo::open(&options.url, log)?;
+            let repo = options.open_repo(log)?;
 
+            if matches.is_present("DRY_RUN") {
+                let results = repo.gc_dry_run()?;
+                for (name, chunks) in &results.reachable {
+                    println!("{}: {} chunk(s)", name, chunks);
+                }
+                for (digest, bytes) in &results.unreachable {
+                    println!(
+                        "chunk {} - unreachable, {} bytes",
+                        hex::encode(digest),
+                        bytes
+                    );
+                }
+                println!(
+                    "found {} unreachable chunk(s) of {} stored, {} bytes",
+                    results.unreachable.len(),
+                    results.stored,
+                    results.unreachable_bytes()
+                );
+                return Ok(());
+            }
             repo.gc(grace_secs)?;
         }
-        ("list", Some(_matches)) => {
-            let repo = Repo::open(&options.url, log)?;
+        ("recover", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let results = if matches.is_present("DRY_RUN") {
+                repo.plan_recover()?
+            } else {
+                repo.recover()?
+            };
+            for path in &results.temp_files {
+                println!("temp file {}", path.display());
+            }
+            for gen in &results.dead_generations {
+                println!("dead generation {}", gen);
+            }
+            for path in &results.incomplete_packs {
+                println!("incomplete pack {}", path.display());
+            }
+        }
+        ("repack", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            if let Some(packing) = packing_from_args(matches) {
+                repo.set_packing(packing)?;
+            }
+            let results = repo.repack()?;
+            println!(
+                "packed {} chunk(s) ({} bytes)",
+                results.chunks, results.bytes
+            );
+            println!(
+                "merged {} pack(s), wrote {} pack(s)",
+                results.merged_packs, results.packs
+            );
+        }
+        ("list", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+
+            let names = match matches.value_of("PATTERN") {
+                Some(pattern) => repo.list_names_matching(pattern)?,
+                None => repo.list_names()?,
+            };
+            for name in names {
+                if matches.is_present("LONG") {
+                    let stat = repo.stat(&name)?;
+                    println!("{}", format_stat(&name, &stat, false));
+                } else {
+                    println!("{}", name);
+                }
+            }
+        }
+        ("stat", Some(matches)) => {
+            let repo = options.open_repo(log)?;
 
-            for name in repo.list_names()? {
-                println!("{}", name);
+            for name in matches.values_of("NAME").expect("names missing") {
+                println!("{}", format_stat(name, &repo.stat(name)?, true));
+            }
+        }
+        ("stat-repo", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let stats = repo.stat_repo()?;
+            println!("names: {}", stats.names);
+            if stats.names_without_size > 0 {
+                println!(
+                    "names without size: {} (not in logical bytes)",
+                    stats.names_without_size
+                );
+            }
+            println!("data chunks: {}", stats.data_chunks);
+            println!("index chunks: {}", stats.index_chunks);
+            println!("unreachable chunks: {}", stats.unreachable_chunks);
+            println!("stored bytes: {}", stats.stored_bytes);
+            println!("logical bytes: {}", stats.logical_bytes);
+            println!("average chunk size: {}", stats.average_chunk_size());
+            println!("dedup ratio: {:.2}", stats.dedup_ratio());
+            if matches.is_present("HISTOGRAM") {
+                print_size_histogram(
+                    &stats.stored_sizes,
+                    &stats.logical_sizes,
+                );
+                println!("largest chunks (as stored):");
+                for (digest, len) in &stats.largest_chunks {
+                    println!("  {} {}", hex::encode(digest), len);
+                }
+            }
+        }
+        ("diff", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let results = repo.diff(
+                matches.value_of("NAME_A").expect("name"),
+                matches.value_of("NAME_B").expect("name"),
+            )?;
+            for (what, stats) in &[
+                ("shared", results.shared),
+                ("only in A", results.only_a),
+                ("only in B", results.only_b),
+            ] {
+                println!(
+                    "{}: {} chunk(s), {} bytes ({} bytes of data)",
+                    what, stats.chunks, stats.bytes, stats.data_bytes
+                );
+            }
+        }
+        ("overlap", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let set_a: Vec<_> =
+                matches.values_of("SET_A").expect("set").collect();
+            let set_b: Vec<_> =
+                matches.values_of("SET_B").expect("set").collect();
+            let results = repo.overlap(&set_a, &set_b)?;
+            for (what, stats) in &[
+                ("shared", results.shared),
+                ("only in set A", results.only_a),
+                ("only in set B", results.only_b),
+            ] {
+                println!(
+                    "{}: {} chunk(s), {} bytes",
+                    what, stats.chunks, stats.bytes
+                );
             }
         }
+        #[cfg(feature = "with-fuse")]
+        ("mount", Some(matches)) => {
+            let dir = matches.value_of_os("DIR").expect("dir");
+            let repo = options.open_repo(log.clone())?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            mount::mount(&repo, &dec, Path::new(dir), &log)?;
+        }
         ("verify", Some(matches)) => {
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
+            let repair_from = match matches.value_of("REPAIR_FROM") {
+                Some(other) => {
+                    Some(Repo::open(&util::replica_url(other)?, log.clone())?)
+                }
+                None => None,
+            };
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            let checkpoint_dir = matches.value_of_os("CHECKPOINT_DIR");
+            if let Some(dir) = checkpoint_dir {
+                fs::create_dir_all(dir)?;
+            }
             for name in matches.values_of("NAME").expect("values") {
-                let results = repo.verify(name, &dec)?;
+                let results = match checkpoint_dir {
+                    Some(dir) => repo.verify_resumable(
+                        name,
+                        &dec,
+                        &checkpoint_path(Path::new(dir), name),
+                    )?,
+                    None => repo.verify(name, &dec)?,
+                };
                 println!("scanned {} chunk(s)", results.scanned);
-                println!("found {} corrupted chunk(s)", results.errors.len());
+                println!(
+                    "found {} missing and {} corrupted chunk(s)",
+                    results.missing().len(),
+                    results.corrupted().len()
+                );
+                for err in &results.errors {
+                    println!("chunk {} - {}", hex::encode(&err.0), err.1);
+                }
+                if matches.is_present("REPAIR") {
+                    for digest in results.corrupted() {
+                        match repo.repair_chunk(digest) {
+                            Ok(true) => println!(
+                                "chunk {} - repaired",
+                                hex::encode(digest)
+                            ),
+                            Ok(false) => println!(
+                                "chunk {} - intact as stored, not repaired",
+                                hex::encode(digest)
+                            ),
+                            Err(e) => println!(
+                                "chunk {} - can't repair: {}",
+                                hex::encode(digest),
+                                e
+                            ),
+                        }
+                    }
+                }
+                if let Some(ref other) = repair_from {
+                    repair_from_other(&repo, other, name, &dec, results)?;
+                }
+                if matches.is_present("REPLICAS") {
+                    let results = repo.verify_replicas(name)?;
+                    println!(
+                        "found {} chunk(s) missing on replicas",
+                        results.errors.len()
+                    );
+                    for err in results.errors {
+                        println!("chunk {} - {}", hex::encode(&err.0), err.1);
+                    }
+                }
+            }
+        }
+        ("digest", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            for name in matches.values_of("NAME").expect("values") {
+                let results = repo.read_verify(name, &dec)?;
+                println!("{}  {}", hex::encode(&results.digest), name);
+            }
+        }
+        ("reconcile", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let inventory = util::read_inventory(
+                io::BufReader::new(fs::File::open(
+                    matches.value_of_os("INVENTORY").expect("inventory"),
+                )?),
+                matches.value_of("PREFIX").expect("prefix"),
+            )?;
+            let results = repo.reconcile(inventory)?;
+            println!("scanned {} object(s)", results.scanned);
+            println!("found {} missing chunk(s)", results.missing.len());
+            for digest in &results.missing {
+                println!("chunk {} - missing", hex::encode(digest));
+            }
+            println!(
+                "found {} unexpected object(s)",
+                results.unexpected.len()
+            );
+            for path in &results.unexpected {
+                println!("{}", path.display());
+            }
+            if !results.missing.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "chunks missing from the inventory",
+                ));
+            }
+        }
+        ("check-refs", Some(_matches)) => {
+            let repo = options.open_repo(log)?;
+            let results = repo.check_refs()?;
+            println!("found {} orphan chunk(s)", results.orphans.len());
+            for digest in &results.orphans {
+                println!("chunk {} - orphan", hex::encode(digest));
+            }
+            println!("found {} dangling chunk(s)", results.dangling.len());
+            for (digest, names) in &results.dangling {
+                println!(
+                    "chunk {} - missing, used by {}",
+                    hex::encode(digest),
+                    names.join(", ")
+                );
+            }
+            for err in &results.errors {
+                println!("chunk {} - {}", hex::encode(&err.0), err.1);
+            }
+            if !results.dangling.is_empty() || !results.errors.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunks referred to are missing or unreadable",
+                ));
+            }
+        }
+        ("check-index", Some(matches)) => {
+            let repo = options.open_repo(log)?;
+            let names = match matches.values_of("NAME") {
+                Some(names) => names.map(|name| name.to_owned()).collect(),
+                None => repo.list_names()?,
+            };
+            let mut corrupted = 0;
+            for name in names {
+                let results = repo.check_index(&name)?;
+                println!(
+                    "{}: scanned {} chunk(s), found {} problem(s)",
+                    name,
+                    results.scanned,
+                    results.errors.len()
+                );
+                corrupted += results.errors.len();
                 for err in results.errors {
                     println!("chunk {} - {}", hex::encode(&err.0), err.1);
                 }
             }
+            if corrupted > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "index corruption found",
+                ));
+            }
         }
         _ => panic!("Unrecognized subcommand"),
     }
@@ -0,0 +1,385 @@
+//! Read-only FUSE mount of the names (`rdedup mount`, Linux only)
+//!
+//! Every name is a file in the root directory of the mount. Data is read
+//! on demand: the index of a name when it's opened, and data chunks as
+//! reads get to them (see `lib::NameReader`). Without chunk lengths in the
+//! index (`rdedup init --bare-index`), the offset of a chunk is known only
+//! after all the chunks before it were read, so reads far ahead of what was
+//! read so far have to read everything before them (once); sequential
+//! reads (`cp`, `grep`) don't suffer from it.
+//!
+//! The FUSE protocol is spoken by `fuser`, serving one request at a time.
+use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs,
+    Request, FUSE_ROOT_ID,
+};
+use lib::{DecryptHandle, NameReader, NameStat, Repo};
+use libc;
+use slog::Logger;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long the kernel can cache entries and attributes for; names can be
+/// added and removed while mounted
+const TTL: Duration = Duration::from_secs(1);
+
+/// Mount the names of `repo` at `mountpoint`, and serve reads until it's
+/// unmounted
+///
+/// Mounting as a regular user is done by `fusermount`.
+pub fn mount(
+    repo: &Repo,
+    dec: &DecryptHandle,
+    mountpoint: &Path,
+    log: &Logger,
+) -> io::Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::NoSuid,
+        MountOption::NoDev,
+        MountOption::FSName("rdedup".into()),
+        MountOption::Subtype("rdedup".into()),
+    ];
+    info!(log, "Mounting"; "mountpoint" => %mountpoint.display());
+    let fs = NamesFs::new(repo, dec, log.clone());
+    fuser::mount2(fs, mountpoint, &options)
+}
+
+/// Errno to reply with for `err`
+fn errno(err: &io::Error) -> i32 {
+    if let Some(errno) = err.raw_os_error() {
+        return errno;
+    }
+    match err.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        _ => libc::EIO,
+    }
+}
+
+/// Name opened for reading
+struct OpenName<'a> {
+    /// Times it's open
+    opens: usize,
+    reader: NameReader<'a>,
+}
+
+impl<'a> OpenName<'a> {
+    /// Up to `size` bytes at `offset`
+    fn read(&mut self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        (&mut self.reader).take(size as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Names of a repository, as a file system
+struct NamesFs<'a> {
+    repo: &'a Repo,
+    dec: &'a DecryptHandle,
+    log: Logger,
+    uid: u32,
+    gid: u32,
+    /// Names seen so far, by inode (minus 2)
+    names: Vec<String>,
+    inodes: HashMap<String, u64>,
+    open_names: HashMap<u64, OpenName<'a>>,
+    /// Entries of open directories (the root), by handle
+    open_dirs: HashMap<u64, Vec<(u64, FileType, String)>>,
+    next_handle: u64,
+}
+
+impl<'a> NamesFs<'a> {
+    fn new(repo: &'a Repo, dec: &'a DecryptHandle, log: Logger) -> Self {
+        NamesFs {
+            repo,
+            dec,
+            log,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            names: vec![],
+            inodes: HashMap::new(),
+            open_names: HashMap::new(),
+            open_dirs: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    fn attr(&self, ino: u64, kind: FileType, size: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm: if kind == FileType::Directory {
+                0o555
+            } else {
+                0o444
+            },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    fn name_attr(&self, ino: u64, stat: &NameStat) -> FileAttr {
+        let mut attr =
+            self.attr(ino, FileType::RegularFile, stat.size.unwrap_or(0));
+        if let Some(created) = stat.created.filter(|c| c.timestamp() >= 0) {
+            let time = UNIX_EPOCH
+                + Duration::new(
+                    created.timestamp() as u64,
+                    created.timestamp_subsec_nanos(),
+                );
+            attr.atime = time;
+            attr.mtime = time;
+            attr.ctime = time;
+        }
+        attr
+    }
+
+    /// Inode of `name`, assigned on first sight
+    fn inode(&mut self, name: &str) -> u64 {
+        if let Some(&ino) = self.inodes.get(name) {
+            return ino;
+        }
+        self.names.push(name.to_owned());
+        let ino = self.names.len() as u64 + 1;
+        self.inodes.insert(name.to_owned(), ino);
+        ino
+    }
+
+    fn name_of(&self, ino: u64) -> Result<&str, i32> {
+        ino.checked_sub(2)
+            .and_then(|i| self.names.get(i as usize))
+            .map(|name| name.as_str())
+            .ok_or(libc::ENOENT)
+    }
+
+    fn stat(&self, name: &str) -> Result<NameStat, i32> {
+        self.repo.stat(name).map_err(|e| errno(&e))
+    }
+
+    fn lookup_name(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<FileAttr, i32> {
+        if parent != FUSE_ROOT_ID {
+            return Err(libc::ENOENT);
+        }
+        let name = name.to_str().ok_or(libc::ENOENT)?;
+        let stat = self.stat(name)?;
+        let ino = self.inode(name);
+        Ok(self.name_attr(ino, &stat))
+    }
+
+    fn getattr_ino(&self, ino: u64) -> Result<FileAttr, i32> {
+        if ino == FUSE_ROOT_ID {
+            return Ok(self.attr(ino, FileType::Directory, 0));
+        }
+        let stat = self.stat(self.name_of(ino)?)?;
+        Ok(self.name_attr(ino, &stat))
+    }
+
+    /// Open flags for the reply
+    fn open_name(&mut self, ino: u64, flags: i32) -> Result<u32, i32> {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        let name = self.name_of(ino)?.to_owned();
+        let stat = self.stat(&name)?;
+        if let Some(open_name) = self.open_names.get_mut(&ino) {
+            open_name.opens += 1;
+        } else {
+            let reader =
+                self.repo.open_reader(&name, self.dec).map_err(|e| {
+                    warn!(self.log, "Can't read the index"; "name" => &name,
+                          "err" => %e);
+                    errno(&e)
+                })?;
+            self.open_names.insert(ino, OpenName { opens: 1, reader });
+        }
+        // without the size, read until the end of the data
+        Ok(if stat.size.is_some() {
+            FOPEN_KEEP_CACHE
+        } else {
+            FOPEN_DIRECT_IO
+        })
+    }
+
+    fn read_name(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        size: usize,
+    ) -> Result<Vec<u8>, i32> {
+        let open_name = self.open_names.get_mut(&ino).ok_or(libc::EBADF)?;
+        open_name.read(offset, size).map_err(|e| {
+            warn!(self.log, "Can't read";
+                  "name" => self.names[ino as usize - 2].as_str(),
+                  "offset" => offset, "err" => %e);
+            errno(&e)
+        })
+    }
+
+    /// Entries are listed when the directory is opened
+    fn open_dir(&mut self, ino: u64) -> Result<u64, i32> {
+        if ino != FUSE_ROOT_ID {
+            return Err(libc::ENOTDIR);
+        }
+        let names = self.repo.list_names().map_err(|e| errno(&e))?;
+        let mut entries = vec![
+            (FUSE_ROOT_ID, FileType::Directory, ".".to_owned()),
+            (FUSE_ROOT_ID, FileType::Directory, "..".to_owned()),
+        ];
+        // names with `/` can't be files
+        for name in names.into_iter().filter(|name| !name.contains('/')) {
+            entries.push((self.inode(&name), FileType::RegularFile, name));
+        }
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.open_dirs.insert(handle, entries);
+        Ok(handle)
+    }
+}
+
+impl<'a> Filesystem for NamesFs<'a> {
+    fn lookup(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.lookup_name(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: Option<u64>,
+        reply: ReplyAttr,
+    ) {
+        match self.getattr_ino(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.open_name(ino, flags) {
+            Ok(open_flags) => reply.opened(0, open_flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_name(ino, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let closed = match self.open_names.get_mut(&ino) {
+            Some(open_name) => {
+                open_name.opens -= 1;
+                open_name.opens == 0
+            }
+            None => false,
+        };
+        if closed {
+            self.open_names.remove(&ino);
+        }
+        reply.ok()
+    }
+
+    fn opendir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _flags: i32,
+        reply: ReplyOpen,
+    ) {
+        match self.open_dir(ino) {
+            Ok(handle) => reply.opened(handle, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Entries from the offset on, as many as fit
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.open_dirs.get(&fh) {
+            Some(entries) => entries,
+            None => return reply.error(libc::EBADF),
+        };
+        for (i, &(ino, kind, ref name)) in
+            entries.iter().enumerate().skip(offset as usize)
+        {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.open_dirs.remove(&fh);
+        reply.ok()
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        reply.statfs(0, 0, 0, self.names.len() as u64, 0, 4096, 255, 4096)
+    }
+}
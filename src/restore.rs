@@ -0,0 +1,139 @@
+//! `rdedup-restore` - restore-only subset of `rdedup`
+//!
+//! Can only read from an existing *repo* (`load`, `list`, `verify`,
+//! `check-index`), which keeps it small enough for rescue media. See
+//! "Restore-only binary" in the README for building it statically.
+
+extern crate clap;
+extern crate hex;
+extern crate rdedup_lib as lib;
+extern crate rpassword;
+#[macro_use]
+extern crate slog;
+extern crate url;
+
+use clap::{Arg, SubCommand};
+use lib::Repo;
+use std::{io, process};
+
+// passphrase creation is only used by `rdedup`
+#[allow(dead_code)]
+mod util;
+
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_size(s: String) -> Result<(), String> {
+    util::parse_size(&s)
+        .map(|_| ())
+        .ok_or_else(|| "Can't parse a human readable byte-size value".into())
+}
+
+fn run() -> io::Result<()> {
+    let matches = clap::App::new("rdedup-restore")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("Dawid Ciężarkiewicz <dpc@dpc.pw>")
+        .about("Restore data from rdedup repository")
+        .arg(Arg::with_name("REPO_DIR").short("d").long("dir").takes_value(true).value_name("PATH")
+             .help("Path to rdedup repository. Override `RDEDUP_DIR` environment variable"))
+        .arg(Arg::with_name("REPO_URI").short("u").long("repo").takes_value(true).value_name("URI").conflicts_with("REPO_DIR")
+             .help("Rdedup repository URI. Overrides the `RDEDUP_URI` environment variable"))
+        .arg(Arg::with_name("AGE_IDENTITY").long("age-identity").takes_value(true).value_name("PATH")
+             .help("Unlock repository with an age identity file, instead of the passphrase"))
+        .subcommand(SubCommand::with_name("load").about("Load data from repository").display_order(0)
+                    .arg(Arg::with_name("MAX_RATE").long("max-rate").takes_value(true).value_name("N").validator(validate_size)
+                         .help("Read at most N bytes per second from the repository (eg. '50M')"))
+                    .arg(Arg::with_name("BURST").long("burst").takes_value(true).value_name("N").validator(validate_size).requires("MAX_RATE")
+                         .help("Allow reading N bytes at once after a pause, above the --max-rate (default: one second worth)"))
+                    .arg(Arg::with_name("SKIP_CORRUPT").long("skip-corrupt")
+                         .help("Leave out data that can't be read (eg. corrupted) and continue, instead of failing"))
+                    .arg(Arg::with_name("NAME").required(true).help("Name to load from")))
+        .subcommand(SubCommand::with_name("list").visible_alias("ls").about("List names stored in the repository").display_order(1)
+                    .arg(Arg::with_name("PATTERN").help("Only list names starting with PATTERN, or matching it if it's a glob (eg. 'home-*-01')")))
+        .subcommand(SubCommand::with_name("verify").about("Verify integrity of data stored in the repository")
+                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to verify")))
+        .subcommand(SubCommand::with_name("check-index").about("Check integrity of the index only (cheap, doesn't need the passphrase)")
+                    .arg(Arg::with_name("NAME").multiple(true).help("Names to check (all by default)")))
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .get_matches();
+
+    let url = util::repo_url(&matches)?;
+    let age_identity = matches.value_of_os("AGE_IDENTITY");
+    let log = slog::Logger::root(slog::Discard, o!());
+    let mut repo = Repo::open(&url, log)?;
+
+    match matches.subcommand() {
+        ("load", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("name agument missing");
+            repo.set_read_rate(util::read_rate_from_args(matches))?;
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            util::load(matches, &repo, name, &dec)?;
+        }
+        ("list", Some(matches)) => {
+            let names = match matches.value_of("PATTERN") {
+                Some(pattern) => repo.list_names_matching(pattern)?,
+                None => repo.list_names()?,
+            };
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        ("verify", Some(matches)) => {
+            let dec = util::unlock_decrypt(&repo, age_identity)?;
+            let mut corrupted = 0;
+            for name in matches.values_of("NAME").expect("values") {
+                let results = repo.verify(name, &dec)?;
+                println!("scanned {} chunk(s)", results.scanned);
+                println!(
+                    "found {} missing and {} corrupted chunk(s)",
+                    results.missing().len(),
+                    results.corrupted().len()
+                );
+                corrupted += results.errors.len();
+                for err in results.errors {
+                    println!("chunk {} - {}", hex::encode(&err.0), err.1);
+                }
+            }
+            if corrupted > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "data corruption found",
+                ));
+            }
+        }
+        ("check-index", Some(matches)) => {
+            let names = match matches.values_of("NAME") {
+                Some(names) => names.map(|name| name.to_owned()).collect(),
+                None => repo.list_names()?,
+            };
+            let mut corrupted = 0;
+            for name in names {
+                let results = repo.check_index(&name)?;
+                println!(
+                    "{}: scanned {} chunk(s), found {} problem(s)",
+                    name,
+                    results.scanned,
+                    results.errors.len()
+                );
+                corrupted += results.errors.len();
+                for err in results.errors {
+                    println!("chunk {} - {}", hex::encode(&err.0), err.1);
+                }
+            }
+            if corrupted > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "index corruption found",
+                ));
+            }
+        }
+        _ => panic!("Unrecognized subcommand"),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        process::exit(-1);
+    }
+}
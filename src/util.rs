@@ -1,6 +1,19 @@
+use clap::ArgMatches;
+use hex;
+use lib::{age, DecryptHandle, Progress, ProgressInfo, Rate, Repo};
+use lib::{ReadOptions, SecretKey};
 use rpassword;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::mem;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::{fs, env, io};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, env, io, process};
+use url::percent_encoding::percent_decode;
+use url::Url;
 
 /// Parse human-readable size string
 ///
@@ -59,6 +72,186 @@ fn test_parse_size() {
     }
 }
 
+// Url parse with `io::Result` shortcut
+fn parse_url(s: &str) -> io::Result<Url> {
+    Url::parse(s).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("URI parsing error : {}", e.description()),
+        )
+    })
+}
+
+/// Repository location from the command line or the environment
+///
+/// Exits if it's not specified at all.
+pub fn repo_url(matches: &ArgMatches) -> io::Result<Url> {
+    let url = if let Some(loc) = matches.value_of_os("REPO_URI") {
+        let s = loc.to_os_string().into_string().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("URI not valid UTF-8 string"),
+            )
+        })?;
+        parse_url(&s)?
+    } else if let Some(dir) = matches.value_of_os("REPO_DIR") {
+        Url::from_file_path(dir).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("URI parsing error: {}", dir.to_string_lossy()),
+            )
+        })?
+    } else if let Some(loc) = env::var_os("RDEDUP_URI") {
+        if env::var_os("RDEDUP_DIR").is_some() {
+            eprintln!(
+                "Can't use both RDEDUP_REPOSITORY and RDEDUP_DIR  at the same time"
+            );
+            process::exit(-1);
+        }
+
+        let s = loc.to_os_string().into_string().map_err(|_e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("URI not valid UTF-8 string"),
+            )
+        })?;
+        parse_url(&s)?
+    } else if let Some(dir) = env::var_os("RDEDUP_DIR") {
+        Url::from_file_path(&dir).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("URI parsing error: {}", dir.to_string_lossy()),
+            )
+        })?
+    } else {
+        eprintln!("Repository location not specified");
+        process::exit(-1);
+    };
+    Ok(url)
+}
+
+/// Location of a replica (or another repository): a URL, or a path of a
+/// directory
+pub fn replica_url(s: &str) -> io::Result<Url> {
+    if s.contains("://") {
+        return parse_url(s);
+    }
+    let path = env::current_dir()?.join(s);
+    Url::from_file_path(&path).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("URI parsing error: {}", s),
+        )
+    })
+}
+
+/// Fields of a CSV `line`, quoted with `"` like in inventory reports
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(mem::replace(&mut field, String::new()))
+            }
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Paths (relative to `prefix`) of the objects in a CSV inventory report
+///
+/// GCS reports start with a header, and have object names in the `name`
+/// column. S3 reports have no header, and have URL-encoded object keys in
+/// the second column. Objects outside of `prefix` are skipped.
+pub fn read_inventory<R: BufRead>(
+    reader: R,
+    prefix: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let prefix = prefix.trim_matches('/');
+    // index of the column with the keys, and are they URL-encoded
+    let mut column = None;
+    let mut paths = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let fields = csv_fields(line);
+        let (index, encoded) = match column {
+            Some(column) => column,
+            None => {
+                if let Some(index) = fields.iter().position(|f| f == "name") {
+                    column = Some((index, false));
+                    continue;
+                }
+                column = Some((1, true));
+                (1, true)
+            }
+        };
+        let key = fields.get(index).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed inventory line: {}", line),
+            )
+        })?;
+        let key = if encoded {
+            percent_decode(key.replace('+', "%20").as_bytes())
+                .decode_utf8()
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed object key: {}", key),
+                    )
+                })?
+                .into_owned()
+        } else {
+            key.clone()
+        };
+        let rel = if prefix.is_empty() {
+            &key[..]
+        } else if key.starts_with(prefix) && key[prefix.len()..].starts_with('/')
+        {
+            &key[prefix.len() + 1..]
+        } else {
+            continue;
+        };
+        // skip "directory" placeholders
+        if rel.is_empty() || rel.ends_with('/') {
+            continue;
+        }
+        paths.push(PathBuf::from(rel));
+    }
+    Ok(paths)
+}
+
+#[test]
+fn test_read_inventory() {
+    let s3 = "\"bucket\",\"backup/repo/a%2Bb/c+d\",\"10\"\n\
+              \"bucket\",\"backup/repo/\",\"0\"\n\
+              \"bucket\",\"backup/other/x\",\"10\"\n";
+    assert_eq!(
+        read_inventory(s3.as_bytes(), "backup/repo/").unwrap(),
+        vec![PathBuf::from("a+b/c d")]
+    );
+
+    let gcs = "bucket,name,size\r\nbucket,\"x,\"\"y\"\"\",1\r\n";
+    assert_eq!(
+        read_inventory(gcs.as_bytes(), "").unwrap(),
+        vec![PathBuf::from("x,\"y\"")]
+    );
+}
+
 fn read_passphrase_env() -> io::Result<Option<String>> {
     if let Ok(pass) = env::var("RDEDUP_PASSPHRASE") {
         eprint!("Using passphrase set in RDEDUP_PASSPHRASE\n");
@@ -71,6 +264,175 @@ fn read_passphrase_env() -> io::Result<Option<String>> {
     Ok(None)
 }
 
+/// Secret key set with `RDEDUP_SECRET` or `RDEDUP_SECRET_FILE`, if any
+pub fn read_secret_key_env() -> io::Result<Option<SecretKey>> {
+    if let Ok(sec_key) = env::var("RDEDUP_SECRET") {
+        eprintln!("Using secret key set in RDEDUP_SECRET");
+        return Ok(Some(SecretKey::from_hex_string(sec_key)?));
+    }
+    if let Ok(file_path) = env::var("RDEDUP_SECRET_FILE") {
+        eprintln!("Using secret key from file set in RDEDUP_SECRET_FILE");
+        return Ok(Some(SecretKey::from_file(file_path)?));
+    }
+    Ok(None)
+}
+
+/// Unlock the names of the repository (see `Repo::unlock_names`) with the
+/// secret key or passphrase
+pub fn unlock_names(repo: &mut Repo) -> io::Result<()> {
+    let sec_key = match read_secret_key_env()? {
+        Some(sec_key) => sec_key,
+        None => repo.secret_key(&|| read_passphrase())?,
+    };
+    repo.unlock_names(&sec_key)
+}
+
+/// Unlock the repository for reading with an age identity, secret key or
+/// passphrase (in that order)
+pub fn unlock_decrypt(
+    repo: &Repo,
+    age_identity: Option<&OsStr>,
+) -> io::Result<DecryptHandle> {
+    if let Some(path) = age_identity {
+        let identity = age::Identity::parse(&fs::read_to_string(path)?)?;
+        repo.unlock_decrypt_age(&identity)
+    } else if let Some(sec_key) = read_secret_key_env()? {
+        repo.unlock_decrypt_secret_key(&sec_key)
+    } else {
+        repo.unlock_decrypt(&|| read_passphrase())
+    }
+}
+
+/// Write the data of `name` to stdout
+///
+/// With `--skip-corrupt`, data chunks that can't be read are left out
+/// (and listed on stderr), and the command fails only at the end.
+pub fn load(
+    matches: &ArgMatches,
+    repo: &Repo,
+    name: &str,
+    dec: &DecryptHandle,
+) -> io::Result<()> {
+    let size = |arg| {
+        matches
+            .value_of(arg)
+            .map(|s| parse_size(s).expect("Invalid size option"))
+    };
+    if matches.is_present("OFFSET") || matches.is_present("LENGTH") {
+        return repo.read_range(
+            name,
+            size("OFFSET").unwrap_or(0),
+            size("LENGTH").unwrap_or(::std::u64::MAX),
+            &mut io::stdout(),
+            dec,
+        );
+    }
+    if !matches.is_present("SKIP_CORRUPT") {
+        let options = ReadOptions {
+            progress: progress_from_args(matches),
+            cancel: None,
+            decoded_cache_size: size("DECODED_CACHE"),
+            memory_budget: size("MEMORY_BUDGET"),
+        };
+        return repo.read_with_options(name, &mut io::stdout(), dec, &options);
+    }
+    let results = repo.read_lossy(name, &mut io::stdout(), dec)?;
+    for chunk in &results.skipped {
+        eprintln!(
+            "skipped chunk {} at offset {} - {}",
+            hex::encode(&chunk.digest),
+            chunk.offset,
+            chunk.error
+        );
+    }
+    if !results.skipped.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} chunk(s) skipped, {} bytes written",
+                results.skipped.len(),
+                results.bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Seconds between progress lines of `--progress`
+const PROGRESS_INTERVAL_SECS: u64 = 1;
+
+/// Progress printed to standard error, with `--progress`
+pub fn progress_from_args(matches: &ArgMatches) -> Option<Arc<dyn Progress>> {
+    if !matches.is_present("PROGRESS") {
+        return None;
+    }
+    let last = Mutex::new(Instant::now());
+    Some(Arc::new(move |info: &ProgressInfo| {
+        let mut last = last.lock().unwrap();
+        if last.elapsed() < Duration::from_secs(PROGRESS_INTERVAL_SECS) {
+            return;
+        }
+        *last = Instant::now();
+        eprintln!("{}", format_progress(info));
+    }))
+}
+
+fn format_progress(info: &ProgressInfo) -> String {
+    let mut line = match info.total {
+        Some(total) if total > 0 => format!(
+            "{:.1}% ({} of {} bytes), {} chunks",
+            100.0 * info.bytes as f64 / total as f64,
+            info.bytes,
+            total,
+            info.chunks
+        ),
+        _ => format!("{} bytes, {} chunks", info.bytes, info.chunks),
+    };
+    if info.dedup_hits > 0 {
+        line += &format!(" ({} already stored)", info.dedup_hits);
+    }
+    line
+}
+
+#[test]
+fn test_format_progress() {
+    let mut info = ProgressInfo {
+        bytes: 512,
+        total: Some(2048),
+        chunks: 3,
+        dedup_hits: 0,
+    };
+    assert_eq!(format_progress(&info), "25.0% (512 of 2048 bytes), 3 chunks");
+    info.total = None;
+    info.dedup_hits = 2;
+    assert_eq!(
+        format_progress(&info),
+        "512 bytes, 3 chunks (2 already stored)"
+    );
+}
+
+/// Read rate limit from `--max-rate` and `--burst`, if given
+pub fn read_rate_from_args(matches: &ArgMatches) -> Option<Rate> {
+    let size = |arg| {
+        matches
+            .value_of(arg)
+            .map(|s| parse_size(s).expect("Invalid size option"))
+    };
+    size("MAX_RATE").map(|bytes_per_sec| Rate {
+        bytes_per_sec,
+        burst: size("BURST").unwrap_or(bytes_per_sec),
+    })
+}
+
+/// Rate limit from option `arg` (with a burst of one second worth), if
+/// given
+pub fn rate_from_arg(matches: &ArgMatches, arg: &str) -> Option<Rate> {
+    matches
+        .value_of(arg)
+        .map(|s| Rate::new(parse_size(s).expect("Invalid size option")))
+}
+
+
 pub fn read_passphrase() -> io::Result<String> {
     if let Some(pass) = read_passphrase_env()? {
         return Ok(pass);